@@ -55,6 +55,65 @@ pub fn on_axis_motion(key_state: &mut u16, axis: Axis, val: i16) {
     }
 }
 
+/// Rotation rate for the gyro sensor, from the keyboard or the right stick of a game controller.
+/// The sdl2 crate doesn't expose the motion sensors of game controllers yet.
+#[derive(Debug, Default)]
+pub struct GyroInput {
+    counter_clockwise: bool,
+    clockwise: bool,
+    axis: i16,
+}
+
+impl GyroInput {
+    pub fn on_keyboard_key(&mut self, scancode: Scancode, pressed: bool) {
+        match scancode {
+            Scancode::Q => self.counter_clockwise = pressed,
+            Scancode::W => self.clockwise = pressed,
+            _ => {}
+        }
+    }
+
+    pub fn on_axis_motion(&mut self, axis: Axis, val: i16) {
+        if axis == Axis::RightX {
+            self.axis = val;
+        }
+    }
+
+    pub fn rate(&self) -> i16 {
+        const KEY_RATE: i16 = i16::MAX / 2;
+        let dead_zone = 4_000;
+        match (self.counter_clockwise, self.clockwise) {
+            (true, false) => -KEY_RATE,
+            (false, true) => KEY_RATE,
+            _ if self.axis > dead_zone || self.axis < -dead_zone => self.axis,
+            _ => 0,
+        }
+    }
+}
+
+/// Tilt for the tilt sensor, from the left stick of a game controller
+#[derive(Debug, Default)]
+pub struct TiltInput {
+    x: i16,
+    y: i16,
+}
+
+impl TiltInput {
+    /// Returns false for the axes that don't tilt the cartridge
+    pub fn on_axis_motion(&mut self, axis: Axis, val: i16) -> bool {
+        match axis {
+            Axis::LeftX => self.x = val,
+            Axis::LeftY => self.y = val,
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn tilt(&self) -> (i16, i16) {
+        (self.x, self.y)
+    }
+}
+
 fn scancode_to_keypad(scancode: Scancode) -> Option<gba_keypad::Keys> {
     match scancode {
         Scancode::Up => Some(gba_keypad::Keys::Up),
@@ -1,11 +1,13 @@
 use sdl2::controller::Button;
 use sdl2::event::Event;
+use sdl2::haptic::Haptic;
 use sdl2::keyboard::Scancode;
+use sdl2::HapticSubsystem;
 
 use structopt::StructOpt;
 
 use std::fs;
-use std::io::Cursor;
+use std::io::{self, BufWriter, Cursor, Write};
 use std::path::Path;
 use std::time;
 
@@ -20,9 +22,12 @@ mod video;
 
 use rustboyadvance_core::prelude::*;
 
+use rustboyadvance_utils::elf::read_line_table;
 use rustboyadvance_utils::FpsCounter;
 
 const LOG_DIR: &str = ".logs";
+const RUMBLE_STRENGTH: f32 = 0.75;
+const SDL_HAPTIC_INFINITY: u32 = u32::MAX;
 
 fn ask_download_bios() {
     const OPEN_SOURCE_BIOS_URL: &str =
@@ -41,6 +46,19 @@ fn load_bios(bios_path: &Path) -> Box<[u8]> {
     }
 }
 
+fn open_haptic(haptic_subsystem: &Option<HapticSubsystem>, joystick_index: u32) -> Option<Haptic> {
+    match haptic_subsystem
+        .as_ref()?
+        .open_from_joystick_id(joystick_index)
+    {
+        Ok(haptic) => Some(haptic),
+        Err(e) => {
+            info!("Game controller doesn't support rumble: {}", e);
+            None
+        }
+    }
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     fs::create_dir_all(LOG_DIR)
         .unwrap_or_else(|_| panic!("could not create log directory ({})", LOG_DIR));
@@ -55,6 +73,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let opts = options::Options::from_args();
 
+    if let Some(path) = &opts.export_save {
+        let mut cartridge = opts.cartridge_from_opts()?;
+        cartridge.export_save(path, opts.save_byte_order)?;
+        info!("Exported the save to {}", path.display());
+        return Ok(());
+    }
+
     info!("Initializing SDL2 context");
     let sdl_context = sdl2::init().expect("failed to initialize sdl2");
 
@@ -78,15 +103,24 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
+    let haptic_subsystem = sdl_context.haptic().ok();
+    let mut haptic = available_controllers
+        .first()
+        .and_then(|&id| open_haptic(&haptic_subsystem, id));
+
     let mut renderer = video::init(&sdl_context)?;
     let (audio_interface, mut _sdl_audio_device) = audio::create_audio_player(&sdl_context)?;
     let rom_name = opts.rom_name();
 
     let bios_bin = load_bios(&opts.bios);
 
+    let cartridge = opts.cartridge_from_opts()?;
+    // Savestates don't include the ROM, keep the patched one around for restoring them
+    let rom_bin: Box<[u8]> = Box::from(cartridge.get_rom_bytes());
+
     let mut gba = Box::new(GameBoyAdvance::new(
         bios_bin.clone(),
-        opts.cartridge_from_opts()?,
+        cartridge,
         audio_interface,
     ));
 
@@ -102,14 +136,33 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     //     normal_panic(panic_info);
     // }));
 
-    // Skip the BIOS animation
-    gba.skip_bios();
+    // Skip the BIOS animation, or the multiboot transfer
+    match opts.multiboot_from_opts()? {
+        Some(image) => gba.boot_multiboot(&image, opts.boot_mode),
+        None => gba.skip_bios(),
+    }
+
+    if let Some(tracer) = opts.tracer_from_opts()? {
+        gba.cpu.start_trace(tracer);
+    }
+
+    gba.set_idle_loop_skipping(opts.skip_idle_loops);
+
+    if opts.profile.is_some() {
+        gba.start_profiling();
+    }
+
+    if opts.coverage.is_some() {
+        gba.start_coverage();
+    }
 
     if opts.gdbserver {
-        gba.start_gdbserver(opts.gdbserver_port);
+        gba.start_gdbserver(opts.gdbserver_addr());
     }
 
     let mut vsync = true;
+    let mut gyro_input = input::GyroInput::default();
+    let mut tilt_input = input::TiltInput::default();
     let mut fps_counter = FpsCounter::default();
     const FRAME_TIME: time::Duration = time::Duration::new(0, 1_000_000_000u32 / 60);
     let mut event_pump = sdl_context.event_pump()?;
@@ -122,7 +175,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     ..
                 } => match scancode {
                     Scancode::Space => vsync = false,
-                    k => input::on_keyboard_key_down(gba.get_key_state_mut(), k),
+                    k => {
+                        input::on_keyboard_key_down(gba.get_key_state_mut(), k);
+                        gyro_input.on_keyboard_key(k, true);
+                    }
                 },
                 Event::KeyUp {
                     scancode: Some(scancode),
@@ -137,7 +193,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             .unwrap();
                         info!("ending debugger...")
                     }
-                    Scancode::F2 => gba.start_gdbserver(opts.gdbserver_port),
+                    Scancode::F2 => gba.start_gdbserver(opts.gdbserver_addr()),
                     Scancode::F5 => {
                         info!("Saving state ...");
                         let save = gba.save_state()?;
@@ -152,23 +208,31 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         if opts.savestate_path().is_file() {
                             let save = read_bin_file(&opts.savestate_path())?;
                             info!("Restoring state from {:?}...", opts.savestate_path());
+                            // The save file is read back along with the state
+                            gba.flush_backup();
                             let (audio_interface, _sdl_audio_device_new) =
                                 audio::create_audio_player(&sdl_context)?;
                             _sdl_audio_device = _sdl_audio_device_new;
-                            let rom = opts.read_rom()?.into_boxed_slice();
                             gba = Box::new(GameBoyAdvance::from_saved_state(
                                 &save,
                                 bios_bin.clone(),
-                                rom,
+                                rom_bin.clone(),
                                 audio_interface,
                             )?);
+                            gba.set_idle_loop_skipping(opts.skip_idle_loops);
+                            if let Some(haptic) = &mut haptic {
+                                haptic.rumble_stop();
+                            }
                             info!("Restored!");
                         } else {
                             info!("Savestate not created, please create one by pressing F5");
                         }
                     }
                     Scancode::Space => vsync = true,
-                    k => input::on_keyboard_key_up(gba.get_key_state_mut(), k),
+                    k => {
+                        input::on_keyboard_key_up(gba.get_key_state_mut(), k);
+                        gyro_input.on_keyboard_key(k, false);
+                    }
                 },
                 Event::ControllerButtonDown { button, .. } => match button {
                     Button::RightStick => vsync = !vsync,
@@ -178,7 +242,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     input::on_controller_button_up(gba.get_key_state_mut(), button);
                 }
                 Event::ControllerAxisMotion { axis, value, .. } => {
-                    input::on_axis_motion(gba.get_key_state_mut(), axis, value);
+                    // Tilt games take the left stick, which otherwise doubles as the dpad
+                    if !(gba.has_tilt_sensor() && tilt_input.on_axis_motion(axis, value)) {
+                        input::on_axis_motion(gba.get_key_state_mut(), axis, value);
+                    }
+                    gyro_input.on_axis_motion(axis, value);
                 }
                 Event::ControllerDeviceRemoved { which, .. } => {
                     let removed = if let Some(active_controller) = &active_controller {
@@ -192,6 +260,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             .unwrap();
                         info!("Removing game controller: {:?}", name);
                         active_controller = None;
+                        haptic = None;
                     }
                 }
                 Event::ControllerDeviceAdded { which, .. } => {
@@ -199,6 +268,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         let controller = controller_subsystem.open(which)?;
                         info!("Adding game controller: {}", controller.name());
                         active_controller = Some(controller);
+                        haptic = open_haptic(&haptic_subsystem, which);
                     }
                 }
                 Event::Quit { .. } => break 'running,
@@ -209,6 +279,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
 
+        gba.set_gyro_rate(gyro_input.rate());
+        let (tilt_x, tilt_y) = tilt_input.tilt();
+        gba.set_tilt(tilt_x, tilt_y);
         if gba.is_debugger_attached() {
             gba.debugger_run()
         } else {
@@ -216,6 +289,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         renderer.render(gba.get_frame_buffer());
 
+        if let (Some(rumble), Some(haptic)) = (gba.poll_rumble(), &mut haptic) {
+            if rumble {
+                haptic.rumble_play(RUMBLE_STRENGTH, SDL_HAPTIC_INFINITY);
+            } else {
+                haptic.rumble_stop();
+            }
+        }
+
         if let Some(fps) = fps_counter.tick() {
             let title = format!("{} ({} fps)", rom_name, fps);
             renderer.set_window_title(&title);
@@ -233,5 +314,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    if let (Some(path), Some(profiler)) = (&opts.profile, gba.stop_profiling()) {
+        gba.write_profile_report(&profiler, &mut io::stdout(), 20)?;
+        let mut file = BufWriter::new(fs::File::create(path)?);
+        gba.write_profile_folded(&profiler, &mut file)?;
+        file.flush()?;
+        info!("wrote profiled call stacks to {}", path.display());
+    }
+
+    if let (Some(path), Some(coverage)) = (&opts.coverage, gba.stop_coverage()) {
+        let mut file = BufWriter::new(fs::File::create(path)?);
+        match opts.coverage_elf() {
+            Some(elf) => {
+                let lines = read_line_table(&read_bin_file(elf)?)?;
+                coverage.write_lcov(&mut file, &gba.get_game_code(), &lines)?;
+            }
+            None => coverage.write_address_list(&mut file)?,
+        }
+        file.flush()?;
+        info!("wrote coverage to {}", path.display());
+    }
+
     Ok(())
 }
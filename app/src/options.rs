@@ -1,15 +1,24 @@
-use std::path::PathBuf;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 
 use rustboyadvance_core::{
-    cartridge::{BackupType, GamepakBuilder},
-    prelude::Cartridge,
+    cartridge::{convert::EepromByteOrder, BackupType, GamepakBuilder},
+    prelude::{
+        arm7tdmi::{
+            trace::{InstructionTracer, TraceFilter, TraceFormat},
+            CpuMode,
+        },
+        Addr, BootMode, Cartridge, GdbServerAddr, MultibootImage,
+    },
 };
-use rustboyadvance_utils::read_bin_file;
 use structopt::StructOpt;
 
 const SAVE_TYPE_POSSIBLE_VALUES: &[&str] =
     &["sram", "flash128k", "flash64k", "eeprom", "autodetect"];
 
+const BOOT_MODE_POSSIBLE_VALUES: &[&str] =
+    &["joybus", "normal", "multiplay1", "multiplay2", "multiplay3"];
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "rustboyadvance-sdl2")]
 pub struct Options {
@@ -17,6 +26,28 @@ pub struct Options {
     #[structopt(name = "ROM", parse(from_os_str))]
     pub rom: PathBuf,
 
+    /// IPS, UPS or BPS patch to apply to the ROM, may be given more than once to apply patches in order.
+    /// Without it, a patch next to the ROM with the same name is applied
+    #[structopt(long, parse(from_os_str))]
+    pub patch: Vec<PathBuf>,
+
+    /// Game overrides file in the format of the built-in overrides.yaml, may be given more than once.
+    /// Its entries take priority over the built-in ones and those of the files given before it
+    #[structopt(long, parse(from_os_str))]
+    pub overrides: Vec<PathBuf>,
+
+    /// Boot the ROM as a multiboot image from EWRAM, implied for .mb and _mb.gba files
+    #[structopt(long)]
+    pub multiboot: bool,
+
+    /// Cartridge to insert while running a multiboot image, none by default
+    #[structopt(long, parse(from_os_str))]
+    pub cartridge: Option<PathBuf>,
+
+    /// How the multiboot image was received, the multiplay modes also tell the image its slave ID
+    #[structopt(long, default_value = "normal", possible_values = BOOT_MODE_POSSIBLE_VALUES)]
+    pub boot_mode: BootMode,
+
     /// Bios file to use
     #[structopt(long, parse(from_os_str))]
     pub bios: PathBuf,
@@ -25,13 +56,22 @@ pub struct Options {
     #[structopt(long)]
     pub silent: bool,
 
-    /// Initalize gdbserver and wait for a connection from gdb
+    /// Initalize gdbserver in the background, gdb can attach and detach at any time
     #[structopt(short = "d", long)]
     pub gdbserver: bool,
 
     #[structopt(long = "port", default_value = "1337")]
     pub gdbserver_port: u16,
 
+    /// Listen for gdb on a unix domain socket at this path instead of the tcp port
+    #[cfg(unix)]
+    #[structopt(long = "gdb-socket", parse(from_os_str))]
+    pub gdbserver_socket: Option<PathBuf>,
+
+    /// Fast forward through loops that only wait for the next hardware event, may shift timings slightly
+    #[structopt(long)]
+    pub skip_idle_loops: bool,
+
     /// Force emulation of RTC, use for games that have RTC but the emulator fails to detect
     #[structopt(long)]
     pub rtc: bool,
@@ -39,21 +79,128 @@ pub struct Options {
     /// Override save type, useful for troublemaking games that fool the auto detection
     #[structopt(long, default_value = "autodetect", possible_values = SAVE_TYPE_POSSIBLE_VALUES)]
     pub save_type: BackupType,
+
+    /// Replace the save file with this save from another emulator or a flash cart, converting it.
+    /// The previous save file is kept as a .bak
+    #[structopt(long, parse(from_os_str))]
+    pub import_save: Option<PathBuf>,
+
+    /// Write the save converted for other emulators to this file and exit
+    #[structopt(long, parse(from_os_str))]
+    pub export_save: Option<PathBuf>,
+
+    /// Byte order of the 64bit blocks of imported and exported EEPROM saves, some emulators and flash carts use little
+    #[structopt(long, default_value = "big", possible_values = &["big", "little"])]
+    pub save_byte_order: EepromByteOrder,
+
+    /// Write every executed instruction to this file, for diffing against other emulators
+    #[structopt(long, parse(from_os_str))]
+    pub trace: Option<PathBuf>,
+
+    /// Layout of the trace lines, mgba is also what NanoBoyAdvance traces look like
+    #[structopt(long, default_value = "mgba", possible_values = &["mgba", "verbose"])]
+    pub trace_format: TraceFormat,
+
+    /// Only trace instructions within this address range, e.g. 08000000-080fffff
+    #[structopt(long, parse(try_from_str = TraceFilter::parse_addr_range))]
+    pub trace_range: Option<RangeInclusive<Addr>>,
+
+    /// Only trace instructions executed in these cpu modes, e.g. irq,svc
+    #[structopt(long, use_delimiter = true)]
+    pub trace_mode: Vec<CpuMode>,
+
+    /// Profile the cycles spent in every function, print the hottest ones on exit and write the call stacks to
+    /// this file in the folded format used by flamegraph tools
+    #[structopt(long, parse(from_os_str))]
+    pub profile: Option<PathBuf>,
+
+    /// Record the executed instructions and write them to this file on exit
+    #[structopt(long, parse(from_os_str))]
+    pub coverage: Option<PathBuf>,
+
+    /// Write the coverage as lcov, mapping addresses to source lines using the debug info of this ELF.
+    /// Defaults to the ROM when it is an ELF file
+    #[structopt(long, parse(from_os_str))]
+    pub coverage_elf: Option<PathBuf>,
 }
 
 type DynError = Box<dyn std::error::Error>;
 
 impl Options {
+    pub fn is_multiboot(&self) -> bool {
+        self.multiboot || MultibootImage::is_multiboot_path(&self.rom)
+    }
+
+    fn cartridge_path(&self) -> Option<&Path> {
+        if self.is_multiboot() {
+            self.cartridge.as_deref()
+        } else {
+            Some(&self.rom)
+        }
+    }
+
     pub fn cartridge_from_opts(&self) -> Result<Cartridge, DynError> {
-        let mut builder = GamepakBuilder::new()
-            .save_type(self.save_type)
-            .file(&self.rom);
+        let path = match self.cartridge_path() {
+            Some(path) => path,
+            None => return Ok(Cartridge::empty()),
+        };
+        let mut builder = GamepakBuilder::new().save_type(self.save_type).file(path);
+        for patch in &self.patch {
+            builder = builder.patch(patch);
+        }
+        for overrides in &self.overrides {
+            builder = builder.overrides_file(overrides);
+        }
+        if let Some(save) = &self.import_save {
+            builder = builder.import_save(save, self.save_byte_order);
+        }
         if self.rtc {
             builder = builder.with_rtc();
         }
         Ok(builder.build()?)
     }
 
+    pub fn multiboot_from_opts(&self) -> Result<Option<MultibootImage>, DynError> {
+        if self.is_multiboot() {
+            Ok(Some(MultibootImage::from_file(&self.rom)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn gdbserver_addr(&self) -> GdbServerAddr {
+        #[cfg(unix)]
+        if let Some(path) = &self.gdbserver_socket {
+            return GdbServerAddr::Unix(path.clone());
+        }
+        GdbServerAddr::Tcp(self.gdbserver_port)
+    }
+
+    pub fn tracer_from_opts(&self) -> Result<Option<InstructionTracer>, DynError> {
+        let path = match &self.trace {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let filter = TraceFilter {
+            addr_range: self.trace_range.clone(),
+            modes: self.trace_mode.clone(),
+        };
+        Ok(Some(InstructionTracer::create(
+            path,
+            self.trace_format,
+            filter,
+        )?))
+    }
+
+    /// The ELF to read line info from for lcov coverage, if any
+    pub fn coverage_elf(&self) -> Option<&Path> {
+        match &self.coverage_elf {
+            Some(elf) => Some(elf),
+            None if self.rom.extension().is_some_and(|ext| ext == "elf") => Some(&self.rom),
+            None => None,
+        }
+    }
+
     pub fn savestate_path(&self) -> PathBuf {
         self.rom.with_extension("savestate")
     }
@@ -61,8 +208,4 @@ impl Options {
     pub fn rom_name(&self) -> &str {
         self.rom.file_name().unwrap().to_str().unwrap()
     }
-
-    pub fn read_rom(&self) -> Result<Vec<u8>, std::io::Error> {
-        read_bin_file(&self.rom)
-    }
 }
@@ -150,6 +150,9 @@ fn thumb_decode(i: u16) -> (&'static str, String) {
         )
     } else if i & 0xff00 == 0xdf00 {
         ("Swi", String::from("exec_thumb_swi"))
+    } else if i & 0xff00 == 0xde00 {
+        // the "always" condition is undefined for conditional branches
+        ("Undefined", String::from("thumb_undefined"))
     } else if i & 0xf000 == 0xd000 {
         (
             "BranchConditional",
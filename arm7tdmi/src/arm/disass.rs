@@ -1,11 +1,13 @@
 use std::fmt;
 
-use crate::bit::BitIndex;
+use bit::BitIndex;
 
 use super::{ArmDecodeHelper, ArmFormat, ArmInstruction};
 
 use super::{AluOpCode, ArmCond, ArmHalfwordTransferType};
-use crate::arm7tdmi::*;
+use crate::psr::RegPSR;
+use crate::registers_consts::*;
+use crate::*;
 
 impl fmt::Display for ArmCond {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -88,7 +90,7 @@ fn is_lsl0(shift: &ShiftedRegister) -> bool {
 impl fmt::Display for ShiftedRegister {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let reg = reg_string(self.reg).to_string();
-        if !is_lsl0(&self) {
+        if !is_lsl0(self) {
             write!(f, "{}", reg)
         } else {
             match self.shift_by {
@@ -134,7 +136,7 @@ impl ArmInstruction {
             BarrelShifterValue::RotatedImmediate(_, _) => {
                 let value = operand2.decode_rotated_immediate().unwrap();
                 write!(f, "#{}\t; {:#x}", value, value)?;
-                Ok(Some(value as u32))
+                Ok(Some(value))
             }
             BarrelShifterValue::ShiftedRegister(shift) => {
                 write!(f, "{}", shift)?;
@@ -149,7 +151,7 @@ impl ArmInstruction {
 
         let opcode = self.raw.opcode();
 
-        let rd = self.raw.bit_range(16..20) as usize;
+        let rd = self.raw.bit_range(12..16) as usize;
         let rn = self.raw.bit_range(16..20) as usize;
 
         match opcode {
@@ -201,12 +203,12 @@ impl ArmInstruction {
         let (ofs_string, comment) = match offset {
             BarrelShifterValue::ImmediateValue(value) => {
                 let value_for_commnet = if rn == REG_PC {
-                    value + self.pc + 8 // account for pipelining
+                    value.wrapping_add(self.pc + 8) // account for pipelining
                 } else {
                     value
                 };
                 (
-                    format!("#{}", value),
+                    format!("#{}", value as i32),
                     Some(format!("\t; {:#x}", value_for_commnet)),
                 )
             }
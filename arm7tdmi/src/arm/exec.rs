@@ -12,11 +12,7 @@ use super::*;
 
 impl<I: MemoryInterface> Arm7tdmiCore<I> {
     pub fn arm_undefined(&mut self, insn: u32) -> CpuAction {
-        panic!(
-            "executing undefined arm instruction {:08x} at @{:08x}",
-            insn,
-            self.pc_arm()
-        )
+        self.undefined_instruction(insn)
     }
 
     /// Branch and Branch with Link (B, BL)
@@ -410,7 +406,9 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
 
         if LOAD {
             let data = match transfer_type {
-                ArmHalfwordTransferType::SignedByte => self.load_8(addr, NonSeq) as u32,
+                ArmHalfwordTransferType::SignedByte => {
+                    self.load_8(addr, NonSeq) as i8 as i32 as u32
+                }
                 ArmHalfwordTransferType::SignedHalfwords => self.ldr_sign_half(addr, NonSeq),
                 ArmHalfwordTransferType::UnsignedHalfwords => self.ldr_half(addr, NonSeq),
             };
@@ -521,19 +519,20 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
                         let val = self.load_32(addr, access);
                         access = Seq;
                         self.set_reg(r, val);
-                        if r == REG_PC {
-                            if psr_transfer {
-                                self.transfer_spsr_mode();
-                            }
-                            self.reload_pipeline32();
-                            result = CpuAction::PipelineFlushed;
+                        if r == REG_PC && psr_transfer {
+                            self.transfer_spsr_mode();
                         }
                         if !full {
                             addr = addr.wrapping_add(4);
                         }
                     }
                 }
+                // +1I, the new pc is fetched afterwards
                 self.idle_cycle();
+                if rlist.bit(REG_PC) {
+                    self.reload_pipeline32();
+                    result = CpuAction::PipelineFlushed;
+                }
             } else {
                 let mut first = true;
                 let mut access = NonSeq;
@@ -571,20 +570,21 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
                 }
             }
         } else {
+            // Empty rlist: r15 is transferred, at the address it would have with all 16 registers in the list
+            let transfer_addr = match (ascending, full) {
+                (false, false) => addr.wrapping_sub(0x3c),
+                (false, true) => addr.wrapping_sub(0x40),
+                (true, false) => addr,
+                (true, true) => addr.wrapping_add(4),
+            };
             if LOAD {
-                let val = self.ldr_word(addr, NonSeq);
+                let val = self.load_32(transfer_addr, NonSeq);
+                self.idle_cycle();
                 self.set_reg(REG_PC, val & !3);
                 self.reload_pipeline32();
                 result = CpuAction::PipelineFlushed;
             } else {
-                // block data store with empty rlist
-                let addr = match (ascending, full) {
-                    (false, false) => addr.wrapping_sub(0x3c),
-                    (false, true) => addr.wrapping_sub(0x40),
-                    (true, false) => addr,
-                    (true, true) => addr.wrapping_add(4),
-                };
-                self.store_aligned_32(addr, self.pc + 4, NonSeq);
+                self.store_aligned_32(transfer_addr, self.pc + 4, NonSeq);
             }
             addr = if ascending {
                 addr.wrapping_add(0x40)
@@ -630,7 +630,7 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
 
         self.set_reg(rd, result);
 
-        let m = self.get_required_multipiler_array_cycles(op2);
+        let m = self.get_required_multipiler_array_cycles(op2, true);
         for _ in 0..m {
             self.idle_cycle();
         }
@@ -677,7 +677,7 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
         self.set_reg(rd_hi, (result >> 32) as i32 as u32);
         self.set_reg(rd_lo, (result & 0xffffffff) as i32 as u32);
         self.idle_cycle();
-        let m = self.get_required_multipiler_array_cycles(self.get_reg(rs));
+        let m = self.get_required_multipiler_array_cycles(op2, U_FLAG);
         for _ in 0..m {
             self.idle_cycle();
         }
@@ -699,11 +699,11 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
         let rd = insn.bit_range(12..16) as usize;
         if BYTE {
             let t = self.load_8(base_addr, NonSeq);
-            self.store_8(base_addr, self.get_reg(insn.rm()) as u8, Seq);
+            self.store_8(base_addr, self.get_reg(insn.rm()) as u8, NonSeq);
             self.set_reg(rd, t as u32);
         } else {
             let t = self.ldr_word(base_addr, NonSeq);
-            self.store_aligned_32(base_addr, self.get_reg(insn.rm()), Seq);
+            self.store_aligned_32(base_addr, self.get_reg(insn.rm()), NonSeq);
             self.set_reg(rd, t);
         }
         self.idle_cycle();
@@ -718,3 +718,33 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
         CpuAction::PipelineFlushed
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::BusIO;
+    use crate::{Arm7tdmiCore, SimpleMemory};
+
+    #[test]
+    fn test_ldrsb_sign_extends() {
+        let mut program: Vec<u8> = [
+            0xe1d100d0u32, // 00: ldrsb r0, [r1]
+            0xe1d120d1,    // 04: ldrsb r2, [r1, #1]
+            0xeafffffe,    // 08: b 0x08
+        ]
+        .iter()
+        .flat_map(|w| w.to_le_bytes())
+        .collect();
+        program.extend_from_slice(&[0x80, 0x7f]);
+        let mut memory = SimpleMemory::new(0x4000);
+        memory.load_program(&program);
+        let mut cpu = Arm7tdmiCore::new(memory);
+        cpu.reset();
+        cpu.gpr[1] = 0x0c;
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.get_reg(0), 0xffff_ff80);
+        assert_eq!(cpu.get_reg(2), 0x7f);
+        assert_eq!(cpu.bus.read_8(0x0c), 0x80);
+    }
+}
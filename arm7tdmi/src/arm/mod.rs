@@ -1,4 +1,3 @@
-#[cfg(feature = "debugger")]
 pub mod disass;
 pub mod exec;
 
@@ -88,7 +87,7 @@ impl From<u32> for ArmFormat {
             BranchExchange
         } else if (0x0E00_0000 & raw) == 0x0A00_0000 {
             BranchLink
-        } else if (0x0E00_0010 & raw) == 0x0600_0000 {
+        } else if (0x0E00_0010 & raw) == 0x0600_0010 {
             Undefined
         } else if (0x0FB0_0FF0 & raw) == 0x0100_0090 {
             SingleDataSwap
@@ -0,0 +1,616 @@
+use crate::alu::{AluOpCode, BarrelShiftOpCode};
+use crate::arm::{ArmCond, ArmInstruction};
+use crate::memory::Addr;
+use crate::InstructionDecoder;
+
+use super::*;
+
+/// The instruction named by a mnemonic, with the condition suffix removed
+#[derive(Debug, PartialEq, Eq)]
+enum Mnemonic {
+    Bx,
+    Branch {
+        link: bool,
+    },
+    DataProcessing {
+        opcode: AluOpCode,
+        s: bool,
+    },
+    Multiply {
+        accumulate: bool,
+        s: bool,
+    },
+    MultiplyLong {
+        signed: bool,
+        accumulate: bool,
+        s: bool,
+    },
+    SingleTransfer {
+        load: bool,
+        byte: bool,
+        user: bool,
+    },
+    HalfwordTransfer {
+        load: bool,
+        sh: u32,
+    },
+    BlockTransfer {
+        load: bool,
+        increment: bool,
+        pre: bool,
+    },
+    Swap {
+        byte: bool,
+    },
+    Swi,
+    Mrs,
+    Msr,
+}
+
+fn parse_alu_opcode(s: &str) -> Option<AluOpCode> {
+    use AluOpCode::*;
+    let opcode = match s {
+        "and" => AND,
+        "eor" => EOR,
+        "sub" => SUB,
+        "rsb" => RSB,
+        "add" => ADD,
+        "adc" => ADC,
+        "sbc" => SBC,
+        "rsc" => RSC,
+        "tst" => TST,
+        "teq" => TEQ,
+        "cmp" => CMP,
+        "cmn" => CMN,
+        "orr" => ORR,
+        "mov" => MOV,
+        "bic" => BIC,
+        "mvn" => MVN,
+        _ => return None,
+    };
+    Some(opcode)
+}
+
+impl Mnemonic {
+    fn parse(s: &str) -> Option<Mnemonic> {
+        use Mnemonic::*;
+        // mnemonics that may take an `s` suffix
+        let (base, s_flag) = match s.strip_suffix('s') {
+            Some(base) => (base, true),
+            None => (s, false),
+        };
+        if let Some(opcode) = parse_alu_opcode(base) {
+            return Some(DataProcessing { opcode, s: s_flag });
+        }
+        let mnemonic = match base {
+            "mul" => Multiply {
+                accumulate: false,
+                s: s_flag,
+            },
+            "mla" => Multiply {
+                accumulate: true,
+                s: s_flag,
+            },
+            "umull" | "smull" | "umlal" | "smlal" => MultiplyLong {
+                signed: base.starts_with('s'),
+                accumulate: base.ends_with("lal"),
+                s: s_flag,
+            },
+            _ => match s {
+                "bx" => Bx,
+                "b" => Branch { link: false },
+                "bl" => Branch { link: true },
+                "swi" => Swi,
+                "mrs" => Mrs,
+                "msr" => Msr,
+                "swp" => Swap { byte: false },
+                "swpb" => Swap { byte: true },
+                _ => return Mnemonic::parse_transfer(s),
+            },
+        };
+        Some(mnemonic)
+    }
+
+    fn parse_transfer(s: &str) -> Option<Mnemonic> {
+        use Mnemonic::*;
+        let (load, suffix) = if let Some(suffix) = s.strip_prefix("ldr") {
+            (true, suffix)
+        } else if let Some(suffix) = s.strip_prefix("str") {
+            (false, suffix)
+        } else if let Some(suffix) = s.strip_prefix("ldm") {
+            return Mnemonic::parse_block_transfer(true, suffix);
+        } else if let Some(suffix) = s.strip_prefix("stm") {
+            return Mnemonic::parse_block_transfer(false, suffix);
+        } else {
+            return None;
+        };
+        let mnemonic = match suffix {
+            "" | "b" | "t" | "bt" => SingleTransfer {
+                load,
+                byte: suffix.contains('b'),
+                user: suffix.contains('t'),
+            },
+            "h" => HalfwordTransfer { load, sh: 0b01 },
+            "sb" => HalfwordTransfer { load, sh: 0b10 },
+            "sh" => HalfwordTransfer { load, sh: 0b11 },
+            _ => return None,
+        };
+        Some(mnemonic)
+    }
+
+    fn parse_block_transfer(load: bool, mode: &str) -> Option<Mnemonic> {
+        let (increment, pre) = match mode {
+            "ia" => (true, false),
+            "ib" => (true, true),
+            "da" => (false, false),
+            "db" => (false, true),
+            _ => return None,
+        };
+        Some(Mnemonic::BlockTransfer {
+            load,
+            increment,
+            pre,
+        })
+    }
+}
+
+/// Splits the condition suffix off a mnemonic.
+/// The disassembler puts it last, e.g. `ldrbteq`, `movsne`.
+fn split_mnemonic(s: &str) -> ParseResult<(Mnemonic, ArmCond)> {
+    if s.len() > 2 {
+        let (base, suffix) = s.split_at(s.len() - 2);
+        if let (Some(cond), Some(mnemonic)) = (parse_cond(suffix), Mnemonic::parse(base)) {
+            return Ok((mnemonic, cond));
+        }
+    }
+    match Mnemonic::parse(s) {
+        Some(mnemonic) => Ok((mnemonic, ArmCond::AL)),
+        None => Err(format!("unknown instruction {}", s)),
+    }
+}
+
+fn parse_shift_op(s: &str) -> ParseResult<BarrelShiftOpCode> {
+    use BarrelShiftOpCode::*;
+    match s {
+        "lsl" | "asl" => Ok(LSL),
+        "lsr" => Ok(LSR),
+        "asr" => Ok(ASR),
+        "ror" => Ok(ROR),
+        _ => Err(format!("{} is not a shift", s)),
+    }
+}
+
+/// Encodes bits 0-11 of a shifted register operand: `rm`, `rm, lsl #n` or `rm, lsl rs`
+fn encode_shifted_reg(rm: &str, shift: Option<&str>, allow_reg_shift: bool) -> ParseResult<u32> {
+    let rm = parse_reg(rm)? as u32;
+    let shift = match shift {
+        Some(shift) => shift.trim(),
+        None => return Ok(rm),
+    };
+    if shift == "rrx" {
+        return Ok((BarrelShiftOpCode::ROR as u32) << 5 | rm);
+    }
+    let (op, amount) = shift
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| format!("expected a shift, got {}", shift))?;
+    let op = parse_shift_op(op)?;
+    let amount = amount.trim();
+    if amount.starts_with('#') {
+        let max = match op {
+            BarrelShiftOpCode::LSR | BarrelShiftOpCode::ASR => 32,
+            _ => 31,
+        };
+        // a shift right by 32 is encoded as a shift by 0
+        let amount = parse_unsigned_imm(amount, max, 1)? & 0x1f;
+        Ok(amount << 7 | (op as u32) << 5 | rm)
+    } else if allow_reg_shift {
+        let rs = parse_reg(amount)? as u32;
+        Ok(rs << 8 | (op as u32) << 5 | 1 << 4 | rm)
+    } else {
+        Err("only shifts by an immediate are allowed here".to_string())
+    }
+}
+
+/// Finds the 8 bit value and even rotation that encode `value`, preferring the smallest rotation
+fn encode_rotated_imm(value: u32) -> Option<u32> {
+    (0..16).find_map(|rotate| {
+        let imm = value.rotate_left(rotate * 2);
+        (imm <= 0xff).then_some(rotate << 8 | imm)
+    })
+}
+
+/// Encodes the second operand of data processing instructions and msr, bit 25 included
+fn encode_operand2(operands: &[String]) -> ParseResult<u32> {
+    match operands {
+        [imm] if imm.starts_with('#') => {
+            let value = parse_imm(imm)?;
+            if value < i32::MIN as i64 || value > u32::MAX as i64 {
+                return Err(format!("{} is out of range", imm));
+            }
+            let encoded = encode_rotated_imm(value as u32)
+                .ok_or_else(|| format!("{} can't be encoded as a rotated immediate", imm))?;
+            Ok(1 << 25 | encoded)
+        }
+        [rm] => encode_shifted_reg(rm, None, true),
+        [rm, shift] => encode_shifted_reg(rm, Some(shift), true),
+        _ => Err("bad operand".to_string()),
+    }
+}
+
+/// Encodes the addressing mode of ldr/str into the P, U, W, Rn bits and the offset, returning them
+/// along with whether the offset is a register
+fn encode_address(
+    operands: &[String],
+    encode_imm: impl Fn(u32) -> ParseResult<u32>,
+    allow_shift: bool,
+) -> ParseResult<(u32, bool)> {
+    let strip_brackets = |address: &str| -> ParseResult<String> {
+        address
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .map(str::to_owned)
+            .ok_or_else(|| format!("expected an address, got {}", address))
+    };
+    // pre-indexed: `[rn, offset]{!}`, post-indexed: `[rn], offset`
+    let (base, pre, writeback, offset) = match operands {
+        [address] => {
+            let (inner, writeback) = match address.strip_suffix('!') {
+                Some(inner) => (inner, true),
+                None => (address.as_str(), false),
+            };
+            let mut parts = split_operands(&strip_brackets(inner)?)?;
+            if parts.is_empty() {
+                return Err(format!("expected an address, got {}", address));
+            }
+            let base = parts.remove(0);
+            (base, true, writeback, parts)
+        }
+        [address, offset @ ..] => (strip_brackets(address)?, false, false, offset.to_vec()),
+        [] => return Err("expected an address".to_string()),
+    };
+    let rn = parse_reg(&base)? as u32;
+    let mut bits = (pre as u32) << 24 | (writeback as u32) << 21 | rn << 16;
+    let is_reg = match offset.as_slice() {
+        [] => {
+            bits |= 1 << 23;
+            false
+        }
+        [imm] if imm.starts_with('#') => {
+            let value = parse_imm(imm)?;
+            let up = !imm.starts_with("#-");
+            bits |= (up as u32) << 23 | encode_imm(value.unsigned_abs() as u32)?;
+            false
+        }
+        [rm, shift @ ..] => {
+            let (up, rm) = match rm.strip_prefix('-') {
+                Some(rm) => (false, rm),
+                None => (true, rm.strip_prefix('+').unwrap_or(rm)),
+            };
+            let shift = match shift {
+                [] => None,
+                [shift] if allow_shift => Some(shift.as_str()),
+                _ => return Err("bad register offset".to_string()),
+            };
+            bits |= (up as u32) << 23 | encode_shifted_reg(rm, shift, false)?;
+            true
+        }
+    };
+    Ok((bits, is_reg))
+}
+
+fn encode(mnemonic: Mnemonic, cond: ArmCond, line: &Line, addr: Addr) -> ParseResult<u32> {
+    use Mnemonic::*;
+    let ops = &line.operands;
+    let raw = match mnemonic {
+        Bx => {
+            let ops = line.expect_operands(1)?;
+            0x012f_ff10 | parse_reg(&ops[0])? as u32
+        }
+        Branch { link } => {
+            let ops = line.expect_operands(1)?;
+            let target = parse_target(&ops[0])?;
+            let offset = target.wrapping_sub(addr.wrapping_add(8)) as i32;
+            if offset % 4 != 0 || !(-(1 << 25)..(1 << 25)).contains(&offset) {
+                return Err(format!("can't branch to {:#x} from {:#x}", target, addr));
+            }
+            0x0a00_0000 | (link as u32) << 24 | ((offset >> 2) as u32 & 0xff_ffff)
+        }
+        DataProcessing { opcode, s } => {
+            use AluOpCode::*;
+            let (rd, rn, op2) = match opcode {
+                MOV | MVN => {
+                    if ops.len() < 2 {
+                        return Err(format!("`{}` takes a register and an operand", opcode));
+                    }
+                    (parse_reg(&ops[0])?, 0, &ops[1..])
+                }
+                TST | TEQ | CMP | CMN => {
+                    if ops.len() < 2 {
+                        return Err(format!("`{}` takes a register and an operand", opcode));
+                    }
+                    (0, parse_reg(&ops[0])?, &ops[1..])
+                }
+                _ => {
+                    if ops.len() < 3 {
+                        return Err(format!("`{}` takes two registers and an operand", opcode));
+                    }
+                    (parse_reg(&ops[0])?, parse_reg(&ops[1])?, &ops[2..])
+                }
+            };
+            // compares always set the flags, it's what tells them apart from the psr transfers
+            let s = s || matches!(opcode, TST | TEQ | CMP | CMN);
+            (opcode as u32) << 21
+                | (s as u32) << 20
+                | (rn as u32) << 16
+                | (rd as u32) << 12
+                | encode_operand2(op2)?
+        }
+        Multiply { accumulate, s } => {
+            let ops = line.expect_operands(if accumulate { 4 } else { 3 })?;
+            let rn = if accumulate { parse_reg(&ops[3])? } else { 0 };
+            (accumulate as u32) << 21
+                | (s as u32) << 20
+                | (parse_reg(&ops[0])? as u32) << 16
+                | (rn as u32) << 12
+                | (parse_reg(&ops[2])? as u32) << 8
+                | 0x90
+                | parse_reg(&ops[1])? as u32
+        }
+        MultiplyLong {
+            signed,
+            accumulate,
+            s,
+        } => {
+            let ops = line.expect_operands(4)?;
+            0x0080_0090
+                | (signed as u32) << 22
+                | (accumulate as u32) << 21
+                | (s as u32) << 20
+                | (parse_reg(&ops[1])? as u32) << 16
+                | (parse_reg(&ops[0])? as u32) << 12
+                | (parse_reg(&ops[3])? as u32) << 8
+                | parse_reg(&ops[2])? as u32
+        }
+        SingleTransfer { load, byte, user } => {
+            if ops.len() < 2 {
+                return Err("expected a register and an address".to_string());
+            }
+            let encode_imm = |offset: u32| {
+                if offset > 0xfff {
+                    Err(format!("offset {:#x} is out of range", offset))
+                } else {
+                    Ok(offset)
+                }
+            };
+            let (mut bits, is_reg) = encode_address(&ops[1..], encode_imm, true)?;
+            let pre = bits & (1 << 24) != 0;
+            if user {
+                if pre {
+                    return Err("`t` only works with post-indexing".to_string());
+                }
+                bits |= 1 << 21;
+            }
+            0x0400_0000
+                | (is_reg as u32) << 25
+                | (byte as u32) << 22
+                | (load as u32) << 20
+                | (parse_reg(&ops[0])? as u32) << 12
+                | bits
+        }
+        HalfwordTransfer { load, sh } => {
+            if ops.len() < 2 {
+                return Err("expected a register and an address".to_string());
+            }
+            let encode_imm = |offset: u32| {
+                if offset > 0xff {
+                    Err(format!("offset {:#x} is out of range", offset))
+                } else {
+                    Ok((offset & 0xf0) << 4 | offset & 0xf)
+                }
+            };
+            let (bits, is_reg) = encode_address(&ops[1..], encode_imm, false)?;
+            (!is_reg as u32) << 22
+                | (load as u32) << 20
+                | (parse_reg(&ops[0])? as u32) << 12
+                | 0x90
+                | sh << 5
+                | bits
+        }
+        BlockTransfer {
+            load,
+            increment,
+            pre,
+        } => {
+            let ops = line.expect_operands(2)?;
+            let (rn, writeback) = match ops[0].strip_suffix('!') {
+                Some(rn) => (rn, true),
+                None => (ops[0].as_str(), false),
+            };
+            let (list, force_user) = match ops[1].strip_suffix('^') {
+                Some(list) => (list, true),
+                None => (ops[1].as_str(), false),
+            };
+            0x0800_0000
+                | (pre as u32) << 24
+                | (increment as u32) << 23
+                | (force_user as u32) << 22
+                | (writeback as u32) << 21
+                | (load as u32) << 20
+                | (parse_reg(rn)? as u32) << 16
+                | parse_reg_list(list)? as u32
+        }
+        Swap { byte } => {
+            let ops = line.expect_operands(3)?;
+            let rn = ops[2]
+                .strip_prefix('[')
+                .and_then(|s| s.strip_suffix(']'))
+                .ok_or_else(|| format!("expected an address, got {}", ops[2]))?;
+            0x0100_0090
+                | (byte as u32) << 22
+                | (parse_reg(rn)? as u32) << 16
+                | (parse_reg(&ops[0])? as u32) << 12
+                | parse_reg(&ops[1])? as u32
+        }
+        Swi => {
+            let ops = line.expect_operands(1)?;
+            0x0f00_0000 | parse_unsigned_imm(&ops[0], 0xff_ffff, 1)?
+        }
+        Mrs => {
+            let ops = line.expect_operands(2)?;
+            let spsr = match ops[1].as_str() {
+                "cpsr" | "cpsr_all" => false,
+                "spsr" | "spsr_all" => true,
+                psr => return Err(format!("{} is not a status register", psr)),
+            };
+            0x010f_0000 | (spsr as u32) << 22 | (parse_reg(&ops[0])? as u32) << 12
+        }
+        Msr => {
+            if ops.len() < 2 {
+                return Err("expected a status register and an operand".to_string());
+            }
+            let (spsr, flags_only) = match ops[0].as_str() {
+                "cpsr" | "cpsr_all" | "cpsr_fc" => (false, false),
+                "spsr" | "spsr_all" | "spsr_fc" => (true, false),
+                "cpsr_f" | "cpsr_flg" => (false, true),
+                "spsr_f" | "spsr_flg" => (true, true),
+                psr => return Err(format!("{} is not a status register", psr)),
+            };
+            if flags_only {
+                0x0128_f000 | (spsr as u32) << 22 | encode_operand2(&ops[1..])?
+            } else {
+                let ops = line.expect_operands(2)?;
+                0x0129_f000 | (spsr as u32) << 22 | parse_reg(&ops[1])? as u32
+            }
+        }
+    };
+    Ok((cond as u32) << 28 | raw)
+}
+
+/// Assembles an ARM instruction to be placed at `addr`
+pub fn assemble_arm(line: &str, addr: Addr) -> AsmResult<ArmInstruction> {
+    with_line(line, {
+        Line::parse(line).and_then(|parsed| {
+            let (mnemonic, cond) = split_mnemonic(&parsed.mnemonic)?;
+            encode(mnemonic, cond, &parsed, addr)
+        })
+    })
+    .map(|raw| ArmInstruction::decode(raw, addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arm::ArmFormat;
+
+    fn asm(line: &str) -> u32 {
+        assemble_arm(line, 0x0800_0000).unwrap().raw
+    }
+
+    #[test]
+    fn test_known_encodings() {
+        assert_eq!(asm("mov r0, #0x4000000"), 0xe3a0_0301);
+        assert_eq!(asm("movs r1, r2, lsl #3"), 0xe1b0_1182);
+        assert_eq!(asm("addne r0, r1, r2, asr r3"), 0x1081_0352);
+        assert_eq!(asm("cmp r0, #1"), 0xe350_0001);
+        assert_eq!(asm("b 0x8000010"), 0xea00_0002);
+        assert_eq!(asm("bl 0x7fffff8"), 0xebff_fffc);
+        assert_eq!(asm("bx lr"), 0xe12f_ff1e);
+        assert_eq!(asm("ldreq r2, [r5, -r6, lsl #5]"), 0x0715_2286);
+        assert_eq!(asm("strteq r2, [r4], -r7, asr #8"), 0x0624_2447);
+        assert_eq!(asm("str r4, [sp, #16]"), 0xe58d_4010);
+        assert_eq!(asm("ldr r0, [r1, #-4]!"), 0xe531_0004);
+        assert_eq!(asm("ldrh r0, [r1, #0x12]"), 0xe1d1_01b2);
+        assert_eq!(asm("ldrsb r0, [r1], -r2"), 0xe011_00d2);
+        assert_eq!(asm("stmdb sp!, {r4-r6, lr}"), 0xe92d_4070);
+        assert_eq!(asm("ldmia sp!, {r4-r6, pc}^"), 0xe8fd_8070);
+        assert_eq!(asm("mla r0, r1, r2, r3"), 0xe020_3291);
+        assert_eq!(asm("umull r0, r1, r2, r3"), 0xe081_0392);
+        assert_eq!(asm("swpb r0, r1, [r2]"), 0xe142_0091);
+        assert_eq!(asm("swi #0x1337"), 0xef00_1337);
+        assert_eq!(asm("mrs r0, CPSR"), 0xe10f_0000);
+        assert_eq!(asm("msr SPSR, r0"), 0xe169_f000);
+        assert_eq!(asm("msr CPSR_f, #0xf0000000"), 0xe328_f20f);
+        assert_eq!(asm("movlo r0, r1, rrx"), 0x31a0_0061);
+    }
+
+    #[test]
+    fn test_condition_suffix() {
+        assert_eq!(
+            split_mnemonic("teq"),
+            Ok((Mnemonic::parse("teq").unwrap(), ArmCond::AL))
+        );
+        assert_eq!(split_mnemonic("bls").unwrap().1, ArmCond::LS);
+        assert_eq!(
+            split_mnemonic("blls"),
+            Ok((Mnemonic::Branch { link: true }, ArmCond::LS))
+        );
+        assert_eq!(split_mnemonic("adcs").unwrap().1, ArmCond::AL);
+        assert_eq!(
+            split_mnemonic("mulls").unwrap(),
+            (
+                Mnemonic::Multiply {
+                    accumulate: false,
+                    s: false
+                },
+                ArmCond::LS
+            )
+        );
+        assert!(split_mnemonic("ldrq").is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |line| assemble_arm(line, 0).unwrap_err().reason;
+        assert_eq!(
+            err("mov r0, #0x101"),
+            "#0x101 can't be encoded as a rotated immediate"
+        );
+        assert_eq!(err("ldr r0, [r1, #4096]"), "offset 0x1000 is out of range");
+        assert_eq!(
+            err("ldrt r0, [r1, #4]"),
+            "`t` only works with post-indexing"
+        );
+        assert_eq!(err("bx"), "`bx` takes 1 operands, got 0");
+        assert_eq!(err("b 0x2"), "can't branch to 0x2 from 0x0");
+    }
+
+    /// xorshift, to cover the encoding space without depending on a rng crate
+    fn random_words(mut state: u32, count: usize) -> impl Iterator<Item = u32> {
+        (0..count).map(move |_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        })
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        let addr = 0x0800_1000;
+        for raw in random_words(0x2545_f491, 200_000) {
+            let insn = ArmInstruction::decode(raw, addr);
+            let is_halfword_transfer = matches!(
+                insn.fmt,
+                ArmFormat::HalfwordDataTransferImmediateOffset
+                    | ArmFormat::HalfwordDataTransferRegOffset
+            );
+            // these can't be disassembled in the first place
+            if insn.fmt == ArmFormat::Undefined
+                || raw >> 28 == 0xf
+                || (is_halfword_transfer && raw & 0x60 == 0)
+            {
+                continue;
+            }
+            let text = insn.to_string();
+            let assembled =
+                assemble_arm(&text, addr).unwrap_or_else(|e| panic!("{:08x}: {}", raw, e));
+            assert_eq!(
+                assembled.to_string(),
+                text,
+                "{:08x} became {:08x}",
+                raw,
+                assembled.raw
+            );
+        }
+    }
+}
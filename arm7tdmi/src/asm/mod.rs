@@ -0,0 +1,283 @@
+//! Assembler for the syntax printed by the disassembler, one instruction at a time.
+//!
+//! Anything the disassembler prints can be assembled back into an instruction with the same disassembly,
+//! which is what makes patching code from the debugger practical. A few common spellings the disassembler
+//! doesn't print are accepted as well, e.g. `hs`/`lo` conditions, `rrx` and `@` comments.
+//!
+//! Branch targets are absolute addresses, so instructions are assembled for the address they will be placed at.
+use std::fmt;
+
+use crate::arm::ArmCond;
+use crate::memory::Addr;
+use crate::CpuState;
+
+mod arm;
+mod thumb;
+
+pub use self::arm::assemble_arm;
+pub use self::thumb::assemble_thumb;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: String,
+    pub reason: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "can't assemble `{}`: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+pub type AsmResult<T> = Result<T, AsmError>;
+
+/// Assembles `line` in the given cpu state, returning the encoding as it would be stored in memory.
+/// A thumb `bl` to an address produces both of its halves.
+pub fn assemble(line: &str, addr: Addr, state: CpuState) -> AsmResult<Vec<u8>> {
+    Ok(match state {
+        CpuState::ARM => assemble_arm(line, addr)?.raw.to_le_bytes().to_vec(),
+        CpuState::THUMB => assemble_thumb(line, addr)?
+            .iter()
+            .flat_map(|insn| insn.raw.to_le_bytes())
+            .collect(),
+    })
+}
+
+/// Errors while parsing a single instruction, the line is attached by `parse_line`
+type ParseResult<T> = Result<T, String>;
+
+/// An instruction split into its lowercase mnemonic and operands
+struct Line {
+    mnemonic: String,
+    operands: Vec<String>,
+}
+
+impl Line {
+    fn parse(line: &str) -> ParseResult<Line> {
+        let code = line.split([';', '@']).next().unwrap().trim();
+        let (mnemonic, operands) = match code.find(char::is_whitespace) {
+            Some(i) => code.split_at(i),
+            None => (code, ""),
+        };
+        if mnemonic.is_empty() {
+            return Err("expected an instruction".to_string());
+        }
+        Ok(Line {
+            mnemonic: mnemonic.to_ascii_lowercase(),
+            operands: split_operands(operands)?,
+        })
+    }
+
+    fn expect_operands(&self, count: usize) -> ParseResult<&[String]> {
+        if self.operands.len() == count {
+            Ok(&self.operands)
+        } else {
+            Err(format!(
+                "`{}` takes {} operands, got {}",
+                self.mnemonic,
+                count,
+                self.operands.len()
+            ))
+        }
+    }
+}
+
+/// Splits on the commas that are not inside of `[]` or `{}`
+fn split_operands(s: &str) -> ParseResult<Vec<String>> {
+    let mut operands = vec![];
+    let mut depth = 0;
+    let mut current = String::new();
+    for c in s.trim().chars() {
+        match c {
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(current.trim().to_ascii_lowercase());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        if depth < 0 {
+            return Err(format!("unbalanced `{}`", c));
+        }
+        current.push(c);
+    }
+    if depth != 0 {
+        return Err("unbalanced brackets".to_string());
+    }
+    if !current.trim().is_empty() || !operands.is_empty() {
+        operands.push(current.trim().to_ascii_lowercase());
+    }
+    Ok(operands)
+}
+
+fn parse_reg(s: &str) -> ParseResult<usize> {
+    let reg = match s.trim() {
+        "sl" => 10,
+        "fp" => 11,
+        "ip" => 12,
+        "sp" => 13,
+        "lr" => 14,
+        "pc" => 15,
+        r => r
+            .strip_prefix('r')
+            .and_then(|n| n.parse().ok())
+            .filter(|n| *n < 16)
+            .ok_or_else(|| format!("{} is not a register", s))?,
+    };
+    Ok(reg)
+}
+
+fn parse_low_reg(s: &str) -> ParseResult<usize> {
+    match parse_reg(s)? {
+        reg if reg < 8 => Ok(reg),
+        _ => Err(format!("{} is not one of r0-r7", s)),
+    }
+}
+
+/// Parses a number, decimal or 0x prefixed hex, with an optional sign
+fn parse_number(s: &str) -> ParseResult<i64> {
+    let s = s.trim();
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .map_err(|_| format!("{} is not a number", s))?;
+    Ok(if negative { -value } else { value })
+}
+
+/// Parses an immediate operand, `#` followed by a number
+fn parse_imm(s: &str) -> ParseResult<i64> {
+    let s = s.trim();
+    match s.strip_prefix('#') {
+        Some(number) => parse_number(number),
+        None => Err(format!("expected an immediate, got {}", s)),
+    }
+}
+
+/// Parses an immediate that must be within `0..=max` and a multiple of `align`
+fn parse_unsigned_imm(s: &str, max: u32, align: u32) -> ParseResult<u32> {
+    let value = parse_imm(s)?;
+    if value < 0 || value > max as i64 {
+        return Err(format!("{} is out of range, expected 0-{:#x}", s, max));
+    }
+    if value % align as i64 != 0 {
+        return Err(format!("{} is not a multiple of {}", s, align));
+    }
+    Ok(value as u32)
+}
+
+/// Parses an absolute branch target, a `#` is tolerated
+fn parse_target(s: &str) -> ParseResult<Addr> {
+    let number = s.trim();
+    let number = number.strip_prefix('#').unwrap_or(number);
+    let value = parse_number(number)?;
+    if value < i32::MIN as i64 || value > u32::MAX as i64 {
+        return Err(format!("{} is not an address", s));
+    }
+    Ok(value as Addr)
+}
+
+/// Parses `{r0, r2-r4, lr}` into a register bitmask
+fn parse_reg_list(s: &str) -> ParseResult<u16> {
+    let inner = s
+        .trim()
+        .strip_prefix('{')
+        .and_then(|s| s.strip_suffix('}'))
+        .ok_or_else(|| format!("expected a register list, got {}", s))?;
+    let mut list = 0u16;
+    for item in inner.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse_reg(first)?, parse_reg(last)?);
+                if first > last {
+                    return Err(format!("bad register range {}", item));
+                }
+                for reg in first..=last {
+                    list |= 1 << reg;
+                }
+            }
+            None => list |= 1 << parse_reg(item)?,
+        }
+    }
+    Ok(list)
+}
+
+fn parse_cond(s: &str) -> Option<ArmCond> {
+    use ArmCond::*;
+    let cond = match s {
+        "eq" => EQ,
+        "ne" => NE,
+        "cs" | "hs" => HS,
+        "cc" | "lo" => LO,
+        "mi" => MI,
+        "pl" => PL,
+        "vs" => VS,
+        "vc" => VC,
+        "hi" => HI,
+        "ls" => LS,
+        "ge" => GE,
+        "lt" => LT,
+        "gt" => GT,
+        "le" => LE,
+        "al" => AL,
+        _ => return None,
+    };
+    Some(cond)
+}
+
+/// Wraps the parsing errors of `line`
+fn with_line<T>(line: &str, result: ParseResult<T>) -> AsmResult<T> {
+    result.map_err(|reason| AsmError {
+        line: line.trim().to_string(),
+        reason,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_operands() {
+        let line = Line::parse("LDR\tr0, [r1, -R2, lsl #2]! ; comment").unwrap();
+        assert_eq!(line.mnemonic, "ldr");
+        assert_eq!(line.operands, ["r0", "[r1, -r2, lsl #2]!"]);
+        let line = Line::parse("push {r4-r6, lr}").unwrap();
+        assert_eq!(line.operands, ["{r4-r6, lr}"]);
+        assert!(Line::parse("ldr r0, [r1").is_err());
+        assert!(Line::parse("  @ only a comment").is_err());
+    }
+
+    #[test]
+    fn test_parse_operands() {
+        assert_eq!(parse_reg("ip"), Ok(12));
+        assert!(parse_reg("r16").is_err());
+        assert_eq!(parse_imm("#-0x10"), Ok(-16));
+        assert_eq!(parse_imm("#42"), Ok(42));
+        assert!(parse_imm("42").is_err());
+        assert_eq!(parse_reg_list("{r0, r2-r4, pc}"), Ok(0b1000_0000_0001_1101));
+        assert_eq!(parse_reg_list("{}"), Ok(0));
+    }
+
+    #[test]
+    fn test_assemble_bytes() {
+        assert_eq!(
+            assemble("bx lr", 0, CpuState::ARM),
+            Ok(vec![0x1e, 0xff, 0x2f, 0xe1])
+        );
+        assert_eq!(
+            assemble("bl 0x08000100", 0x0800_0000, CpuState::THUMB),
+            Ok(vec![0x00, 0xf0, 0x7e, 0xf8])
+        );
+        let err = assemble("frobnicate r0", 0, CpuState::ARM).unwrap_err();
+        assert_eq!(err.line, "frobnicate r0");
+    }
+}
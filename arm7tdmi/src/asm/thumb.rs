@@ -0,0 +1,366 @@
+use crate::memory::Addr;
+use crate::thumb::ThumbInstruction;
+use crate::InstructionDecoder;
+
+use super::*;
+
+fn alu_opcode(s: &str) -> Option<u16> {
+    let op = match s {
+        "and" => 0b0000,
+        "eor" => 0b0001,
+        "lsl" => 0b0010,
+        "lsr" => 0b0011,
+        "asr" => 0b0100,
+        "adc" => 0b0101,
+        "sbc" => 0b0110,
+        "ror" => 0b0111,
+        "tst" => 0b1000,
+        "neg" => 0b1001,
+        "cmp" => 0b1010,
+        "cmn" => 0b1011,
+        "orr" => 0b1100,
+        "mul" => 0b1101,
+        "bic" => 0b1110,
+        "mvn" => 0b1111,
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn low_reg(s: &str) -> ParseResult<u16> {
+    parse_low_reg(s).map(|reg| reg as u16)
+}
+
+fn is_imm(s: &str) -> bool {
+    s.starts_with('#')
+}
+
+/// Splits `[rb, offset]` into its two parts
+fn parse_address(s: &str) -> ParseResult<(String, String)> {
+    let inner = s
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .ok_or_else(|| format!("expected an address, got {}", s))?;
+    match split_operands(inner)?.as_slice() {
+        [base, offset] => Ok((base.clone(), offset.clone())),
+        [base] => Ok((base.clone(), "#0".to_string())),
+        _ => Err(format!("bad address {}", s)),
+    }
+}
+
+/// Parses a register list of low registers, plus `extra` if it's allowed
+fn parse_low_reg_list(s: &str, extra: Option<usize>) -> ParseResult<(u16, bool)> {
+    let list = parse_reg_list(s)?;
+    let extra_bit = extra.map_or(0, |reg| 1 << reg);
+    if list & !(0xff | extra_bit) != 0 {
+        return Err(format!("{} can't be transferred", s));
+    }
+    Ok((list & 0xff, list & extra_bit != 0))
+}
+
+/// Offset from the pc of a thumb instruction at `addr` to `target`, checked to fit in `bits` halfwords
+fn branch_offset(target: Addr, addr: Addr, bits: u32) -> ParseResult<u16> {
+    let offset = target.wrapping_sub(addr.wrapping_add(4)) as i32;
+    let range = 1 << bits;
+    if offset % 2 != 0 || !(-range..range).contains(&offset) {
+        return Err(format!("can't branch to {:#x} from {:#x}", target, addr));
+    }
+    Ok(((offset >> 1) as u32 & ((range as u32) - 1)) as u16)
+}
+
+/// Encodes ldr/str/ldrb/strb/ldrh/strh, which have both register and immediate offset forms
+fn encode_load_store(mnemonic: &str, ops: &[String]) -> ParseResult<u16> {
+    let [rd, address] = ops else {
+        return Err("expected a register and an address".to_string());
+    };
+    let load = mnemonic.starts_with("ldr");
+    let rd = low_reg(rd)?;
+    let (base, offset) = parse_address(address)?;
+    let raw = match (mnemonic, base.as_str()) {
+        ("ldr", "pc") => 0x4800 | rd << 8 | (parse_unsigned_imm(&offset, 0x3fc, 4)? >> 2) as u16,
+        ("ldr" | "str", "sp") => {
+            0x9000
+                | (load as u16) << 11
+                | rd << 8
+                | (parse_unsigned_imm(&offset, 0x3fc, 4)? >> 2) as u16
+        }
+        _ => {
+            let rb = low_reg(&base)?;
+            let halfword = mnemonic.ends_with('h');
+            let byte = mnemonic.ends_with('b');
+            if is_imm(&offset) {
+                let offset5 = if halfword {
+                    parse_unsigned_imm(&offset, 62, 2)? >> 1
+                } else if byte {
+                    parse_unsigned_imm(&offset, 31, 1)?
+                } else {
+                    parse_unsigned_imm(&offset, 124, 4)? >> 2
+                } as u16;
+                let base = if halfword {
+                    0x8000
+                } else {
+                    0x6000 | (byte as u16) << 12
+                };
+                base | (load as u16) << 11 | offset5 << 6 | rb << 3 | rd
+            } else {
+                let ro = low_reg(&offset)?;
+                let base = if halfword {
+                    // strh and ldrh share their encoding with the sign extending loads
+                    0x5200 | (load as u16) << 11
+                } else {
+                    0x5000 | (load as u16) << 11 | (byte as u16) << 10
+                };
+                base | ro << 6 | rb << 3 | rd
+            }
+        }
+    };
+    Ok(raw)
+}
+
+/// Encodes a single instruction, except for the two instruction form of `bl`
+fn encode(line: &Line, addr: Addr) -> ParseResult<u16> {
+    let mnemonic = line.mnemonic.as_str();
+    let ops = &line.operands;
+    let raw = match (mnemonic, ops.as_slice()) {
+        // format 1
+        ("lsl" | "lsr" | "asr", [rd, rs, imm]) => {
+            let op: u16 = match mnemonic {
+                "lsl" => 0,
+                "lsr" => 1,
+                _ => 2,
+            };
+            let amount = parse_unsigned_imm(imm, 31, 1)? as u16;
+            op << 11 | amount << 6 | low_reg(rs)? << 3 | low_reg(rd)?
+        }
+        // format 2
+        ("add" | "sub", [rd, rs, operand]) if rs != "pc" && rs != "sp" => {
+            let (immediate, value) = if is_imm(operand) {
+                (true, parse_unsigned_imm(operand, 7, 1)? as u16)
+            } else {
+                (false, low_reg(operand)?)
+            };
+            0x1800
+                | (immediate as u16) << 10
+                | ((mnemonic == "sub") as u16) << 9
+                | value << 6
+                | low_reg(rs)? << 3
+                | low_reg(rd)?
+        }
+        // format 13
+        ("add" | "sub", [sp, imm]) if sp == "sp" && is_imm(imm) => {
+            let value = parse_imm(imm)?;
+            let value = if mnemonic == "sub" { -value } else { value };
+            if value.unsigned_abs() > 0x1fc || value % 4 != 0 {
+                return Err(format!("can't add {} to sp", imm));
+            }
+            0xb000 | ((value < 0) as u16) << 7 | (value.unsigned_abs() >> 2) as u16
+        }
+        // format 3
+        ("mov" | "cmp" | "add" | "sub", [rd, imm]) if is_imm(imm) => {
+            let op: u16 = match mnemonic {
+                "mov" => 0,
+                "cmp" => 1,
+                "add" => 2,
+                _ => 3,
+            };
+            0x2000 | op << 11 | low_reg(rd)? << 8 | parse_unsigned_imm(imm, 0xff, 1)? as u16
+        }
+        // format 5, cmp between low registers is an alu op
+        ("add" | "mov" | "cmp", [rd, rs])
+            if mnemonic != "cmp" || parse_reg(rd)? > 7 || parse_reg(rs)? > 7 =>
+        {
+            let op: u16 = match mnemonic {
+                "add" => 0,
+                "cmp" => 1,
+                _ => 2,
+            };
+            let (rd, rs) = (parse_reg(rd)? as u16, parse_reg(rs)? as u16);
+            0x4400 | op << 8 | (rd >> 3) << 7 | (rs >> 3) << 6 | (rs & 7) << 3 | rd & 7
+        }
+        ("bx", [rs]) => {
+            let rs = parse_reg(rs)? as u16;
+            0x4700 | (rs >> 3) << 6 | (rs & 7) << 3
+        }
+        // format 4
+        (op, [rd, rs]) if alu_opcode(op).is_some() => {
+            0x4000 | alu_opcode(op).unwrap() << 6 | low_reg(rs)? << 3 | low_reg(rd)?
+        }
+        // formats 6, 7, 9, 10 and 11
+        ("ldr" | "str" | "ldrb" | "strb" | "ldrh" | "strh", _) => encode_load_store(mnemonic, ops)?,
+        // format 8
+        ("ldsb" | "ldrsb" | "ldsh" | "ldrsh", [rd, address]) => {
+            let (rb, ro) = parse_address(address)?;
+            let halfword = mnemonic.ends_with('h');
+            0x5600
+                | (halfword as u16) << 11
+                | low_reg(&ro)? << 6
+                | low_reg(&rb)? << 3
+                | low_reg(rd)?
+        }
+        // format 12
+        ("add", [rd, base, imm]) => {
+            let sp = match base.as_str() {
+                "pc" => false,
+                "sp" => true,
+                _ => return Err(format!("can't add an offset to {}", base)),
+            };
+            0xa000
+                | (sp as u16) << 11
+                | low_reg(rd)? << 8
+                | (parse_unsigned_imm(imm, 0x3fc, 4)? >> 2) as u16
+        }
+        // format 14
+        ("push" | "pop", [list]) => {
+            let load = mnemonic == "pop";
+            let (list, extra) = parse_low_reg_list(list, Some(if load { 15 } else { 14 }))?;
+            0xb400 | (load as u16) << 11 | (extra as u16) << 8 | list
+        }
+        // format 15
+        ("ldm" | "stm" | "ldmia" | "stmia", [rb, list]) => {
+            let rb = rb
+                .strip_suffix('!')
+                .ok_or_else(|| format!("`{}` always writes back to the base register", mnemonic))?;
+            let (list, _) = parse_low_reg_list(list, None)?;
+            0xc000 | (mnemonic.starts_with("ldm") as u16) << 11 | low_reg(rb)? << 8 | list
+        }
+        // format 17
+        ("swi", [comment]) => {
+            let comment = match comment.strip_prefix('#') {
+                Some(_) => comment.to_string(),
+                None => format!("#{}", comment),
+            };
+            0xdf00 | parse_unsigned_imm(&comment, 0xff, 1)? as u16
+        }
+        // format 18
+        ("b", [target]) => 0xe000 | branch_offset(parse_target(target)?, addr, 11)?,
+        // format 19, one half at a time
+        ("bl", [imm]) if is_imm(imm) => {
+            // the disassembly shows the sign extended offset as an unsigned word
+            let value = parse_imm(imm)? as u32 as i32 as i64;
+            if value % (1 << 12) != 0 || !(-(1 << 22)..(1 << 22)).contains(&value) {
+                return Err(format!("{} is not a valid upper offset", imm));
+            }
+            0xf000 | ((value >> 12) as u16 & 0x7ff)
+        }
+        ("bl", [lr, imm]) if lr == "lr" => {
+            0xf800 | (parse_unsigned_imm(imm, 0xffe, 2)? >> 1) as u16
+        }
+        // format 16
+        (b, [target]) if b.len() == 3 && b.starts_with('b') => {
+            let cond = parse_cond(&b[1..])
+                .filter(|cond| *cond != ArmCond::AL)
+                .ok_or_else(|| format!("unknown instruction {}", b))?;
+            0xd000 | (cond as u16) << 8 | branch_offset(parse_target(target)?, addr, 8)?
+        }
+        _ => return Err(format!("can't assemble `{}` with these operands", mnemonic)),
+    };
+    Ok(raw)
+}
+
+/// Assembles a thumb instruction to be placed at `addr`.
+/// `bl` to an address takes two instructions, the disassembly of its halves is accepted as well.
+pub fn assemble_thumb(line: &str, addr: Addr) -> AsmResult<Vec<ThumbInstruction>> {
+    let encoded = with_line(line, {
+        Line::parse(line).and_then(|parsed| match parsed.operands.as_slice() {
+            [target] if parsed.mnemonic == "bl" && !is_imm(target) => {
+                let offset = parse_target(target)?.wrapping_sub(addr.wrapping_add(4)) as i32;
+                if offset % 2 != 0 || !(-(1 << 22)..(1 << 22)).contains(&offset) {
+                    return Err(format!("can't branch to {} from {:#x}", target, addr));
+                }
+                let high = 0xf000 | ((offset >> 12) as u16 & 0x7ff);
+                let low = 0xf800 | ((offset >> 1) as u16 & 0x7ff);
+                Ok(vec![high, low])
+            }
+            _ => Ok(vec![encode(&parsed, addr)?]),
+        })
+    })?;
+    Ok(encoded
+        .into_iter()
+        .enumerate()
+        .map(|(i, raw)| ThumbInstruction::decode(raw, addr + 2 * i as u32))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thumb::ThumbFormat;
+
+    fn asm(line: &str) -> u16 {
+        let insns = assemble_thumb(line, 0x0800_0000).unwrap();
+        assert_eq!(insns.len(), 1);
+        insns[0].raw
+    }
+
+    #[test]
+    fn test_known_encodings() {
+        assert_eq!(asm("lsl r0, r1, #4"), 0x0108);
+        assert_eq!(asm("add r0, r1, r2"), 0x1888);
+        assert_eq!(asm("sub r3, r3, #1"), 0x1e5b);
+        assert_eq!(asm("mov r0, #0xff"), 0x20ff);
+        assert_eq!(asm("cmp r0, r1"), 0x4288);
+        assert_eq!(asm("cmp r8, r1"), 0x4588);
+        assert_eq!(asm("mov r8, lr"), 0x46f0);
+        assert_eq!(asm("bx lr"), 0x4770);
+        assert_eq!(asm("ldr r1, [pc, #0x10]"), 0x4904);
+        assert_eq!(asm("ldrb r0, [r1, r2]"), 0x5c88);
+        assert_eq!(asm("ldsh r0, [r1, r2]"), 0x5e88);
+        assert_eq!(asm("str r0, [r1, #0x7c]"), 0x67c8);
+        assert_eq!(asm("strh r0, [r1, #2]"), 0x8048);
+        assert_eq!(asm("ldr r0, [sp, #8]"), 0x9802);
+        assert_eq!(asm("add r0, sp, #0x10"), 0xa804);
+        assert_eq!(asm("add sp, #-0x10"), 0xb084);
+        assert_eq!(asm("sub sp, #0x10"), 0xb084);
+        assert_eq!(asm("push {r4-r7, lr}"), 0xb5f0);
+        assert_eq!(asm("pop {pc}"), 0xbd00);
+        assert_eq!(asm("stmia r0!, {r1, r2}"), 0xc006);
+        assert_eq!(asm("bne 0x7fffffc"), 0xd1fc);
+        assert_eq!(asm("swi 0x5"), 0xdf05);
+        assert_eq!(asm("b 0x8000000"), 0xe7fe);
+    }
+
+    #[test]
+    fn test_branch_with_link() {
+        let bl = assemble_thumb("bl 0x7ff0000", 0x0800_0000).unwrap();
+        let raw: Vec<u16> = bl.iter().map(|insn| insn.raw).collect();
+        assert_eq!(raw, [0xf7ef, 0xfffe]);
+        assert_eq!(bl[1].pc, 0x0800_0002);
+        assert_eq!(bl[0].to_string(), "bl\t#0xfffef000");
+        assert_eq!(bl[1].to_string(), "bl\tlr, #0xffc");
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |line| assemble_thumb(line, 0).unwrap_err().reason;
+        assert_eq!(err("add r8, r1, r2"), "r8 is not one of r0-r7");
+        assert_eq!(err("ldr r0, [r1, #0x3]"), "#0x3 is not a multiple of 4");
+        assert_eq!(err("push {r8}"), "{r8} can't be transferred");
+        assert_eq!(
+            err("ldm r0, {r1}"),
+            "`ldm` always writes back to the base register"
+        );
+        assert_eq!(err("beq 0x1000"), "can't branch to 0x1000 from 0x0");
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        let addr = 0x0800_1000;
+        for raw in 0..=u16::MAX {
+            let insn = ThumbInstruction::decode(raw, addr);
+            if insn.fmt == ThumbFormat::Undefined {
+                continue;
+            }
+            let text = insn.to_string();
+            let assembled =
+                assemble_thumb(&text, addr).unwrap_or_else(|e| panic!("{:04x}: {}", raw, e));
+            assert_eq!(assembled.len(), 1);
+            assert_eq!(
+                assembled[0].to_string(),
+                text,
+                "{:04x} became {:04x}",
+                raw,
+                assembled[0].raw
+            );
+        }
+    }
+}
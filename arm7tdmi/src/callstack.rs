@@ -0,0 +1,246 @@
+/// Shadow call stack, built by watching the branches the cpu takes instead of unwinding the real stack.
+/// Read by the profiler and by gdb through `monitor bt`. `monitor bt raw` lists the frames for the unwinder
+/// in core/gdb/unwinder.py, which makes gdb's own `bt` walk them too.
+use std::collections::VecDeque;
+
+use super::memory::Addr;
+use super::{CpuMode, Exception};
+
+/// Frames beyond this are dropped from the bottom, so code that never returns can't grow the stack forever
+const MAX_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallFrameKind {
+    /// A subroutine call, through `bl` or by setting lr before branching (`mov lr, pc; bx rN`)
+    Call,
+    /// Exception entry, the handler returns to the interrupted code
+    Exception(Exception),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallFrame {
+    pub kind: CallFrameKind,
+    /// Address of the instruction that made the call, or the one that was interrupted
+    pub call_site: Addr,
+    /// Entry point of the called function or the exception vector
+    pub target: Addr,
+    /// Where execution resumes once this frame returns
+    pub return_addr: Addr,
+    /// Stack pointer of the caller at the time of the call
+    pub sp: u32,
+    /// Mode of the caller
+    pub mode: CpuMode,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: VecDeque<CallFrame>,
+}
+
+impl CallStack {
+    /// Frames from the outermost to the innermost
+    pub fn frames(&self) -> &VecDeque<CallFrame> {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub(crate) fn push(&mut self, frame: CallFrame) {
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+    }
+
+    /// Called when the instruction at `addr` flushed the pipeline, jumping to `target`.
+    /// `lr` is the link register after the instruction executed.
+    pub(crate) fn on_branch(
+        &mut self,
+        addr: Addr,
+        insn_size: u32,
+        target: Addr,
+        lr: u32,
+        sp: u32,
+        mode: CpuMode,
+    ) {
+        let target = target & !1;
+        if let Some(top) = self.frames.back() {
+            // swi and undefined instructions already pushed their frame on exception entry
+            if matches!(top.kind, CallFrameKind::Exception(_)) && top.call_site == addr {
+                return;
+            }
+        }
+        // Returning to any pending frame also discards the frames above it, which covers longjmp-like unwinding
+        if let Some(index) = self.frames.iter().rposition(|f| f.return_addr == target) {
+            self.frames.truncate(index);
+        } else if lr & !1 == addr.wrapping_add(insn_size) {
+            self.push(CallFrame {
+                kind: CallFrameKind::Call,
+                call_site: addr,
+                target,
+                return_addr: lr & !1,
+                sp,
+                mode,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Arm7tdmiCore, SimpleMemory};
+
+    const SP: u32 = 0x0300_7f00;
+
+    #[test]
+    fn test_call_and_return() {
+        let mut cs = CallStack::default();
+        // bl 0x08000100
+        cs.on_branch(
+            0x0800_0000,
+            4,
+            0x0800_0100,
+            0x0800_0004,
+            SP,
+            CpuMode::System,
+        );
+        // thumb bl, lr has the thumb bit set
+        cs.on_branch(
+            0x0800_0104,
+            2,
+            0x0800_0201,
+            0x0800_0107,
+            SP,
+            CpuMode::System,
+        );
+        // plain branch inside the callee, lr is unrelated
+        cs.on_branch(
+            0x0800_0204,
+            2,
+            0x0800_0210,
+            0x0800_0107,
+            SP,
+            CpuMode::System,
+        );
+        assert_eq!(cs.depth(), 2);
+        assert_eq!(cs.frames()[1].target, 0x0800_0200);
+        assert_eq!(cs.frames()[1].return_addr, 0x0800_0106);
+
+        // pop {pc}
+        cs.on_branch(
+            0x0800_0220,
+            2,
+            0x0800_0107,
+            0x0800_0107,
+            SP,
+            CpuMode::System,
+        );
+        assert_eq!(cs.depth(), 1);
+        // bx lr
+        cs.on_branch(
+            0x0800_0110,
+            2,
+            0x0800_0004,
+            0x0800_0004,
+            SP,
+            CpuMode::System,
+        );
+        assert_eq!(cs.depth(), 0);
+    }
+
+    #[test]
+    fn test_call_through_register() {
+        let mut cs = CallStack::default();
+        // mov lr, pc ; bx r3
+        cs.on_branch(
+            0x0800_0004,
+            4,
+            0x0300_0001,
+            0x0800_0008,
+            SP,
+            CpuMode::System,
+        );
+        assert_eq!(
+            cs.frames(),
+            &[CallFrame {
+                kind: CallFrameKind::Call,
+                call_site: 0x0800_0004,
+                target: 0x0300_0000,
+                return_addr: 0x0800_0008,
+                sp: SP,
+                mode: CpuMode::System,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_exception_frames() {
+        let mut cs = CallStack::default();
+        cs.push(CallFrame {
+            kind: CallFrameKind::Exception(Exception::SoftwareInterrupt),
+            call_site: 0x0800_0010,
+            target: 0x08,
+            return_addr: 0x0800_0012,
+            sp: SP,
+            mode: CpuMode::System,
+        });
+        // the swi instruction itself flushed the pipeline, and lr points right after it
+        cs.on_branch(0x0800_0010, 2, 0x08, 0x0800_0012, SP, CpuMode::System);
+        assert_eq!(cs.depth(), 1);
+        // movs pc, lr
+        cs.on_branch(
+            0x0000_0170,
+            4,
+            0x0800_0012,
+            0x0800_0012,
+            SP,
+            CpuMode::Supervisor,
+        );
+        assert_eq!(cs.depth(), 0);
+    }
+
+    #[test]
+    fn test_depth_is_bounded() {
+        let mut cs = CallStack::default();
+        for i in 0..(MAX_DEPTH as u32 + 10) {
+            let addr = 0x0800_0000 + i * 4;
+            cs.on_branch(addr, 4, 0x0900_0000, addr + 4, SP, CpuMode::System);
+        }
+        assert_eq!(cs.depth(), MAX_DEPTH);
+        assert_eq!(cs.frames()[0].call_site, 0x0800_0000 + 10 * 4);
+    }
+
+    #[test]
+    fn test_tracks_cpu_branches() {
+        let program: [u32; 9] = [
+            0xeb000002, // 00: bl 0x10
+            0xeafffffe, // 04: b 0x04
+            0, 0, 0xe92d4000, // 10: push {lr}
+            0xeb000001, // 14: bl 0x20
+            0xe8bd8000, // 18: pop {pc}
+            0, 0xe12fff1e, // 20: bx lr
+        ];
+        let program: Vec<u8> = program.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let mut memory = SimpleMemory::new(0x4000);
+        memory.load_program(&program);
+        let mut cpu = Arm7tdmiCore::new(memory);
+        cpu.set_call_stack_tracking(true);
+        cpu.reset();
+        cpu.gpr[13] = 0x1000;
+
+        let mut depths = vec![];
+        for _ in 0..6 {
+            cpu.step();
+            depths.push(cpu.call_stack().unwrap().depth());
+        }
+        assert_eq!(depths, [1, 1, 2, 1, 0, 0]);
+        assert_eq!(cpu.get_next_pc(), 0x04);
+    }
+}
@@ -0,0 +1,568 @@
+//! Data driven conformance tests for the cpu.
+//!
+//! A test vector describes the cpu and memory before executing a single instruction, the state expected after it,
+//! and optionally every bus transaction the instruction is expected to make. Vectors are plain serde types, the test
+//! suite of this crate reads them from the json files in `tests/vectors`:
+//!
+//! ```json
+//! {
+//!     "name": "adds with carry out",
+//!     "initial": {
+//!         "regs": { "r1": "0xffffffff", "r2": 1, "pc": "0x100" },
+//!         "cpsr": "0x1f",
+//!         "memory": [{ "addr": "0x100", "data": ["0xe0910002"] }]
+//!     },
+//!     "final": {
+//!         "regs": { "r0": 0, "pc": "0x104" },
+//!         "cpsr": "0x6000001f"
+//!     },
+//!     "transactions": [{ "kind": "read", "addr": "0x108", "size": 4, "value": 0, "access": "S" }]
+//! }
+//! ```
+//!
+//! Numbers are either json numbers or strings in decimal or `0x` hex. `pc` is the address of the instruction to
+//! execute, and of the next one in the final state. Registers missing from the initial state are zero, the ones
+//! missing from the final state are expected to keep their initial value, and so are `cpsr` and `spsr`. The cpu
+//! starts in system mode and ARM state unless `cpsr` says otherwise.
+//!
+//! Memory is backed by a [`SimpleMemory`] of [`MEMORY_SIZE`] bytes without waitstates, so every transaction is a
+//! single cycle and `cycles` simply counts them. The pipeline is filled before the test starts, the transactions
+//! are those of executing the instruction at `pc`, starting with the prefetch of `pc + 2 * insn size`.
+//!
+//! The vectors in `tests/vectors` cover every instruction class of both instruction sets and every ALU operation,
+//! not every encoding. Vectors from elsewhere are run as well when `ARM7TDMI_TEST_VECTORS` names a json file or a
+//! directory of them.
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::Deserialize;
+
+use crate::memory::{Addr, DebugRead, DebugWrite, MemoryAccess, MemoryInterface};
+use crate::psr::RegPSR;
+use crate::{Arm7tdmiCore, CpuState, SimpleMemory};
+
+/// Size of the memory tests run in, starting at address 0
+pub const MEMORY_SIZE: usize = 0x4000;
+
+/// Cpsr of tests that don't set one, system mode in ARM state
+const DEFAULT_CPSR: u32 = 0x0000_001f;
+
+/// A 32 bit value, written either as a number or as a decimal or `0x` hex string
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Word(pub u32);
+
+impl<'de> Deserialize<'de> for Word {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Word, D::Error> {
+        struct WordVisitor;
+
+        impl<'de> Visitor<'de> for WordVisitor {
+            type Value = Word;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(
+                    f,
+                    "a 32 bit number or a string with a decimal or hex number"
+                )
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Word, E> {
+                u32::try_from(v)
+                    .map(Word)
+                    .map_err(|_| E::custom(format!("{} doesn't fit in 32 bits", v)))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Word, E> {
+                i32::try_from(v)
+                    .map(|v| Word(v as u32))
+                    .map_err(|_| E::custom(format!("{} doesn't fit in 32 bits", v)))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Word, E> {
+                let parsed = match v.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => v.parse(),
+                };
+                parsed
+                    .map(Word)
+                    .map_err(|_| E::custom(format!("{} is not a 32 bit number", v)))
+            }
+        }
+
+        deserializer.deserialize_any(WordVisitor)
+    }
+}
+
+fn default_block_size() -> usize {
+    4
+}
+
+/// Consecutive values stored little endian starting at `addr`
+#[derive(Debug, Clone, Deserialize)]
+pub struct MemoryBlock {
+    pub addr: Word,
+    /// Size of each value in bytes, 1, 2 or 4
+    #[serde(default = "default_block_size")]
+    pub size: usize,
+    pub data: Vec<Word>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CpuTestState {
+    /// Registers of the current mode by name, `r0`-`r15`, `sp`, `lr` or `pc`
+    #[serde(default)]
+    pub regs: BTreeMap<String, Word>,
+    pub cpsr: Option<Word>,
+    /// Spsr of the current mode
+    pub spsr: Option<Word>,
+    #[serde(default)]
+    pub memory: Vec<MemoryBlock>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Access {
+    #[serde(rename = "N")]
+    NonSeq,
+    #[serde(rename = "S")]
+    Seq,
+}
+
+impl From<MemoryAccess> for Access {
+    fn from(access: MemoryAccess) -> Access {
+        match access {
+            MemoryAccess::NonSeq => Access::NonSeq,
+            MemoryAccess::Seq => Access::Seq,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Transaction {
+    Read {
+        addr: Word,
+        size: usize,
+        value: Word,
+        access: Access,
+    },
+    Write {
+        addr: Word,
+        size: usize,
+        value: Word,
+        access: Access,
+    },
+    Idle,
+}
+
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = |access: &Access| match access {
+            Access::NonSeq => "N",
+            Access::Seq => "S",
+        };
+        match self {
+            Transaction::Read {
+                addr,
+                size,
+                value,
+                access: a,
+            } => write!(
+                f,
+                "read{} [{:08x}] = {:x} ({})",
+                size * 8,
+                addr.0,
+                value.0,
+                access(a)
+            ),
+            Transaction::Write {
+                addr,
+                size,
+                value,
+                access: a,
+            } => write!(
+                f,
+                "write{} [{:08x}] = {:x} ({})",
+                size * 8,
+                addr.0,
+                value.0,
+                access(a)
+            ),
+            Transaction::Idle => write!(f, "idle"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CpuTest {
+    pub name: String,
+    pub initial: CpuTestState,
+    #[serde(rename = "final")]
+    pub expected: CpuTestState,
+    /// Every bus transaction made by the instruction, not checked if missing
+    pub transactions: Option<Vec<Transaction>>,
+    /// Total cycles taken by the instruction, not checked if missing
+    pub cycles: Option<usize>,
+}
+
+/// A test that didn't end in the expected state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestFailure {
+    pub name: String,
+    pub mismatches: Vec<String>,
+}
+
+impl fmt::Display for TestFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.name)?;
+        for mismatch in &self.mismatches {
+            write!(f, "\n    {}", mismatch)?;
+        }
+        Ok(())
+    }
+}
+
+/// [`SimpleMemory`] that records every transaction made by the cpu
+struct RecordingMemory {
+    memory: SimpleMemory,
+    transactions: Vec<Transaction>,
+}
+
+impl RecordingMemory {
+    fn read(&mut self, addr: Addr, size: usize, value: u32, access: MemoryAccess) {
+        self.transactions.push(Transaction::Read {
+            addr: Word(addr),
+            size,
+            value: Word(value),
+            access: access.into(),
+        });
+    }
+
+    fn write(&mut self, addr: Addr, size: usize, value: u32, access: MemoryAccess) {
+        self.transactions.push(Transaction::Write {
+            addr: Word(addr),
+            size,
+            value: Word(value),
+            access: access.into(),
+        });
+    }
+}
+
+impl MemoryInterface for RecordingMemory {
+    fn load_8(&mut self, addr: u32, access: MemoryAccess) -> u8 {
+        let value = self.memory.load_8(addr, access);
+        self.read(addr, 1, value as u32, access);
+        value
+    }
+
+    fn load_16(&mut self, addr: u32, access: MemoryAccess) -> u16 {
+        let value = self.memory.load_16(addr, access);
+        self.read(addr, 2, value as u32, access);
+        value
+    }
+
+    fn load_32(&mut self, addr: u32, access: MemoryAccess) -> u32 {
+        let value = self.memory.load_32(addr, access);
+        self.read(addr, 4, value, access);
+        value
+    }
+
+    fn store_8(&mut self, addr: u32, value: u8, access: MemoryAccess) {
+        self.write(addr, 1, value as u32, access);
+        self.memory.store_8(addr, value, access);
+    }
+
+    fn store_16(&mut self, addr: u32, value: u16, access: MemoryAccess) {
+        self.write(addr, 2, value as u32, access);
+        self.memory.store_16(addr, value, access);
+    }
+
+    fn store_32(&mut self, addr: u32, value: u32, access: MemoryAccess) {
+        self.write(addr, 4, value, access);
+        self.memory.store_32(addr, value, access);
+    }
+
+    fn idle_cycle(&mut self) {
+        self.transactions.push(Transaction::Idle);
+    }
+}
+
+fn parse_reg(name: &str) -> Result<usize, String> {
+    match name {
+        "sp" => Ok(13),
+        "lr" => Ok(14),
+        "pc" => Ok(15),
+        _ => name
+            .strip_prefix('r')
+            .and_then(|n| n.parse().ok())
+            .filter(|n| *n < 16)
+            .ok_or_else(|| format!("{} is not a register", name)),
+    }
+}
+
+/// Registers of a test state, indexed by register number
+fn parse_regs(state: &CpuTestState) -> Result<[Option<u32>; 16], String> {
+    let mut regs = [None; 16];
+    for (name, value) in &state.regs {
+        regs[parse_reg(name)?] = Some(value.0);
+    }
+    Ok(regs)
+}
+
+fn check_block(block: &MemoryBlock) -> Result<(), String> {
+    let end = block.addr.0 as usize + block.size * block.data.len();
+    if ![1, 2, 4].contains(&block.size) {
+        Err(format!(
+            "memory values of {} bytes are not supported",
+            block.size
+        ))
+    } else if end > MEMORY_SIZE {
+        Err(format!("memory at {:#x} is out of bounds", block.addr.0))
+    } else {
+        Ok(())
+    }
+}
+
+/// Runs a test and checks the resulting state, an error in the test itself is reported as a failure as well
+pub fn run_test(test: &CpuTest) -> Result<(), TestFailure> {
+    let fail = |mismatches: Vec<String>| {
+        Err(TestFailure {
+            name: test.name.clone(),
+            mismatches,
+        })
+    };
+    let (initial_regs, final_regs) = match (parse_regs(&test.initial), parse_regs(&test.expected)) {
+        (Ok(initial), Ok(expected)) => (initial, expected),
+        (Err(e), _) | (_, Err(e)) => return fail(vec![e]),
+    };
+    if let Err(e) = test
+        .initial
+        .memory
+        .iter()
+        .chain(&test.expected.memory)
+        .try_for_each(check_block)
+    {
+        return fail(vec![e]);
+    }
+
+    let mut memory = SimpleMemory::new(MEMORY_SIZE);
+    for block in &test.initial.memory {
+        for (i, value) in block.data.iter().enumerate() {
+            let addr = block.addr.0 + (i * block.size) as Addr;
+            match block.size {
+                1 => memory.debug_write_8(addr, value.0 as u8),
+                2 => memory.debug_write_16(addr, value.0 as u16),
+                _ => memory.debug_write_32(addr, value.0),
+            }
+        }
+    }
+    let mut cpu = Arm7tdmiCore::new(RecordingMemory {
+        memory,
+        transactions: vec![],
+    });
+    let initial_cpsr = test.initial.cpsr.map_or(DEFAULT_CPSR, |cpsr| cpsr.0);
+    cpu.cpsr = RegPSR::new(initial_cpsr);
+    if let Some(spsr) = test.initial.spsr {
+        cpu.spsr = RegPSR::new(spsr.0);
+    }
+    for (r, value) in initial_regs.iter().enumerate().take(15) {
+        cpu.gpr[r] = value.unwrap_or(0);
+    }
+    let initial_pc = initial_regs[15].unwrap_or(0);
+    cpu.set_next_pc(initial_pc);
+    cpu.bus.transactions.clear();
+
+    cpu.step();
+
+    let mut mismatches = vec![];
+    for r in 0..16 {
+        let expected = match (final_regs[r], r) {
+            (Some(value), _) => value,
+            (None, 15) => {
+                let insn_size = match RegPSR::new(initial_cpsr).state() {
+                    CpuState::ARM => 4,
+                    CpuState::THUMB => 2,
+                };
+                initial_pc.wrapping_add(insn_size)
+            }
+            (None, _) => initial_regs[r].unwrap_or(0),
+        };
+        let actual = if r == 15 {
+            cpu.get_next_pc()
+        } else {
+            cpu.gpr[r]
+        };
+        if actual != expected {
+            mismatches.push(format!(
+                "r{}: expected {:08x}, got {:08x}",
+                r, expected, actual
+            ));
+        }
+    }
+    let expected_cpsr = test.expected.cpsr.map_or(initial_cpsr, |cpsr| cpsr.0);
+    let expected_cpsr = RegPSR::new(expected_cpsr).get();
+    if cpu.cpsr.get() != expected_cpsr {
+        mismatches.push(format!(
+            "cpsr: expected {:08x}, got {:08x}",
+            expected_cpsr,
+            cpu.cpsr.get()
+        ));
+    }
+    if let Some(spsr) = test.expected.spsr.or(test.initial.spsr) {
+        let expected_spsr = RegPSR::new(spsr.0).get();
+        if cpu.spsr.get() != expected_spsr {
+            mismatches.push(format!(
+                "spsr: expected {:08x}, got {:08x}",
+                expected_spsr,
+                cpu.spsr.get()
+            ));
+        }
+    }
+    let bus = &mut cpu.bus;
+    for block in &test.expected.memory {
+        for (i, value) in block.data.iter().enumerate() {
+            let addr = block.addr.0 + (i * block.size) as Addr;
+            let (actual, expected) = match block.size {
+                1 => (bus.memory.debug_read_8(addr) as u32, value.0 & 0xff),
+                2 => (bus.memory.debug_read_16(addr) as u32, value.0 & 0xffff),
+                _ => (bus.memory.debug_read_32(addr), value.0),
+            };
+            if actual != expected {
+                mismatches.push(format!(
+                    "memory at {:08x}: expected {:x}, got {:x}",
+                    addr, expected, actual
+                ));
+            }
+        }
+    }
+    if let Some(transactions) = &test.transactions {
+        if *transactions != bus.transactions {
+            let list = |transactions: &[Transaction]| {
+                transactions
+                    .iter()
+                    .map(Transaction::to_string)
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            };
+            mismatches.push(format!(
+                "transactions: expected [{}], got [{}]",
+                list(transactions),
+                list(&bus.transactions)
+            ));
+        }
+    }
+    if let Some(cycles) = test.cycles {
+        if cycles != bus.transactions.len() {
+            mismatches.push(format!(
+                "cycles: expected {}, got {}",
+                cycles,
+                bus.transactions.len()
+            ));
+        }
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        fail(mismatches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// Extra vectors to run, a json file or a directory of them
+    const EXTRA_VECTORS_ENV: &str = "ARM7TDMI_TEST_VECTORS";
+
+    fn vector_files(path: &Path) -> Vec<PathBuf> {
+        if path.is_file() {
+            return vec![path.to_path_buf()];
+        }
+        let mut files: Vec<PathBuf> = fs::read_dir(path)
+            .unwrap_or_else(|e| panic!("can't read {}: {}", path.display(), e))
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn load_vectors(path: &Path) -> Vec<CpuTest> {
+        let json = fs::read_to_string(path).unwrap();
+        serde_json::from_str(&json)
+            .unwrap_or_else(|e| panic!("can't parse {}: {}", path.display(), e))
+    }
+
+    #[test]
+    fn test_conformance_vectors() {
+        let mut paths = vector_files(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/vectors"));
+        if let Some(extra) = std::env::var_os(EXTRA_VECTORS_ENV) {
+            paths.extend(vector_files(Path::new(&extra)));
+        }
+        let mut failures = vec![];
+        let mut count = 0;
+        for path in paths {
+            for test in load_vectors(&path) {
+                count += 1;
+                if let Err(failure) = run_test(&test) {
+                    failures.push(format!("{}: {}", path.display(), failure));
+                }
+            }
+        }
+        assert!(count > 0);
+        assert!(
+            failures.is_empty(),
+            "{} of {} cpu tests failed\n{}",
+            failures.len(),
+            count,
+            failures.join("\n")
+        );
+    }
+
+    #[test]
+    fn test_reports_mismatches() {
+        let test: CpuTest = serde_json::from_str(
+            r#"{
+                "name": "mov r0, #1",
+                "initial": {
+                    "regs": { "r0": 5, "pc": "0x100" },
+                    "memory": [{ "addr": "0x100", "data": ["0xe3a00001"] }]
+                },
+                "final": {
+                    "regs": { "r0": 2 },
+                    "cpsr": "0x8000001f",
+                    "memory": [{ "addr": "0x100", "size": 2, "data": ["0x0001"] }]
+                },
+                "transactions": [],
+                "cycles": 1
+            }"#,
+        )
+        .unwrap();
+        let failure = run_test(&test).unwrap_err();
+        assert_eq!(
+            failure.mismatches,
+            [
+                "r0: expected 00000002, got 00000001",
+                "cpsr: expected 8000001f, got 0000001f",
+                "transactions: expected [], got [read32 [00000108] = 0 (S)]",
+            ]
+        );
+
+        let mut test = test;
+        test.initial.regs.insert("r16".to_string(), Word(0));
+        assert_eq!(
+            run_test(&test).unwrap_err().mismatches,
+            ["r16 is not a register"]
+        );
+    }
+
+    #[test]
+    fn test_word_formats() {
+        let words: Vec<Word> = serde_json::from_str(r#"[1, -1, "0x10", "42"]"#).unwrap();
+        assert_eq!(words, [Word(1), Word(0xffff_ffff), Word(0x10), Word(42)]);
+        assert!(serde_json::from_str::<Word>("4294967296").is_err());
+        assert!(serde_json::from_str::<Word>(r#""0xg""#).is_err());
+    }
+}
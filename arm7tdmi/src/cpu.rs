@@ -1,19 +1,21 @@
 use std::fmt;
+use std::io;
 
 use ansi_term::Style;
 use bit::BitIndex;
-use log::debug;
+use log::{debug, error};
 use num::FromPrimitive;
 use serde::{Deserialize, Serialize};
 
-use rustboyadvance_utils::{Shared, WeakPointer};
-
-pub use super::exception::Exception;
+use super::exception::UndefinedHookSlot;
+pub use super::exception::{Exception, InterruptLine, UndefinedHook, UndefinedInstruction};
 use super::reg_string;
 
 use super::{arm::ArmCond, psr::RegPSR, Addr, CpuMode, CpuState};
 
+use super::callstack::CallStack;
 use super::memory::{MemoryAccess, MemoryInterface};
+use super::trace::{InstructionTracer, TracedOpcode, TracerSlot};
 use MemoryAccess::*;
 
 use cfg_if::cfg_if;
@@ -107,7 +109,8 @@ impl Default for DebuggerState {
 #[derive(Clone)]
 pub struct Arm7tdmiCore<I: MemoryInterface> {
     pub pc: u32,
-    pub bus: Shared<I>,
+    /// The bus is owned by the core, use a handle type to share it with the rest of the system
+    pub bus: I,
 
     next_fetch_access: MemoryAccess,
     pipeline: [u32; 2],
@@ -118,16 +121,30 @@ pub struct Arm7tdmiCore<I: MemoryInterface> {
 
     pub banks: BankedRegisters,
 
+    /// Bitmask of the asserted `InterruptLine`s, driven by the system and not part of the saved state
+    pub(crate) interrupt_lines: u8,
+
     /// Hardware breakpoints for use by gdb
     breakpoints: Vec<Addr>,
 
+    /// Writes every executed instruction to a trace file
+    tracer: TracerSlot,
+
+    /// Shadow call stack, only maintained while call stack tracking is enabled
+    pub(crate) call_stack: Option<CallStack>,
+
+    /// Consulted on every undefined instruction
+    pub(crate) undefined_hook: UndefinedHookSlot,
+    /// The undefined instruction the hook asked to break on, until taken by `take_undefined_break`
+    pub(crate) undefined_break: Option<UndefinedInstruction>,
+
     /// Deprecated in-house debugger state
     #[cfg(feature = "debugger")]
     pub dbg: DebuggerState,
 }
 
 impl<I: MemoryInterface> Arm7tdmiCore<I> {
-    pub fn new(bus: Shared<I>) -> Arm7tdmiCore<I> {
+    pub fn new(bus: I) -> Arm7tdmiCore<I> {
         let cpsr = RegPSR::new(0x0000_00D3);
         Arm7tdmiCore {
             bus,
@@ -138,19 +155,20 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
             cpsr,
             spsr: Default::default(),
             banks: BankedRegisters::default(),
+            interrupt_lines: 0,
 
             breakpoints: Vec::new(),
+            tracer: TracerSlot::default(),
+            call_stack: None,
+            undefined_hook: UndefinedHookSlot(None),
+            undefined_break: None,
 
             #[cfg(feature = "debugger")]
             dbg: DebuggerState::default(),
         }
     }
 
-    pub fn weak_ptr(&mut self) -> WeakPointer<Arm7tdmiCore<I>> {
-        WeakPointer::new(self as *mut Arm7tdmiCore<I>)
-    }
-
-    pub fn from_saved_state(bus: Shared<I>, state: SavedCpuState) -> Arm7tdmiCore<I> {
+    pub fn from_saved_state(bus: I, state: SavedCpuState) -> Arm7tdmiCore<I> {
         Arm7tdmiCore {
             bus,
 
@@ -162,8 +180,13 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
 
             pipeline: state.pipeline,
             next_fetch_access: state.next_fetch_access,
+            interrupt_lines: 0,
 
             breakpoints: Vec::new(), // TODO include breakpoints in saved state
+            tracer: TracerSlot::default(),
+            call_stack: None,
+            undefined_hook: UndefinedHookSlot(None),
+            undefined_break: None,
 
             // savestate does not keep debugger related information, so just reinitialize to default
             #[cfg(feature = "debugger")]
@@ -191,10 +214,10 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
         self.banks = state.banks;
         self.pipeline = state.pipeline;
         self.next_fetch_access = state.next_fetch_access;
-    }
-
-    pub fn set_memory_interface(&mut self, i: Shared<I>) {
-        self.bus = i;
+        // The shadow stack describes the execution we are leaving behind
+        if let Some(call_stack) = &mut self.call_stack {
+            call_stack.clear();
+        }
     }
 
     pub fn add_breakpoint(&mut self, addr: Addr) {
@@ -209,6 +232,11 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
         }
     }
 
+    pub fn clear_breakpoints(&mut self) {
+        debug!("clearing all breakpoints");
+        self.breakpoints.clear();
+    }
+
     pub fn check_breakpoint(&self) -> Option<u32> {
         let next_pc = self.get_next_pc();
         for bp in &self.breakpoints {
@@ -219,6 +247,87 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
         None
     }
 
+    /// Starts writing every executed instruction to `tracer`, replacing the previous tracer if any
+    pub fn start_trace(&mut self, tracer: InstructionTracer) {
+        debug!("starting instruction trace {:?}", tracer);
+        self.tracer.0 = Some(tracer);
+    }
+
+    /// Stops tracing and flushes whatever is left in the trace buffer
+    pub fn stop_trace(&mut self) -> io::Result<()> {
+        match self.tracer.0.take() {
+            Some(mut tracer) => tracer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.tracer.0.is_some()
+    }
+
+    /// Starts or stops maintaining a shadow call stack.
+    /// The stack starts out empty, so calls made before tracking was enabled are not part of it.
+    pub fn set_call_stack_tracking(&mut self, enabled: bool) {
+        match (enabled, &self.call_stack) {
+            (true, None) => self.call_stack = Some(CallStack::default()),
+            (false, _) => self.call_stack = None,
+            _ => {}
+        }
+    }
+
+    /// The shadow call stack, if tracking is enabled
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.call_stack.as_ref()
+    }
+
+    /// Registers a hook that is called before the Undefined exception is taken, replacing the previous hook if any
+    pub fn set_undefined_hook(&mut self, hook: Option<UndefinedHook>) {
+        self.undefined_hook.0 = hook;
+    }
+
+    /// Shorthand for a hook that breaks on every undefined instruction
+    pub fn set_break_on_undefined(&mut self, enabled: bool) {
+        self.set_undefined_hook(match enabled {
+            true => Some(Box::new(|_| true)),
+            false => None,
+        });
+    }
+
+    /// The undefined instruction the hook asked to break on, if one was executed and not yet taken.
+    /// By then the cpu has entered the Undefined exception, with lr pointing after the instruction.
+    pub fn check_undefined_break(&self) -> Option<UndefinedInstruction> {
+        self.undefined_break
+    }
+
+    pub fn take_undefined_break(&mut self) -> Option<UndefinedInstruction> {
+        self.undefined_break.take()
+    }
+
+    /// Called after the instruction at `addr` flushed the pipeline
+    #[inline]
+    fn track_branch(&mut self, addr: Addr, insn_size: u32) {
+        let target = self.get_next_pc();
+        let (lr, sp, mode) = (self.gpr[14], self.gpr[13], self.cpsr.mode());
+        if let Some(call_stack) = &mut self.call_stack {
+            call_stack.on_branch(addr, insn_size, target, lr, sp, mode);
+        }
+    }
+
+    #[inline]
+    fn trace(&mut self, opcode: TracedOpcode) {
+        if let Some(tracer) = &mut self.tracer.0 {
+            // Same as get_next_pc(), the pipeline is two instructions ahead
+            let addr = match opcode {
+                TracedOpcode::Arm(_) => self.pc.wrapping_sub(8),
+                TracedOpcode::Thumb(_) => self.pc.wrapping_sub(4),
+            };
+            if let Err(e) = tracer.trace(addr, opcode, &self.gpr, self.pc, self.cpsr) {
+                error!("failed to write instruction trace, tracing stopped: {}", e);
+                self.tracer.0 = None;
+            }
+        }
+    }
+
     #[cfg(feature = "debugger")]
     pub fn set_verbose(&mut self, v: bool) {
         self.dbg.verbose = v;
@@ -299,6 +408,102 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
         self.gpr
     }
 
+    /// Gets register `r` as seen from `mode`, whether or not `mode` is the current mode.
+    pub fn get_banked_reg(&self, mode: CpuMode, r: usize) -> u32 {
+        let current_mode = self.cpsr.mode();
+        match r {
+            0..=7 => self.gpr[r],
+            8..=12 => {
+                if (mode == CpuMode::Fiq) == (current_mode == CpuMode::Fiq) {
+                    self.gpr[r]
+                } else if mode == CpuMode::Fiq {
+                    self.banks.gpr_banked_fiq_r8_12[r - 8]
+                } else {
+                    self.banks.gpr_banked_old_r8_12[r - 8]
+                }
+            }
+            13 | 14 => {
+                if mode.bank_index() == current_mode.bank_index() {
+                    self.gpr[r]
+                } else if r == 13 {
+                    self.banks.gpr_banked_r13[mode.bank_index()]
+                } else {
+                    self.banks.gpr_banked_r14[mode.bank_index()]
+                }
+            }
+            _ => panic!("invalid register {}", r),
+        }
+    }
+
+    /// Sets register `r` as seen from `mode`, whether or not `mode` is the current mode.
+    pub fn set_banked_reg(&mut self, mode: CpuMode, r: usize, val: u32) {
+        let current_mode = self.cpsr.mode();
+        match r {
+            0..=7 => self.gpr[r] = val,
+            8..=12 => {
+                if (mode == CpuMode::Fiq) == (current_mode == CpuMode::Fiq) {
+                    self.gpr[r] = val;
+                } else if mode == CpuMode::Fiq {
+                    self.banks.gpr_banked_fiq_r8_12[r - 8] = val;
+                } else {
+                    self.banks.gpr_banked_old_r8_12[r - 8] = val;
+                }
+            }
+            13 | 14 => {
+                if mode.bank_index() == current_mode.bank_index() {
+                    self.gpr[r] = val;
+                } else if r == 13 {
+                    self.banks.gpr_banked_r13[mode.bank_index()] = val;
+                } else {
+                    self.banks.gpr_banked_r14[mode.bank_index()] = val;
+                }
+            }
+            _ => panic!("invalid register {}", r),
+        }
+    }
+
+    /// Gets the SPSR of `mode`, or None for User and System modes which have no SPSR.
+    pub fn get_spsr(&self, mode: CpuMode) -> Option<RegPSR> {
+        mode.spsr_index()?;
+        if mode.bank_index() == self.cpsr.mode().bank_index() {
+            Some(self.spsr)
+        } else {
+            Some(self.banks.spsr_bank[mode.bank_index()])
+        }
+    }
+
+    /// Sets the SPSR of `mode`, does nothing for User and System modes.
+    pub fn set_spsr(&mut self, mode: CpuMode, val: RegPSR) {
+        if mode.spsr_index().is_none() {
+            return;
+        }
+        if mode.bank_index() == self.cpsr.mode().bank_index() {
+            self.spsr = val;
+        } else {
+            self.banks.spsr_bank[mode.bank_index()] = val;
+        }
+    }
+
+    /// Switches to `new_mode`, swapping in the banked registers of the new mode
+    pub fn set_mode(&mut self, new_mode: CpuMode) {
+        self.change_mode(self.cpsr.mode(), new_mode);
+    }
+
+    /// Sets the address of the next instruction to execute and refills the pipeline.
+    /// The instruction set is taken from the T bit of the CPSR.
+    pub fn set_next_pc(&mut self, addr: Addr) {
+        match self.cpsr.state() {
+            CpuState::ARM => {
+                self.pc = addr & !3;
+                self.reload_pipeline32();
+            }
+            CpuState::THUMB => {
+                self.pc = addr & !1;
+                self.reload_pipeline16();
+            }
+        }
+    }
+
     pub(super) fn change_mode(&mut self, old_mode: CpuMode, new_mode: CpuMode) {
         let new_index = new_mode.bank_index();
         let old_index = old_mode.bank_index();
@@ -343,7 +548,10 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
         }
     }
 
-    pub(super) fn get_required_multipiler_array_cycles(&self, rs: u32) -> usize {
+    /// Internal cycles taken by the multiplier array, which terminates early once the remaining bytes of `rs` are all
+    /// zeroes, or all ones for signed multiplies (MUL, MLA, SMULL, SMLAL).
+    pub(super) fn get_required_multipiler_array_cycles(&self, rs: u32, signed: bool) -> usize {
+        let rs = if signed && (rs as i32) < 0 { !rs } else { rs };
         if rs & 0xff == rs {
             1
         } else if rs & 0xffff == rs {
@@ -450,10 +658,13 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
         self.pipeline[1]
     }
 
-    /// Perform a pipeline step
-    /// If an instruction was executed in this step, return it.
+    /// Perform a pipeline step: fetch the next opcode and execute the decoded one.
+    /// All cycles are reported to the bus through `MemoryInterface`.
     #[inline]
     pub fn step(&mut self) {
+        if self.interrupt_lines != 0 {
+            self.sample_interrupt_lines();
+        }
         match self.cpsr.state() {
             CpuState::ARM => {
                 let pc = self.pc & !3;
@@ -462,6 +673,7 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
                 let insn = self.pipeline[0];
                 self.pipeline[0] = self.pipeline[1];
                 self.pipeline[1] = fetched_now;
+                self.trace(TracedOpcode::Arm(insn));
                 let cond = ArmCond::from_u8(insn.bit_range(28..32) as u8)
                     .unwrap_or_else(|| unsafe { std::hint::unreachable_unchecked() });
                 if cond != ArmCond::AL && !self.check_arm_cond(cond) {
@@ -474,7 +686,11 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
                         self.next_fetch_access = access;
                         self.advance_arm();
                     }
-                    CpuAction::PipelineFlushed => {}
+                    CpuAction::PipelineFlushed => {
+                        if self.call_stack.is_some() {
+                            self.track_branch(pc.wrapping_sub(8), 4);
+                        }
+                    }
                 }
             }
             CpuState::THUMB => {
@@ -484,12 +700,17 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
                 let insn = self.pipeline[0];
                 self.pipeline[0] = self.pipeline[1];
                 self.pipeline[1] = fetched_now as u32;
+                self.trace(TracedOpcode::Thumb(insn as u16));
                 match self.step_thumb_exec(insn as u16) {
                     CpuAction::AdvancePC(access) => {
                         self.advance_thumb();
                         self.next_fetch_access = access;
                     }
-                    CpuAction::PipelineFlushed => {}
+                    CpuAction::PipelineFlushed => {
+                        if self.call_stack.is_some() {
+                            self.track_branch(pc.wrapping_sub(4), 2);
+                        }
+                    }
                 }
             }
         }
@@ -498,7 +719,7 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
     /// Get's the address of the next instruction that is going to be executed
     pub fn get_next_pc(&self) -> Addr {
         let insn_size = self.word_size() as u32;
-        self.pc - 2 * insn_size
+        self.pc.wrapping_sub(2 * insn_size)
     }
 
     pub fn get_cpu_state(&self) -> CpuState {
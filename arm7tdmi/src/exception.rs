@@ -1,4 +1,8 @@
-use super::memory::MemoryInterface;
+use log::debug;
+
+use super::callstack::{CallFrame, CallFrameKind};
+use super::cpu::CpuAction;
+use super::memory::{Addr, MemoryInterface};
 use super::Arm7tdmiCore;
 use super::{CpuMode, CpuState};
 
@@ -16,15 +20,42 @@ pub enum Exception {
     Fiq = 0x1c,
 }
 
+/// External interrupt inputs of the core
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum InterruptLine {
+    Irq = 0b01,
+    Fiq = 0b10,
+}
+
+/// An undefined instruction the cpu is entering the Undefined exception for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UndefinedInstruction {
+    pub addr: Addr,
+    pub opcode: u32,
+    pub state: CpuState,
+}
+
+/// Called before the cpu enters the Undefined exception, returns whether execution should break afterwards
+pub type UndefinedHook = Box<dyn FnMut(&UndefinedInstruction) -> bool>;
+
+pub(crate) struct UndefinedHookSlot(pub(crate) Option<UndefinedHook>);
+
+impl Clone for UndefinedHookSlot {
+    fn clone(&self) -> Self {
+        UndefinedHookSlot(None)
+    }
+}
+
 impl<I: MemoryInterface> Arm7tdmiCore<I> {
     pub fn exception(&mut self, e: Exception, lr: u32) {
         use Exception::*;
         let (new_mode, irq_disable, fiq_disable) = match e {
             Reset => (CpuMode::Supervisor, true, true),
-            UndefinedInstruction => (CpuMode::Undefined, false, false),
+            UndefinedInstruction => (CpuMode::Undefined, true, false),
             SoftwareInterrupt => (CpuMode::Supervisor, true, false),
-            DataAbort => (CpuMode::Abort, false, false),
-            PrefatchAbort => (CpuMode::Abort, false, false),
+            DataAbort => (CpuMode::Abort, true, false),
+            PrefatchAbort => (CpuMode::Abort, true, false),
             Reserved => panic!("Cpu reserved exception"),
             Irq => (CpuMode::Irq, true, false),
             Fiq => (CpuMode::Fiq, true, true),
@@ -37,10 +68,33 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
             }
         }
 
-        let new_bank = new_mode.bank_index();
-        self.banks.spsr_bank[new_bank] = self.cpsr;
-        self.banks.gpr_banked_r14[new_bank] = lr;
+        let call_site = self.get_next_pc();
+        let (sp, mode) = (self.gpr[13], self.cpsr.mode());
+        if let Some(call_stack) = &mut self.call_stack {
+            // Where the handler is expected to return to, given the lr it was entered with
+            let return_addr = match e {
+                Irq | Fiq | PrefatchAbort => lr.wrapping_sub(4),
+                DataAbort => lr.wrapping_sub(8),
+                _ => lr,
+            };
+            match e {
+                Reset => call_stack.clear(),
+                _ => call_stack.push(CallFrame {
+                    kind: CallFrameKind::Exception(e),
+                    call_site,
+                    target: e as u32,
+                    return_addr,
+                    sp,
+                    mode,
+                }),
+            }
+        }
+
+        // Written after the mode switch, which does not touch the registers when already in `new_mode`
+        let old_cpsr = self.cpsr;
         self.change_mode(self.cpsr.mode(), new_mode);
+        self.spsr = old_cpsr;
+        self.gpr[14] = lr;
 
         // Set appropriate CPSR bits
         self.cpsr.set_state(CpuState::ARM);
@@ -57,6 +111,8 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
         self.reload_pipeline32();
     }
 
+    /// Takes the IRQ exception unless IRQs are masked by the CPSR.
+    /// Call this between steps while the interrupt line is asserted.
     #[inline]
     pub fn irq(&mut self) {
         if !self.cpsr.irq_disabled() {
@@ -65,8 +121,253 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
         }
     }
 
+    /// Takes the FIQ exception unless FIQs are masked by the CPSR.
+    #[inline]
+    pub fn fiq(&mut self) {
+        if !self.cpsr.fiq_disabled() {
+            let lr = self.get_next_pc() + 4;
+            self.exception(Exception::Fiq, lr);
+        }
+    }
+
+    /// Asserts or deasserts an interrupt input.
+    /// Asserted lines are sampled before every step, like the level triggered inputs of the real core,
+    /// so the device driving a line has to deassert it once the handler acknowledged the interrupt.
+    pub fn set_interrupt_line(&mut self, line: InterruptLine, asserted: bool) {
+        if asserted {
+            self.interrupt_lines |= line as u8;
+        } else {
+            self.interrupt_lines &= !(line as u8);
+        }
+    }
+
+    pub fn interrupt_line(&self, line: InterruptLine) -> bool {
+        self.interrupt_lines & line as u8 != 0
+    }
+
+    /// Takes the exception for the asserted lines that are not masked, FIQ having priority over IRQ
+    #[inline]
+    pub(crate) fn sample_interrupt_lines(&mut self) {
+        if self.interrupt_line(InterruptLine::Fiq) && !self.cpsr.fiq_disabled() {
+            self.fiq();
+        } else if self.interrupt_line(InterruptLine::Irq) {
+            self.irq();
+        }
+    }
+
+    /// Enters the Undefined exception for the instruction being executed.
+    /// Execution Time: 2S+1I+1N
+    pub(crate) fn undefined_instruction(&mut self, opcode: u32) -> CpuAction {
+        let insn = UndefinedInstruction {
+            addr: self.get_next_pc(),
+            opcode,
+            state: self.cpsr.state(),
+        };
+        // Games may execute these on purpose, the undefined hook is there to catch the ones that matter
+        debug!(
+            "undefined {:?} instruction {:08x} at 0x{:08x}",
+            insn.state, insn.opcode, insn.addr
+        );
+        if let Some(hook) = &mut self.undefined_hook.0 {
+            if hook(&insn) {
+                self.undefined_break = Some(insn);
+            }
+        }
+        let lr = insn.addr.wrapping_add(self.word_size() as u32);
+        self.idle_cycle();
+        self.exception(Exception::UndefinedInstruction, lr);
+        CpuAction::PipelineFlushed
+    }
+
     #[inline]
     pub fn software_interrupt(&mut self, lr: u32, _cmt: u32) {
         self.exception(Exception::SoftwareInterrupt, lr);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleMemory;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_undefined_instruction() {
+        let program: [u32; 2] = [
+            0xe7f000f0, // 00: undefined
+            0xeafffffe, // 04: b 0x04
+        ];
+        let program: Vec<u8> = program.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let mut memory = SimpleMemory::new(0x4000);
+        memory.load_program(&program);
+        let mut cpu = Arm7tdmiCore::new(memory);
+        cpu.reset();
+
+        let seen = Rc::new(RefCell::new(vec![]));
+        let hook_seen = seen.clone();
+        cpu.set_undefined_hook(Some(Box::new(move |insn| {
+            hook_seen.borrow_mut().push(*insn);
+            true
+        })));
+        cpu.step();
+
+        let expected = UndefinedInstruction {
+            addr: 0,
+            opcode: 0xe7f000f0,
+            state: CpuState::ARM,
+        };
+        assert_eq!(*seen.borrow(), [expected]);
+        assert_eq!(cpu.take_undefined_break(), Some(expected));
+        assert_eq!(cpu.take_undefined_break(), None);
+        assert_eq!(cpu.cpsr.mode(), CpuMode::Undefined);
+        assert!(cpu.cpsr.irq_disabled());
+        assert_eq!(cpu.spsr.mode(), CpuMode::Supervisor);
+        assert_eq!(cpu.get_reg(14), 0x04);
+        assert_eq!(cpu.get_next_pc(), 0x04);
+
+        cpu.step();
+        assert_eq!(seen.borrow().len(), 1);
+        assert_eq!(cpu.get_next_pc(), 0x04);
+    }
+
+    /// A cpu in System mode with interrupts enabled, about to execute at 0x100
+    fn interrupt_test_cpu() -> Arm7tdmiCore<SimpleMemory> {
+        let mut program = [0u32; 0x42];
+        program[0x18 / 4] = 0xe3a01002; // 18: mov r1, #2
+        program[0x1c / 4] = 0xe3a08055; // 1c: mov r8, #0x55
+        program[0x20 / 4] = 0xe25ef004; // 20: subs pc, lr, #4
+        program[0x100 / 4] = 0xe3a00001; // 100: mov r0, #1
+        program[0x104 / 4] = 0xeafffffe; // 104: b 0x104
+        let program: Vec<u8> = program.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let mut memory = SimpleMemory::new(0x4000);
+        memory.load_program(&program);
+        let mut cpu = Arm7tdmiCore::new(memory);
+        cpu.set_mode(CpuMode::System);
+        cpu.cpsr.set_irq_disabled(false);
+        cpu.cpsr.set_fiq_disabled(false);
+        cpu.set_next_pc(0x100);
+        cpu
+    }
+
+    #[test]
+    fn test_fiq_banking() {
+        let mut cpu = interrupt_test_cpu();
+        for r in 8..15 {
+            cpu.gpr[r] = 0x1000 + r as u32;
+            cpu.set_banked_reg(CpuMode::Fiq, r, 0xf000 + r as u32);
+        }
+
+        cpu.set_interrupt_line(InterruptLine::Fiq, true);
+        cpu.step();
+        cpu.set_interrupt_line(InterruptLine::Fiq, false);
+        assert_eq!(cpu.cpsr.mode(), CpuMode::Fiq);
+        assert!(cpu.cpsr.irq_disabled() && cpu.cpsr.fiq_disabled());
+        assert_eq!(cpu.spsr.get(), CpuMode::System as u32);
+        assert_eq!(cpu.gpr[8], 0x55);
+        assert_eq!(&cpu.gpr[9..13], &[0xf009, 0xf00a, 0xf00b, 0xf00c]);
+        assert_eq!(cpu.gpr[13], 0xf00d);
+        assert_eq!(cpu.gpr[14], 0x104);
+        assert_eq!(cpu.get_banked_reg(CpuMode::System, 8), 0x1008);
+        assert_eq!(cpu.get_banked_reg(CpuMode::User, 14), 0x100e);
+
+        // subs pc, lr, #4
+        cpu.step();
+        assert_eq!(cpu.cpsr.mode(), CpuMode::System);
+        assert!(!cpu.cpsr.fiq_disabled());
+        assert_eq!(cpu.get_next_pc(), 0x100);
+        for r in 8..15 {
+            assert_eq!(cpu.gpr[r], 0x1000 + r as u32);
+        }
+        assert_eq!(cpu.get_banked_reg(CpuMode::Fiq, 8), 0x55);
+        assert_eq!(cpu.get_banked_reg(CpuMode::Fiq, 14), 0x104);
+
+        cpu.step();
+        assert_eq!(cpu.gpr[0], 1);
+    }
+
+    #[test]
+    fn test_interrupt_lines() {
+        let mut cpu = interrupt_test_cpu();
+        cpu.set_interrupt_line(InterruptLine::Irq, true);
+        cpu.set_interrupt_line(InterruptLine::Fiq, true);
+        assert!(cpu.interrupt_line(InterruptLine::Irq));
+        cpu.step();
+        assert_eq!(cpu.cpsr.mode(), CpuMode::Fiq);
+
+        // FIQ masked, the IRQ is taken instead
+        let mut cpu = interrupt_test_cpu();
+        cpu.cpsr.set_fiq_disabled(true);
+        cpu.set_interrupt_line(InterruptLine::Irq, true);
+        cpu.set_interrupt_line(InterruptLine::Fiq, true);
+        cpu.step();
+        assert_eq!(cpu.cpsr.mode(), CpuMode::Irq);
+        assert_eq!(cpu.gpr[1], 2);
+        assert_eq!(cpu.gpr[14], 0x104);
+
+        // Both masked by the IRQ handler, and nothing happens once deasserted
+        cpu.step();
+        assert_eq!(cpu.cpsr.mode(), CpuMode::Irq);
+        let mut cpu = interrupt_test_cpu();
+        cpu.set_interrupt_line(InterruptLine::Irq, true);
+        cpu.set_interrupt_line(InterruptLine::Irq, false);
+        assert!(!cpu.interrupt_line(InterruptLine::Irq));
+        cpu.step();
+        assert_eq!(cpu.cpsr.mode(), CpuMode::System);
+        assert_eq!(cpu.gpr[0], 1);
+    }
+
+    #[test]
+    fn test_exception_from_same_mode() {
+        let program: [u32; 3] = [
+            0xef000000, // 00: swi 0
+            0, 0xeafffffe, // 08: b 0x08
+        ];
+        let program: Vec<u8> = program.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let mut memory = SimpleMemory::new(0x4000);
+        memory.load_program(&program);
+        let mut cpu = Arm7tdmiCore::new(memory);
+        cpu.reset();
+        cpu.cpsr.set_fiq_disabled(false);
+
+        cpu.step();
+        assert_eq!(cpu.cpsr.mode(), CpuMode::Supervisor);
+        assert_eq!(cpu.spsr.get(), 0x93);
+        assert_eq!(cpu.gpr[14], 0x04);
+        assert_eq!(cpu.get_next_pc(), 0x08);
+    }
+
+    #[test]
+    fn test_mode_switch_banking() {
+        let mut cpu = interrupt_test_cpu();
+        let modes = [
+            CpuMode::System,
+            CpuMode::Fiq,
+            CpuMode::Irq,
+            CpuMode::Supervisor,
+            CpuMode::Abort,
+            CpuMode::Undefined,
+        ];
+        for (i, &mode) in modes.iter().enumerate() {
+            cpu.set_mode(mode);
+            for r in 8..15 {
+                cpu.gpr[r] = ((i as u32) << 8) | r as u32;
+            }
+        }
+        cpu.set_mode(CpuMode::User);
+        for (i, &mode) in modes.iter().enumerate() {
+            for r in 8..15 {
+                // Only FIQ banks r8-r12, the rest of the modes share them with User
+                let owner = match (mode, r) {
+                    (CpuMode::Fiq, _) | (_, 13..=14) => i,
+                    _ => modes.len() - 1,
+                };
+                let expected = ((owner as u32) << 8) | r as u32;
+                assert_eq!(cpu.get_banked_reg(mode, r), expected, "{:?} r{}", mode, r);
+            }
+        }
+        for r in 8..15 {
+            assert_eq!(cpu.gpr[r], cpu.get_banked_reg(CpuMode::System, r));
+        }
+    }
+}
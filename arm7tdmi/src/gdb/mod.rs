@@ -1,15 +1,155 @@
+use std::fmt;
 use std::io;
-use std::net::{TcpListener, TcpStream};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
 
 use log::info;
 
 mod breakpoints;
+pub mod regs;
 pub mod target;
 
 // Re-export the gdbstub crate
 pub extern crate gdbstub;
 pub extern crate gdbstub_arch;
 
+use gdbstub::conn::ConnectionExt;
+
+/// A type-erased connection to a gdb client, either over tcp or over a unix domain socket.
+pub type GdbConnection = Box<dyn ConnectionExt<Error = io::Error>>;
+
+/// Address a gdbserver listens on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GdbServerAddr {
+    /// Listen on all interfaces on the given tcp port
+    Tcp(u16),
+    /// Listen on a unix domain socket at the given path
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for GdbServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GdbServerAddr::Tcp(port) => write!(f, "0.0.0.0:{}", port),
+            #[cfg(unix)]
+            GdbServerAddr::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A bound gdbserver socket that can accept any number of gdb clients, one after another.
+pub enum GdbListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl GdbListener {
+    /// Bind to `addr` without waiting for a client.
+    /// A stale unix socket file left behind by a previous session is removed first.
+    pub fn bind(addr: &GdbServerAddr) -> io::Result<GdbListener> {
+        info!("gdbserver listening on {}", addr);
+        match addr {
+            GdbServerAddr::Tcp(port) => Ok(GdbListener::Tcp(TcpListener::bind(format!(
+                "0.0.0.0:{port}"
+            ))?)),
+            #[cfg(unix)]
+            GdbServerAddr::Unix(path) => {
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(GdbListener::Unix(UnixListener::bind(path)?, path.clone()))
+            }
+        }
+    }
+
+    /// Something to wake a thread blocked in `accept()` with, to get it to notice that it should stop
+    pub fn waker(&self) -> io::Result<GdbListenerWaker> {
+        match self {
+            GdbListener::Tcp(sock) => {
+                let port = sock.local_addr()?.port();
+                Ok(GdbListenerWaker::Tcp(SocketAddr::from((
+                    Ipv4Addr::LOCALHOST,
+                    port,
+                ))))
+            }
+            #[cfg(unix)]
+            GdbListener::Unix(_, path) => Ok(GdbListenerWaker::Unix(path.clone())),
+        }
+    }
+
+    /// Blocks until a gdb client connects.
+    /// i.e: Running `target remote localhost:<port>` or `target remote <path>` from the GDB prompt.
+    /// Also returns a handle to close the connection with from another thread.
+    pub fn accept(&self) -> io::Result<(GdbConnection, GdbConnectionCloser)> {
+        match self {
+            GdbListener::Tcp(sock) => {
+                let (stream, addr) = sock.accept()?;
+                info!("gdb connected from {:?}", addr);
+                let closer = GdbConnectionCloser::Tcp(stream.try_clone()?);
+                Ok((Box::new(stream), closer))
+            }
+            #[cfg(unix)]
+            GdbListener::Unix(sock, path) => {
+                let (stream, _) = sock.accept()?;
+                info!("gdb connected on {:?}", path);
+                let closer = GdbConnectionCloser::Unix(stream.try_clone()?);
+                Ok((Box::new(stream), closer))
+            }
+        }
+    }
+}
+
+/// Wakes the accepting thread of a `GdbListener` by connecting to it
+#[derive(Debug, Clone)]
+pub enum GdbListenerWaker {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl GdbListenerWaker {
+    /// The connection is closed right away, fails if the listener is already gone
+    pub fn wake(&self) -> io::Result<()> {
+        match self {
+            GdbListenerWaker::Tcp(addr) => TcpStream::connect(addr).map(drop),
+            #[cfg(unix)]
+            GdbListenerWaker::Unix(path) => UnixStream::connect(path).map(drop),
+        }
+    }
+}
+
+/// Closes a connection accepted by a `GdbListener`, a thread blocked reading from it gets an error or end of file
+#[derive(Debug)]
+pub enum GdbConnectionCloser {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl GdbConnectionCloser {
+    pub fn close(&self) -> io::Result<()> {
+        match self {
+            GdbConnectionCloser::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            GdbConnectionCloser::Unix(stream) => stream.shutdown(Shutdown::Both),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for GdbListener {
+    fn drop(&mut self) {
+        if let GdbListener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
 /// Wait for tcp connection on port
 pub fn wait_for_connection(port: u16) -> io::Result<TcpStream> {
     let bind_addr = format!("0.0.0.0:{port}");
@@ -0,0 +1,366 @@
+/// Full ARM7TDMI register set for gdb, including the banked registers and SPSRs of every mode.
+use std::num::NonZeroUsize;
+
+use gdbstub::arch::{Arch, RegId, Registers, SingleStepGdbBehavior};
+use gdbstub_arch::arm::ArmBreakpointKind;
+
+use crate::memory::MemoryInterface;
+use crate::psr::RegPSR;
+use crate::{Arm7tdmiCore, CpuMode, CpuState};
+
+/// Modes that own a copy of r13 and r14, ordered by `CpuMode::bank_index`
+pub const BANKED_MODES: [CpuMode; 6] = [
+    CpuMode::User,
+    CpuMode::Fiq,
+    CpuMode::Irq,
+    CpuMode::Supervisor,
+    CpuMode::Abort,
+    CpuMode::Undefined,
+];
+
+/// Modes that own an SPSR, ordered by `CpuMode::spsr_index`
+pub const SPSR_MODES: [CpuMode; 5] = [
+    CpuMode::Fiq,
+    CpuMode::Irq,
+    CpuMode::Supervisor,
+    CpuMode::Abort,
+    CpuMode::Undefined,
+];
+
+const REGNUM_CPSR: usize = 25;
+const REGNUM_SPSR: usize = 26;
+const REGNUM_R8_USR: usize = REGNUM_SPSR + 5;
+const REGNUM_R8_FIQ: usize = REGNUM_R8_USR + 5;
+const REGNUM_R13_R14: usize = REGNUM_R8_FIQ + 5;
+const REGNUM_LAST: usize = REGNUM_R13_R14 + 2 * 6 - 1;
+
+/// Target description served to gdb.
+/// The core feature follows gdb's own arm-core.xml, the banked registers are exposed as a separate feature
+/// so they show up in `info all-registers` and can be used as `$r13_irq`, `$spsr_svc`, etc.
+pub const TARGET_DESCRIPTION_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>armv4t</architecture>
+  <feature name="org.gnu.gdb.arm.core">
+    <reg name="r0" bitsize="32" type="uint32" regnum="0"/>
+    <reg name="r1" bitsize="32" type="uint32"/>
+    <reg name="r2" bitsize="32" type="uint32"/>
+    <reg name="r3" bitsize="32" type="uint32"/>
+    <reg name="r4" bitsize="32" type="uint32"/>
+    <reg name="r5" bitsize="32" type="uint32"/>
+    <reg name="r6" bitsize="32" type="uint32"/>
+    <reg name="r7" bitsize="32" type="uint32"/>
+    <reg name="r8" bitsize="32" type="uint32"/>
+    <reg name="r9" bitsize="32" type="uint32"/>
+    <reg name="r10" bitsize="32" type="uint32"/>
+    <reg name="r11" bitsize="32" type="uint32"/>
+    <reg name="r12" bitsize="32" type="uint32"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="lr" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="cpsr" bitsize="32" regnum="25"/>
+  </feature>
+  <feature name="org.rustboyadvance.arm7tdmi.banked">
+    <reg name="spsr_fiq" bitsize="32" regnum="26" group="banked"/>
+    <reg name="spsr_irq" bitsize="32" group="banked"/>
+    <reg name="spsr_svc" bitsize="32" group="banked"/>
+    <reg name="spsr_abt" bitsize="32" group="banked"/>
+    <reg name="spsr_und" bitsize="32" group="banked"/>
+    <reg name="r8_usr" bitsize="32" type="uint32" group="banked"/>
+    <reg name="r9_usr" bitsize="32" type="uint32" group="banked"/>
+    <reg name="r10_usr" bitsize="32" type="uint32" group="banked"/>
+    <reg name="r11_usr" bitsize="32" type="uint32" group="banked"/>
+    <reg name="r12_usr" bitsize="32" type="uint32" group="banked"/>
+    <reg name="r8_fiq" bitsize="32" type="uint32" group="banked"/>
+    <reg name="r9_fiq" bitsize="32" type="uint32" group="banked"/>
+    <reg name="r10_fiq" bitsize="32" type="uint32" group="banked"/>
+    <reg name="r11_fiq" bitsize="32" type="uint32" group="banked"/>
+    <reg name="r12_fiq" bitsize="32" type="uint32" group="banked"/>
+    <reg name="r13_usr" bitsize="32" type="data_ptr" group="banked"/>
+    <reg name="r14_usr" bitsize="32" type="code_ptr" group="banked"/>
+    <reg name="r13_fiq" bitsize="32" type="data_ptr" group="banked"/>
+    <reg name="r14_fiq" bitsize="32" type="code_ptr" group="banked"/>
+    <reg name="r13_irq" bitsize="32" type="data_ptr" group="banked"/>
+    <reg name="r14_irq" bitsize="32" type="code_ptr" group="banked"/>
+    <reg name="r13_svc" bitsize="32" type="data_ptr" group="banked"/>
+    <reg name="r14_svc" bitsize="32" type="code_ptr" group="banked"/>
+    <reg name="r13_abt" bitsize="32" type="data_ptr" group="banked"/>
+    <reg name="r14_abt" bitsize="32" type="code_ptr" group="banked"/>
+    <reg name="r13_und" bitsize="32" type="data_ptr" group="banked"/>
+    <reg name="r14_und" bitsize="32" type="code_ptr" group="banked"/>
+  </feature>
+</target>"#;
+
+/// Implements `Arch` for the ARM7TDMI with the register layout of `TARGET_DESCRIPTION_XML`
+pub enum Arm7tdmiArch {}
+
+impl Arch for Arm7tdmiArch {
+    type Usize = u32;
+    type Registers = Arm7tdmiRegs;
+    type RegId = Arm7tdmiRegId;
+    type BreakpointKind = ArmBreakpointKind;
+
+    #[inline(always)]
+    fn single_step_gdb_behavior() -> SingleStepGdbBehavior {
+        SingleStepGdbBehavior::Optional
+    }
+}
+
+/// Every register of the ARM7TDMI, as exchanged with gdb in `g`/`G` packets.
+/// Registers of the current mode appear both in `r`/`sp`/`lr` and in their banked slot.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Arm7tdmiRegs {
+    /// General purpose registers (R0-R12) of the current mode
+    pub r: [u32; 13],
+    /// Stack Pointer (R13) of the current mode
+    pub sp: u32,
+    /// Link Register (R14) of the current mode
+    pub lr: u32,
+    /// Address of the next instruction to execute
+    pub pc: u32,
+    pub cpsr: u32,
+    /// SPSRs, indexed by `CpuMode::spsr_index`
+    pub spsr: [u32; 5],
+    /// R8-R12 of every mode but FIQ
+    pub r8_12_usr: [u32; 5],
+    /// R8-R12 of FIQ mode
+    pub r8_12_fiq: [u32; 5],
+    /// R13 of every mode, indexed by `CpuMode::bank_index`
+    pub r13: [u32; 6],
+    /// R14 of every mode, indexed by `CpuMode::bank_index`
+    pub r14: [u32; 6],
+}
+
+impl Arm7tdmiRegs {
+    fn iter(&self) -> impl Iterator<Item = &u32> {
+        self.r
+            .iter()
+            .chain([&self.sp, &self.lr, &self.pc, &self.cpsr])
+            .chain(self.spsr.iter())
+            .chain(self.r8_12_usr.iter())
+            .chain(self.r8_12_fiq.iter())
+            .chain(
+                self.r13
+                    .iter()
+                    .zip(self.r14.iter())
+                    .flat_map(|(r13, r14)| [r13, r14]),
+            )
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut u32> {
+        self.r
+            .iter_mut()
+            .chain([&mut self.sp, &mut self.lr, &mut self.pc, &mut self.cpsr])
+            .chain(self.spsr.iter_mut())
+            .chain(self.r8_12_usr.iter_mut())
+            .chain(self.r8_12_fiq.iter_mut())
+            .chain(
+                self.r13
+                    .iter_mut()
+                    .zip(self.r14.iter_mut())
+                    .flat_map(|(r13, r14)| [r13, r14]),
+            )
+    }
+
+    const NUM_REGS: usize = 13 + 4 + 5 + 5 + 5 + 12;
+}
+
+impl Registers for Arm7tdmiRegs {
+    type ProgramCounter = u32;
+
+    fn pc(&self) -> Self::ProgramCounter {
+        self.pc
+    }
+
+    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+        for reg in self.iter() {
+            for b in reg.to_le_bytes() {
+                write_byte(Some(b));
+            }
+        }
+    }
+
+    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        if bytes.len() != Self::NUM_REGS * 4 {
+            return Err(());
+        }
+        for (reg, chunk) in self.iter_mut().zip(bytes.chunks_exact(4)) {
+            *reg = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        Ok(())
+    }
+}
+
+/// Register identifiers, numbered as in `TARGET_DESCRIPTION_XML`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arm7tdmiRegId {
+    /// R0-R15 of the current mode
+    Gpr(u8),
+    Cpsr,
+    Spsr(CpuMode),
+    /// R8-R14 of the given mode
+    Banked(CpuMode, u8),
+}
+
+impl RegId for Arm7tdmiRegId {
+    fn from_raw_id(id: usize) -> Option<(Self, Option<NonZeroUsize>)> {
+        let reg = match id {
+            0..=15 => Arm7tdmiRegId::Gpr(id as u8),
+            REGNUM_CPSR => Arm7tdmiRegId::Cpsr,
+            REGNUM_SPSR..=REGNUM_LAST => match id {
+                _ if id < REGNUM_R8_USR => Arm7tdmiRegId::Spsr(SPSR_MODES[id - REGNUM_SPSR]),
+                _ if id < REGNUM_R8_FIQ => {
+                    Arm7tdmiRegId::Banked(CpuMode::User, (8 + id - REGNUM_R8_USR) as u8)
+                }
+                _ if id < REGNUM_R13_R14 => {
+                    Arm7tdmiRegId::Banked(CpuMode::Fiq, (8 + id - REGNUM_R8_FIQ) as u8)
+                }
+                _ => {
+                    let n = id - REGNUM_R13_R14;
+                    Arm7tdmiRegId::Banked(BANKED_MODES[n / 2], (13 + n % 2) as u8)
+                }
+            },
+            _ => return None,
+        };
+        Some((reg, Some(NonZeroUsize::new(4)?)))
+    }
+}
+
+/// Error returned when gdb tries to put the cpu in a state it can't be in
+#[derive(Debug)]
+pub struct InvalidCpsr(pub u32);
+
+impl<I: MemoryInterface> Arm7tdmiCore<I> {
+    pub fn gdb_read_registers(&self, regs: &mut Arm7tdmiRegs) {
+        regs.r.copy_from_slice(&self.gpr[..13]);
+        regs.sp = self.gpr[13];
+        regs.lr = self.gpr[14];
+        regs.pc = self.get_next_pc();
+        regs.cpsr = self.cpsr.get();
+        for (spsr, mode) in regs.spsr.iter_mut().zip(SPSR_MODES) {
+            *spsr = self.get_spsr(mode).unwrap_or_default().get();
+        }
+        for r in 8..=12 {
+            regs.r8_12_usr[r - 8] = self.get_banked_reg(CpuMode::User, r);
+            regs.r8_12_fiq[r - 8] = self.get_banked_reg(CpuMode::Fiq, r);
+        }
+        for (i, mode) in BANKED_MODES.into_iter().enumerate() {
+            regs.r13[i] = self.get_banked_reg(mode, 13);
+            regs.r14[i] = self.get_banked_reg(mode, 14);
+        }
+    }
+
+    /// Writes back a full register set.
+    /// Banked registers are written before the registers of the current mode, so the latter win when they alias.
+    pub fn gdb_write_registers(&mut self, regs: &Arm7tdmiRegs) -> Result<(), InvalidCpsr> {
+        let pc_changed = regs.pc != self.get_next_pc();
+        let state_changed = self.gdb_write_cpsr(regs.cpsr)?;
+        for (spsr, mode) in regs.spsr.iter().zip(SPSR_MODES) {
+            self.set_spsr(mode, RegPSR::new(*spsr));
+        }
+        for r in 8..=12 {
+            self.set_banked_reg(CpuMode::User, r, regs.r8_12_usr[r - 8]);
+            self.set_banked_reg(CpuMode::Fiq, r, regs.r8_12_fiq[r - 8]);
+        }
+        for (i, mode) in BANKED_MODES.into_iter().enumerate() {
+            self.set_banked_reg(mode, 13, regs.r13[i]);
+            self.set_banked_reg(mode, 14, regs.r14[i]);
+        }
+        self.gpr[..13].copy_from_slice(&regs.r);
+        self.gpr[13] = regs.sp;
+        self.gpr[14] = regs.lr;
+        if pc_changed || state_changed {
+            self.set_next_pc(regs.pc);
+        }
+        Ok(())
+    }
+
+    pub fn gdb_read_register(&self, reg: Arm7tdmiRegId) -> u32 {
+        match reg {
+            Arm7tdmiRegId::Gpr(15) => self.get_next_pc(),
+            Arm7tdmiRegId::Gpr(r) => self.gpr[r as usize],
+            Arm7tdmiRegId::Cpsr => self.cpsr.get(),
+            Arm7tdmiRegId::Spsr(mode) => self.get_spsr(mode).unwrap_or_default().get(),
+            Arm7tdmiRegId::Banked(mode, r) => self.get_banked_reg(mode, r as usize),
+        }
+    }
+
+    pub fn gdb_write_register(&mut self, reg: Arm7tdmiRegId, val: u32) -> Result<(), InvalidCpsr> {
+        match reg {
+            Arm7tdmiRegId::Gpr(15) => self.set_next_pc(val),
+            Arm7tdmiRegId::Gpr(r) => self.gpr[r as usize] = val,
+            Arm7tdmiRegId::Cpsr => {
+                let next_pc = self.get_next_pc();
+                if self.gdb_write_cpsr(val)? {
+                    self.set_next_pc(next_pc);
+                }
+            }
+            Arm7tdmiRegId::Spsr(mode) => self.set_spsr(mode, RegPSR::new(val)),
+            Arm7tdmiRegId::Banked(mode, r) => self.set_banked_reg(mode, r as usize, val),
+        }
+        Ok(())
+    }
+
+    /// Sets the CPSR, switching register banks if the mode changed.
+    /// Returns true if the instruction set state changed, in which case the pipeline needs a refill.
+    fn gdb_write_cpsr(&mut self, val: u32) -> Result<bool, InvalidCpsr> {
+        let new_cpsr = RegPSR::new(val);
+        let new_mode = num::FromPrimitive::from_u32(val & 0b1_1111).ok_or(InvalidCpsr(val))?;
+        let old_state: CpuState = self.cpsr.state();
+        self.set_mode(new_mode);
+        self.cpsr = new_cpsr;
+        Ok(old_state != self.cpsr.state())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regnums_match_target_description() {
+        let names: Vec<&str> = TARGET_DESCRIPTION_XML
+            .split("<reg name=\"")
+            .skip(1)
+            .map(|s| s.split('"').next().unwrap())
+            .collect();
+        assert_eq!(names.len(), Arm7tdmiRegs::NUM_REGS);
+
+        let regnums = (0..=15).chain(REGNUM_CPSR..=REGNUM_LAST);
+        for (name, regnum) in names.iter().zip(regnums) {
+            let (reg, _) = Arm7tdmiRegId::from_raw_id(regnum).unwrap();
+            let expected = match reg {
+                Arm7tdmiRegId::Gpr(13) => "sp".to_string(),
+                Arm7tdmiRegId::Gpr(14) => "lr".to_string(),
+                Arm7tdmiRegId::Gpr(15) => "pc".to_string(),
+                Arm7tdmiRegId::Gpr(r) => format!("r{}", r),
+                Arm7tdmiRegId::Cpsr => "cpsr".to_string(),
+                Arm7tdmiRegId::Spsr(mode) => format!("spsr_{}", mode).to_lowercase(),
+                Arm7tdmiRegId::Banked(mode, r) => format!("r{}_{}", r, mode).to_lowercase(),
+            };
+            assert_eq!(*name, expected);
+        }
+        assert!(Arm7tdmiRegId::from_raw_id(16).is_none());
+        assert!(Arm7tdmiRegId::from_raw_id(REGNUM_LAST + 1).is_none());
+    }
+
+    #[test]
+    fn test_registers_serialize_roundtrip() {
+        let mut regs = Arm7tdmiRegs::default();
+        for (i, reg) in regs.iter_mut().enumerate() {
+            *reg = 0x1000_0000 + i as u32;
+        }
+        let mut bytes = Vec::new();
+        regs.gdb_serialize(|b| bytes.push(b.unwrap()));
+        assert_eq!(bytes.len(), Arm7tdmiRegs::NUM_REGS * 4);
+
+        let mut decoded = Arm7tdmiRegs::default();
+        decoded.gdb_deserialize(&bytes).unwrap();
+        assert_eq!(regs, decoded);
+        assert_eq!(decoded.cpsr, 0x1000_0010);
+        assert_eq!(
+            decoded.r14[5],
+            0x1000_0000 + Arm7tdmiRegs::NUM_REGS as u32 - 1
+        );
+    }
+}
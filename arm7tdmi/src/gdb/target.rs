@@ -1,5 +1,8 @@
 /// Implementing the Target trait for gdbstub
 use gdbstub::common::Signal;
+use gdbstub::target::ext::base::single_register_access::{
+    SingleRegisterAccess, SingleRegisterAccessOps,
+};
 use gdbstub::target::ext::base::singlethread::{
     SingleThreadBase, SingleThreadResume, SingleThreadSingleStep,
 };
@@ -8,17 +11,19 @@ use gdbstub::target::ext::base::BaseOps;
 use gdbstub::target::ext::breakpoints::BreakpointsOps;
 use gdbstub::target::{self, Target, TargetError, TargetResult};
 
-use crate::memory::{DebugRead, MemoryInterface};
-use crate::registers_consts::*;
+use crate::memory::{DebugWrite, MemoryInterface};
 use crate::Arm7tdmiCore;
 
-pub trait MemoryGdbInterface: MemoryInterface + DebugRead {
+use super::copy_range_to_buf;
+use super::regs::{Arm7tdmiArch, Arm7tdmiRegId, Arm7tdmiRegs, TARGET_DESCRIPTION_XML};
+
+pub trait MemoryGdbInterface: MemoryInterface + DebugWrite {
     fn memory_map_xml(&self, offset: u64, length: usize, buf: &mut [u8]) -> usize;
 }
 
 impl<I: MemoryGdbInterface> Target for Arm7tdmiCore<I> {
     type Error = ();
-    type Arch = gdbstub_arch::arm::Armv4t;
+    type Arch = Arm7tdmiArch;
 
     #[inline(always)]
     fn base_ops(&mut self) -> BaseOps<Self::Arch, Self::Error> {
@@ -34,31 +39,30 @@ impl<I: MemoryGdbInterface> Target for Arm7tdmiCore<I> {
     fn support_memory_map(&mut self) -> Option<target::ext::memory_map::MemoryMapOps<Self>> {
         Some(self)
     }
+
+    fn support_target_description_xml_override(
+        &mut self,
+    ) -> Option<
+        target::ext::target_description_xml_override::TargetDescriptionXmlOverrideOps<'_, Self>,
+    > {
+        Some(self)
+    }
 }
 
 impl<I: MemoryGdbInterface> SingleThreadBase for Arm7tdmiCore<I> {
-    fn read_registers(
-        &mut self,
-        regs: &mut gdbstub_arch::arm::reg::ArmCoreRegs,
-    ) -> TargetResult<(), Self> {
-        regs.pc = self.get_next_pc();
-        regs.lr = self.get_reg(REG_LR);
-        regs.sp = self.get_reg(REG_SP);
-        regs.r[..].copy_from_slice(&self.gpr[..13]);
-        regs.cpsr = self.cpsr.get();
+    fn read_registers(&mut self, regs: &mut Arm7tdmiRegs) -> TargetResult<(), Self> {
+        self.gdb_read_registers(regs);
         Ok(())
     }
 
-    fn write_registers(
-        &mut self,
-        regs: &gdbstub_arch::arm::reg::ArmCoreRegs,
-    ) -> TargetResult<(), Self> {
-        self.set_reg(REG_PC, regs.pc);
-        self.set_reg(REG_LR, regs.lr);
-        self.set_reg(REG_SP, regs.sp);
-        self.gpr[..13].copy_from_slice(&regs.r);
-        self.cpsr.set(regs.cpsr);
-        Ok(())
+    fn write_registers(&mut self, regs: &Arm7tdmiRegs) -> TargetResult<(), Self> {
+        self.gdb_write_registers(regs)
+            .map_err(|_| TargetError::NonFatal)
+    }
+
+    #[inline(always)]
+    fn support_single_register_access(&mut self) -> Option<SingleRegisterAccessOps<'_, (), Self>> {
+        Some(self)
     }
 
     fn read_addrs(&mut self, start_addr: u32, data: &mut [u8]) -> TargetResult<(), Self> {
@@ -66,9 +70,9 @@ impl<I: MemoryGdbInterface> SingleThreadBase for Arm7tdmiCore<I> {
         Ok(())
     }
 
-    fn write_addrs(&mut self, _start_addr: u32, _data: &[u8]) -> TargetResult<(), Self> {
-        // todo!("implement DebugWrite bus extention")
-        Err(TargetError::NonFatal)
+    fn write_addrs(&mut self, start_addr: u32, data: &[u8]) -> TargetResult<(), Self> {
+        self.bus.debug_write_bytes(start_addr, data);
+        Ok(())
     }
 
     // most targets will want to support at resumption as well...
@@ -111,3 +115,46 @@ impl<I: MemoryGdbInterface> target::ext::memory_map::MemoryMap for Arm7tdmiCore<
         Ok(self.bus.memory_map_xml(offset, length, buf))
     }
 }
+
+impl<I: MemoryGdbInterface> SingleRegisterAccess<()> for Arm7tdmiCore<I> {
+    fn read_register(
+        &mut self,
+        _tid: (),
+        reg_id: Arm7tdmiRegId,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        let bytes = self.gdb_read_register(reg_id).to_le_bytes();
+        buf[..4].copy_from_slice(&bytes);
+        Ok(bytes.len())
+    }
+
+    fn write_register(
+        &mut self,
+        _tid: (),
+        reg_id: Arm7tdmiRegId,
+        val: &[u8],
+    ) -> TargetResult<(), Self> {
+        let val = u32::from_le_bytes(val.try_into().map_err(|_| TargetError::NonFatal)?);
+        self.gdb_write_register(reg_id, val)
+            .map_err(|_| TargetError::NonFatal)
+    }
+}
+
+impl<I: MemoryGdbInterface>
+    target::ext::target_description_xml_override::TargetDescriptionXmlOverride for Arm7tdmiCore<I>
+{
+    fn target_description_xml(
+        &self,
+        _annex: &[u8],
+        offset: u64,
+        length: usize,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        Ok(copy_range_to_buf(
+            TARGET_DESCRIPTION_XML.as_bytes(),
+            offset,
+            length,
+            buf,
+        ))
+    }
+}
@@ -1,3 +1,30 @@
+//! ARM7TDMI interpreter, free of any knowledge about the system it is embedded in.
+//!
+//! [`Arm7tdmiCore`] owns its bus, which can be anything implementing [`memory::MemoryInterface`].
+//! Systems that need to reach the bus from elsewhere should pass in a handle type, while tests
+//! can use [`SimpleMemory`] directly:
+//!
+//! ```
+//! use arm7tdmi::{Arm7tdmiCore, SimpleMemory};
+//!
+//! let mut memory = SimpleMemory::new(0x100);
+//! memory.load_program(&[0x01, 0x00, 0xa0, 0xe3]); // mov r0, #1
+//! let mut cpu = Arm7tdmiCore::new(memory);
+//! cpu.reset();
+//! cpu.step();
+//! assert_eq!(cpu.get_reg(0), 1);
+//! ```
+//!
+//! * Stepping: [`Arm7tdmiCore::step`] executes one instruction, [`Arm7tdmiCore::get_next_pc`]
+//!   is the address of the next one.
+//! * Interrupts: [`Arm7tdmiCore::set_interrupt_line`] drives the IRQ and FIQ inputs, which are
+//!   sampled before every step. [`Arm7tdmiCore::irq`] and [`Arm7tdmiCore::fiq`] take the
+//!   exception right away instead, unless it is masked.
+//! * State: registers through [`Arm7tdmiCore::get_reg`]/[`Arm7tdmiCore::set_reg`] and the
+//!   banked variants, the PSRs through `cpsr` and `spsr`, snapshots through
+//!   [`Arm7tdmiCore::save_state`]/[`Arm7tdmiCore::restore_state`].
+//! * The bus stays reachable as `cpu.bus`.
+
 #[macro_use]
 extern crate serde;
 
@@ -5,6 +32,7 @@ extern crate serde;
 extern crate enum_primitive_derive;
 
 use std::fmt;
+use std::str::FromStr;
 
 use num::Num;
 use serde::{Deserialize, Serialize};
@@ -18,6 +46,10 @@ use thumb::ThumbInstruction;
 pub mod cpu;
 pub use cpu::*;
 pub mod alu;
+pub mod asm;
+pub mod callstack;
+#[cfg(test)]
+mod conformance;
 pub mod memory;
 pub use alu::*;
 use memory::Addr;
@@ -27,6 +59,7 @@ pub mod gdb;
 pub use gdb::{gdbstub, gdbstub_arch};
 pub mod psr;
 mod simple_memory;
+pub mod trace;
 pub use simple_memory::SimpleMemory;
 
 pub mod registers_consts {
@@ -50,7 +83,6 @@ impl DecodedInstruction {
     }
 }
 
-#[cfg(feature = "debugger")]
 impl fmt::Display for DecodedInstruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -144,3 +176,20 @@ impl fmt::Display for CpuMode {
         }
     }
 }
+
+impl FromStr for CpuMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use CpuMode::*;
+        match s.to_ascii_lowercase().as_str() {
+            "usr" | "user" => Ok(User),
+            "fiq" => Ok(Fiq),
+            "irq" => Ok(Irq),
+            "svc" | "supervisor" => Ok(Supervisor),
+            "abt" | "abort" => Ok(Abort),
+            "und" | "undefined" => Ok(Undefined),
+            "sys" | "system" => Ok(System),
+            _ => Err(format!("{} is not a valid cpu mode", s)),
+        }
+    }
+}
@@ -3,7 +3,7 @@ use std::fmt;
 
 pub type Addr = u32;
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum MemoryAccess {
     NonSeq = 0,
     Seq,
@@ -38,6 +38,7 @@ pub enum MemoryAccessWidth {
 
 /// A trait meant to abstract memory accesses and report the access type back to the user of the arm7tdmi::Arm7tdmiCore
 ///
+/// ```ignore
 /// struct Memory {
 ///     data: [u8; 0x4000]
 /// }
@@ -60,9 +61,11 @@ pub enum MemoryAccessWidth {
 ///     // implement rest of trait methods
 /// }
 ///
-/// let mem = Shared::new(Memory { ... });
-/// let cpu = arm7tdmi::Arm7tdmiCore::new(mem.clone())
+/// let cpu = arm7tdmi::Arm7tdmiCore::new(Memory { ... });
+/// ```
 ///
+/// The core owns its bus. A bus that is shared with other parts of the system goes through a handle type
+/// implementing this trait, and `Box<dyn MemoryInterface>` works when the bus is picked at runtime.
 pub trait MemoryInterface {
     /// Read a byte
     fn load_8(&mut self, addr: u32, access: MemoryAccess) -> u8;
@@ -81,6 +84,53 @@ pub trait MemoryInterface {
     fn idle_cycle(&mut self);
 }
 
+macro_rules! forward_memory_interface {
+    () => {
+        #[inline]
+        fn load_8(&mut self, addr: u32, access: MemoryAccess) -> u8 {
+            (**self).load_8(addr, access)
+        }
+
+        #[inline]
+        fn load_16(&mut self, addr: u32, access: MemoryAccess) -> u16 {
+            (**self).load_16(addr, access)
+        }
+
+        #[inline]
+        fn load_32(&mut self, addr: u32, access: MemoryAccess) -> u32 {
+            (**self).load_32(addr, access)
+        }
+
+        #[inline]
+        fn store_8(&mut self, addr: u32, value: u8, access: MemoryAccess) {
+            (**self).store_8(addr, value, access)
+        }
+
+        #[inline]
+        fn store_16(&mut self, addr: u32, value: u16, access: MemoryAccess) {
+            (**self).store_16(addr, value, access)
+        }
+
+        #[inline]
+        fn store_32(&mut self, addr: u32, value: u32, access: MemoryAccess) {
+            (**self).store_32(addr, value, access)
+        }
+
+        #[inline]
+        fn idle_cycle(&mut self) {
+            (**self).idle_cycle()
+        }
+    };
+}
+
+impl<T: MemoryInterface + ?Sized> MemoryInterface for Box<T> {
+    forward_memory_interface!();
+}
+
+impl<T: MemoryInterface + ?Sized> MemoryInterface for &mut T {
+    forward_memory_interface!();
+}
+
 impl<I: MemoryInterface> MemoryInterface for Arm7tdmiCore<I> {
     #[inline]
     fn load_8(&mut self, addr: u32, access: MemoryAccess) -> u8 {
@@ -241,6 +291,28 @@ pub trait DebugRead: BusIO {
     }
 }
 
+/// Helper trait for writing memory as if we were an all-powerfull debugger, read-only memory included
+pub trait DebugWrite: DebugRead {
+    fn debug_write_32(&mut self, addr: Addr, value: u32) {
+        self.debug_write_16(addr, value as u16);
+        self.debug_write_16(addr + 2, (value >> 16) as u16);
+    }
+
+    fn debug_write_16(&mut self, addr: Addr, value: u16) {
+        self.debug_write_8(addr, value as u8);
+        self.debug_write_8(addr + 1, (value >> 8) as u8);
+    }
+
+    fn debug_write_8(&mut self, addr: Addr, value: u8);
+
+    fn debug_write_bytes(&mut self, start_addr: Addr, bytes: &[u8]) {
+        bytes
+            .iter()
+            .enumerate()
+            .for_each(|(idx, byte)| self.debug_write_8(start_addr + (idx as Addr), *byte));
+    }
+}
+
 /// The caller is assumed to handle out of bound accesses,
 /// For performance reasons, this impl trusts that 'addr' is within the array range.
 impl BusIO for Box<[u8]> {
@@ -263,3 +335,10 @@ impl DebugRead for Box<[u8]> {
         self[addr as usize]
     }
 }
+
+impl DebugWrite for Box<[u8]> {
+    #[inline]
+    fn debug_write_8(&mut self, addr: Addr, value: u8) {
+        self[addr as usize] = value;
+    }
+}
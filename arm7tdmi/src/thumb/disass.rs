@@ -1,9 +1,9 @@
 use std::fmt;
 
-use crate::bit::BitIndex;
+use bit::BitIndex;
 
 use super::*;
-use crate::arm7tdmi::*;
+use crate::*;
 
 use super::ThumbDecodeHelper;
 
@@ -54,7 +54,7 @@ impl ThumbInstruction {
     fn fmt_thumb_high_reg_op_or_bx(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let op = self.raw.format5_op();
         let dst_reg = if self.raw.flag(consts::flags::FLAG_H1) {
-            self.raw & 0b111 + 8
+            (self.raw & 0b111) + 8
         } else {
             self.raw & 0b111
         };
@@ -82,7 +82,7 @@ impl ThumbInstruction {
             "ldr\t{Rd}, [pc, #{Imm:#x}] ; = #{effective:#x}",
             Rd = reg_string(self.raw.bit_range(8..11)),
             Imm = self.raw.word8(),
-            effective = (self.pc + 4 & !0b10) + (self.raw.word8() as Addr)
+            effective = ((self.pc + 4) & !0b10) + (self.raw.word8() as Addr)
         )
     }
 
@@ -192,7 +192,13 @@ impl ThumbInstruction {
     }
 
     fn fmt_thumb_add_sp(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "add\tsp, #{imm:x}", imm = self.raw.sword7())
+        let imm = self.raw.sword7();
+        write!(
+            f,
+            "add\tsp, #{sign}{imm:#x}",
+            sign = if imm < 0 { "-" } else { "" },
+            imm = imm.abs()
+        )
     }
 
     fn fmt_register_list(&self, f: &mut fmt::Formatter<'_>, rlist: u8) -> fmt::Result {
@@ -263,19 +269,18 @@ impl ThumbInstruction {
         )
     }
 
+    /// The two halves of `bl` are shown on their own, the first one adds the high part of the offset to lr
+    /// and the second one branches to lr plus the low part
     fn fmt_thumb_branch_long_with_link(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "bl\t#0x{:08x}", {
-            let offset11 = self.raw.offset11();
-            if self.raw.flag(consts::flags::FLAG_LOW_OFFSET) {
-                (offset11 << 1) as i32
-            } else {
-                ((offset11 << 21) >> 9) as i32
-            }
-        })
+        let offset11 = self.raw.offset11();
+        if self.raw.flag(consts::flags::FLAG_LOW_OFFSET) {
+            write!(f, "bl\tlr, #{:#x}", offset11 << 1)
+        } else {
+            write!(f, "bl\t#0x{:08x}", (offset11 << 21) >> 9)
+        }
     }
 }
 
-#[cfg(feature = "debugger")]
 impl fmt::Display for ThumbInstruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.fmt {
@@ -126,7 +126,8 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
             CMN => self.alu_add_flags(dst, src, &mut carry, &mut overflow),
             ORR => dst | src,
             MUL => {
-                let m = self.get_required_multipiler_array_cycles(src);
+                // MUL Rd, Rs is MUL Rd, Rs, Rd in arm terms, so Rd is the operand that terminates the multiplication
+                let m = self.get_required_multipiler_array_cycles(dst, true);
                 for _ in 0..m {
                     self.idle_cycle();
                 }
@@ -393,20 +394,7 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
         &mut self,
         insn: u16,
     ) -> CpuAction {
-        macro_rules! push {
-            ($r:expr, $access:ident) => {
-                self.gpr[REG_SP] -= 4;
-                let stack_addr = self.gpr[REG_SP] & !3;
-                self.store_32(stack_addr, self.get_reg($r), $access);
-                $access = Seq;
-            };
-        }
         macro_rules! pop {
-            ($r:expr) => {
-                let val = self.load_32(self.gpr[REG_SP] & !3, Seq);
-                self.set_reg($r, val);
-                self.gpr[REG_SP] += 4;
-            };
             ($r:expr, $access:ident) => {
                 let val = self.load_32(self.gpr[REG_SP] & !3, $access);
                 $access = Seq;
@@ -423,22 +411,28 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
                     pop!(r, access);
                 }
             }
-            if FLAG_R {
-                pop!(REG_PC);
-                self.pc &= !1;
+            let new_pc = FLAG_R.then(|| {
+                let val = self.load_32(self.gpr[REG_SP] & !3, access);
+                self.gpr[REG_SP] += 4;
+                val
+            });
+            // Idle 1 cycle, the new pc is fetched afterwards
+            self.idle_cycle();
+            if let Some(new_pc) = new_pc {
+                self.pc = new_pc & !1;
                 result = CpuAction::PipelineFlushed;
                 self.reload_pipeline16();
             }
-            // Idle 1 cycle
-            self.idle_cycle();
         } else {
-            if FLAG_R {
-                push!(REG_LR, access);
-            }
-            for r in (0..8).rev() {
-                if rlist.bit(r) {
-                    push!(r, access);
-                }
+            // registers are stored in ascending order starting from the lowest address
+            let count = rlist.count_ones() + FLAG_R as u32;
+            self.gpr[REG_SP] -= 4 * count;
+            let mut stack_addr = self.gpr[REG_SP] & !3;
+            let regs = (0..8).filter(|r| rlist.bit(*r));
+            for r in regs.chain(FLAG_R.then_some(REG_LR)) {
+                self.store_32(stack_addr, self.get_reg(r), access);
+                stack_addr += 4;
+                access = Seq;
             }
         }
 
@@ -496,6 +490,7 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
             // From gbatek.htm: Empty Rlist: R15 loaded/stored (ARMv4 only), and Rb=Rb+40h (ARMv4-v5).
             if LOAD {
                 let val = self.load_32(addr, NonSeq);
+                self.idle_cycle();
                 self.pc = val & !1;
                 result = CpuAction::PipelineFlushed;
                 self.reload_pipeline16();
@@ -566,10 +561,85 @@ impl<I: MemoryInterface> Arm7tdmiCore<I> {
     }
 
     pub fn thumb_undefined(&mut self, insn: u16) -> CpuAction {
-        panic!(
-            "executing undefind thumb instruction {:04x} at @{:08x}",
-            insn,
-            self.pc_thumb()
-        )
+        self.undefined_instruction(insn as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::{BusIO, MemoryAccess, MemoryInterface};
+    use crate::registers_consts::{REG_LR, REG_SP};
+    use crate::{Arm7tdmiCore, CpuState, SimpleMemory};
+
+    /// Records the order of the word stores, which hardware registers written by STM can observe
+    struct StoreLog {
+        memory: SimpleMemory,
+        stores: Vec<(u32, MemoryAccess)>,
+    }
+
+    impl MemoryInterface for StoreLog {
+        fn load_8(&mut self, addr: u32, access: MemoryAccess) -> u8 {
+            self.memory.load_8(addr, access)
+        }
+        fn load_16(&mut self, addr: u32, access: MemoryAccess) -> u16 {
+            self.memory.load_16(addr, access)
+        }
+        fn load_32(&mut self, addr: u32, access: MemoryAccess) -> u32 {
+            self.memory.load_32(addr, access)
+        }
+        fn store_8(&mut self, addr: u32, value: u8, access: MemoryAccess) {
+            self.memory.store_8(addr, value, access)
+        }
+        fn store_16(&mut self, addr: u32, value: u16, access: MemoryAccess) {
+            self.memory.store_16(addr, value, access)
+        }
+        fn store_32(&mut self, addr: u32, value: u32, access: MemoryAccess) {
+            self.stores.push((addr, access));
+            self.memory.store_32(addr, value, access)
+        }
+        fn idle_cycle(&mut self) {}
+    }
+
+    #[test]
+    fn test_push_pop_order() {
+        let program: Vec<u8> = [
+            0xb503u16, // 00: push {r0, r1, lr}
+            0xbc1c,    // 02: pop {r2, r3, r4}
+            0xe7fe,    // 04: b 0x04
+        ]
+        .iter()
+        .flat_map(|h| h.to_le_bytes())
+        .collect();
+        let mut memory = SimpleMemory::new(0x4000);
+        memory.load_program(&program);
+        let mut cpu = Arm7tdmiCore::new(StoreLog {
+            memory,
+            stores: vec![],
+        });
+        cpu.cpsr.set_state(CpuState::THUMB);
+        cpu.set_next_pc(0);
+        cpu.gpr[0] = 0x1111;
+        cpu.gpr[1] = 0x2222;
+        cpu.gpr[REG_LR] = 0x3333;
+        cpu.gpr[REG_SP] = 0x200;
+
+        cpu.step();
+        // Stored in ascending order like STMDB, the lowest register at the lowest address and LR last
+        assert_eq!(cpu.gpr[REG_SP], 0x200 - 12);
+        assert_eq!(
+            cpu.bus.stores,
+            [
+                (0x1f4, MemoryAccess::NonSeq),
+                (0x1f8, MemoryAccess::Seq),
+                (0x1fc, MemoryAccess::Seq)
+            ]
+        );
+        assert_eq!(cpu.bus.memory.read_32(0x1f4), 0x1111);
+        assert_eq!(cpu.bus.memory.read_32(0x1f8), 0x2222);
+        assert_eq!(cpu.bus.memory.read_32(0x1fc), 0x3333);
+
+        cpu.step();
+        assert_eq!(cpu.gpr[REG_SP], 0x200);
+        assert_eq!(&cpu.gpr[2..5], &[0x1111, 0x2222, 0x3333]);
     }
 }
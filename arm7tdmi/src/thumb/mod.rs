@@ -6,7 +6,6 @@ use bit::BitIndex;
 use byteorder::{LittleEndian, ReadBytesExt};
 use num::FromPrimitive;
 
-#[cfg(feature = "debugger")]
 pub mod disass;
 pub mod exec;
 
@@ -90,6 +89,8 @@ impl From<u16> for ThumbFormat {
             LdmStm
         } else if raw & 0xff00 == 0xdf00 {
             Swi
+        } else if raw & 0xff00 == 0xde00 {
+            Undefined
         } else if raw & 0xf000 == 0xd000 {
             BranchConditional
         } else if raw & 0xf800 == 0xe000 {
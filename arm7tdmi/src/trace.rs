@@ -0,0 +1,332 @@
+/// Instruction tracing, used to diff the cpu against other emulators
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::arm::ArmInstruction;
+use super::memory::Addr;
+use super::psr::RegPSR;
+use super::thumb::ThumbInstruction;
+use super::{CpuMode, InstructionDecoder};
+
+/// Layout of each line in the trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+    /// Registers first, then the opcode, like the trace logs produced by mGBA and NanoBoyAdvance:
+    ///
+    /// `00000000 ... 08000008 cpsr: 6000001F |     E3A00301: <disassembly>`
+    ///
+    /// r15 is the value of the pc as seen by the instruction, i.e. ahead by two instructions.
+    #[default]
+    Mgba,
+    /// Address, opcode and disassembly first, then the registers and decoded cpsr flags
+    Verbose,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mgba" | "nba" => Ok(TraceFormat::Mgba),
+            "verbose" => Ok(TraceFormat::Verbose),
+            _ => Err(format!("{} is not a valid trace format", s)),
+        }
+    }
+}
+
+/// Selects which instructions end up in the trace
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    /// Only trace instructions in this address range
+    pub addr_range: Option<RangeInclusive<Addr>>,
+    /// Only trace instructions executed in one of these modes, empty means all modes
+    pub modes: Vec<CpuMode>,
+}
+
+impl TraceFilter {
+    fn matches(&self, addr: Addr, mode: CpuMode) -> bool {
+        if let Some(range) = &self.addr_range {
+            if !range.contains(&addr) {
+                return false;
+            }
+        }
+        // System mode is user mode with privileges, users don't expect to tell them apart
+        let normalize = |mode| match mode {
+            CpuMode::System => CpuMode::User,
+            mode => mode,
+        };
+        self.modes.is_empty() || self.modes.iter().any(|m| normalize(*m) == normalize(mode))
+    }
+
+    /// Parses an inclusive address range written as `start-end`, in hex
+    pub fn parse_addr_range(s: &str) -> Result<RangeInclusive<Addr>, String> {
+        let parse_addr = |s: &str| {
+            let s = s.trim();
+            let digits = s
+                .strip_prefix("0x")
+                .or_else(|| s.strip_prefix("0X"))
+                .unwrap_or(s);
+            Addr::from_str_radix(digits, 16).map_err(|_| format!("{} is not a valid address", s))
+        };
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected an address range like start-end, got {}", s))?;
+        let (start, end) = (parse_addr(start)?, parse_addr(end)?);
+        if start > end {
+            return Err(format!("address range {} is empty", s));
+        }
+        Ok(start..=end)
+    }
+}
+
+/// The instruction about to be executed
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum TracedOpcode {
+    Arm(u32),
+    Thumb(u16),
+}
+
+/// Writes a line for every instruction the cpu executes, including the ones that failed their condition check
+pub struct InstructionTracer {
+    writer: Box<dyn Write>,
+    format: TraceFormat,
+    filter: TraceFilter,
+}
+
+impl fmt::Debug for InstructionTracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InstructionTracer")
+            .field("format", &self.format)
+            .field("filter", &self.filter)
+            .finish()
+    }
+}
+
+impl InstructionTracer {
+    pub fn new<W: Write + 'static>(
+        writer: W,
+        format: TraceFormat,
+        filter: TraceFilter,
+    ) -> InstructionTracer {
+        InstructionTracer {
+            writer: Box::new(writer),
+            format,
+            filter,
+        }
+    }
+
+    /// Trace into a newly created file, truncating it if it already exists
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        format: TraceFormat,
+        filter: TraceFilter,
+    ) -> io::Result<InstructionTracer> {
+        let file = File::create(path)?;
+        Ok(InstructionTracer::new(
+            BufWriter::with_capacity(1 << 20, file),
+            format,
+            filter,
+        ))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// `gpr` and `pc` are the registers before `opcode` (found at `addr`) is executed
+    pub(crate) fn trace(
+        &mut self,
+        addr: Addr,
+        opcode: TracedOpcode,
+        gpr: &[u32; 15],
+        pc: u32,
+        cpsr: RegPSR,
+    ) -> io::Result<()> {
+        if !self.filter.matches(addr, cpsr.mode()) {
+            return Ok(());
+        }
+        let w = &mut self.writer;
+        match self.format {
+            TraceFormat::Mgba => {
+                for r in gpr {
+                    write!(w, "{:08X} ", r)?;
+                }
+                write!(w, "{:08X} cpsr: {:08X} |     ", pc, cpsr.get())?;
+                match opcode {
+                    TracedOpcode::Arm(insn) => {
+                        writeln!(w, "{:08X}: {}", insn, ArmInstruction::decode(insn, addr))
+                    }
+                    TracedOpcode::Thumb(insn) => writeln!(
+                        w,
+                        "    {:04X}: {}",
+                        insn,
+                        ThumbInstruction::decode(insn, addr)
+                    ),
+                }
+            }
+            TraceFormat::Verbose => {
+                let disass = match opcode {
+                    TracedOpcode::Arm(insn) => {
+                        write!(w, "{:08x}: {:08x}  ", addr, insn)?;
+                        ArmInstruction::decode(insn, addr).to_string()
+                    }
+                    TracedOpcode::Thumb(insn) => {
+                        write!(w, "{:08x}:     {:04x}  ", addr, insn)?;
+                        ThumbInstruction::decode(insn, addr).to_string()
+                    }
+                };
+                write!(w, "{:<32}", disass)?;
+                for (i, r) in gpr.iter().enumerate() {
+                    write!(w, " r{}={:08x}", i, r)?;
+                }
+                let flag = |set: bool, c: char| if set { c } else { '-' };
+                writeln!(
+                    w,
+                    " r15={:08x} cpsr={:08x} [{}{}{}{}{}{}{}] {}",
+                    pc,
+                    cpsr.get(),
+                    flag(cpsr.N(), 'N'),
+                    flag(cpsr.Z(), 'Z'),
+                    flag(cpsr.C(), 'C'),
+                    flag(cpsr.V(), 'V'),
+                    flag(cpsr.irq_disabled(), 'I'),
+                    flag(cpsr.fiq_disabled(), 'F'),
+                    flag(cpsr.state().into(), 'T'),
+                    cpsr.mode()
+                )
+            }
+        }
+    }
+}
+
+/// Holds the tracer of a cpu, a cloned cpu starts without one since it can't share the trace file
+#[derive(Debug, Default)]
+pub(crate) struct TracerSlot(pub(crate) Option<InstructionTracer>);
+
+impl Clone for TracerSlot {
+    fn clone(&self) -> Self {
+        TracerSlot(None)
+    }
+}
+
+impl Drop for InstructionTracer {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Lets the test look at what was written after handing the writer over to the tracer
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    fn registers() -> [u32; 15] {
+        let mut gpr = [0; 15];
+        gpr[0] = 0xca5;
+        gpr[13] = 0x0300_7f00;
+        gpr
+    }
+
+    #[test]
+    fn test_mgba_format() {
+        let buffer = SharedBuffer::default();
+        let mut tracer =
+            InstructionTracer::new(buffer.clone(), TraceFormat::Mgba, TraceFilter::default());
+        let cpsr = RegPSR::new(0x6000_001f);
+        tracer
+            .trace(
+                0x0800_0000,
+                TracedOpcode::Arm(0xe3a0_0301),
+                &registers(),
+                0x0800_0008,
+                cpsr,
+            )
+            .unwrap();
+        assert_eq!(
+            buffer.contents(),
+            "00000CA5 00000000 00000000 00000000 00000000 00000000 00000000 00000000 \
+             00000000 00000000 00000000 00000000 00000000 03007F00 00000000 08000008 \
+             cpsr: 6000001F |     E3A00301: mov\tr0, #67108864\t; 0x4000000\n"
+        );
+    }
+
+    #[test]
+    fn test_verbose_format_flags() {
+        let buffer = SharedBuffer::default();
+        let mut tracer =
+            InstructionTracer::new(buffer.clone(), TraceFormat::Verbose, TraceFilter::default());
+        let cpsr = RegPSR::new(0x4000_00b2);
+        tracer
+            .trace(
+                0x0300_0000,
+                TracedOpcode::Thumb(0x4770),
+                &registers(),
+                0x0300_0004,
+                cpsr,
+            )
+            .unwrap();
+        let line = buffer.contents();
+        assert!(line.starts_with("03000000:     4770  bx\tlr"));
+        assert!(line.ends_with(" r15=03000004 cpsr=400000b2 [-Z--I-T] IRQ\n"));
+    }
+
+    #[test]
+    fn test_filter() {
+        let buffer = SharedBuffer::default();
+        let filter = TraceFilter {
+            addr_range: Some(TraceFilter::parse_addr_range("0x08000000-0x080000ff").unwrap()),
+            modes: vec![CpuMode::User],
+        };
+        let mut tracer = InstructionTracer::new(buffer.clone(), TraceFormat::Mgba, filter);
+        let mut trace = |addr, mode| {
+            let mut cpsr = RegPSR::new(0);
+            cpsr.set_mode(mode);
+            tracer
+                .trace(
+                    addr,
+                    TracedOpcode::Arm(0xe1a0_0000),
+                    &[0; 15],
+                    addr + 8,
+                    cpsr,
+                )
+                .unwrap();
+        };
+        trace(0x0800_0000, CpuMode::System);
+        trace(0x0800_0100, CpuMode::System);
+        trace(0x0800_0004, CpuMode::Irq);
+        assert_eq!(buffer.contents().lines().count(), 1);
+    }
+
+    #[test]
+    fn test_parse_addr_range() {
+        assert_eq!(
+            TraceFilter::parse_addr_range("3000000-0x03007fff"),
+            Ok(0x0300_0000..=0x0300_7fff)
+        );
+        assert!(TraceFilter::parse_addr_range("08000000").is_err());
+        assert!(TraceFilter::parse_addr_range("0x100-0x10").is_err());
+    }
+}
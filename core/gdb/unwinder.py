@@ -0,0 +1,61 @@
+# Frame unwinder for gdb that follows the call stack tracked by the rustboyadvance gdbserver.
+#
+# gdb unwinds ARM code by analyzing function prologues, which fails on most GBA roms as they come without debug
+# info. The emulator knows every call and exception entry it executed, this unwinder reads them through
+# `monitor backtrace raw` so gdb's own commands (bt, up, down, finish, frame ...) use them.
+#
+# Load it once connected, or from your .gdbinit (needs gdb 11 or newer, built with python):
+#
+#   (gdb) target remote localhost:1337
+#   (gdb) source core/gdb/unwinder.py
+#   (gdb) bt
+#
+# Frames the emulator didn't see being entered, e.g. the outermost one, are left to gdb's own unwinders.
+
+import gdb
+from gdb.unwinder import Unwinder, register_unwinder
+
+
+class FrameId(object):
+    def __init__(self, sp, pc):
+        self.sp = sp
+        self.pc = pc
+
+
+class ShadowCallStackUnwinder(Unwinder):
+    def __init__(self):
+        super(ShadowCallStackUnwinder, self).__init__("rustboyadvance")
+        # (entry point, return address, caller sp) of every tracked frame, innermost first
+        self.frames = None
+        gdb.events.cont.connect(self.invalidate)
+
+    def invalidate(self, _event):
+        self.frames = None
+
+    def fetch(self):
+        try:
+            out = gdb.execute("monitor backtrace raw", to_string=True)
+        except gdb.error:
+            return []
+        frames = []
+        for line in out.splitlines():
+            fields = line.split()
+            if len(fields) == 3:
+                frames.append(tuple(int(field, 16) for field in fields))
+        return frames
+
+    def __call__(self, pending_frame):
+        level = pending_frame.level()
+        if level == 0 or self.frames is None:
+            self.frames = self.fetch()
+        if level >= len(self.frames):
+            return None
+        target, return_addr, sp = self.frames[level]
+        ptr = pending_frame.read_register("pc").type
+        info = pending_frame.create_unwind_info(FrameId(gdb.Value(sp).cast(ptr), gdb.Value(target).cast(ptr)))
+        info.add_saved_register("pc", gdb.Value(return_addr).cast(ptr))
+        info.add_saved_register("sp", gdb.Value(sp).cast(pending_frame.read_register("sp").type))
+        return info
+
+
+register_unwinder(None, ShadowCallStackUnwinder(), replace=True)
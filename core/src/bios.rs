@@ -1,6 +1,6 @@
-use super::SysBus;
+use super::CpuBus;
 use arm7tdmi::{
-    memory::{Addr, BusIO, DebugRead},
+    memory::{Addr, BusIO, DebugRead, DebugWrite},
     Arm7tdmiCore,
 };
 
@@ -14,7 +14,7 @@ pub struct Bios {
     /// Last read value
     last_opcode: u32,
     /// Arm pointer - used only to read the PC register
-    arm_core: WeakPointer<Arm7tdmiCore<SysBus>>,
+    arm_core: WeakPointer<Arm7tdmiCore<CpuBus>>,
 }
 
 impl Bios {
@@ -26,7 +26,7 @@ impl Bios {
         }
     }
 
-    pub(super) fn connect_arm_core(&mut self, arm_ptr: WeakPointer<Arm7tdmiCore<SysBus>>) {
+    pub(super) fn connect_arm_core(&mut self, arm_ptr: WeakPointer<Arm7tdmiCore<CpuBus>>) {
         self.arm_core = arm_ptr;
     }
 
@@ -83,3 +83,11 @@ impl DebugRead for Bios {
         self.rom[addr as usize]
     }
 }
+
+impl DebugWrite for Bios {
+    fn debug_write_8(&mut self, addr: Addr, value: u8) {
+        if let Some(byte) = self.rom.get_mut(addr as usize) {
+            *byte = value;
+        }
+    }
+}
@@ -1,21 +1,32 @@
+use std::ffi::OsString;
 use std::fmt;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File};
+use std::io;
 use std::io::prelude::*;
-use std::io::SeekFrom;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 
+use super::convert::fit_to_size;
 use super::BackupMemoryInterface;
-use rustboyadvance_utils::write_bin_file;
+use rustboyadvance_utils::read_bin_file;
 
+/// Games write their saves a byte or a block at a time, the file is written once they have been done with it for this long
+const QUIET_PERIOD: Duration = Duration::from_secs(1);
+
+/// The backup memory, kept in sync with a save file on disk.
+///
+/// The file is never written in place: a new one is written next to it and renamed over it, and the previous one is
+/// kept as a `.bak`, so a crash or power loss leaves either the old save or the new one intact.
 #[derive(Debug)]
 pub struct BackupFile {
     size: usize,
     path: Option<PathBuf>,
-    file: Option<File>,
     buffer: Vec<u8>,
+    /// When the buffer was last written, if it has changes that are not on disk yet
+    dirty_since: Option<Instant>,
 }
 
 impl Clone for BackupFile {
@@ -69,38 +80,81 @@ impl<'de> Deserialize<'de> for BackupFile {
     }
 }
 
-impl BackupFile {
-    pub fn new(size: usize, path: Option<PathBuf>) -> BackupFile {
-        // TODO handle errors without unwrap
-        let mut file: Option<File> = None;
-        let buffer = if let Some(path) = &path {
-            if !path.is_file() {
-                write_bin_file(path, &vec![0xff; size]).unwrap();
-            }
+/// `path` with `suffix` appended to the file name, e.g. game.sav.bak
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
 
-            let mut _file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(path)
-                .unwrap();
+/// Replaces the file at `path` with `data`, keeping what was there before as a `.bak`
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = with_suffix(path, ".tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(data)?;
+    tmp.sync_all()?;
+    drop(tmp);
 
-            let mut buffer = Vec::new();
-            _file.read_to_end(&mut buffer).unwrap();
-            buffer.resize(size, 0xff);
+    if path.is_file() {
+        fs::copy(path, with_suffix(path, ".bak"))?;
+    }
+    fs::rename(&tmp_path, path)
+}
 
-            file = Some(_file);
+/// Reads the save at `path`, falling back to the `.bak` if it was cut short
+fn read_save(path: &Path, size: usize) -> Option<Vec<u8>> {
+    let corrupt = match read_bin_file(path) {
+        Ok(bytes) if bytes.len() >= size => return Some(bytes),
+        Ok(bytes) => {
+            warn!(
+                "save file {} is {} bytes, expected {}",
+                path.display(),
+                bytes.len(),
+                size
+            );
+            Some(bytes)
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            error!("failed to read save file {}: {}", path.display(), err);
+            None
+        }
+    };
+
+    let bak_path = with_suffix(path, ".bak");
+    match read_bin_file(&bak_path) {
+        Ok(bytes) if bytes.len() >= size => {
+            warn!("recovering the save from {}", bak_path.display());
+            Some(bytes)
+        }
+        _ => corrupt,
+    }
+}
 
-            buffer
-        } else {
-            vec![0xff; size]
+impl BackupFile {
+    pub fn new(size: usize, path: Option<PathBuf>) -> BackupFile {
+        let mut create = false;
+        let buffer = match path.as_deref().map(|path| (path, read_save(path, size))) {
+            Some((_, Some(buffer))) => fit_to_size(buffer, size),
+            Some((_, None)) => {
+                create = true;
+                vec![0xff; size]
+            }
+            None => vec![0xff; size],
         };
 
-        BackupFile {
+        let mut backup_file = BackupFile {
             size,
             path,
-            file,
             buffer,
+            dirty_since: None,
+        };
+        if create {
+            // Like a fresh cartridge, the file is there before the game first saves
+            backup_file.dirty_since = Some(Instant::now());
+            backup_file.flush();
         }
+        backup_file
     }
 
     pub fn bytes(&self) -> &[u8] {
@@ -108,13 +162,51 @@ impl BackupFile {
     }
 
     pub fn bytes_mut(&mut self) -> &mut [u8] {
+        self.dirty_since = Some(Instant::now());
         &mut self.buffer
     }
 
+    /// Writes the changes to the save file, errors are logged and the write is retried on the next flush
     pub fn flush(&mut self) {
-        if let Some(file) = &mut self.file {
-            file.seek(SeekFrom::Start(0)).unwrap();
-            file.write_all(&self.buffer).unwrap();
+        if self.dirty_since.is_none() {
+            return;
+        }
+        if let Some(path) = &self.path {
+            if let Err(err) = write_atomic(path, &self.buffer) {
+                error!("failed to write save file {}: {}", path.display(), err);
+                return;
+            }
+        }
+        self.dirty_since = None;
+    }
+
+    /// Forgets the changes since the last flush, they are never written to the file
+    pub(crate) fn discard_changes(&mut self) {
+        self.dirty_since = None;
+    }
+
+    /// Flushes once the game hasn't written to the backup memory for a while
+    pub fn flush_if_idle(&mut self) {
+        if let Some(dirty_since) = self.dirty_since {
+            if dirty_since.elapsed() >= QUIET_PERIOD {
+                self.flush();
+            }
+        }
+    }
+}
+
+impl Drop for BackupFile {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl fmt::Display for BackupFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", bytesize::ByteSize::b(self.size as u64))?;
+        match &self.path {
+            Some(path) => write!(f, ", saved to {}", path.display()),
+            None => write!(f, ", not saved to disk"),
         }
     }
 }
@@ -122,10 +214,7 @@ impl BackupFile {
 impl BackupMemoryInterface for BackupFile {
     fn write(&mut self, offset: usize, value: u8) {
         self.buffer[offset] = value;
-        if let Some(file) = &mut self.file {
-            file.seek(SeekFrom::Start(offset as u64)).unwrap();
-            file.write_all(&[value]).unwrap();
-        }
+        self.dirty_since = Some(Instant::now());
     }
 
     fn read(&self, offset: usize) -> u8 {
@@ -135,6 +224,74 @@ impl BackupMemoryInterface for BackupFile {
     fn resize(&mut self, new_size: usize) {
         self.size = new_size;
         self.buffer.resize(new_size, 0xff);
-        self.flush();
+        self.dirty_since = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rba-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_debounced_atomic_writes() {
+        let dir = temp_dir("backup-write");
+        let path = dir.join("game.sav");
+
+        let mut memory = BackupFile::new(0x10, Some(path.clone()));
+        assert_eq!(fs::read(&path).unwrap(), vec![0xff; 0x10]);
+
+        memory.write(0, 0x12);
+        memory.flush_if_idle();
+        assert_eq!(fs::read(&path).unwrap()[0], 0xff);
+        memory.dirty_since = Some(Instant::now() - QUIET_PERIOD);
+        memory.flush_if_idle();
+        assert_eq!(fs::read(&path).unwrap()[0], 0x12);
+        assert_eq!(fs::read(dir.join("game.sav.bak")).unwrap()[0], 0xff);
+        assert!(!dir.join("game.sav.tmp").exists());
+
+        // Dropping the memory writes whatever is left
+        memory.write(0, 0x34);
+        drop(memory);
+        assert_eq!(fs::read(&path).unwrap()[0], 0x34);
+        assert_eq!(fs::read(dir.join("game.sav.bak")).unwrap()[0], 0x12);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_truncated_save() {
+        let dir = temp_dir("backup-recover");
+        let path = dir.join("game.sav");
+        fs::write(dir.join("game.sav.bak"), vec![0x56; 0x10]).unwrap();
+
+        // Cut short by a crash
+        fs::write(&path, vec![0x78; 0x4]).unwrap();
+        assert_eq!(
+            BackupFile::new(0x10, Some(path.clone())).bytes(),
+            &[0x56; 0x10]
+        );
+
+        // A complete save is used as it is
+        fs::write(&path, vec![0x78; 0x10]).unwrap();
+        assert_eq!(
+            BackupFile::new(0x10, Some(path.clone())).bytes(),
+            &[0x78; 0x10]
+        );
+
+        // Without a usable backup the truncated save is the best there is
+        fs::remove_file(dir.join("game.sav.bak")).unwrap();
+        fs::write(&path, vec![0x78; 0x4]).unwrap();
+        let memory = BackupFile::new(0x10, Some(path.clone()));
+        assert_eq!(&memory.bytes()[..4], &[0x78; 4]);
+        assert_eq!(&memory.bytes()[4..], &[0xff; 0xc]);
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,242 @@
+//! Conversion between this emulator's save files and the layouts other emulators and flash carts use
+//!
+//! Saves are stored as a raw image of the backup memory, which is what most emulators do too. They differ in:
+//! - the byte order of each 64bit EEPROM block
+//! - padding, e.g. flash carts that always write 64KB or 128KB, or EEPROM saves padded to 8KB
+//! - the file extension, `.sa1` files are raw saves under another name
+use std::path::Path;
+use std::str::FromStr;
+
+use rustboyadvance_utils::read_bin_file;
+
+use super::BackupType;
+use crate::{GBAError, GBAResult};
+
+const SRAM_SIZE: usize = 0x8000;
+const FLASH64K_SIZE: usize = 0x10000;
+const FLASH128K_SIZE: usize = 0x20000;
+const EEPROM512_SIZE: usize = 0x200;
+const EEPROM8K_SIZE: usize = 0x2000;
+
+/// The EEPROM is accessed in 64bit blocks
+const EEPROM_BLOCK_SIZE: usize = 8;
+
+/// Magic of gzip streams, which VBA uses for its `.sgm` savestates
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// How the 64bit blocks of an EEPROM save are laid out in the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EepromByteOrder {
+    /// Most significant byte first, in the order the chip shifts the bits out.
+    /// This emulator, mGBA and VBA all store EEPROM saves this way
+    #[default]
+    BigEndian,
+    /// Every block stored as a little endian word, as some other emulators and flash carts do
+    LittleEndian,
+}
+
+impl FromStr for EepromByteOrder {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "big" => Ok(EepromByteOrder::BigEndian),
+            "little" => Ok(EepromByteOrder::LittleEndian),
+            _ => Err(format!("{} is not a valid byte order", s)),
+        }
+    }
+}
+
+/// Converts between little and big endian EEPROM blocks, which is the same operation both ways
+pub fn swap_eeprom_blocks(bytes: &mut [u8]) {
+    for block in bytes.chunks_exact_mut(EEPROM_BLOCK_SIZE) {
+        block.reverse();
+    }
+}
+
+/// Sizes of the save files the backup type is stored in, the first one is used for new saves
+pub fn save_sizes(backup_type: BackupType) -> &'static [usize] {
+    match backup_type {
+        BackupType::Sram => &[SRAM_SIZE],
+        BackupType::Flash | BackupType::Flash512 => &[FLASH64K_SIZE],
+        BackupType::Flash1M => &[FLASH128K_SIZE],
+        BackupType::Eeprom => &[EEPROM512_SIZE, EEPROM8K_SIZE],
+        BackupType::AutoDetect => &[],
+    }
+}
+
+/// Pads or truncates a save to `size`, warning about it as the save may not be for this backup type.
+/// Padding uses 0xff like erased memory, and truncating only loses data when the cut off part isn't blank.
+pub fn fit_to_size(mut bytes: Vec<u8>, size: usize) -> Vec<u8> {
+    if bytes.len() < size {
+        warn!(
+            "save is {} but the backup memory is {}, the rest is left blank",
+            bytesize::ByteSize::b(bytes.len() as u64),
+            bytesize::ByteSize::b(size as u64)
+        );
+        bytes.resize(size, 0xff);
+    } else if bytes.len() > size {
+        if is_blank(&bytes[size..]) {
+            warn!(
+                "save is {} but the backup memory is {}, ignoring the padding",
+                bytesize::ByteSize::b(bytes.len() as u64),
+                bytesize::ByteSize::b(size as u64)
+            );
+        } else {
+            warn!(
+                "save is {} but the backup memory is {}, the data past it is lost",
+                bytesize::ByteSize::b(bytes.len() as u64),
+                bytesize::ByteSize::b(size as u64)
+            );
+        }
+        bytes.truncate(size);
+    }
+    bytes
+}
+
+/// Whether the memory holds no data, erased memory reads 0xff but some tools pad with zeroes
+pub(super) fn is_blank(bytes: &[u8]) -> bool {
+    bytes.iter().all(|&b| b == 0xff) || bytes.iter().all(|&b| b == 0)
+}
+
+/// The smallest EEPROM that holds the save, the game tells which one it really is once it accesses it
+pub(super) fn eeprom_size_for(save_len: usize) -> usize {
+    if save_len <= EEPROM512_SIZE {
+        EEPROM512_SIZE
+    } else {
+        EEPROM8K_SIZE
+    }
+}
+
+/// Converts a save from another emulator to the layout this emulator uses for `backup_type`
+pub fn import_save(
+    bytes: Vec<u8>,
+    backup_type: BackupType,
+    eeprom_byte_order: EepromByteOrder,
+) -> GBAResult<Vec<u8>> {
+    if bytes.starts_with(GZIP_MAGIC) {
+        return Err(GBAError::CartridgeLoadError(
+            "save is compressed, use the .sav of VBA rather than its .sgm savestates".to_string(),
+        ));
+    }
+    match backup_type {
+        BackupType::AutoDetect => Err(GBAError::CartridgeLoadError(
+            "can't convert a save without knowing its backup type".to_string(),
+        )),
+        BackupType::Eeprom => {
+            let size = eeprom_size_for(bytes.len());
+            let mut bytes = fit_to_size(bytes, size);
+            if eeprom_byte_order == EepromByteOrder::LittleEndian {
+                swap_eeprom_blocks(&mut bytes);
+            }
+            Ok(bytes)
+        }
+        _ => Ok(fit_to_size(bytes, save_sizes(backup_type)[0])),
+    }
+}
+
+pub fn import_save_file(
+    path: &Path,
+    backup_type: BackupType,
+    eeprom_byte_order: EepromByteOrder,
+) -> GBAResult<Vec<u8>> {
+    import_save(read_bin_file(path)?, backup_type, eeprom_byte_order)
+}
+
+/// Converts a save of this emulator for use elsewhere, padding it to `size` if the target expects larger files
+pub fn export_save(
+    bytes: &[u8],
+    backup_type: BackupType,
+    eeprom_byte_order: EepromByteOrder,
+    size: Option<usize>,
+) -> Vec<u8> {
+    let mut bytes = bytes.to_vec();
+    if backup_type == BackupType::Eeprom && eeprom_byte_order == EepromByteOrder::LittleEndian {
+        swap_eeprom_blocks(&mut bytes);
+    }
+    match size {
+        Some(size) => fit_to_size(bytes, size),
+        None => bytes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eeprom_byte_order() {
+        let mut save = vec![0xff; EEPROM512_SIZE];
+        save[..8].copy_from_slice(&0x0123_4567_89ab_cdefu64.to_le_bytes());
+
+        let imported = import_save(
+            save.clone(),
+            BackupType::Eeprom,
+            EepromByteOrder::LittleEndian,
+        )
+        .unwrap();
+        assert_eq!(imported[..8], 0x0123_4567_89ab_cdefu64.to_be_bytes());
+        assert_eq!(
+            export_save(
+                &imported,
+                BackupType::Eeprom,
+                EepromByteOrder::LittleEndian,
+                None
+            ),
+            save
+        );
+        assert_eq!(
+            import_save(save.clone(), BackupType::Eeprom, EepromByteOrder::BigEndian).unwrap(),
+            save
+        );
+    }
+
+    #[test]
+    fn test_sizes() {
+        // EEPROM saves are kept at the size that fits them
+        let eeprom = import_save(vec![0; 0x100], BackupType::Eeprom, Default::default()).unwrap();
+        assert_eq!(eeprom.len(), EEPROM512_SIZE);
+        assert_eq!(eeprom[0x1ff], 0xff);
+        let eeprom = import_save(vec![0; 0x2000], BackupType::Eeprom, Default::default()).unwrap();
+        assert_eq!(eeprom.len(), EEPROM8K_SIZE);
+
+        // SRAM padded by flash carts
+        let mut sram = vec![0xff; FLASH64K_SIZE];
+        sram[0] = 0x12;
+        let sram = import_save(sram, BackupType::Sram, Default::default()).unwrap();
+        assert_eq!(sram.len(), SRAM_SIZE);
+        assert_eq!(sram[0], 0x12);
+
+        // Truncated flash
+        let flash = import_save(
+            vec![0; FLASH64K_SIZE],
+            BackupType::Flash1M,
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(flash.len(), FLASH128K_SIZE);
+        assert!(is_blank(&flash[FLASH64K_SIZE..]));
+
+        assert_eq!(
+            export_save(
+                &[0; EEPROM512_SIZE],
+                BackupType::Eeprom,
+                Default::default(),
+                Some(EEPROM8K_SIZE)
+            )
+            .len(),
+            EEPROM8K_SIZE
+        );
+    }
+
+    #[test]
+    fn test_rejects_savestates() {
+        let sgm = vec![0x1f, 0x8b, 0x08, 0x00, 0, 0, 0, 0];
+        assert!(import_save(sgm, BackupType::Sram, Default::default()).is_err());
+        assert!(import_save(
+            vec![0; SRAM_SIZE],
+            BackupType::AutoDetect,
+            Default::default()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,350 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use memmem::{Searcher, TwoWaySearcher};
+use serde::{Deserialize, Serialize};
+
+use super::eeprom::EepromController;
+use super::flash::{Flash, FlashSize};
+use super::{BackupFile, BackupMemoryInterface, BackupType};
+use crate::cartridge::BackupMedia;
+
+/// Version strings the save libraries of the SDK leave in the ROM
+const ID_STRINGS: &[(&str, BackupType)] = &[
+    ("EEPROM_V", BackupType::Eeprom),
+    ("SRAM_V", BackupType::Sram),
+    ("SRAM_F_V", BackupType::Sram),
+    ("FLASH_V", BackupType::Flash),
+    ("FLASH512_V", BackupType::Flash512),
+    ("FLASH1M_V", BackupType::Flash1M),
+];
+
+/// Counts the ID strings in the ROM and picks the most common backup type among them.
+/// A ROM usually has a single one, but some link the libraries of several backup types.
+pub(crate) fn scan_id_strings(bytes: &[u8]) -> Option<BackupType> {
+    let mut counts = Vec::new();
+    for (id_string, backup_type) in ID_STRINGS {
+        let searcher = TwoWaySearcher::new(id_string.as_bytes());
+        let mut count = 0;
+        let mut offset = 0;
+        while let Some(pos) = searcher.search_in(&bytes[offset..]) {
+            count += 1;
+            offset += pos + 1;
+        }
+        if count > 0 {
+            counts.push((*id_string, *backup_type, count));
+        }
+    }
+
+    for (id_string, _, count) in &counts {
+        debug!("found backup id string {} {} times", id_string, count);
+    }
+    let &(id_string, backup_type, _) = counts.iter().rev().max_by_key(|(_, _, count)| *count)?;
+    if counts
+        .iter()
+        .any(|(_, other_type, _)| *other_type != backup_type)
+    {
+        warn!(
+            "the ROM has id strings of several backup types, going with {}",
+            id_string
+        );
+    }
+    Some(backup_type)
+}
+
+/// Guesses the backup type from the size of an existing save file
+pub(crate) fn guess_from_save_file(path: &Path) -> Option<BackupType> {
+    match fs::metadata(path).ok()?.len() {
+        0x200 | 0x2000 => Some(BackupType::Eeprom),
+        0x8000 => Some(BackupType::Sram),
+        0x10000 => Some(BackupType::Flash512),
+        0x20000 => Some(BackupType::Flash1M),
+        _ => None,
+    }
+}
+
+pub(crate) fn create_backup(backup_type: BackupType, backup_path: Option<PathBuf>) -> BackupMedia {
+    match backup_type {
+        BackupType::Flash | BackupType::Flash512 => {
+            BackupMedia::Flash(Flash::new(backup_path, FlashSize::Flash64k))
+        }
+        BackupType::Flash1M => BackupMedia::Flash(Flash::new(backup_path, FlashSize::Flash128k)),
+        BackupType::Sram => BackupMedia::Sram(BackupFile::new(0x8000, backup_path)),
+        BackupType::Eeprom => BackupMedia::Eeprom(EepromController::new(backup_path)),
+        BackupType::AutoDetect => BackupMedia::Undetected,
+    }
+}
+
+/// What the backup type was decided by
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackupSource {
+    /// Set by the user
+    Forced,
+    /// Listed in the overrides
+    GameDatabase,
+    IdString,
+    SaveFile,
+    /// The way the game accesses the backup memory
+    Runtime,
+    Undetected,
+}
+
+impl fmt::Display for BackupSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupSource::Forced => write!(f, "forced"),
+            BackupSource::GameDatabase => write!(f, "game database"),
+            BackupSource::IdString => write!(f, "id string"),
+            BackupSource::SaveFile => write!(f, "save file size"),
+            BackupSource::Runtime => write!(f, "game accesses"),
+            BackupSource::Undetected => write!(f, "undetected"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BackupAccessStats {
+    pub sram_writes: u64,
+    pub flash_commands: u64,
+    pub eeprom_dmas: u64,
+}
+
+/// Corrects a guessed backup type by watching how the game accesses it.
+/// Only the size of the save file and the lack of any clue count as guesses, the game database and the ID strings
+/// are trusted.
+///
+/// SRAM and flash games are told apart by their first writes, flash games always start with the 0xaa, 0x55 command
+/// prefix. Flash games that switch banks have the 128KB chip, and the length of the DMAs to the EEPROM tells its
+/// address width.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackupDetector {
+    source: BackupSource,
+    path: Option<PathBuf>,
+    /// The accesses so far settled the backup type
+    confirmed: bool,
+    /// Progress of the flash command prefix
+    flash_prefix: u8,
+    stats: BackupAccessStats,
+}
+
+impl fmt::Display for BackupDetector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}, {} sram writes, {} flash commands, {} eeprom dmas",
+            self.source,
+            if self.confirmed { " (confirmed)" } else { "" },
+            self.stats.sram_writes,
+            self.stats.flash_commands,
+            self.stats.eeprom_dmas
+        )
+    }
+}
+
+impl BackupDetector {
+    pub(crate) fn new(source: BackupSource, path: Option<PathBuf>) -> BackupDetector {
+        BackupDetector {
+            source,
+            path,
+            confirmed: false,
+            flash_prefix: 0,
+            stats: Default::default(),
+        }
+    }
+
+    pub fn source(&self) -> BackupSource {
+        self.source
+    }
+
+    pub fn stats(&self) -> &BackupAccessStats {
+        &self.stats
+    }
+
+    /// Only guesses are corrected, along with the type the accesses so far pointed to
+    fn correctable(&self) -> bool {
+        !self.confirmed
+            && matches!(
+                self.source,
+                BackupSource::SaveFile | BackupSource::Undetected | BackupSource::Runtime
+            )
+    }
+
+    fn switch_to(&mut self, backup: &mut BackupMedia, backup_type: BackupType) {
+        warn!(
+            "the game accesses the backup memory like {:?}, replacing {}",
+            backup_type, backup
+        );
+        // Whatever was written so far was meant for the other type
+        if let Some(memory) = backup.memory_mut() {
+            memory.discard_changes();
+        }
+        *backup = create_backup(backup_type, self.path.clone());
+        self.source = BackupSource::Runtime;
+    }
+
+    /// Settles the backup type, replaying the first write of the game if the backup memory is replaced
+    fn decide(&mut self, backup: &mut BackupMedia, backup_type: BackupType, replay_first: bool) {
+        self.confirmed = true;
+        let matches = match backup {
+            BackupMedia::Sram(_) => backup_type == BackupType::Sram,
+            BackupMedia::Flash(_) => backup_type != BackupType::Sram,
+            _ => false,
+        };
+        if matches {
+            return;
+        }
+        self.switch_to(backup, backup_type);
+        if replay_first {
+            match backup {
+                BackupMedia::Sram(memory) => memory.write(0x5555, 0xaa),
+                BackupMedia::Flash(flash) => flash.write(0x0e00_5555, 0xaa),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Must be called before the write is handled by the backup memory
+    pub(crate) fn on_sram_write(&mut self, backup: &mut BackupMedia, addr: u32, value: u8) {
+        self.stats.sram_writes += 1;
+        let offset = addr & 0xffff;
+
+        let prefix_done = self.flash_prefix == 2;
+        self.flash_prefix = match (self.flash_prefix, offset, value) {
+            (0, 0x5555, 0xaa) => 1,
+            (1, 0x2aaa, 0x55) => 2,
+            _ => 0,
+        };
+        if prefix_done {
+            self.stats.flash_commands += 1;
+            if (offset, value) == (0x5555, 0xb0) && self.source != BackupSource::Forced {
+                if let BackupMedia::Flash(flash) = backup {
+                    if flash.size() < FlashSize::Flash128k.into() {
+                        warn!("the game switches flash banks, it must have the 128KB chip");
+                        flash.set_size(FlashSize::Flash128k);
+                    }
+                }
+            }
+        }
+
+        if !self.correctable() {
+            return;
+        }
+        match (self.stats.sram_writes, self.flash_prefix) {
+            (1, 1) => {
+                // Either the start of a flash command or an SRAM write, the next write tells
+                if let BackupMedia::Undetected = backup {
+                    self.switch_to(backup, BackupType::Flash);
+                }
+            }
+            (1, _) => self.decide(backup, BackupType::Sram, false),
+            (2, 2) => self.decide(backup, BackupType::Flash, true),
+            _ => self.decide(backup, BackupType::Sram, true),
+        }
+    }
+
+    /// Switches to an EEPROM when the game transfers to one, must be called before the EEPROM sees the transfer
+    pub(crate) fn on_eeprom_dma(&mut self, backup: &mut BackupMedia) {
+        self.stats.eeprom_dmas += 1;
+        if let BackupMedia::Eeprom(_) = backup {
+            self.confirmed = true;
+        } else if self.correctable() {
+            self.switch_to(backup, BackupType::Eeprom);
+            self.confirmed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(detector: &mut BackupDetector, backup: &mut BackupMedia, addr: u32, value: u8) {
+        detector.on_sram_write(backup, addr, value);
+        match backup {
+            BackupMedia::Sram(memory) => memory.write((addr & 0x7fff) as usize, value),
+            BackupMedia::Flash(flash) => flash.write(addr, value),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_scan_id_strings() {
+        let mut rom = vec![0; 0x100];
+        assert_eq!(scan_id_strings(&rom), None);
+        rom[0x10..0x1b].copy_from_slice(b"EEPROM_V124");
+        assert_eq!(scan_id_strings(&rom), Some(BackupType::Eeprom));
+        rom[0x20..0x2c].copy_from_slice(b"FLASH1M_V103");
+        rom[0x30..0x3c].copy_from_slice(b"FLASH1M_V103");
+        assert_eq!(scan_id_strings(&rom), Some(BackupType::Flash1M));
+    }
+
+    #[test]
+    fn test_sram_guessed_as_flash() {
+        let mut detector = BackupDetector::new(BackupSource::SaveFile, None);
+        let mut backup = create_backup(BackupType::Flash, None);
+        write(&mut detector, &mut backup, 0x0e00_5555, 0xaa);
+        write(&mut detector, &mut backup, 0x0e00_0000, 0x12);
+        match &backup {
+            BackupMedia::Sram(memory) => {
+                assert_eq!(memory.read(0x5555), 0xaa);
+                assert_eq!(memory.read(0), 0x12);
+            }
+            _ => panic!("expected sram, got {}", backup),
+        }
+        assert_eq!(detector.source(), BackupSource::Runtime);
+
+        // Settled, later command prefixes are just data
+        write(&mut detector, &mut backup, 0x0e00_5555, 0xaa);
+        write(&mut detector, &mut backup, 0x0e00_2aaa, 0x55);
+        assert!(matches!(backup, BackupMedia::Sram(_)));
+    }
+
+    #[test]
+    fn test_undetected_flash_with_banks() {
+        let mut detector = BackupDetector::new(BackupSource::Undetected, None);
+        let mut backup = BackupMedia::Undetected;
+        for &(addr, value) in &[
+            (0x0e00_5555, 0xaa),
+            (0x0e00_2aaa, 0x55),
+            (0x0e00_5555, 0xb0),
+            (0x0e00_0000, 0x01),
+        ] {
+            write(&mut detector, &mut backup, addr, value);
+        }
+        match &backup {
+            BackupMedia::Flash(flash) => assert_eq!(flash.size(), 0x20000),
+            _ => panic!("expected flash, got {}", backup),
+        }
+        assert_eq!(detector.stats().flash_commands, 1);
+    }
+
+    #[test]
+    fn test_forced_type_is_kept() {
+        let mut detector = BackupDetector::new(BackupSource::Forced, None);
+        let mut backup = create_backup(BackupType::Sram, None);
+        write(&mut detector, &mut backup, 0x0e00_5555, 0xaa);
+        write(&mut detector, &mut backup, 0x0e00_2aaa, 0x55);
+        detector.on_eeprom_dma(&mut backup);
+        assert!(matches!(backup, BackupMedia::Sram(_)));
+    }
+
+    #[test]
+    fn test_id_string_type_is_kept() {
+        // A write without the flash command prefix is not enough to overrule the ID string
+        let mut detector = BackupDetector::new(BackupSource::IdString, None);
+        let mut backup = create_backup(BackupType::Flash, None);
+        write(&mut detector, &mut backup, 0x0e00_0000, 0x12);
+        detector.on_eeprom_dma(&mut backup);
+        assert!(matches!(backup, BackupMedia::Flash(_)));
+        assert_eq!(detector.source(), BackupSource::IdString);
+    }
+
+    #[test]
+    fn test_eeprom_dma() {
+        let mut detector = BackupDetector::new(BackupSource::SaveFile, None);
+        let mut backup = create_backup(BackupType::Sram, None);
+        detector.on_eeprom_dma(&mut backup);
+        assert!(matches!(backup, BackupMedia::Eeprom(_)));
+    }
+}
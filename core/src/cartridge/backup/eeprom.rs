@@ -1,9 +1,11 @@
+use super::convert::{eeprom_size_for, is_blank};
 use super::{BackupFile, BackupMemoryInterface};
 
 use num::FromPrimitive;
 use serde::{Deserialize, Serialize};
 
 use std::cell::RefCell;
+use std::fmt;
 use std::fs;
 use std::path::PathBuf;
 
@@ -278,19 +280,26 @@ impl EepromController {
         let mut detect = true;
         let mut eeprom_type = EepromType::Eeprom512;
         if let Some(path) = &path {
-            if let Ok(metadata) = fs::metadata(path) {
-                let human_size = bytesize::ByteSize::b(metadata.len());
-                let assumed_type = match metadata.len() {
-                    512 => EepromType::Eeprom512,
-                    8192 => EepromType::Eeprom8k,
-                    _ => panic!("invalid file size ({}) for eeprom save", human_size),
+            if let Ok(bytes) = fs::read(path) {
+                let human_size = bytesize::ByteSize::b(bytes.len() as u64);
+                eeprom_type = match eeprom_size_for(bytes.len()) {
+                    0x200 => EepromType::Eeprom512,
+                    _ => EepromType::Eeprom8k,
                 };
-                detect = false;
-                info!(
-                    "save file is size {}, assuming eeprom type is {:?}",
-                    human_size, assumed_type
-                );
-                eeprom_type = assumed_type;
+                // Some emulators pad 512 byte saves to 8KB, so a mostly blank one is left to be detected
+                detect = bytes.len() != eeprom_type.size()
+                    || (bytes.len() == 0x2000 && is_blank(&bytes[0x200..]));
+                if detect {
+                    info!(
+                        "save file is size {}, detecting the eeprom type when the game accesses it",
+                        human_size
+                    );
+                } else {
+                    info!(
+                        "save file is size {}, assuming eeprom type is {:?}",
+                        human_size, eeprom_type
+                    );
+                }
             }
         }
 
@@ -308,6 +317,10 @@ impl EepromController {
         }
     }
 
+    pub(crate) fn memory_mut(&mut self) -> &mut BackupFile {
+        &mut self.chip.get_mut().memory
+    }
+
     pub fn write_half(&mut self, address: u32, value: u16) {
         assert!(!self.detect);
         self.chip.borrow_mut().clock_data_in(address, value as u8);
@@ -319,41 +332,59 @@ impl EepromController {
         chip.clock_data_out(address) as u16
     }
 
-    pub fn on_dma3_transfer(&mut self, src: u32, dst: u32, count: usize) {
+    /// The EEPROM type a DMA of `count` halfwords to the EEPROM is meant for
+    fn type_from_dma_count(count: usize) -> Option<EepromType> {
         use EepromType::*;
-        if self.detect {
-            match (src, dst) {
-                // DMA to EEPROM
-                (_, 0x0d000000..=0x0dffffff) => {
-                    debug!(
-                        "caught eeprom dma transfer src={:#x} dst={:#x} count={}",
-                        src, dst, count
-                    );
-                    let eeprom_type = match count {
-                        // Read(11) + 6bit address + stop bit
-                        9 => Eeprom512,
-                        // Read(11) + 14bit address + stop bit
-                        17 => Eeprom8k,
-                        // Write(11) + 6bit address + 64bit value + stop bit
-                        73 => Eeprom512,
-                        // Write(11) + 14bit address + 64bit value + stop bit
-                        81 => Eeprom8k,
-                        _ => panic!(
-                            "unexpected bit count ({}) when detecting eeprom size",
-                            count
-                        ),
-                    };
+        match count {
+            // Read(11) + 6bit address + stop bit
+            9 => Some(Eeprom512),
+            // Read(11) + 14bit address + stop bit
+            17 => Some(Eeprom8k),
+            // Write(11) + 6bit address + 64bit value + stop bit
+            73 => Some(Eeprom512),
+            // Write(11) + 14bit address + 64bit value + stop bit
+            81 => Some(Eeprom8k),
+            _ => None,
+        }
+    }
+
+    pub fn on_dma3_transfer(&mut self, src: u32, dst: u32, count: usize) {
+        match (src, dst) {
+            // DMA to EEPROM
+            (_, 0x0d000000..=0x0dffffff) => {
+                debug!(
+                    "caught eeprom dma transfer src={:#x} dst={:#x} count={}",
+                    src, dst, count
+                );
+                let eeprom_type = match Self::type_from_dma_count(count) {
+                    Some(eeprom_type) => eeprom_type,
+                    None => {
+                        warn!("unexpected bit count ({}) in eeprom dma", count);
+                        return;
+                    }
+                };
+                let mut chip = self.chip.borrow_mut();
+                if self.detect {
                     info!("detected eeprom type: {:?}", eeprom_type);
-                    self.chip.borrow_mut().set_type(eeprom_type);
+                    chip.set_type(eeprom_type);
                     self.detect = false;
+                } else if usize::from(chip.addr_bits) != usize::from(eeprom_type.bits()) {
+                    // The size was assumed from the save file, but the game knows better
+                    warn!(
+                        "the game accesses the eeprom as {:?}, correcting its type",
+                        eeprom_type
+                    );
+                    chip.set_type(eeprom_type);
                 }
-                // EEPROM to DMA
-                (0x0d000000..=0x0dffffff, _) => {
-                    panic!("reading from eeprom when real size is not detected yet is not supported by this emulator")
-                }
-                _ => { /* Not a eeprom dma, doing nothing */ }
             }
-        } else {
+            // EEPROM to DMA
+            (0x0d000000..=0x0dffffff, _) if self.detect => {
+                panic!("reading from eeprom when real size is not detected yet is not supported by this emulator")
+            }
+            _ => {}
+        }
+
+        if !self.detect {
             // this might be a eeprom request, so we need to reset the eeprom state machine if its dirty (due to bad behaving games, or tests roms)
             let mut chip = self.chip.borrow_mut();
             if !chip.is_transmitting() {
@@ -363,6 +394,18 @@ impl EepromController {
     }
 }
 
+impl fmt::Display for EepromController {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let chip = self.chip.borrow();
+        if self.detect {
+            write!(f, "EEPROM (size not detected yet)")
+        } else {
+            let addr_bits: usize = chip.addr_bits.into();
+            write!(f, "EEPROM ({}bit addressing), {}", addr_bits, chip.memory)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::super::EEPROM_BASE_ADDR;
@@ -515,4 +558,26 @@ mod tests {
             assert_eq!(0, chip.tx_count);
         }
     }
+
+    #[test]
+    fn test_save_file_size() {
+        let path = std::env::temp_dir().join(format!("rba-eeprom-{}.sav", std::process::id()));
+        let open = |bytes: &[u8]| {
+            fs::write(&path, bytes).unwrap();
+            let spi = EepromController::new(Some(path.clone()));
+            let chip = spi.chip.borrow();
+            let addr_bits: usize = chip.addr_bits.into();
+            (spi.detect, addr_bits, chip.memory.bytes().len())
+        };
+
+        assert_eq!(open(&[0; 0x200]), (false, 6, 0x200));
+        let mut padded = vec![0xff; 0x2000];
+        padded[..0x200].copy_from_slice(&[0; 0x200]);
+        assert_eq!(open(&padded), (true, 14, 0x2000));
+        padded[0x1000] = 0;
+        assert_eq!(open(&padded), (false, 14, 0x2000));
+        // Used to panic
+        assert_eq!(open(&[0; 0x100]), (true, 6, 0x200));
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -3,6 +3,7 @@ use super::{BackupFile, BackupMemoryInterface};
 use num::FromPrimitive;
 use serde::{Deserialize, Serialize};
 
+use std::fmt;
 use std::path::PathBuf;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -39,6 +40,15 @@ pub enum FlashSize {
     Flash128k,
 }
 
+impl FlashSize {
+    fn chip_id(&self) -> u16 {
+        match self {
+            FlashSize::Flash64k => MACRONIX_64K_CHIP_ID,
+            FlashSize::Flash128k => MACRONIX_128K_CHIP_ID,
+        }
+    }
+}
+
 impl From<FlashSize> for usize {
     fn from(val: FlashSize) -> Self {
         match val {
@@ -67,11 +77,7 @@ const BANK_SIZE: usize = 0x10000;
 
 impl Flash {
     pub fn new(flash_path: Option<PathBuf>, flash_size: FlashSize) -> Flash {
-        let chip_id = match flash_size {
-            FlashSize::Flash64k => MACRONIX_64K_CHIP_ID,
-            FlashSize::Flash128k => MACRONIX_128K_CHIP_ID,
-        };
-
+        let chip_id = flash_size.chip_id();
         let size: usize = flash_size.into();
         let memory = BackupFile::new(size, flash_path);
 
@@ -85,6 +91,27 @@ impl Flash {
         }
     }
 
+    pub(crate) fn memory_mut(&mut self) -> &mut BackupFile {
+        &mut self.memory
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Replaces the chip with one of another size, keeping the contents that fit
+    pub(crate) fn set_size(&mut self, flash_size: FlashSize) {
+        self.chip_id = flash_size.chip_id();
+        self.size = flash_size.into();
+        self.bank = 0;
+        self.memory.resize(self.size);
+    }
+
+    /// Answers with the ID of another chip than the Macronix one of its size, which some games check for
+    pub(crate) fn set_chip_id(&mut self, chip_id: u16) {
+        self.chip_id = chip_id;
+    }
+
     fn reset_sequence(&mut self) {
         self.wrseq = FlashWriteSequence::Initial;
     }
@@ -185,7 +212,11 @@ impl Flash {
                     }
                     FlashMode::Select => {
                         if addr == 0x0E00_0000 {
-                            self.bank = value as usize;
+                            if (value as usize + 1) * BANK_SIZE <= self.size {
+                                self.bank = value as usize;
+                            } else {
+                                warn!("[FLASH] no bank {} in a {} byte chip", value, self.size);
+                            }
                         }
                     }
                     _ => panic!("Flash sequence is invalid"),
@@ -196,3 +227,9 @@ impl Flash {
         }
     }
 }
+
+impl fmt::Display for Flash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Flash (chip id {:04x}), {}", self.chip_id, self.memory)
+    }
+}
@@ -2,7 +2,10 @@ use std::fmt;
 use std::str::FromStr;
 
 mod backup_file;
+pub(crate) use backup_file::write_atomic;
 pub use backup_file::BackupFile;
+pub mod convert;
+pub mod detect;
 pub mod eeprom;
 pub mod flash;
 
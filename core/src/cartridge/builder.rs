@@ -1,26 +1,31 @@
 use std::path::{Path, PathBuf};
 
-use memmem::{Searcher, TwoWaySearcher};
-use num::FromPrimitive;
-
-use super::super::overrides;
+use super::super::overrides::{self, GameOverrides};
+use super::super::sched::Scheduler;
 use super::super::{GBAError, GBAResult};
-use super::backup::eeprom::*;
-use super::backup::flash::*;
-use super::backup::{BackupFile, BackupType};
+use super::backup::convert::{import_save_file, EepromByteOrder};
+use super::backup::detect::{
+    create_backup, guess_from_save_file, scan_id_strings, BackupDetector, BackupSource,
+};
+use super::backup::write_atomic;
+use super::backup::BackupType;
 use super::gpio::Gpio;
 use super::header;
-use super::BackupMedia;
-use super::Cartridge;
+use super::patch::crc32;
+use super::rtc::RtcClock;
+use super::tilt_sensor::TiltSensor;
+use super::{BackupMedia, Cartridge};
 
-use super::loader::{load_from_bytes, load_from_file, LoadRom};
+use super::loader::{find_patch, load_from_bytes, load_from_file, load_patch_from_file, LoadRom};
 
 #[derive(Debug)]
-#[allow(dead_code)]
 pub enum GpioDeviceType {
     Rtc,
+    /// Also has an RTC
     SolarSensor,
+    /// Also has a rumble motor
     Gyro,
+    Rumble,
     None,
 }
 
@@ -31,7 +36,13 @@ pub struct GamepakBuilder {
     save_path: Option<PathBuf>,
     save_type: BackupType,
     gpio_device: GpioDeviceType,
+    tilt_sensor: bool,
+    rtc_clock: RtcClock,
+    patches: Vec<PathBuf>,
+    discover_patch: bool,
     create_backup_file: bool,
+    override_files: Vec<PathBuf>,
+    import_save: Option<(PathBuf, EepromByteOrder)>,
 }
 
 impl Default for GamepakBuilder {
@@ -48,7 +59,13 @@ impl GamepakBuilder {
             save_path: None,
             bytes: None,
             gpio_device: GpioDeviceType::None,
+            tilt_sensor: false,
+            rtc_clock: RtcClock::default(),
+            patches: vec![],
+            discover_patch: true,
             create_backup_file: true,
+            override_files: vec![],
+            import_save: None,
         }
     }
 
@@ -67,11 +84,37 @@ impl GamepakBuilder {
         self
     }
 
+    /// Applies an IPS, UPS or BPS patch to the ROM, patches are applied in the order they are given
+    pub fn patch(mut self, path: &Path) -> Self {
+        self.patches.push(path.to_path_buf());
+        self
+    }
+
+    /// Without explicit patches, a patch named like the ROM file next to it is applied, e.g. game.ips for game.gba
+    pub fn without_patch_discovery(mut self) -> Self {
+        self.discover_patch = false;
+        self
+    }
+
+    /// Loads game overrides from a file in the format of the built-in overrides.yaml.
+    /// Its entries take priority over the built-in ones and those of files given before it.
+    pub fn overrides_file(mut self, path: &Path) -> Self {
+        self.override_files.push(path.to_path_buf());
+        self
+    }
+
     pub fn save_path(mut self, path: &Path) -> Self {
         self.save_path = Some(path.to_path_buf());
         self
     }
 
+    /// Replaces the save file with a save from another emulator or a flash cart, converted to the layout used here.
+    /// The previous save file is kept as a `.bak`.
+    pub fn import_save(mut self, path: &Path, eeprom_byte_order: EepromByteOrder) -> Self {
+        self.import_save = Some((path.to_path_buf(), eeprom_byte_order));
+        self
+    }
+
     pub fn save_type(mut self, save_type: BackupType) -> Self {
         self.save_type = save_type;
         self
@@ -107,6 +150,27 @@ impl GamepakBuilder {
         self
     }
 
+    pub fn with_solar_sensor(mut self) -> Self {
+        self.gpio_device = GpioDeviceType::SolarSensor;
+        self
+    }
+
+    pub fn with_gyro(mut self) -> Self {
+        self.gpio_device = GpioDeviceType::Gyro;
+        self
+    }
+
+    /// The clock of the RTC, should the cartridge have one
+    pub fn rtc_clock(mut self, clock: RtcClock) -> Self {
+        self.rtc_clock = clock;
+        self
+    }
+
+    pub fn with_tilt_sensor(mut self) -> Self {
+        self.tilt_sensor = true;
+        self
+    }
+
     pub fn build(mut self) -> GBAResult<Cartridge> {
         let (bytes, symbols) = if let Some(bytes) = self.bytes {
             match load_from_bytes(bytes.to_vec())? {
@@ -126,6 +190,17 @@ impl GamepakBuilder {
             ))
         }?;
 
+        let mut patches = self.patches.clone();
+        if patches.is_empty() && self.discover_patch {
+            patches.extend(self.path.as_deref().and_then(find_patch));
+        }
+        let mut bytes = bytes;
+        for path in &patches {
+            let patch = load_patch_from_file(path)?;
+            info!("Applying {} patch {:?}", patch.format(), path);
+            bytes = patch.apply(&bytes)?;
+        }
+
         let header = header::parse(&bytes)?;
         info!("Loaded ROM: {:?}", header);
 
@@ -140,27 +215,85 @@ impl GamepakBuilder {
         }
 
         let mut save_type = self.save_type;
+        let mut backup_source = if save_type == BackupType::AutoDetect {
+            BackupSource::Undetected
+        } else {
+            BackupSource::Forced
+        };
         let mut gpio_device = self.gpio_device;
+        let mut tilt_sensor = self.tilt_sensor;
+        let mut flash_chip_id = None;
+        let mut idle_loops = vec![];
 
-        if let Some(overrides) = overrides::get_game_overrides(&header.game_code) {
+        let user_overrides = self
+            .override_files
+            .iter()
+            .map(|path| GameOverrides::from_file(path))
+            .collect::<GBAResult<Vec<_>>>()?;
+        if let Some(overrides) =
+            overrides::get_game_overrides(&user_overrides, &header.game_code, crc32(&bytes))
+        {
             info!(
                 "Found game overrides for {}: {:#?}",
                 header.game_code, overrides
             );
             if let Some(override_save_type) = overrides.save_type() {
-                if override_save_type != save_type && save_type != BackupType::AutoDetect {
-                    warn!(
-                        "Forced save type {:?} takes priority of {:?}",
-                        save_type, override_save_type
-                    );
+                if backup_source == BackupSource::Forced {
+                    if override_save_type != save_type {
+                        warn!(
+                            "Forced save type {:?} takes priority of {:?}",
+                            save_type, override_save_type
+                        );
+                    }
+                } else {
+                    save_type = override_save_type;
+                    backup_source = BackupSource::GameDatabase;
                 }
-                save_type = override_save_type;
             }
 
-            if overrides.force_rtc() {
+            tilt_sensor |= overrides.tilt_sensor();
+            flash_chip_id = overrides.flash_chip_id();
+            idle_loops = overrides.idle_loops().to_vec();
+
+            if overrides.gyro() {
+                match gpio_device {
+                    GpioDeviceType::None => gpio_device = GpioDeviceType::Gyro,
+                    GpioDeviceType::Gyro => {}
+                    _ => {
+                        warn!(
+                            "Can't use the gyro due to forced gpio device type {:?}",
+                            gpio_device
+                        );
+                    }
+                }
+            } else if overrides.rumble() {
+                match gpio_device {
+                    GpioDeviceType::None => gpio_device = GpioDeviceType::Rumble,
+                    GpioDeviceType::Rumble | GpioDeviceType::Gyro => {}
+                    _ => {
+                        warn!(
+                            "Can't use the rumble due to forced gpio device type {:?}",
+                            gpio_device
+                        );
+                    }
+                }
+            } else if overrides.solar_sensor() {
+                match gpio_device {
+                    GpioDeviceType::None | GpioDeviceType::Rtc => {
+                        gpio_device = GpioDeviceType::SolarSensor
+                    }
+                    GpioDeviceType::SolarSensor => {}
+                    _ => {
+                        warn!(
+                            "Can't use the solar sensor due to forced gpio device type {:?}",
+                            gpio_device
+                        );
+                    }
+                }
+            } else if overrides.force_rtc() {
                 match gpio_device {
                     GpioDeviceType::None => gpio_device = GpioDeviceType::Rtc,
-                    GpioDeviceType::Rtc => {}
+                    GpioDeviceType::Rtc | GpioDeviceType::SolarSensor => {}
                     _ => {
                         warn!(
                             "Can't use RTC due to forced gpio device type {:?}",
@@ -171,24 +304,81 @@ impl GamepakBuilder {
             }
         }
 
+        let backup_path = self
+            .save_path
+            .map(|path| path.with_extension(BACKUP_FILE_EXT));
         if save_type == BackupType::AutoDetect {
-            if let Some(detected) = detect_backup_type(&bytes) {
+            if let Some(detected) = scan_id_strings(&bytes) {
                 info!("Detected Backup: {:?}", detected);
                 save_type = detected;
+                backup_source = BackupSource::IdString;
+            } else if let Some(guessed) = self
+                .import_save
+                .as_ref()
+                .map(|(path, _)| path.as_path())
+                .or(backup_path.as_deref())
+                .and_then(guess_from_save_file)
+            {
+                info!("Guessed Backup from the save file size: {:?}", guessed);
+                save_type = guessed;
+                backup_source = BackupSource::SaveFile;
             } else {
-                warn!("could not detect backup save type");
+                warn!("could not detect backup save type, waiting for the game to access it");
             }
         }
 
-        let backup = create_backup(save_type, self.save_path);
+        if let Some((path, eeprom_byte_order)) = &self.import_save {
+            let save_path = backup_path.as_deref().ok_or_else(|| {
+                GBAError::CartridgeLoadError(
+                    "there is no save file to import the save to".to_string(),
+                )
+            })?;
+            let save = import_save_file(path, save_type, *eeprom_byte_order)?;
+            info!("Importing the save {:?} to {:?}", path, save_path);
+            write_atomic(save_path, &save)?;
+        }
+
+        let mut backup = create_backup(save_type, backup_path.clone());
+        if let Some(chip_id) = flash_chip_id {
+            match &mut backup {
+                BackupMedia::Flash(flash) => flash.set_chip_id(chip_id),
+                _ => warn!(
+                    "ignoring the flash chip id override, the backup is {}",
+                    backup
+                ),
+            }
+        }
+        let backup_detector = BackupDetector::new(backup_source, backup_path);
 
-        let gpio = match gpio_device {
+        let mut gpio = match gpio_device {
             GpioDeviceType::None => None,
             GpioDeviceType::Rtc => {
                 info!("Emulating RTC!");
                 Some(Gpio::new_rtc())
             }
-            _ => unimplemented!("Gpio device {:?} not implemented", gpio_device),
+            GpioDeviceType::SolarSensor => {
+                info!("Emulating RTC and solar sensor!");
+                Some(Gpio::new_solar_sensor())
+            }
+            GpioDeviceType::Gyro => {
+                info!("Emulating gyro sensor and rumble!");
+                Some(Gpio::new_gyro())
+            }
+            GpioDeviceType::Rumble => {
+                info!("Emulating rumble!");
+                Some(Gpio::new_rumble())
+            }
+        };
+
+        if let Some(rtc) = gpio.as_mut().and_then(|gpio| gpio.rtc.as_mut()) {
+            rtc.set_clock(self.rtc_clock);
+        }
+
+        let tilt_sensor = if tilt_sensor {
+            info!("Emulating tilt sensor!");
+            Some(TiltSensor::new())
+        } else {
+            None
         };
 
         let size = bytes.len();
@@ -198,33 +388,185 @@ impl GamepakBuilder {
             bytes: bytes.into_boxed_slice(),
             size,
             backup,
+            backup_detector,
             symbols,
+            tilt_sensor,
+            idle_loops,
+            scheduler: Scheduler::new_shared(),
         })
     }
 }
 
 const BACKUP_FILE_EXT: &str = "sav";
-fn create_backup(backup_type: BackupType, rom_path: Option<PathBuf>) -> BackupMedia {
-    let backup_path = rom_path.map(|rom_path| rom_path.with_extension(BACKUP_FILE_EXT));
-    match backup_type {
-        BackupType::Flash | BackupType::Flash512 => {
-            BackupMedia::Flash(Flash::new(backup_path, FlashSize::Flash64k))
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides_enable_devices() {
+        // Game codes with entries in overrides.yaml, and the override keys of the devices they enable
+        let games: &[(&[u8; 4], &[&str])] = &[
+            (b"U3IE", &["rtc", "solar_sensor"]),
+            (b"RZWE", &["gyro", "rumble"]),
+            (b"V49E", &["rumble"]),
+            (b"KYGE", &["tilt_sensor"]),
+        ];
+        for &(game_code, expected) in games {
+            let mut rom = vec![0; 0x200];
+            rom[0xac..0xb0].copy_from_slice(game_code);
+            rom[0x100..0x10b].copy_from_slice(b"EEPROM_V124");
+            let cartridge = GamepakBuilder::new()
+                .buffer(&rom)
+                .without_backup_to_file()
+                .build()
+                .unwrap();
+            let gpio = cartridge.get_gpio().as_ref();
+            let devices = [
+                ("rtc", gpio.is_some_and(|gpio| gpio.rtc.is_some())),
+                (
+                    "solar_sensor",
+                    gpio.is_some_and(|gpio| gpio.solar_sensor.is_some()),
+                ),
+                ("gyro", gpio.is_some_and(|gpio| gpio.gyro.is_some())),
+                ("rumble", gpio.is_some_and(|gpio| gpio.rumble.is_some())),
+                ("tilt_sensor", cartridge.has_tilt_sensor()),
+            ];
+            let enabled: Vec<&str> = devices
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(key, _)| *key)
+                .collect();
+            let game_code = std::str::from_utf8(game_code).unwrap();
+            assert_eq!(enabled, expected, "{}", game_code);
+            // The tilt sensor is mapped over the SRAM region, not the GPIO port, and leaves the EEPROM alone
+            if expected.contains(&"tilt_sensor") {
+                assert!(gpio.is_none(), "{}", game_code);
+                assert!(matches!(cartridge.backup, BackupMedia::Eeprom(_)));
+            }
         }
-        BackupType::Flash1M => BackupMedia::Flash(Flash::new(backup_path, FlashSize::Flash128k)),
-        BackupType::Sram => BackupMedia::Sram(BackupFile::new(0x8000, backup_path)),
-        BackupType::Eeprom => BackupMedia::Eeprom(EepromController::new(backup_path)),
-        BackupType::AutoDetect => BackupMedia::Undetected,
     }
-}
 
-fn detect_backup_type(bytes: &[u8]) -> Option<BackupType> {
-    const ID_STRINGS: &[&str] = &["EEPROM", "SRAM", "FLASH_", "FLASH512_", "FLASH1M_"];
+    #[test]
+    fn test_overrides_file() {
+        let dir = std::env::temp_dir().join(format!("rba-overrides-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let valid = dir.join("valid.yaml");
+        std::fs::write(
+            &valid,
+            "- code: TEST\n  save_type: flash128k\n  flash_chip_id: 0x1362\n  rumble: true\n  idle_loops: [0x080000c4]\n",
+        )
+        .unwrap();
+        let invalid = dir.join("invalid.yaml");
+        std::fs::write(&invalid, "- code: TEST\n  save_type: flash\n").unwrap();
 
-    for i in 0..5 {
-        let search = TwoWaySearcher::new(ID_STRINGS[i].as_bytes());
-        if let Some(_) = search.search_in(bytes) {
-            return Some(BackupType::from_u8(i as u8).unwrap());
+        let mut rom = vec![0; 0x200];
+        rom[0xac..0xb0].copy_from_slice(b"TEST");
+        let build = |path: &Path| {
+            GamepakBuilder::new()
+                .buffer(&rom)
+                .without_backup_to_file()
+                .overrides_file(path)
+                .build()
+        };
+        let cartridge = build(&valid).unwrap();
+        let error = build(&invalid).unwrap_err();
+        let missing = build(&dir.join("missing.yaml")).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        match &cartridge.backup {
+            BackupMedia::Flash(flash) => assert!(flash.to_string().contains("chip id 1362")),
+            backup => panic!("expected flash, got {}", backup),
         }
+        let gpio = cartridge.get_gpio().as_ref().unwrap();
+        assert!(gpio.rumble.is_some());
+        assert!(gpio.gyro.is_none());
+        assert_eq!(cartridge.idle_loops(), &[0x080000c4]);
+        match error {
+            GBAError::CartridgeLoadError(message) => {
+                assert!(
+                    message.contains("invalid.yaml: entry 1 (TEST)"),
+                    "{}",
+                    message
+                )
+            }
+            error => panic!("unexpected error {}", error),
+        }
+        assert!(matches!(missing, GBAError::IO(_)));
+    }
+
+    #[test]
+    fn test_import_and_export_save() {
+        let dir = std::env::temp_dir().join(format!("rba-import-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.gba");
+        let mut rom = vec![0; 0x200];
+        rom[0x100..0x10b].copy_from_slice(b"EEPROM_V124");
+        std::fs::write(&rom_path, &rom).unwrap();
+        std::fs::write(dir.join("game.sav"), vec![0x11; 0x200]).unwrap();
+        // A little endian EEPROM save from another emulator
+        let mut other = vec![0xff; 0x200];
+        other[..8].copy_from_slice(&0x0123_4567_89ab_cdefu64.to_le_bytes());
+        std::fs::write(dir.join("other.sav"), &other).unwrap();
+
+        let mut cartridge = GamepakBuilder::new()
+            .file(&rom_path)
+            .without_patch_discovery()
+            .import_save(&dir.join("other.sav"), EepromByteOrder::LittleEndian)
+            .build()
+            .unwrap();
+        let save = std::fs::read(dir.join("game.sav")).unwrap();
+        let bak = std::fs::read(dir.join("game.sav.bak")).unwrap();
+        cartridge
+            .export_save(&dir.join("exported.sav"), EepromByteOrder::LittleEndian)
+            .unwrap();
+        let exported = std::fs::read(dir.join("exported.sav")).unwrap();
+        drop(cartridge);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(save[..8], 0x0123_4567_89ab_cdefu64.to_be_bytes());
+        assert_eq!(save.len(), 0x200);
+        assert_eq!(bak, vec![0x11; 0x200]);
+        assert_eq!(exported, other);
+    }
+
+    #[test]
+    fn test_import_save_needs_save_file() {
+        let result = GamepakBuilder::new()
+            .buffer(&[0; 0x200])
+            .with_sram()
+            .without_backup_to_file()
+            .import_save(Path::new("other.sav"), EepromByteOrder::BigEndian)
+            .build();
+        assert!(matches!(result, Err(GBAError::CartridgeLoadError(_))));
+    }
+
+    #[test]
+    fn test_discovers_patch_next_to_rom() {
+        let dir = std::env::temp_dir().join(format!("rba-patch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.gba");
+        std::fs::write(&rom_path, vec![0; 0x200]).unwrap();
+        // Writes "U3IE" as the game code
+        let mut ips = b"PATCH\x00\x00\xac\x00\x04U3IE".to_vec();
+        ips.extend_from_slice(b"EOF");
+        std::fs::write(dir.join("game.ips"), &ips).unwrap();
+
+        let build = |discover: bool| {
+            let mut builder = GamepakBuilder::new()
+                .file(&rom_path)
+                .without_backup_to_file();
+            if !discover {
+                builder = builder.without_patch_discovery();
+            }
+            builder.build().unwrap()
+        };
+        let patched = build(true);
+        let unpatched = build(false);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(&patched.get_rom_bytes()[0xac..0xb0], b"U3IE");
+        assert!(patched.get_gpio().is_some());
+        assert_eq!(&unpatched.get_rom_bytes()[0xac..0xb0], &[0; 4]);
     }
-    None
 }
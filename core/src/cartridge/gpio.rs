@@ -1,4 +1,7 @@
+use super::gyro::Gyro;
 use super::rtc::Rtc;
+use super::rumble::Rumble;
+use super::solar_sensor::SolarSensor;
 use super::{GPIO_PORT_CONTROL, GPIO_PORT_DATA, GPIO_PORT_DIRECTION};
 
 use bit::BitIndex;
@@ -28,6 +31,9 @@ pub trait GpioDevice: Sized {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Gpio {
     pub(crate) rtc: Option<Rtc>,
+    pub(crate) solar_sensor: Option<SolarSensor>,
+    pub(crate) gyro: Option<Gyro>,
+    pub(crate) rumble: Option<Rumble>,
     direction: GpioState,
     control: GpioPortControl,
 }
@@ -36,6 +42,9 @@ impl Gpio {
     pub fn new_none() -> Self {
         Gpio {
             rtc: None,
+            solar_sensor: None,
+            gyro: None,
+            rumble: None,
             direction: [GpioDirection::Out; 4],
             control: GpioPortControl::WriteOnly,
         }
@@ -44,11 +53,39 @@ impl Gpio {
     pub fn new_rtc() -> Self {
         Gpio {
             rtc: Some(Rtc::new()),
+            solar_sensor: None,
+            gyro: None,
+            rumble: None,
             direction: [GpioDirection::Out; 4],
             control: GpioPortControl::WriteOnly,
         }
     }
 
+    /// The Boktai cartridges, with the solar sensor sharing the port with an RTC
+    pub fn new_solar_sensor() -> Self {
+        Gpio {
+            solar_sensor: Some(SolarSensor::new()),
+            ..Gpio::new_rtc()
+        }
+    }
+
+    /// WarioWare: Twisted!, with a gyro sensor and a rumble motor
+    pub fn new_gyro() -> Self {
+        Gpio {
+            gyro: Some(Gyro::new()),
+            rumble: Some(Rumble::new()),
+            ..Gpio::new_none()
+        }
+    }
+
+    /// Drill Dozer, with just a rumble motor
+    pub fn new_rumble() -> Self {
+        Gpio {
+            rumble: Some(Rumble::new()),
+            ..Gpio::new_none()
+        }
+    }
+
     pub fn is_readable(&self) -> bool {
         self.control != GpioPortControl::WriteOnly
     }
@@ -56,11 +93,17 @@ impl Gpio {
     pub fn read(&self, addr: u32) -> u16 {
         match addr {
             GPIO_PORT_DATA => {
+                let mut data = 0;
                 if let Some(rtc) = &self.rtc {
-                    rtc.read(&self.direction)
-                } else {
-                    0
+                    data |= rtc.read(&self.direction);
+                }
+                if let Some(solar_sensor) = &self.solar_sensor {
+                    data |= solar_sensor.read(&self.direction);
                 }
+                if let Some(gyro) = &self.gyro {
+                    data |= gyro.read(&self.direction);
+                }
+                data
             }
             GPIO_PORT_DIRECTION => {
                 let mut direction = 0u16;
@@ -74,12 +117,23 @@ impl Gpio {
         }
     }
 
-    pub fn write(&mut self, addr: u32, value: u16) {
+    /// `timestamp` is the current cycle, which the RTC reads its clock at
+    pub fn write(&mut self, addr: u32, value: u16, timestamp: usize) {
         match addr {
             GPIO_PORT_DATA => {
                 if let Some(rtc) = &mut self.rtc {
+                    rtc.set_timestamp(timestamp);
                     rtc.write(&self.direction, value);
                 }
+                if let Some(solar_sensor) = &mut self.solar_sensor {
+                    solar_sensor.write(&self.direction, value);
+                }
+                if let Some(gyro) = &mut self.gyro {
+                    gyro.write(&self.direction, value);
+                }
+                if let Some(rumble) = &mut self.rumble {
+                    rumble.write(&self.direction, value);
+                }
             }
             GPIO_PORT_DIRECTION => {
                 for i in 0..4 {
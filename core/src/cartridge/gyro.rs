@@ -0,0 +1,121 @@
+use bit::BitIndex;
+use serde::{Deserialize, Serialize};
+
+use super::gpio::{GpioDevice, GpioState};
+
+#[derive(Clone, Copy, Debug)]
+enum Port {
+    /// Starts a conversion, latching the current rotation rate
+    Reset = 0,
+    /// The next bit of the conversion is shifted out on a falling edge
+    Clock = 1,
+    /// Serial output of the ADC, most significant bit first
+    Data = 2,
+}
+
+impl Port {
+    #[inline]
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// ADC reading at rest
+const CENTER: u16 = 0x6c0;
+
+/// Model of the gyro sensor and its ADC in WarioWare: Twisted!
+///
+/// Every conversion is shifted out as a 16 bit value, of which the ADC fills the low 12 bits.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Gyro {
+    /// Rotation rate around the axis perpendicular to the screen as set by the frontend
+    rate: i16,
+    /// The conversion being shifted out
+    sample: u16,
+    clock: bool,
+    data: bool,
+}
+
+impl Default for Gyro {
+    fn default() -> Gyro {
+        Gyro::new()
+    }
+}
+
+impl Gyro {
+    pub fn new() -> Self {
+        Gyro {
+            rate: 0,
+            sample: 0,
+            clock: false,
+            data: false,
+        }
+    }
+
+    /// Takes effect on the next conversion
+    pub fn set_rate(&mut self, rate: i16) {
+        self.rate = rate;
+    }
+
+    fn convert(&self) -> u16 {
+        // The full range of `rate` maps to +-0x400 around the center, well within 12 bits
+        (CENTER as i32 + (self.rate as i32 >> 5)) as u16
+    }
+}
+
+impl GpioDevice for Gyro {
+    fn write(&mut self, _gpio_state: &GpioState, data: u16) {
+        if data.bit(Port::Reset.index()) {
+            self.sample = self.convert();
+        }
+        let clock = data.bit(Port::Clock.index());
+        if self.clock && !clock {
+            self.data = self.sample.bit(15);
+            self.sample <<= 1;
+        }
+        self.clock = clock;
+    }
+
+    fn read(&self, _gpio_state: &GpioState) -> u16 {
+        let mut result = 0;
+        result.set_bit(Port::Data.index(), self.data);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::gpio::GpioDirection;
+    use super::*;
+
+    fn read_conversion(gyro: &mut Gyro) -> u16 {
+        let gpio_state = [
+            GpioDirection::Out,
+            GpioDirection::Out,
+            GpioDirection::In,
+            GpioDirection::Out,
+        ];
+        gyro.write(&gpio_state, 0b0011);
+        gyro.write(&gpio_state, 0b0010);
+        let mut value = 0;
+        for _ in 0..16 {
+            gyro.write(&gpio_state, 0b0000);
+            value = (value << 1) | gyro.read(&gpio_state).bit(Port::Data.index()) as u16;
+            gyro.write(&gpio_state, 0b0010);
+        }
+        value
+    }
+
+    #[test]
+    fn test_conversion() {
+        let mut gyro = Gyro::new();
+        assert_eq!(read_conversion(&mut gyro), 0x6c0);
+
+        gyro.set_rate(i16::MAX);
+        assert_eq!(read_conversion(&mut gyro), 0xabf);
+        gyro.set_rate(i16::MIN);
+        assert_eq!(read_conversion(&mut gyro), 0x2c0);
+        gyro.set_rate(-0x1000);
+        assert_eq!(read_conversion(&mut gyro), 0x640);
+    }
+}
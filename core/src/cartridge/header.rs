@@ -28,7 +28,7 @@ use super::super::{GBAError, GBAResult};
 ///   0C6h    26    Not used         (seems to be unused)
 ///   0E0h    4     JOYBUS Entry Pt. (32bit ARM branch opcode, eg. "B joy_start")
 ///
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct CartridgeHeader {
     // rom_entry_point: Addr,
     pub game_title: String,
@@ -4,13 +4,15 @@ use super::super::{GBAError, GBAResult};
 use std::collections::HashMap;
 use std::io::prelude::*;
 use std::io::Cursor;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 #[cfg(feature = "elf_support")]
 use rustboyadvance_utils::elf::{load_elf, GoblinError};
 use rustboyadvance_utils::read_bin_file;
 use zip::ZipArchive;
 
+use super::patch::{Patch, PATCH_EXTENSIONS};
+
 #[cfg(feature = "elf_support")]
 use crate::sysbus::consts::CART_BASE;
 
@@ -91,3 +93,20 @@ pub(super) fn load_from_bytes(bytes: Vec<u8>) -> LoadRomResult {
     // if everything else failed, load the rom as raw binary
     Ok(LoadRom::Raw(bytes))
 }
+
+/// Finds a patch named like the ROM next to it, e.g. game.ips for game.gba
+pub(super) fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    let mut found = PATCH_EXTENSIONS
+        .iter()
+        .map(|extension| rom_path.with_extension(extension))
+        .filter(|path| path.is_file());
+    let patch = found.next()?;
+    for ignored in found {
+        warn!("ignoring {:?} in favor of {:?}", ignored, patch);
+    }
+    Some(patch)
+}
+
+pub(super) fn load_patch_from_file(path: &Path) -> GBAResult<Patch> {
+    Patch::from_bytes(read_bin_file(path)?)
+}
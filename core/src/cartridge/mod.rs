@@ -1,25 +1,41 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
 
 use serde::{Deserialize, Serialize};
 
-use arm7tdmi::memory::{Addr, BusIO, DebugRead};
+use arm7tdmi::memory::{Addr, BusIO, DebugRead, DebugWrite};
+
+use super::sched::{Scheduler, SchedulerConnect, SharedScheduler};
+use super::{GBAError, GBAResult};
 
 pub mod header;
 use header::CartridgeHeader;
 
 mod backup;
+use backup::convert::EepromByteOrder;
+use backup::detect::BackupDetector;
+pub use backup::detect::{BackupAccessStats, BackupSource};
 use backup::eeprom::EepromController;
-use backup::flash::Flash;
-pub use backup::BackupType;
+use backup::flash::{Flash, FlashSize};
+pub use backup::{convert, BackupType};
 use backup::{BackupFile, BackupMemoryInterface};
 
 mod gpio;
+mod gyro;
 mod rtc;
+pub use rtc::RtcClock;
+mod rumble;
+mod solar_sensor;
+mod tilt_sensor;
 use gpio::Gpio;
+use tilt_sensor::TiltSensor;
 
 mod builder;
 mod loader;
+mod patch;
 pub use builder::GamepakBuilder;
+pub use patch::{Patch, PatchFormat};
 
 pub const GPIO_PORT_DATA: u32 = 0xC4;
 pub const GPIO_PORT_DIRECTION: u32 = 0xC6;
@@ -33,6 +49,28 @@ pub enum BackupMedia {
     Undetected,
 }
 
+impl BackupMedia {
+    fn memory_mut(&mut self) -> Option<&mut BackupFile> {
+        match self {
+            BackupMedia::Sram(file) => Some(file),
+            BackupMedia::Flash(flash) => Some(flash.memory_mut()),
+            BackupMedia::Eeprom(eeprom) => Some(eeprom.memory_mut()),
+            BackupMedia::Undetected => None,
+        }
+    }
+}
+
+impl fmt::Display for BackupMedia {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupMedia::Sram(file) => write!(f, "SRAM, {}", file),
+            BackupMedia::Flash(flash) => write!(f, "{}", flash),
+            BackupMedia::Eeprom(eeprom) => write!(f, "{}", eeprom),
+            BackupMedia::Undetected => write!(f, "none detected"),
+        }
+    }
+}
+
 pub type SymbolTable = HashMap<String, u32>;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -45,16 +83,167 @@ pub struct Cartridge {
     gpio: Option<Gpio>,
     symbols: Option<SymbolTable>, // TODO move it somewhere else
     pub(crate) backup: BackupMedia,
+    backup_detector: BackupDetector,
+    /// Mapped over the SRAM region, which these cartridges don't otherwise use
+    tilt_sensor: Option<TiltSensor>,
+    /// Branches closing idle loops of the game, from the overrides
+    pub(crate) idle_loops: Vec<Addr>,
+    #[serde(skip)]
+    #[serde(default = "Scheduler::new_shared")]
+    scheduler: SharedScheduler,
+}
+
+impl SchedulerConnect for Cartridge {
+    fn connect_scheduler(&mut self, scheduler: SharedScheduler) {
+        self.scheduler = scheduler;
+    }
 }
 
 impl Cartridge {
+    /// No cartridge inserted, the ROM region reads as open bus
+    pub fn empty() -> Cartridge {
+        Cartridge {
+            header: CartridgeHeader::default(),
+            bytes: Default::default(),
+            size: 0,
+            gpio: None,
+            symbols: None,
+            backup: BackupMedia::Undetected,
+            backup_detector: BackupDetector::new(BackupSource::Undetected, None),
+            tilt_sensor: None,
+            idle_loops: vec![],
+            scheduler: Scheduler::new_shared(),
+        }
+    }
+
     pub fn get_symbols(&self) -> &Option<SymbolTable> {
         &self.symbols
     }
+    /// Finds the symbol closest below `addr` in the same memory region, returning its name and how far `addr` is into it
+    pub fn symbolize(&self, addr: Addr) -> Option<(&str, u32)> {
+        self.symbols
+            .as_ref()?
+            .iter()
+            // Skip the ARM mapping symbols ($a, $t, $d) that only mark the instruction set in use
+            .filter(|(name, _)| !name.is_empty() && !name.starts_with('$'))
+            .map(|(name, value)| (name.as_str(), value & !1))
+            .filter(|(_, value)| *value <= addr && value >> 24 == addr >> 24)
+            .max_by_key(|(_, value)| *value)
+            .map(|(name, value)| (name, addr - value))
+    }
+
+    pub fn idle_loops(&self) -> &[Addr] {
+        &self.idle_loops
+    }
+
     pub fn get_gpio(&self) -> &Option<Gpio> {
         &self.gpio
     }
 
+    /// Sets the light level seen by the solar sensor, if the cartridge has one
+    pub fn set_solar_sensor_level(&mut self, level: u8) {
+        if let Some(solar_sensor) = self
+            .gpio
+            .as_mut()
+            .and_then(|gpio| gpio.solar_sensor.as_mut())
+        {
+            solar_sensor.set_level(level);
+        }
+    }
+
+    /// Sets the rotation rate seen by the gyro sensor, if the cartridge has one
+    pub fn set_gyro_rate(&mut self, rate: i16) {
+        if let Some(gyro) = self.gpio.as_mut().and_then(|gpio| gpio.gyro.as_mut()) {
+            gyro.set_rate(rate);
+        }
+    }
+
+    /// Returns the state of the rumble motor if it changed since the last call
+    pub fn poll_rumble(&mut self) -> Option<bool> {
+        self.gpio.as_mut()?.rumble.as_mut()?.poll()
+    }
+
+    pub fn rtc_clock(&self) -> Option<RtcClock> {
+        Some(self.gpio.as_ref()?.rtc.as_ref()?.clock())
+    }
+
+    /// Replaces the clock of the RTC, if the cartridge has one
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        if let Some(rtc) = self.gpio.as_mut().and_then(|gpio| gpio.rtc.as_mut()) {
+            rtc.set_clock(clock);
+        }
+    }
+
+    pub fn has_tilt_sensor(&self) -> bool {
+        self.tilt_sensor.is_some()
+    }
+
+    /// Sets the tilt seen by the tilt sensor, if the cartridge has one
+    pub fn set_tilt(&mut self, x: i16, y: i16) {
+        if let Some(tilt_sensor) = &mut self.tilt_sensor {
+            tilt_sensor.set_tilt(x, y);
+        }
+    }
+
+    /// How the backup type was decided, and how the game accessed it so far
+    pub fn backup_detection(&self) -> &BackupDetector {
+        &self.backup_detector
+    }
+
+    pub(crate) fn is_eeprom_access(&self, addr: u32) -> bool {
+        addr & 0xff000000 == GAMEPAK_WS2_HI
+            && (self.bytes.len() <= 16 * 1024 * 1024 || addr >= EEPROM_BASE_ADDR)
+    }
+
+    /// Lets the EEPROM see the DMA transfers that drive it, replacing the backup memory with one if it was guessed wrong
+    pub(crate) fn on_dma3_transfer(&mut self, src: u32, dst: u32, count: usize) {
+        if self.is_eeprom_access(dst) {
+            self.backup_detector.on_eeprom_dma(&mut self.backup);
+        }
+        if let BackupMedia::Eeprom(eeprom) = &mut self.backup {
+            eeprom.on_dma3_transfer(src, dst, count);
+        }
+    }
+
+    /// Writes the save converted for other emulators and flash carts to `path`
+    pub fn export_save(
+        &mut self,
+        path: &Path,
+        eeprom_byte_order: EepromByteOrder,
+    ) -> GBAResult<()> {
+        let backup_type = match &self.backup {
+            BackupMedia::Sram(_) => BackupType::Sram,
+            BackupMedia::Flash(flash) if flash.size() == FlashSize::Flash128k.into() => {
+                BackupType::Flash1M
+            }
+            BackupMedia::Flash(_) => BackupType::Flash512,
+            BackupMedia::Eeprom(_) => BackupType::Eeprom,
+            BackupMedia::Undetected => {
+                return Err(GBAError::CartridgeLoadError(
+                    "no save to export, the backup type isn't known".to_string(),
+                ))
+            }
+        };
+        let memory = self.backup.memory_mut().unwrap();
+        let bytes = convert::export_save(memory.bytes(), backup_type, eeprom_byte_order, None);
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    /// Writes the backup memory to the save file right away
+    pub fn flush_backup(&mut self) {
+        if let Some(memory) = self.backup.memory_mut() {
+            memory.flush();
+        }
+    }
+
+    /// Writes the backup memory to the save file once the game is done saving
+    pub(crate) fn flush_backup_if_idle(&mut self) {
+        if let Some(memory) = self.backup.memory_mut() {
+            memory.flush_if_idle();
+        }
+    }
+
     pub fn set_rom_bytes(&mut self, bytes: Box<[u8]>) {
         self.size = bytes.len();
         self.bytes = bytes;
@@ -73,6 +262,10 @@ impl Cartridge {
             gpio: self.gpio.clone(),
             symbols: self.symbols.clone(),
             backup: self.backup.clone(),
+            backup_detector: self.backup_detector.clone(),
+            tilt_sensor: self.tilt_sensor.clone(),
+            idle_loops: self.idle_loops.clone(),
+            scheduler: self.scheduler.clone(),
         }
     }
 
@@ -81,6 +274,9 @@ impl Cartridge {
         self.gpio = other.gpio;
         self.symbols = other.symbols;
         self.backup = other.backup;
+        self.backup_detector = other.backup_detector;
+        self.tilt_sensor = other.tilt_sensor;
+        self.idle_loops = other.idle_loops;
     }
 
     #[inline]
@@ -113,9 +309,10 @@ impl BusIO for Cartridge {
     fn read_8(&mut self, addr: Addr) -> u8 {
         let offset = (addr & 0x01ff_ffff) as usize;
         match addr & 0xff000000 {
-            SRAM_LO | SRAM_HI => match &self.backup {
-                BackupMedia::Sram(memory) => memory.read((addr & 0x7FFF) as usize),
-                BackupMedia::Flash(flash) => flash.read(addr),
+            SRAM_LO | SRAM_HI => match (&self.tilt_sensor, &self.backup) {
+                (Some(tilt_sensor), _) => tilt_sensor.read(addr),
+                (_, BackupMedia::Sram(memory)) => memory.read((addr & 0x7FFF) as usize),
+                (_, BackupMedia::Flash(flash)) => flash.read(addr),
                 _ => 0,
             },
             _ => {
@@ -138,9 +335,7 @@ impl BusIO for Cartridge {
             }
         }
 
-        if addr & 0xff000000 == GAMEPAK_WS2_HI
-            && (self.bytes.len() <= 16 * 1024 * 1024 || addr >= EEPROM_BASE_ADDR)
-        {
+        if self.is_eeprom_access(addr) {
             if let BackupMedia::Eeprom(spi) = &self.backup {
                 return spi.read_half(addr);
             }
@@ -150,11 +345,18 @@ impl BusIO for Cartridge {
 
     fn write_8(&mut self, addr: u32, value: u8) {
         match addr & 0xff000000 {
-            SRAM_LO | SRAM_HI => match &mut self.backup {
-                BackupMedia::Flash(flash) => flash.write(addr, value),
-                BackupMedia::Sram(memory) => memory.write((addr & 0x7FFF) as usize, value),
-                _ => {}
-            },
+            SRAM_LO | SRAM_HI => {
+                if let Some(tilt_sensor) = &mut self.tilt_sensor {
+                    return tilt_sensor.write(addr, value);
+                }
+                self.backup_detector
+                    .on_sram_write(&mut self.backup, addr, value);
+                match &mut self.backup {
+                    BackupMedia::Flash(flash) => flash.write(addr, value),
+                    BackupMedia::Sram(memory) => memory.write((addr & 0x7FFF) as usize, value),
+                    _ => {}
+                }
+            }
             _ => {} // TODO allow the debugger to write
         };
     }
@@ -162,14 +364,12 @@ impl BusIO for Cartridge {
     fn write_16(&mut self, addr: u32, value: u16) {
         if is_gpio_access(addr) {
             if let Some(gpio) = &mut self.gpio {
-                gpio.write(addr & 0x1ff_ffff, value);
+                gpio.write(addr & 0x1ff_ffff, value, self.scheduler.timestamp());
                 return;
             }
         }
 
-        if addr & 0xff000000 == GAMEPAK_WS2_HI
-            && (self.bytes.len() <= 16 * 1024 * 1024 || addr >= EEPROM_BASE_ADDR)
-        {
+        if self.is_eeprom_access(addr) {
             if let BackupMedia::Eeprom(spi) = &mut self.backup {
                 return spi.write_half(addr, value);
             }
@@ -188,3 +388,18 @@ impl DebugRead for Cartridge {
         }
     }
 }
+
+impl DebugWrite for Cartridge {
+    /// Patches the rom, the backup memory is written like the cpu would
+    fn debug_write_8(&mut self, addr: Addr, value: u8) {
+        match addr & 0xff000000 {
+            SRAM_LO | SRAM_HI => self.write_8(addr, value),
+            _ => {
+                let offset = (addr & 0x01ff_ffff) as usize;
+                if offset < self.size {
+                    self.bytes[offset] = value;
+                }
+            }
+        }
+    }
+}
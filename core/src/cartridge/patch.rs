@@ -0,0 +1,426 @@
+/// Soft-patching of ROMs with IPS, UPS and BPS patches, applied when the cartridge is loaded.
+use std::fmt;
+
+use crc32fast::Hasher;
+
+use super::super::{GBAError, GBAResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    Ips,
+    Ups,
+    Bps,
+}
+
+impl fmt::Display for PatchFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchFormat::Ips => write!(f, "IPS"),
+            PatchFormat::Ups => write!(f, "UPS"),
+            PatchFormat::Bps => write!(f, "BPS"),
+        }
+    }
+}
+
+/// Patches making anything larger than a cartridge can hold are rejected
+const MAX_ROM_SIZE: usize = 32 * 1024 * 1024;
+
+/// The extensions patches are looked up by next to a ROM
+pub const PATCH_EXTENSIONS: &[&str] = &["ips", "ups", "bps"];
+
+pub(super) fn crc32(bytes: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+fn patch_error(format: PatchFormat, reason: &str) -> GBAError {
+    GBAError::CartridgeLoadError(format!("{} patch: {}", format, reason))
+}
+
+#[derive(Debug, Clone)]
+pub struct Patch {
+    format: PatchFormat,
+    bytes: Vec<u8>,
+}
+
+impl Patch {
+    /// Detects the format from the magic at the start of the patch
+    pub fn from_bytes(bytes: Vec<u8>) -> GBAResult<Patch> {
+        let format = if bytes.starts_with(b"PATCH") {
+            PatchFormat::Ips
+        } else if bytes.starts_with(b"UPS1") {
+            PatchFormat::Ups
+        } else if bytes.starts_with(b"BPS1") {
+            PatchFormat::Bps
+        } else {
+            return Err(GBAError::CartridgeLoadError(
+                "unknown patch format".to_owned(),
+            ));
+        };
+        Ok(Patch { format, bytes })
+    }
+
+    pub fn format(&self) -> PatchFormat {
+        self.format
+    }
+
+    /// Returns the patched ROM. UPS and BPS patches carry checksums of the ROM they apply to, of the result and of
+    /// themselves, which are all validated. IPS patches have none.
+    pub fn apply(&self, rom: &[u8]) -> GBAResult<Vec<u8>> {
+        match self.format {
+            PatchFormat::Ips => apply_ips(&self.bytes, rom),
+            PatchFormat::Ups => apply_ups(&self.bytes, rom),
+            PatchFormat::Bps => apply_bps(&self.bytes, rom),
+        }
+    }
+}
+
+struct PatchReader<'a> {
+    format: PatchFormat,
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(format: PatchFormat, bytes: &'a [u8], pos: usize) -> PatchReader<'a> {
+        PatchReader { format, bytes, pos }
+    }
+
+    fn read_bytes(&mut self, count: usize) -> GBAResult<&'a [u8]> {
+        match self
+            .pos
+            .checked_add(count)
+            .and_then(|end| self.bytes.get(self.pos..end))
+        {
+            Some(bytes) => {
+                self.pos += count;
+                Ok(bytes)
+            }
+            None => Err(patch_error(self.format, "unexpected end of patch")),
+        }
+    }
+
+    fn read_u8(&mut self) -> GBAResult<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_be(&mut self, count: usize) -> GBAResult<usize> {
+        Ok(self
+            .read_bytes(count)?
+            .iter()
+            .fold(0, |value, &byte| value << 8 | byte as usize))
+    }
+
+    /// The variable length integers of UPS and BPS
+    fn read_number(&mut self) -> GBAResult<usize> {
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.read_u8()?;
+            value = (byte as usize & 0x7f)
+                .checked_mul(shift)
+                .and_then(|bits| value.checked_add(bits))
+                .ok_or_else(|| patch_error(self.format, "number overflow"))?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift
+                .checked_shl(7)
+                .ok_or_else(|| patch_error(self.format, "number overflow"))?;
+            value += shift;
+        }
+    }
+}
+
+fn apply_ips(patch: &[u8], rom: &[u8]) -> GBAResult<Vec<u8>> {
+    const EOF: usize = 0x45_4f_46;
+    let format = PatchFormat::Ips;
+    let mut reader = PatchReader::new(format, patch, 5);
+    let mut target = rom.to_vec();
+    loop {
+        let offset = reader.read_be(3)?;
+        if offset == EOF {
+            break;
+        }
+        let size = reader.read_be(2)?;
+        let (size, data) = if size == 0 {
+            let size = reader.read_be(2)?;
+            (size, None)
+        } else {
+            (size, Some(reader.read_bytes(size)?))
+        };
+        if target.len() < offset + size {
+            target.resize(offset + size, 0);
+        }
+        match data {
+            Some(data) => target[offset..offset + size].copy_from_slice(data),
+            None => {
+                let value = reader.read_u8()?;
+                target[offset..offset + size].fill(value);
+            }
+        }
+    }
+    // An extension adds the size to truncate the ROM to after the EOF marker
+    if reader.pos + 3 <= patch.len() {
+        target.truncate(reader.read_be(3)?);
+    }
+    Ok(target)
+}
+
+/// Splits the source, target and patch checksums that end UPS and BPS patches off the patch
+fn split_checksums(format: PatchFormat, patch: &[u8]) -> GBAResult<(&[u8], [u32; 3])> {
+    if patch.len() < 4 + 12 {
+        return Err(patch_error(format, "too short"));
+    }
+    let (body, footer) = patch.split_at(patch.len() - 12);
+    let mut checksums = [0; 3];
+    for (checksum, bytes) in checksums.iter_mut().zip(footer.chunks(4)) {
+        *checksum = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    if crc32(&patch[..patch.len() - 4]) != checksums[2] {
+        return Err(patch_error(
+            format,
+            "the patch is corrupted, its checksum doesn't match",
+        ));
+    }
+    Ok((body, checksums))
+}
+
+fn check_sizes(
+    format: PatchFormat,
+    rom: &[u8],
+    source_size: usize,
+    source_checksum: u32,
+    target_size: usize,
+) -> GBAResult<()> {
+    if rom.len() != source_size || crc32(rom) != source_checksum {
+        return Err(patch_error(
+            format,
+            "the patch was made for a different ROM",
+        ));
+    }
+    if target_size > MAX_ROM_SIZE {
+        return Err(patch_error(format, "the patched ROM is too large"));
+    }
+    Ok(())
+}
+
+fn check_target(format: PatchFormat, target: &[u8], checksum: u32) -> GBAResult<()> {
+    if crc32(target) != checksum {
+        return Err(patch_error(
+            format,
+            "the patched ROM doesn't match the patch's checksum",
+        ));
+    }
+    Ok(())
+}
+
+fn apply_ups(patch: &[u8], rom: &[u8]) -> GBAResult<Vec<u8>> {
+    let format = PatchFormat::Ups;
+    let (body, [source_checksum, target_checksum, _]) = split_checksums(format, patch)?;
+    let mut reader = PatchReader::new(format, body, 4);
+    let source_size = reader.read_number()?;
+    let target_size = reader.read_number()?;
+    check_sizes(format, rom, source_size, source_checksum, target_size)?;
+
+    let mut target = rom.to_vec();
+    target.resize(target_size, 0);
+    let mut offset = 0usize;
+    while reader.pos < body.len() {
+        offset = offset.saturating_add(reader.read_number()?);
+        loop {
+            let xor = reader.read_u8()?;
+            if let Some(byte) = target.get_mut(offset) {
+                *byte ^= xor;
+            }
+            offset = offset.saturating_add(1);
+            if xor == 0 {
+                break;
+            }
+        }
+    }
+
+    check_target(format, &target, target_checksum)?;
+    Ok(target)
+}
+
+fn apply_bps(patch: &[u8], rom: &[u8]) -> GBAResult<Vec<u8>> {
+    let format = PatchFormat::Bps;
+    let (body, [source_checksum, target_checksum, _]) = split_checksums(format, patch)?;
+    let mut reader = PatchReader::new(format, body, 4);
+    let source_size = reader.read_number()?;
+    let target_size = reader.read_number()?;
+    let metadata_size = reader.read_number()?;
+    reader.read_bytes(metadata_size)?;
+    check_sizes(format, rom, source_size, source_checksum, target_size)?;
+
+    fn relative_offset(reader: &mut PatchReader, offset: usize) -> GBAResult<usize> {
+        let data = reader.read_number()?;
+        let delta = data >> 1;
+        let offset = if data & 1 != 0 {
+            offset.checked_sub(delta)
+        } else {
+            offset.checked_add(delta)
+        };
+        offset.ok_or_else(|| patch_error(reader.format, "copy out of bounds"))
+    }
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset = 0;
+    let mut target_offset = 0;
+    while reader.pos < body.len() {
+        let data = reader.read_number()?;
+        let length = (data >> 2) + 1;
+        if length > target_size - target.len() {
+            return Err(patch_error(
+                format,
+                "writes past the end of the patched ROM",
+            ));
+        }
+        match data & 3 {
+            // Copy from the same offset in the source
+            0 => match rom.get(target.len()..target.len() + length) {
+                Some(bytes) => target.extend_from_slice(bytes),
+                None => return Err(patch_error(format, "copy out of bounds")),
+            },
+            // Copy from the patch
+            1 => target.extend_from_slice(reader.read_bytes(length)?),
+            // Copy from anywhere in the source
+            2 => {
+                source_offset = relative_offset(&mut reader, source_offset)?;
+                match rom.get(source_offset..source_offset.saturating_add(length)) {
+                    Some(bytes) => target.extend_from_slice(bytes),
+                    None => return Err(patch_error(format, "copy out of bounds")),
+                }
+                source_offset += length;
+            }
+            // Copy from what was already written, byte by byte as the ranges may overlap
+            _ => {
+                target_offset = relative_offset(&mut reader, target_offset)?;
+                for _ in 0..length {
+                    match target.get(target_offset) {
+                        Some(&byte) => target.push(byte),
+                        None => return Err(patch_error(format, "copy out of bounds")),
+                    }
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if target.len() != target_size {
+        return Err(patch_error(format, "the patched ROM is too short"));
+    }
+
+    check_target(format, &target, target_checksum)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(mut value: usize) -> Vec<u8> {
+        let mut bytes = vec![];
+        loop {
+            let bits = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(0x80 | bits);
+                return bytes;
+            }
+            bytes.push(bits);
+            value -= 1;
+        }
+    }
+
+    fn with_checksums(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    fn apply(patch: Vec<u8>, rom: &[u8]) -> GBAResult<Vec<u8>> {
+        Patch::from_bytes(patch)?.apply(rom)
+    }
+
+    #[test]
+    fn test_number() {
+        for value in [0, 1, 0x7f, 0x80, 0x3fff, 0x4080, 0x1234_5678] {
+            let bytes = number(value);
+            let mut reader = PatchReader::new(PatchFormat::Bps, &bytes, 0);
+            assert_eq!(reader.read_number().unwrap(), value);
+            assert_eq!(reader.pos, bytes.len());
+        }
+    }
+
+    #[test]
+    fn test_ips() {
+        let rom = [0u8; 8];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0xaa, 0xbb]);
+        // RLE record growing the ROM
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 4, 0xcc]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(
+            apply(patch.clone(), &rom).unwrap(),
+            [0, 0xaa, 0xbb, 0, 0, 0, 0xcc, 0xcc, 0xcc, 0xcc]
+        );
+
+        patch.extend_from_slice(&[0, 0, 2]);
+        assert_eq!(apply(patch, &rom).unwrap(), [0, 0xaa]);
+
+        let truncated = b"PATCH\x00\x00\x01\x00\x02\xaa".to_vec();
+        assert!(apply(truncated, &rom).is_err());
+    }
+
+    #[test]
+    fn test_ups() {
+        let rom = [1u8, 2, 3, 4];
+        let target = [1u8, 7, 3, 4, 0, 9];
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(number(rom.len()));
+        patch.extend(number(target.len()));
+        patch.extend(number(1));
+        patch.extend_from_slice(&[2 ^ 7, 0]);
+        patch.extend(number(2));
+        patch.extend_from_slice(&[9, 0]);
+        let patch = with_checksums(patch, &rom, &target);
+        assert_eq!(apply(patch.clone(), &rom).unwrap(), target);
+
+        assert!(apply(patch.clone(), &[1, 2, 3, 5]).is_err());
+        let mut corrupted = patch;
+        corrupted[6] ^= 1;
+        assert!(apply(corrupted, &rom).is_err());
+    }
+
+    #[test]
+    fn test_bps() {
+        let rom = b"abcdefgh";
+        let target = b"abcXYZXYZXYZfgab";
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(number(rom.len()));
+        patch.extend(number(target.len()));
+        patch.extend(number(3));
+        patch.extend_from_slice(b"abc");
+        // source read "abc"
+        patch.extend(number((3 - 1) << 2));
+        // target read "XYZ"
+        patch.extend(number((3 - 1) << 2 | 1));
+        patch.extend_from_slice(b"XYZ");
+        // target copy of the overlapping "XYZXYZ"
+        patch.extend(number((6 - 1) << 2 | 3));
+        patch.extend(number(3 << 1));
+        // source copy of "fg" then back to "ab"
+        patch.extend(number((2 - 1) << 2 | 2));
+        patch.extend(number(5 << 1));
+        patch.extend(number((2 - 1) << 2 | 2));
+        patch.extend(number(7 << 1 | 1));
+        let patch = with_checksums(patch, rom, target);
+        assert_eq!(apply(patch.clone(), rom).unwrap(), target);
+
+        assert!(apply(patch, b"abcdefgi").is_err());
+        assert!(apply(b"BPS2".to_vec(), rom).is_err());
+    }
+}
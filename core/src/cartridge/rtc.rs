@@ -9,6 +9,78 @@ use std::cmp;
 
 use super::gpio::{GpioDevice, GpioDirection, GpioState};
 
+/// Cycles of the system clock in a second
+const CYCLES_PER_SECOND: usize = 16 * 1024 * 1024;
+
+/// Where the RTC takes the date and time from.
+///
+/// Times are in seconds since 1970-01-01 00:00:00 as shown on the clock, without a time zone.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RtcClock {
+    /// The local time of the host, shifted by `offset` seconds
+    Host { offset: i64 },
+    /// Shows `start` at power on and advances with the emulated cycles, so it is the same on every run
+    Emulated { start: i64 },
+    /// Stays at `time` until it is set again
+    Manual { time: i64 },
+}
+
+impl Default for RtcClock {
+    fn default() -> RtcClock {
+        RtcClock::Host { offset: 0 }
+    }
+}
+
+impl RtcClock {
+    pub fn emulated(start: NaiveDateTime) -> RtcClock {
+        RtcClock::Emulated {
+            start: start.timestamp(),
+        }
+    }
+
+    pub fn manual(time: NaiveDateTime) -> RtcClock {
+        RtcClock::Manual {
+            time: time.timestamp(),
+        }
+    }
+
+    fn host_time() -> i64 {
+        Local::now().naive_local().timestamp()
+    }
+
+    /// The date and time shown after `timestamp` cycles
+    pub fn now(&self, timestamp: usize) -> NaiveDateTime {
+        let secs = match *self {
+            RtcClock::Host { offset } => RtcClock::host_time().saturating_add(offset),
+            RtcClock::Emulated { start } => {
+                start.saturating_add((timestamp / CYCLES_PER_SECOND) as i64)
+            }
+            RtcClock::Manual { time } => time,
+        };
+        NaiveDateTime::from_timestamp_opt(secs, 0)
+            .unwrap_or_else(|| NaiveDate::from_ymd(2000, 1, 1).and_hms(0, 0, 0))
+    }
+
+    /// Adjusts the clock to show `time` after `timestamp` cycles
+    pub fn set(&mut self, time: NaiveDateTime, timestamp: usize) {
+        let secs = time.timestamp();
+        match self {
+            RtcClock::Host { offset } => *offset = secs - RtcClock::host_time(),
+            RtcClock::Emulated { start } => *start = secs - (timestamp / CYCLES_PER_SECOND) as i64,
+            RtcClock::Manual { time } => *time = secs,
+        }
+    }
+}
+
+fn bcd2num(bcd: u8) -> Option<u32> {
+    let (tens, ones) = (bcd >> 4, bcd & 0xf);
+    if tens > 9 || ones > 9 {
+        None
+    } else {
+        Some((tens * 10 + ones) as u32)
+    }
+}
+
 fn num2bcd(mut num: u8) -> u8 {
     num = cmp::min(num, 99);
 
@@ -194,6 +266,10 @@ pub struct Rtc {
     status: registers::StatusRegister,
     serial_buffer: SerialBuffer,
     internal_buffer: [u8; 8],
+    clock: RtcClock,
+    /// Cycle timestamp of the access being handled
+    #[serde(skip)]
+    timestamp: usize,
 }
 
 impl Rtc {
@@ -206,9 +282,24 @@ impl Rtc {
             status: registers::StatusRegister(0x82),
             serial_buffer: SerialBuffer::new(),
             internal_buffer: [0; 8],
+            clock: RtcClock::default(),
+            timestamp: 0,
         }
     }
 
+    pub fn clock(&self) -> RtcClock {
+        self.clock
+    }
+
+    pub fn set_clock(&mut self, clock: RtcClock) {
+        self.clock = clock;
+    }
+
+    /// Must be called before each access, for the clock to know the current time
+    pub fn set_timestamp(&mut self, timestamp: usize) {
+        self.timestamp = timestamp;
+    }
+
     fn serial_read(&mut self) {
         self.serial_buffer.push_bit(self.sio.high());
     }
@@ -231,36 +322,19 @@ impl Rtc {
         match r {
             RegisterKind::Status => self.internal_buffer[0] = self.status.read(),
             RegisterKind::DateTime => {
-                let local: DateTime<Local> = Local::now();
-                let year = local.year();
-                assert!((2000..=2099).contains(&year)); // Wonder if I will live to see this one fail
-
-                let hour = if self.status.mode_24h() {
-                    local.hour()
-                } else {
-                    let (_, hour12) = local.hour12();
-                    hour12 - 1
-                };
-
-                self.internal_buffer[0] = num2bcd((year % 100) as u8);
-                self.internal_buffer[1] = num2bcd(local.month() as u8);
-                self.internal_buffer[2] = num2bcd(local.day() as u8);
-                self.internal_buffer[3] = num2bcd(local.weekday().number_from_monday() as u8);
-                self.internal_buffer[4] = num2bcd(hour as u8);
-                self.internal_buffer[5] = num2bcd(local.minute() as u8);
-                self.internal_buffer[6] = num2bcd(local.second() as u8);
+                let now = self.clock.now(self.timestamp);
+                // The year register only holds 2000-2099, other years wrap around
+                self.internal_buffer[0] = num2bcd(now.year().rem_euclid(100) as u8);
+                self.internal_buffer[1] = num2bcd(now.month() as u8);
+                self.internal_buffer[2] = num2bcd(now.day() as u8);
+                self.internal_buffer[3] = num2bcd(now.weekday().num_days_from_sunday() as u8);
+                let time = self.encode_time(now.time());
+                self.internal_buffer[4..7].copy_from_slice(&time);
             }
             RegisterKind::Time => {
-                let local: DateTime<Local> = Local::now();
-                let hour = if self.status.mode_24h() {
-                    local.hour()
-                } else {
-                    let (_, hour12) = local.hour12();
-                    hour12 - 1
-                };
-                self.internal_buffer[0] = num2bcd(hour as u8);
-                self.internal_buffer[1] = num2bcd(local.minute() as u8);
-                self.internal_buffer[2] = num2bcd(local.second() as u8);
+                let now = self.clock.now(self.timestamp);
+                let time = self.encode_time(now.time());
+                self.internal_buffer[0..3].copy_from_slice(&time);
             }
             _ => warn!("RTC: read {:?} not implemented", r),
         }
@@ -271,9 +345,53 @@ impl Rtc {
         match r {
             Status => self.status.write(self.internal_buffer[0]),
             ForceReset => self.force_reset(),
+            DateTime => {
+                let buffer = &self.internal_buffer;
+                // The day of the week is implied by the date
+                let date = bcd2num(buffer[0]).and_then(|year| {
+                    NaiveDate::from_ymd_opt(
+                        2000 + year as i32,
+                        bcd2num(buffer[1])?,
+                        bcd2num(buffer[2])?,
+                    )
+                });
+                match (date, self.decode_time(&buffer[4..7])) {
+                    (Some(date), Some(time)) => self.clock.set(date.and_time(time), self.timestamp),
+                    _ => warn!("RTC: invalid date time {:x?}", &buffer[0..7]),
+                }
+            }
+            Time => match self.decode_time(&self.internal_buffer[0..3]) {
+                Some(time) => {
+                    let date = self.clock.now(self.timestamp).date();
+                    self.clock.set(date.and_time(time), self.timestamp);
+                }
+                None => warn!("RTC: invalid time {:x?}", &self.internal_buffer[0..3]),
+            },
             _ => warn!("RTC: write {:?} not implemented", r),
         }
     }
+
+    /// In 12 hour mode bit 6 of the hour is set for PM
+    fn encode_time(&self, time: NaiveTime) -> [u8; 3] {
+        let hour = if self.status.mode_24h() {
+            num2bcd(time.hour() as u8)
+        } else {
+            num2bcd((time.hour() % 12) as u8) | if time.hour() >= 12 { 0x40 } else { 0 }
+        };
+        [
+            hour,
+            num2bcd(time.minute() as u8),
+            num2bcd(time.second() as u8),
+        ]
+    }
+
+    fn decode_time(&self, bytes: &[u8]) -> Option<NaiveTime> {
+        let mut hour = bcd2num(bytes[0] & 0x3f)?;
+        if !self.status.mode_24h() && bytes[0] & 0x40 != 0 {
+            hour += 12;
+        }
+        NaiveTime::from_hms_opt(hour, bcd2num(bytes[1])?, bcd2num(bytes[2])?)
+    }
 }
 
 impl GpioDevice for Rtc {
@@ -641,4 +759,71 @@ mod tests {
         assert_eq!(bytes[1], num2bcd(local.month() as u8));
         assert_eq!(bytes[2], num2bcd(local.day() as u8));
     }
+
+    fn read_date_time(rtc: &mut Rtc) -> [u8; 7] {
+        let mut gpio_state = [GpioDirection::Out; 4];
+        start_serial_transfer(rtc, &gpio_state);
+        transmit_bits(rtc, &gpio_state, &[0, 1, 1, 0, 0, 1, 0, 1]);
+        gpio_state[Port::Sio.index()] = GpioDirection::In;
+        let mut bytes = [0; 7];
+        receive_bytes(rtc, &gpio_state, &mut bytes);
+        bytes
+    }
+
+    fn write_date_time(rtc: &mut Rtc, bytes: &[u8; 7]) {
+        let gpio_state = [GpioDirection::Out; 4];
+        start_serial_transfer(rtc, &gpio_state);
+        transmit_bits(rtc, &gpio_state, &[0, 1, 1, 0, 0, 1, 0, 0]);
+        for byte in bytes {
+            for i in 0..8 {
+                transmit(rtc, &gpio_state, byte.bit(i) as u8);
+            }
+        }
+        assert_eq!(rtc.state, RtcState::Idle);
+    }
+
+    #[test]
+    fn test_emulated_clock() {
+        let mut rtc = Rtc::new();
+        let start = NaiveDate::from_ymd(2099, 12, 31).and_hms(23, 59, 59);
+        rtc.set_clock(RtcClock::emulated(start));
+        // In 12 hour mode, with the PM flag
+        assert_eq!(
+            read_date_time(&mut rtc),
+            [0x99, 0x12, 0x31, 0x04, 0x51, 0x59, 0x59]
+        );
+
+        // Years past 2099 wrap around
+        rtc.set_timestamp(CYCLES_PER_SECOND);
+        assert_eq!(
+            read_date_time(&mut rtc),
+            [0x00, 0x01, 0x01, 0x05, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_write_date_time() {
+        let mut rtc = Rtc::new();
+        let time = NaiveDate::from_ymd(2004, 2, 29).and_hms(12, 0, 0);
+        rtc.set_clock(RtcClock::manual(time));
+
+        write_date_time(&mut rtc, &[0x21, 0x03, 0x14, 0x00, 0x43, 0x09, 0x26]);
+        let expected = NaiveDate::from_ymd(2021, 3, 14).and_hms(15, 9, 26);
+        assert_eq!(rtc.clock(), RtcClock::manual(expected));
+
+        // Invalid dates are ignored
+        write_date_time(&mut rtc, &[0x21, 0x13, 0x14, 0x00, 0x03, 0x09, 0x26]);
+        assert_eq!(rtc.clock(), RtcClock::manual(expected));
+
+        // An emulated clock keeps advancing from the written time
+        rtc.status.set_mode_24h(true);
+        rtc.set_clock(RtcClock::emulated(time));
+        rtc.set_timestamp(10 * CYCLES_PER_SECOND);
+        write_date_time(&mut rtc, &[0x21, 0x03, 0x14, 0x00, 0x15, 0x09, 0x26]);
+        rtc.set_timestamp(20 * CYCLES_PER_SECOND);
+        assert_eq!(
+            read_date_time(&mut rtc),
+            [0x21, 0x03, 0x14, 0x00, 0x15, 0x09, 0x36]
+        );
+    }
 }
@@ -0,0 +1,74 @@
+use bit::BitIndex;
+use serde::{Deserialize, Serialize};
+
+use super::gpio::{GpioDevice, GpioState};
+
+/// The motor is driven by this pin of the GPIO port
+const PORT_MOTOR: usize = 3;
+
+/// Rumble motor of WarioWare: Twisted!
+///
+/// Games drive the motor in short pulses, so the frontend is told it is on when it was on at any point since it last
+/// polled.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Rumble {
+    on: bool,
+    /// The motor was on at some point since the last poll
+    pulsed: bool,
+    /// What the last poll reported
+    reported: bool,
+}
+
+impl Rumble {
+    pub fn new() -> Self {
+        Rumble::default()
+    }
+
+    /// Returns the state of the motor if it changed since the last call
+    pub fn poll(&mut self) -> Option<bool> {
+        let on = self.on || self.pulsed;
+        self.pulsed = false;
+        if on != self.reported {
+            self.reported = on;
+            Some(on)
+        } else {
+            None
+        }
+    }
+}
+
+impl GpioDevice for Rumble {
+    fn write(&mut self, _gpio_state: &GpioState, data: u16) {
+        self.on = data.bit(PORT_MOTOR);
+        self.pulsed |= self.on;
+    }
+
+    fn read(&self, _gpio_state: &GpioState) -> u16 {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::gpio::GpioDirection;
+    use super::*;
+
+    #[test]
+    fn test_poll() {
+        let gpio_state = [GpioDirection::Out; 4];
+        let mut rumble = Rumble::new();
+        assert_eq!(rumble.poll(), None);
+
+        rumble.write(&gpio_state, 0b1000);
+        assert_eq!(rumble.poll(), Some(true));
+        assert_eq!(rumble.poll(), None);
+        rumble.write(&gpio_state, 0b0000);
+        assert_eq!(rumble.poll(), Some(false));
+
+        // A pulse between two polls is not lost
+        rumble.write(&gpio_state, 0b1000);
+        rumble.write(&gpio_state, 0b0000);
+        assert_eq!(rumble.poll(), Some(true));
+        assert_eq!(rumble.poll(), Some(false));
+    }
+}
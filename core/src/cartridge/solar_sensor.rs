@@ -0,0 +1,137 @@
+use bit::BitIndex;
+use serde::{Deserialize, Serialize};
+
+use super::gpio::{GpioDevice, GpioState};
+
+#[derive(Clone, Copy, Debug)]
+enum Port {
+    /// Advances the counter on a rising edge
+    Clock = 0,
+    /// Clears the counter and samples the light level
+    Reset = 1,
+    /// Active low, the RTC sharing the port is selected while it is high
+    Cs = 2,
+    /// Goes high once the counter reaches the sampled light level
+    Flag = 3,
+}
+
+impl Port {
+    #[inline]
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Model of the photodiode and counter in the Boktai cartridges
+///
+/// The game resets the counter, then clocks it until the flag goes high.
+/// The brighter the light, the fewer clocks that takes.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SolarSensor {
+    /// Light level as set by the frontend, from 0 for darkness to 255 for direct sunlight
+    level: u8,
+    /// Counter value at which the flag goes high, sampled from the light level on reset
+    threshold: u8,
+    counter: u16,
+    clock: bool,
+}
+
+impl Default for SolarSensor {
+    fn default() -> SolarSensor {
+        SolarSensor::new()
+    }
+}
+
+impl SolarSensor {
+    pub fn new() -> Self {
+        SolarSensor {
+            level: 0,
+            threshold: 0xff,
+            counter: 0,
+            clock: false,
+        }
+    }
+
+    /// Takes effect the next time the game resets the counter
+    pub fn set_level(&mut self, level: u8) {
+        self.level = level;
+    }
+
+    fn flag(&self) -> bool {
+        self.counter >= self.threshold as u16
+    }
+}
+
+impl GpioDevice for SolarSensor {
+    fn write(&mut self, _gpio_state: &GpioState, data: u16) {
+        if data.bit(Port::Cs.index()) {
+            return;
+        }
+        if data.bit(Port::Reset.index()) {
+            self.counter = 0;
+            self.threshold = 0xff - self.level;
+        }
+        let clock = data.bit(Port::Clock.index());
+        if clock && !self.clock {
+            self.counter = self.counter.saturating_add(1);
+        }
+        self.clock = clock;
+    }
+
+    fn read(&self, _gpio_state: &GpioState) -> u16 {
+        let mut result = 0;
+        result.set_bit(Port::Flag.index(), self.flag());
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::gpio::GpioDirection;
+    use super::*;
+
+    /// Counts the clocks until the flag goes high, like the game does
+    fn measure(sensor: &mut SolarSensor) -> u16 {
+        let gpio_state = [
+            GpioDirection::Out,
+            GpioDirection::Out,
+            GpioDirection::Out,
+            GpioDirection::In,
+        ];
+        sensor.write(&gpio_state, 0b0010);
+        sensor.write(&gpio_state, 0b0000);
+        let mut clocks = 0;
+        while !sensor.read(&gpio_state).bit(Port::Flag.index()) {
+            sensor.write(&gpio_state, 0b0001);
+            sensor.write(&gpio_state, 0b0000);
+            clocks += 1;
+        }
+        clocks
+    }
+
+    #[test]
+    fn test_light_level() {
+        let mut sensor = SolarSensor::new();
+        assert_eq!(measure(&mut sensor), 0xff);
+
+        sensor.set_level(0x40);
+        assert_eq!(measure(&mut sensor), 0xbf);
+
+        sensor.set_level(0xff);
+        assert_eq!(measure(&mut sensor), 0);
+    }
+
+    #[test]
+    fn test_ignores_rtc_transfers() {
+        let mut sensor = SolarSensor::new();
+        sensor.set_level(0xf0);
+        measure(&mut sensor);
+
+        let gpio_state = [GpioDirection::Out; 4];
+        sensor.write(&gpio_state, 0b0110);
+        sensor.write(&gpio_state, 0b0101);
+        sensor.write(&gpio_state, 0b0100);
+        assert!(sensor.read(&gpio_state).bit(Port::Flag.index()));
+        assert_eq!(sensor.counter, 0x0f);
+    }
+}
@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+use arm7tdmi::memory::Addr;
+
+/// ADC reading of a level cartridge, on both axes
+const CENTER: u16 = 0x3a0;
+
+/// Model of the 2 axis accelerometer mapped into the SRAM region by Yoshi's Universal Gravitation and Koro Koro Puzzle
+///
+/// The game starts a conversion by writing 0x55 then 0xaa, then reads the 12 bit result of each axis.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TiltSensor {
+    /// Tilt as set by the frontend, positive to the right and towards the player
+    tilt: (i16, i16),
+    /// The last conversion
+    x: u16,
+    y: u16,
+    /// 0x55 was written and a write of 0xaa will start the conversion
+    armed: bool,
+}
+
+impl Default for TiltSensor {
+    fn default() -> TiltSensor {
+        TiltSensor::new()
+    }
+}
+
+impl TiltSensor {
+    pub fn new() -> Self {
+        TiltSensor {
+            tilt: (0, 0),
+            x: CENTER,
+            y: CENTER,
+            armed: false,
+        }
+    }
+
+    /// Takes effect on the next conversion
+    pub fn set_tilt(&mut self, x: i16, y: i16) {
+        self.tilt = (x, y);
+    }
+
+    fn convert(tilt: i16) -> u16 {
+        // The full range of the input maps to the +-0x100 the sensor reports when held on its side
+        (CENTER as i32 + (tilt as i32 >> 7)) as u16
+    }
+
+    pub fn read(&self, addr: Addr) -> u8 {
+        match addr & 0xffff {
+            0x8200 => self.x as u8,
+            // Bit 7 is set when the conversion is done, which it always is
+            0x8300 => (self.x >> 8) as u8 | 0x80,
+            0x8400 => self.y as u8,
+            0x8500 => (self.y >> 8) as u8,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: Addr, value: u8) {
+        match (addr & 0xffff, value) {
+            (0x8000, 0x55) => self.armed = true,
+            (0x8100, 0xaa) if self.armed => {
+                self.x = Self::convert(self.tilt.0);
+                self.y = Self::convert(self.tilt.1);
+                self.armed = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(sensor: &mut TiltSensor) -> (u16, u16) {
+        sensor.write(0x0e00_8000, 0x55);
+        sensor.write(0x0e00_8100, 0xaa);
+        assert!(sensor.read(0x0e00_8300) & 0x80 != 0);
+        let x = sensor.read(0x0e00_8200) as u16 | ((sensor.read(0x0e00_8300) as u16 & 0xf) << 8);
+        let y = sensor.read(0x0e00_8400) as u16 | ((sensor.read(0x0e00_8500) as u16 & 0xf) << 8);
+        (x, y)
+    }
+
+    #[test]
+    fn test_conversion() {
+        let mut sensor = TiltSensor::new();
+        assert_eq!(sample(&mut sensor), (0x3a0, 0x3a0));
+
+        sensor.set_tilt(i16::MAX, i16::MIN);
+        assert_eq!(sample(&mut sensor), (0x49f, 0x2a0));
+
+        // Without the 0x55 write the conversion doesn't start
+        sensor.set_tilt(0, 0);
+        sensor.write(0x0e00_8100, 0xaa);
+        assert_eq!(sensor.read(0x0e00_8200), 0x9f);
+    }
+}
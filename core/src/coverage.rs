@@ -0,0 +1,264 @@
+/// Code coverage of the emulated program.
+///
+/// Records which instructions in ROM, IWRAM and EWRAM were executed and in which cpu state. Instructions that failed
+/// their condition check count as executed, the same way a line with a not taken `if` is covered.
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use bit_set::BitSet;
+
+use arm7tdmi::memory::Addr;
+use arm7tdmi::CpuState;
+use rustboyadvance_utils::elf::LineRange;
+
+use crate::sysbus::consts::*;
+
+/// Executed instructions of a memory region, mirrors are folded onto the first copy
+#[derive(Debug, Clone)]
+struct CoveredRegion {
+    base: Addr,
+    mask: u32,
+    /// Indexed by halfword offset into the region
+    arm: BitSet,
+    thumb: BitSet,
+}
+
+impl CoveredRegion {
+    fn new(base: Addr, size: usize) -> CoveredRegion {
+        CoveredRegion {
+            base,
+            mask: size as u32 - 1,
+            arm: BitSet::new(),
+            thumb: BitSet::new(),
+        }
+    }
+
+    fn index(&self, addr: Addr) -> usize {
+        ((addr & self.mask) >> 1) as usize
+    }
+
+    fn addr(&self, index: usize) -> Addr {
+        self.base + ((index as u32) << 1)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    rom: CoveredRegion,
+    iwram: CoveredRegion,
+    ewram: CoveredRegion,
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage {
+            rom: CoveredRegion::new(CART_BASE, 0x200_0000),
+            iwram: CoveredRegion::new(IWRAM_ADDR, INTERNAL_RAM_SIZE),
+            ewram: CoveredRegion::new(EWRAM_ADDR, WORK_RAM_SIZE),
+        }
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    fn region(&self, addr: Addr) -> Option<&CoveredRegion> {
+        match addr >> 24 {
+            0x02 => Some(&self.ewram),
+            0x03 => Some(&self.iwram),
+            0x08..=0x0d => Some(&self.rom),
+            _ => None,
+        }
+    }
+
+    fn region_mut(&mut self, addr: Addr) -> Option<&mut CoveredRegion> {
+        match addr >> 24 {
+            0x02 => Some(&mut self.ewram),
+            0x03 => Some(&mut self.iwram),
+            0x08..=0x0d => Some(&mut self.rom),
+            _ => None,
+        }
+    }
+
+    /// Marks the instruction at `addr` as executed, instructions outside of ROM, IWRAM and EWRAM are ignored
+    pub(crate) fn record(&mut self, addr: Addr, state: CpuState) {
+        if let Some(region) = self.region_mut(addr) {
+            let index = region.index(addr);
+            match state {
+                CpuState::ARM => region.arm.insert(index),
+                CpuState::THUMB => region.thumb.insert(index),
+            };
+        }
+    }
+
+    /// Returns the states the instruction at `addr` was executed in
+    pub fn executed_states(&self, addr: Addr) -> Vec<CpuState> {
+        let mut states = vec![];
+        if let Some(region) = self.region(addr) {
+            let index = region.index(addr);
+            if region.arm.contains(index) {
+                states.push(CpuState::ARM);
+            }
+            if region.thumb.contains(index) {
+                states.push(CpuState::THUMB);
+            }
+        }
+        states
+    }
+
+    pub fn is_executed(&self, addr: Addr) -> bool {
+        !self.executed_states(addr).is_empty()
+    }
+
+    /// All executed instructions sorted by address.
+    /// Code may run in both states at the same address, e.g. when it's overwritten in IWRAM.
+    pub fn executed(&self) -> Vec<(Addr, CpuState)> {
+        let mut executed = vec![];
+        for region in [&self.ewram, &self.iwram, &self.rom] {
+            let mut region_executed: Vec<(Addr, CpuState)> = region
+                .arm
+                .iter()
+                .map(|index| (region.addr(index), CpuState::ARM))
+                .chain(
+                    region
+                        .thumb
+                        .iter()
+                        .map(|index| (region.addr(index), CpuState::THUMB)),
+                )
+                .collect();
+            region_executed.sort_by_key(|(addr, state)| (*addr, *state as u8));
+            executed.append(&mut region_executed);
+        }
+        executed
+    }
+
+    /// Writes the executed instructions, one `<address> <arm|thumb>` per line.
+    /// The first column can be piped into `addr2line -e game.elf` to find the source lines.
+    pub fn write_address_list<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for (addr, state) in self.executed() {
+            let state = match state {
+                CpuState::ARM => "arm",
+                CpuState::THUMB => "thumb",
+            };
+            writeln!(w, "0x{:08x} {}", addr, state)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the line coverage of the sources in `lines` as an lcov tracefile, for genhtml and friends.
+    /// A line is covered if any of its instructions was executed.
+    pub fn write_lcov<W: Write>(
+        &self,
+        w: &mut W,
+        test_name: &str,
+        lines: &[LineRange],
+    ) -> io::Result<()> {
+        let mut files: BTreeMap<&PathBuf, BTreeMap<u32, bool>> = BTreeMap::new();
+        for range in lines {
+            let hit = (range.start..range.end)
+                .step_by(2)
+                .any(|addr| self.is_executed(addr));
+            *files
+                .entry(&range.file)
+                .or_default()
+                .entry(range.line)
+                .or_default() |= hit;
+        }
+        for (file, lines) in files {
+            writeln!(w, "TN:{}", test_name)?;
+            writeln!(w, "SF:{}", file.display())?;
+            for (line, hit) in &lines {
+                writeln!(w, "DA:{},{}", line, *hit as u32)?;
+            }
+            writeln!(w, "LF:{}", lines.len())?;
+            writeln!(w, "LH:{}", lines.values().filter(|hit| **hit).count())?;
+            writeln!(w, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_folds_mirrors() {
+        let mut coverage = Coverage::new();
+        coverage.record(0x0800_0100, CpuState::ARM);
+        coverage.record(0x0a00_0102, CpuState::THUMB);
+        coverage.record(0x0300_8010, CpuState::THUMB);
+        coverage.record(0x0204_0000, CpuState::ARM);
+        coverage.record(0x0000_0128, CpuState::ARM);
+        coverage.record(0x0300_0010, CpuState::ARM);
+
+        assert!(coverage.is_executed(0x0800_0102));
+        assert!(!coverage.is_executed(0x0000_0128));
+        assert_eq!(
+            coverage.executed_states(0x0300_0010),
+            [CpuState::ARM, CpuState::THUMB]
+        );
+        assert_eq!(
+            coverage.executed(),
+            [
+                (0x0200_0000, CpuState::ARM),
+                (0x0300_0010, CpuState::ARM),
+                (0x0300_0010, CpuState::THUMB),
+                (0x0800_0100, CpuState::ARM),
+                (0x0800_0102, CpuState::THUMB),
+            ]
+        );
+
+        let mut list = vec![];
+        coverage.write_address_list(&mut list).unwrap();
+        assert!(String::from_utf8(list)
+            .unwrap()
+            .starts_with("0x02000000 arm\n0x03000010 arm\n0x03000010 thumb\n"));
+    }
+
+    #[test]
+    fn test_lcov() {
+        let mut coverage = Coverage::new();
+        coverage.record(0x0800_0000, CpuState::THUMB);
+        coverage.record(0x0800_0010, CpuState::THUMB);
+
+        let line = |start, end, file: &str, line| LineRange {
+            start,
+            end,
+            file: PathBuf::from(file),
+            line,
+        };
+        let lines = [
+            line(0x0800_0000, 0x0800_0004, "/src/main.c", 10),
+            line(0x0800_0004, 0x0800_0008, "/src/main.c", 11),
+            // the same line may have its code split up by the optimizer
+            line(0x0800_0008, 0x0800_000c, "/src/main.c", 12),
+            line(0x0800_000c, 0x0800_0012, "/src/main.c", 13),
+            line(0x0800_0012, 0x0800_0014, "/src/main.c", 12),
+            line(0x0300_0000, 0x0300_0008, "/src/irq.c", 3),
+        ];
+        let mut lcov = vec![];
+        coverage.write_lcov(&mut lcov, "scene1", &lines).unwrap();
+        assert_eq!(
+            String::from_utf8(lcov).unwrap(),
+            "TN:scene1\n\
+             SF:/src/irq.c\n\
+             DA:3,0\n\
+             LF:1\n\
+             LH:0\n\
+             end_of_record\n\
+             TN:scene1\n\
+             SF:/src/main.c\n\
+             DA:10,1\n\
+             DA:11,0\n\
+             DA:12,0\n\
+             DA:13,1\n\
+             LF:4\n\
+             LH:2\n\
+             end_of_record\n"
+        );
+    }
+}
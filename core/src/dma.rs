@@ -1,5 +1,4 @@
 use super::arm7tdmi::memory::{MemoryAccess, MemoryInterface};
-use super::cartridge::BackupMedia;
 use super::interrupt::{self, Interrupt, InterruptConnect, SharedInterruptFlags};
 use super::iodev::consts::{REG_FIFO_A, REG_FIFO_B};
 use super::sched::{EventType, Scheduler};
@@ -129,13 +128,11 @@ impl DmaChannel {
         };
 
         if self.id == 3 && word_size == 2 {
-            if let BackupMedia::Eeprom(eeprom) = &mut sb.cartridge.backup {
-                eeprom.on_dma3_transfer(
-                    self.internal.src_addr,
-                    self.internal.dst_addr,
-                    count as usize,
-                )
-            }
+            sb.cartridge.on_dma3_transfer(
+                self.internal.src_addr,
+                self.internal.dst_addr,
+                count as usize,
+            );
         }
 
         let fifo_mode = self.fifo_mode;
@@ -6,29 +6,39 @@ use serde::{Deserialize, Serialize};
 
 use crate::gdb_support::{gdb_thread::start_gdb_server_thread, DebuggerRequestHandler};
 
-use super::cartridge::Cartridge;
+use super::cartridge::{Cartridge, RtcClock};
+use super::coverage::Coverage;
 use super::dma::DmaController;
 use super::gpu::*;
+use super::idle_loop::IdleLoopDetector;
 use super::interrupt::*;
 use super::iodev::*;
+use super::multiboot::{BootMode, MultibootImage};
+use super::profiler::Profiler;
 use super::sched::{EventType, Scheduler, SchedulerConnect, SharedScheduler};
 use super::sound::SoundController;
-use super::sysbus::SysBus;
+use super::sysbus::{CpuBus, SysBus};
 use super::timer::Timers;
 
 use super::sound::interface::DynAudioInterface;
 
+use arm7tdmi::callstack::CallFrameKind;
+use arm7tdmi::gdb::GdbServerAddr;
+use arm7tdmi::memory::Addr;
 use arm7tdmi::Arm7tdmiCore;
-use rustboyadvance_utils::Shared;
+use rustboyadvance_utils::{Shared, WeakPointer};
 
 pub struct GameBoyAdvance {
-    pub cpu: Box<Arm7tdmiCore<SysBus>>,
+    pub cpu: Box<Arm7tdmiCore<CpuBus>>,
     pub(crate) sysbus: Shared<SysBus>,
     pub(crate) io_devs: Shared<IoDevices>,
     pub(crate) scheduler: SharedScheduler,
     interrupt_flags: SharedInterruptFlags,
     audio_interface: DynAudioInterface,
     pub(crate) debugger: Option<DebuggerRequestHandler>,
+    profiler: Option<Box<Profiler>>,
+    coverage: Option<Box<Coverage>>,
+    idle_loops: Option<Box<IdleLoopDetector>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -42,6 +52,30 @@ struct SaveState {
     cpu_state: arm7tdmi::SavedCpuState,
 }
 
+/// A frame of the emulated program's call stack, see [`GameBoyAdvance::backtrace`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BacktraceFrame {
+    /// The current pc for the innermost frame, the call site for the others
+    pub pc: Addr,
+    /// The function containing `pc` and the offset into it, when the cartridge has symbols
+    pub function: Option<(String, u32)>,
+    /// How the function was entered, unknown for the outermost frame
+    pub entered_by: Option<CallFrameKind>,
+}
+
+impl std::fmt::Display for BacktraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "0x{:08x}", self.pc)?;
+        if let Some((name, offset)) = &self.function {
+            write!(f, " in {}+0x{:x}", name, offset)?;
+        }
+        if let Some(CallFrameKind::Exception(e)) = &self.entered_by {
+            write!(f, " <{:?} handler>", e)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, PartialEq)]
 enum BusMaster {
     Dma,
@@ -99,7 +133,7 @@ impl GameBoyAdvance {
             gamepak,
         ));
 
-        let cpu = Box::new(Arm7tdmiCore::new(sysbus.clone()));
+        let cpu = Box::new(Arm7tdmiCore::new(CpuBus::new(sysbus.clone())));
 
         let mut gba = GameBoyAdvance {
             cpu,
@@ -109,9 +143,12 @@ impl GameBoyAdvance {
             scheduler,
             interrupt_flags,
             debugger: None,
+            profiler: None,
+            coverage: None,
+            idle_loops: None,
         };
 
-        gba.sysbus.init(gba.cpu.weak_ptr());
+        gba.sysbus.init(WeakPointer::new(&mut *gba.cpu));
 
         gba
     }
@@ -140,11 +177,11 @@ impl GameBoyAdvance {
             decoded.iwram,
         ));
         let mut arm7tdmi = Box::new(Arm7tdmiCore::from_saved_state(
-            sysbus.clone(),
+            CpuBus::new(sysbus.clone()),
             decoded.cpu_state,
         ));
 
-        sysbus.init(arm7tdmi.weak_ptr());
+        sysbus.init(WeakPointer::new(&mut *arm7tdmi));
 
         Ok(GameBoyAdvance {
             cpu: arm7tdmi,
@@ -154,6 +191,9 @@ impl GameBoyAdvance {
             audio_interface,
             scheduler,
             debugger: None,
+            profiler: None,
+            coverage: None,
+            idle_loops: None,
         })
     }
 
@@ -172,6 +212,8 @@ impl GameBoyAdvance {
     }
 
     pub fn restore_state(&mut self, bytes: &[u8]) -> bincode::Result<()> {
+        // The save file is read back along with the state, it has to be up to date
+        self.flush_backup();
         let decoded: Box<SaveState> = bincode::deserialize_from(bytes)?;
 
         self.cpu.restore_state(decoded.cpu_state);
@@ -179,7 +221,6 @@ impl GameBoyAdvance {
         self.interrupt_flags = Rc::new(Cell::new(IrqBitmask(decoded.interrupt_flags)));
         self.io_devs = Shared::new(decoded.io_devs);
         // Restore memory state
-        self.cpu.set_memory_interface(self.sysbus.clone());
         self.sysbus.set_iwram(decoded.iwram);
         self.sysbus.set_ewram(decoded.ewram);
         // Redistribute shared pointers
@@ -187,11 +228,20 @@ impl GameBoyAdvance {
         self.sysbus.connect_scheduler(self.scheduler.clone());
         self.sysbus.set_io_devices(self.io_devs.clone());
         self.sysbus.cartridge.update_from(decoded.cartridge);
-        self.sysbus.init(self.cpu.weak_ptr());
+        self.sysbus.init(WeakPointer::new(&mut *self.cpu));
+        if let Some(idle_loops) = &mut self.idle_loops {
+            idle_loops.forget();
+        }
 
         Ok(())
     }
 
+    /// Writes unsaved changes of the backup memory to the save file, which otherwise happens once the game is done
+    /// saving and when the emulator is dropped
+    pub fn flush_backup(&mut self) {
+        self.sysbus.cartridge.flush_backup();
+    }
+
     pub fn get_game_title(&self) -> String {
         self.sysbus.cartridge.header.game_title.clone()
     }
@@ -210,6 +260,41 @@ impl GameBoyAdvance {
         &mut self.sysbus.io.keyinput
     }
 
+    /// Sets the light level seen by the solar sensor of the Boktai cartridges, from 0 for darkness to 255
+    pub fn set_solar_sensor_level(&mut self, level: u8) {
+        self.sysbus.cartridge.set_solar_sensor_level(level);
+    }
+
+    /// Sets the rotation rate seen by the gyro sensor of WarioWare: Twisted!, the full range of `rate` being the
+    /// fastest rotation the sensor measures
+    pub fn set_gyro_rate(&mut self, rate: i16) {
+        self.sysbus.cartridge.set_gyro_rate(rate);
+    }
+
+    /// Returns whether the rumble motor should be on, if that changed since the last call
+    pub fn poll_rumble(&mut self) -> Option<bool> {
+        self.sysbus.cartridge.poll_rumble()
+    }
+
+    /// The clock of the cartridge's RTC, if it has one
+    pub fn rtc_clock(&self) -> Option<RtcClock> {
+        self.sysbus.cartridge.rtc_clock()
+    }
+
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        self.sysbus.cartridge.set_rtc_clock(clock);
+    }
+
+    pub fn has_tilt_sensor(&self) -> bool {
+        self.sysbus.cartridge.has_tilt_sensor()
+    }
+
+    /// Sets the tilt seen by the tilt sensor of Yoshi's Universal Gravitation and Koro Koro Puzzle, positive to the
+    /// right and towards the player, the full range of each axis being the cartridge held on its side
+    pub fn set_tilt(&mut self, x: i16, y: i16) {
+        self.sysbus.cartridge.set_tilt(x, y);
+    }
+
     /// Advance the emulation for one frame worth of time
     pub fn frame(&mut self) {
         static mut OVERSHOOT: usize = 0;
@@ -217,24 +302,29 @@ impl GameBoyAdvance {
             OVERSHOOT = CYCLES_FULL_REFRESH
                 .saturating_sub(self.run::<false>(CYCLES_FULL_REFRESH - OVERSHOOT));
         }
+        self.sysbus.cartridge.flush_backup_if_idle();
     }
 
     /// like frame() but stop if a breakpoint is reached
-    fn frame_interruptible(&mut self) {
+    pub(crate) fn frame_interruptible(&mut self) {
         static mut OVERSHOOT: usize = 0;
         unsafe {
             OVERSHOOT = CYCLES_FULL_REFRESH
                 .saturating_sub(self.run::<true>(CYCLES_FULL_REFRESH - OVERSHOOT));
         }
+        self.sysbus.cartridge.flush_backup_if_idle();
     }
 
-    pub fn start_gdbserver(&mut self, port: u16) {
+    /// Starts listening for gdb connections on `addr` without blocking the emulation.
+    /// gdb may attach and detach any number of times while the server is running.
+    pub fn start_gdbserver(&mut self, addr: GdbServerAddr) {
         if self.is_debugger_attached() {
             warn!("debugger already attached!");
         } else {
-            match start_gdb_server_thread(self, port) {
+            match start_gdb_server_thread(self, addr) {
                 Ok(debugger) => {
-                    info!("attached to the debugger, have fun!");
+                    info!("gdbserver started, have fun!");
+                    self.cpu.set_call_stack_tracking(true);
                     self.debugger = Some(debugger)
                 }
                 Err(e) => {
@@ -249,11 +339,24 @@ impl GameBoyAdvance {
         self.debugger.is_some()
     }
 
+    /// Returns true if a gdb client is currently connected to the gdbserver
+    pub fn is_gdb_client_connected(&self) -> bool {
+        self.debugger
+            .as_ref()
+            .map(|debugger| debugger.connected)
+            .unwrap_or(false)
+    }
+
     /// Recv & handle messages from the debugger, and return if we are stopped or not
     pub fn debugger_run(&mut self) {
         let debugger = self.debugger.take().expect("debugger should be None here");
         self.debugger = debugger.handle_incoming_requests(self);
         self.frame_interruptible();
+        if let Some(insn) = self.cpu.take_undefined_break() {
+            if let Some(debugger) = &mut self.debugger {
+                debugger.notify_undefined(insn);
+            }
+        }
     }
 
     #[inline]
@@ -272,7 +375,39 @@ impl GameBoyAdvance {
         if self.io_devs.intc.irq_pending() {
             self.cpu_interrupt();
         }
-        self.cpu.step();
+        let pc = self.cpu.get_next_pc();
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(pc, self.cpu.cpsr.state());
+        }
+        match &mut self.profiler {
+            None => {
+                self.cpu.step();
+                self.skip_idle_loop(pc);
+            }
+            Some(profiler) => {
+                profiler.begin_instruction(pc, self.cpu.call_stack());
+                let start_time = self.scheduler.timestamp();
+                self.cpu.step();
+                // Skipped cycles are accounted to the branch that closes the idle loop
+                self.skip_idle_loop(pc);
+                let cycles = self.scheduler.timestamp() - start_time;
+                // the profiler is still there, cpu.step() can't reach it
+                self.profiler.as_mut().unwrap().end_instruction(cycles);
+            }
+        }
+    }
+
+    /// Fast-forwards to the next event if the instruction at `pc` closed an idle loop, the same way as in Halt mode
+    #[inline]
+    fn skip_idle_loop(&mut self, pc: Addr) {
+        if let Some(idle_loops) = &mut self.idle_loops {
+            // A pending interrupt is taken by the next step, and would break out of the loop
+            if idle_loops.on_step(pc, &self.cpu) && !self.io_devs.intc.irq_pending() {
+                let start_time = self.scheduler.timestamp();
+                self.scheduler.fast_forward_to_next();
+                idle_loops.record_skip(self.scheduler.timestamp() - start_time);
+            }
+        }
     }
 
     #[inline]
@@ -291,7 +426,13 @@ impl GameBoyAdvance {
         // 2. DMA inactive and halt state is RUN - CPU can run
         // 3. DMA inactive and halt state is HALT - CPU is blocked
         match self.get_bus_master() {
-            Some(BusMaster::Dma) => self.dma_step(),
+            Some(BusMaster::Dma) => {
+                let start_time = self.scheduler.timestamp();
+                self.dma_step();
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.record_dma(self.scheduler.timestamp() - start_time);
+                }
+            }
             Some(BusMaster::Cpu) => self.cpu_step(),
             None => {
                 // Halt mode - system is in a low-power mode, only (IE and IF) can release CPU from this state.
@@ -300,7 +441,11 @@ impl GameBoyAdvance {
                 } else {
                     // Fast-forward to next pending HW event so we don't waste time idle-looping when we know the only way
                     // To get out of Halt mode is through an interrupt.
+                    let start_time = self.scheduler.timestamp();
                     self.scheduler.fast_forward_to_next();
+                    if let Some(profiler) = &mut self.profiler {
+                        profiler.record_halt(self.scheduler.timestamp() - start_time);
+                    }
                 }
             }
         }
@@ -318,11 +463,16 @@ impl GameBoyAdvance {
             .schedule_at(EventType::RunLimitReached, end_time);
 
         'running: loop {
+            if let Some(idle_loops) = &mut self.idle_loops {
+                // Handling events may have changed whatever the loop is polling
+                idle_loops.forget();
+            }
             // The tricky part is to avoid unnecessary calls for Scheduler::handle_events,
             // performance-wise it would be best to run as many cycles as fast as possible while we know there are no pending events.
             // Safety: Since we pushed a RunLimitReached event, we know this check has a hard limit
+            // Events that are due are handled first, fast forwarding lands exactly on the timestamp of the next one
             while self.scheduler.timestamp()
-                <= unsafe { self.scheduler.timestamp_of_next_event_unchecked() }
+                < unsafe { self.scheduler.timestamp_of_next_event_unchecked() }
             {
                 self.single_step();
                 if CHECK_BREAKPOINTS {
@@ -335,6 +485,12 @@ impl GameBoyAdvance {
                         }
                         break 'running;
                     }
+                    if self.cpu.check_undefined_break().is_some() {
+                        // Left for the caller to take, the debugger may be busy running a monitor command
+                        self.scheduler.cancel_pending(EventType::RunLimitReached);
+                        let _ = self.handle_events();
+                        break 'running;
+                    }
                 }
             }
 
@@ -381,6 +537,17 @@ impl GameBoyAdvance {
     }
 
     pub fn skip_bios(&mut self) {
+        self.boot_at(0x0800_0000);
+    }
+
+    /// Boots the image like the BIOS does after receiving it over the link cable, the cartridge is left as it is
+    pub fn boot_multiboot(&mut self, image: &MultibootImage, boot_mode: BootMode) {
+        image.load(&mut self.sysbus.ewram, boot_mode);
+        self.boot_at(0x0200_0000);
+    }
+
+    /// Starts execution at `entry` in the state the BIOS leaves the system in
+    fn boot_at(&mut self, entry: Addr) {
         self.cpu.banks.gpr_banked_r13[0] = 0x0300_7f00; // USR/SYS
         self.cpu.banks.gpr_banked_r13[1] = 0x0300_7f00; // FIQ
         self.cpu.banks.gpr_banked_r13[2] = 0x0300_7fa0; // IRQ
@@ -388,7 +555,7 @@ impl GameBoyAdvance {
         self.cpu.banks.gpr_banked_r13[4] = 0x0300_7f00; // ABT
         self.cpu.banks.gpr_banked_r13[5] = 0x0300_7f00; // UND
         self.cpu.gpr[13] = 0x0300_7f00;
-        self.cpu.pc = 0x0800_0000;
+        self.cpu.pc = entry;
         self.cpu.cpsr.set(0x5f);
         self.sysbus.io.gpu.skip_bios();
     }
@@ -417,6 +584,100 @@ impl GameBoyAdvance {
         None
     }
 
+    /// Walks the shadow call stack of the cpu, innermost frame first.
+    /// Returns None unless call stack tracking is enabled, which it is once the gdbserver is started.
+    pub fn backtrace(&self) -> Option<Vec<BacktraceFrame>> {
+        let frames = self.cpu.call_stack()?.frames();
+        let cartridge = &self.sysbus.cartridge;
+        let make_frame = |pc: Addr, entered_by: Option<CallFrameKind>| BacktraceFrame {
+            pc,
+            function: cartridge
+                .symbolize(pc)
+                .map(|(name, offset)| (name.to_owned(), offset)),
+            entered_by,
+        };
+        let mut backtrace = vec![make_frame(
+            self.cpu.get_next_pc(),
+            frames.back().map(|f| f.kind),
+        )];
+        for (i, frame) in frames.iter().enumerate().rev() {
+            let entered_by = i.checked_sub(1).map(|caller| frames[caller].kind);
+            backtrace.push(make_frame(frame.call_site, entered_by));
+        }
+        Some(backtrace)
+    }
+
+    /// Starts accounting the cycles spent by every executed instruction, discarding the previous profile.
+    /// Enables call stack tracking so cycles can be attributed to call stacks as well.
+    pub fn start_profiling(&mut self) {
+        self.cpu.set_call_stack_tracking(true);
+        self.profiler = Some(Box::new(Profiler::new()));
+    }
+
+    /// Stops profiling and returns the profile collected so far
+    pub fn stop_profiling(&mut self) -> Option<Box<Profiler>> {
+        self.profiler.take()
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_deref()
+    }
+
+    /// Writes the report of `profiler` with function names resolved from the cartridge symbols
+    pub fn write_profile_report<W: std::io::Write>(
+        &self,
+        profiler: &Profiler,
+        w: &mut W,
+        top: usize,
+    ) -> std::io::Result<()> {
+        profiler.write_report(w, |addr| self.sysbus.cartridge.symbolize(addr), top)
+    }
+
+    /// Writes the call stacks of `profiler` in the folded format, with function names resolved from the cartridge symbols
+    pub fn write_profile_folded<W: std::io::Write>(
+        &self,
+        profiler: &Profiler,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        profiler.write_folded(w, |addr| self.sysbus.cartridge.symbolize(addr))
+    }
+
+    /// Enables fast forwarding through busy-wait loops, off by default.
+    /// A loop is only skipped once one of its iterations proved to have no effect, but the loop then exits as soon as
+    /// the event it waits for is handled instead of on its next poll, which shifts the timing by up to one iteration.
+    /// The idle loops the overrides list for the game are always skipped while enabled.
+    pub fn set_idle_loop_skipping(&mut self, enabled: bool) {
+        match (enabled, &self.idle_loops) {
+            (true, None) => {
+                let known = self.sysbus.cartridge.idle_loops().to_vec();
+                self.idle_loops = Some(Box::new(IdleLoopDetector::new(known)))
+            }
+            (false, _) => self.idle_loops = None,
+            _ => {}
+        }
+    }
+
+    /// Cycles fast forwarded through idle loops since skipping was enabled
+    pub fn idle_loop_skipped_cycles(&self) -> Option<u64> {
+        self.idle_loops
+            .as_ref()
+            .map(|idle_loops| idle_loops.skipped_cycles())
+    }
+
+    /// Starts recording which instructions are executed, discarding the previous coverage
+    pub fn start_coverage(&mut self) {
+        self.coverage = Some(Box::new(Coverage::new()));
+    }
+
+    /// Stops recording coverage and returns what was recorded so far
+    pub fn stop_coverage(&mut self) -> Option<Box<Coverage>> {
+        self.coverage.take()
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_deref()
+    }
+
     pub fn get_frame_buffer(&self) -> &[u32] {
         self.sysbus.io.gpu.get_frame_buffer()
     }
@@ -472,4 +733,172 @@ mod tests {
         assert_eq!(insn, 0xe7fe); // loop
         assert_eq!(0, gba.cpu.gpr[7]);
     }
+
+    /// A rom that branches over the header to `program`, which is placed at 0x080000c0
+    fn make_test_rom(program: &[u32]) -> Vec<u8> {
+        let mut rom = vec![0; 0xc0];
+        rom[..4].copy_from_slice(&0xea00002eu32.to_le_bytes()); // b 0x080000c0
+        rom.extend(program.iter().flat_map(|w| w.to_le_bytes()));
+        rom
+    }
+
+    #[test]
+    fn test_rtc_clock_in_savestate() {
+        let start = chrono::NaiveDate::from_ymd(2005, 1, 1).and_hms(0, 0, 0);
+        let cartridge = GamepakBuilder::new()
+            .buffer(&make_test_rom(&[0xeafffffe])) // b .
+            .without_backup_to_file()
+            .with_rtc()
+            .rtc_clock(RtcClock::emulated(start))
+            .build()
+            .unwrap();
+        let bios = vec![0; 0x4000].into_boxed_slice();
+        let mut gba = GameBoyAdvance::new(bios, cartridge, NullAudio::new());
+        assert_eq!(gba.rtc_clock(), Some(RtcClock::emulated(start)));
+
+        let state = gba.save_state().unwrap();
+        gba.set_rtc_clock(RtcClock::manual(start));
+        gba.restore_state(&state).unwrap();
+        assert_eq!(gba.rtc_clock(), Some(RtcClock::emulated(start)));
+    }
+
+    #[test]
+    fn test_halt_without_interrupts() {
+        let rom = make_test_rom(&[
+            0xe3a00301, // mov r0, #0x04000000
+            0xe3a01000, // mov r1, #0
+            0xe5c01301, // strb r1, [r0, #0x301]
+            0xeafffffe, // b .
+        ]);
+        let mut gba = make_mock_gba(&rom);
+        gba.frame();
+        gba.frame();
+        assert_eq!(gba.io_devs.haltcnt, HaltState::Halt);
+        assert_eq!(gba.scheduler.timestamp(), 2 * CYCLES_FULL_REFRESH);
+    }
+
+    #[test]
+    fn test_idle_loop_skipping() {
+        let rom = make_test_rom(&[
+            0xe3a00301, // c0: mov r0, #0x04000000
+            0xe3a02403, // c4: mov r2, #0x03000000
+            0xe3a03000, // c8: mov r3, #0
+            0xe1d010b6, // cc: ldrh r1, [r0, #6]
+            0xe35100a0, // d0: cmp r1, #160
+            0x1afffffc, // d4: bne 0xcc
+            0xe2833001, // d8: add r3, r3, #1
+            0xe5823000, // dc: str r3, [r2]
+            0xe1d010b6, // e0: ldrh r1, [r0, #6]
+            0xe35100a0, // e4: cmp r1, #160
+            0x0afffffc, // e8: beq 0xe0
+            0xeafffff6, // ec: b 0xcc
+        ]);
+        let mut vblanks = vec![];
+        for skip in [false, true] {
+            let mut gba = make_mock_gba(&rom);
+            gba.set_idle_loop_skipping(skip);
+            for _ in 0..10 {
+                gba.frame();
+            }
+            vblanks.push(gba.sysbus.read_32(0x0300_0000));
+            if skip {
+                let skipped = gba.idle_loop_skipped_cycles().unwrap();
+                assert!(skipped > 5 * CYCLES_FULL_REFRESH as u64, "{}", skipped);
+            } else {
+                assert_eq!(gba.idle_loop_skipped_cycles(), None);
+            }
+        }
+        assert_eq!(vblanks, [10, 10]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_gdbserver_stops_when_dropped() {
+        let dir = std::env::temp_dir().join(format!("rba-gdb-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("gdb.sock");
+
+        for connect_client in [false, true] {
+            let mut gba = make_mock_gba(&make_test_rom(&[0xeafffffe])); // b .
+            gba.start_gdbserver(GdbServerAddr::Unix(path.clone()));
+            assert!(gba.is_debugger_attached());
+            let client =
+                connect_client.then(|| std::os::unix::net::UnixStream::connect(&path).unwrap());
+            // The server thread is joined, which removes the socket
+            drop(gba);
+            assert!(!path.exists(), "client connected: {}", connect_client);
+            drop(client);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_known_idle_loop() {
+        // Waits for vblank while advancing a random seed in r3, which is never idle on its own
+        let rom = make_test_rom(&[
+            0xe3a00301, // c0: mov r0, #0x04000000
+            0xe1d010b6, // c4: ldrh r1, [r0, #6]
+            0xe2833001, // c8: add r3, r3, #1
+            0xe35100a0, // cc: cmp r1, #160
+            0x1afffffb, // d0: bne 0xc4
+            0xe2844001, // d4: add r4, r4, #1
+            0xeafffffd, // d8: b 0xd4
+        ]);
+        let mut gba = make_mock_gba(&rom);
+        gba.set_idle_loop_skipping(true);
+        gba.frame();
+        assert_eq!(gba.idle_loop_skipped_cycles(), Some(0));
+
+        let mut gba = make_mock_gba(&rom);
+        gba.sysbus.cartridge.idle_loops = vec![0x0800_00d0];
+        gba.set_idle_loop_skipping(true);
+        gba.frame();
+        assert!(gba.idle_loop_skipped_cycles().unwrap() > 0);
+        // Reached vblank all the same
+        assert_ne!(gba.cpu.gpr[4], 0);
+    }
+
+    #[test]
+    fn test_timer_polling_is_not_idle() {
+        let rom = make_test_rom(&[
+            0xe3a00301, // c0: mov r0, #0x04000000
+            0xe2800c01, // c4: add r0, r0, #0x100
+            0xe3a01083, // c8: mov r1, #0x83
+            0xe1c010b2, // cc: strh r1, [r0, #2]
+            0xe1d020b0, // d0: ldrh r2, [r0]
+            0xe3520a01, // d4: cmp r2, #0x1000
+            0x1afffffc, // d8: bne 0xd0
+            0xeafffffe, // dc: b .
+        ]);
+        let mut gba = make_mock_gba(&rom);
+        gba.set_idle_loop_skipping(true);
+        gba.frame();
+        assert_eq!(gba.idle_loop_skipped_cycles(), Some(0));
+        assert_ne!(gba.sysbus.read_16(0x0400_0100), 0);
+    }
+
+    #[test]
+    fn test_boot_multiboot_without_cartridge() {
+        let mut image = vec![0; 0xe4];
+        image[..4].copy_from_slice(&0xea000037u32.to_le_bytes()); // b 0x020000e4
+        for insn in &[
+            0xe59f0000u32, // ldr r0, [pc]
+            0xeafffffe,    // b .
+            0x0200_00c4,   // the boot mode byte
+        ] {
+            image.extend_from_slice(&insn.to_le_bytes());
+        }
+        let image = MultibootImage::from_bytes(image).unwrap();
+
+        let bios = vec![0; 0x4000].into_boxed_slice();
+        let mut gba = GameBoyAdvance::new(bios, Cartridge::empty(), NullAudio::new());
+        gba.boot_multiboot(&image, BootMode::Multiplay { slave_id: 2 });
+        gba.frame();
+
+        assert_eq!(gba.cpu.gpr[0], 0x0200_00c4);
+        assert_eq!(gba.cpu.pc & !0xff, 0x0200_0000);
+        assert_eq!(gba.sysbus.read_16(0x0200_00c4), 0x0203);
+        // The rom region reads as open bus
+        assert_eq!(gba.sysbus.read_16(0x0800_1234), 0x091a);
+    }
 }
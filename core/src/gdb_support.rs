@@ -1,47 +1,66 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread::JoinHandle;
 
 type SendSync<T> = Arc<Mutex<T>>;
 
+use arm7tdmi::gdb::regs::{Arm7tdmiRegId, Arm7tdmiRegs};
+use arm7tdmi::gdb::{GdbConnectionCloser, GdbListenerWaker};
 use arm7tdmi::gdbstub::common::Signal;
 use arm7tdmi::gdbstub::stub::{DisconnectReason, SingleThreadStopReason};
+use arm7tdmi::gdbstub::target::ext::base::single_register_access::SingleRegisterAccess;
 use arm7tdmi::gdbstub::target::TargetError;
 use arm7tdmi::gdbstub::target::{ext::base::singlethread::SingleThreadBase, Target};
-use arm7tdmi::gdbstub_arch::arm::reg::ArmCoreRegs;
 use arm7tdmi::memory::Addr;
+use arm7tdmi::UndefinedInstruction;
 use crossbeam::channel::Receiver;
 
 // mod target;
 mod event_loop;
 pub(crate) mod gdb_thread;
 mod memory_map;
+pub(crate) mod monitor;
 mod target;
+use monitor::MonitorCommand;
 use target::DebuggerTarget;
 
 use crate::GameBoyAdvance;
 
 #[derive(Debug)]
 pub(crate) enum DebuggerRequest {
-    ReadRegs(SendSync<ArmCoreRegs>),
-    WriteRegs(ArmCoreRegs),
+    ReadRegs(SendSync<Arm7tdmiRegs>),
+    WriteRegs(Arm7tdmiRegs),
+    ReadReg(Arm7tdmiRegId, SendSync<u32>),
+    WriteReg(Arm7tdmiRegId, u32),
     ReadAddrs(Addr, SendSync<Box<[u8]>>),
-    #[allow(unused)]
     WriteAddrs(Addr, Box<[u8]>),
     AddSwBreakpoint(Addr),
     DelSwBreakpoint(Addr),
     Interrupt,
     Resume,
     SingleStep,
-    Reset,
+    /// Run a `monitor` command, the console output is written back to the string
+    Monitor(MonitorCommand, SendSync<String>),
+    Connected,
     Disconnected(DisconnectReason),
+    /// The gdbserver thread stopped listening for new connections
+    ServerClosed,
 }
 
 pub(crate) struct DebuggerRequestHandler {
     rx: Receiver<DebuggerRequest>,
     request_complete_signal: Arc<(Mutex<bool>, Condvar)>,
     stop_signal: Arc<(Mutex<Option<SingleThreadStopReason<u32>>>, Condvar)>,
-    thread: JoinHandle<()>,
+    /// Joined when the handler is dropped
+    thread: Option<JoinHandle<()>>,
+    /// Tells the gdbserver thread to stop, `waker` gets it out of waiting for a connection
+    shutdown: Arc<AtomicBool>,
+    waker: GdbListenerWaker,
+    /// The connection of the current client, closed on shutdown to get the thread out of reading from it
+    connection: Arc<Mutex<Option<GdbConnectionCloser>>>,
     pub(crate) stopped: bool,
+    /// Whether a gdb client is currently attached to the gdbserver
+    pub(crate) connected: bool,
 }
 
 impl DebuggerRequestHandler {
@@ -49,7 +68,7 @@ impl DebuggerRequestHandler {
         mut self,
         gba: &mut GameBoyAdvance,
     ) -> Option<DebuggerRequestHandler> {
-        if self.thread.is_finished() {
+        if self.thread.as_ref().is_none_or(JoinHandle::is_finished) {
             warn!("gdb server thread unexpectdly died");
             return self.terminate();
         }
@@ -57,12 +76,11 @@ impl DebuggerRequestHandler {
             // Handle as much as messages as possible
             while let Ok(mut req) = self.rx.try_recv() {
                 match self.handle_request(gba, &mut req) {
-                    Ok(Some(disconnect_reason)) => {
-                        debug!("Debugger disconnected due to {:?}", disconnect_reason);
+                    Ok(true) => {
                         debug!("closing gdbserver thread");
                         return self.terminate();
                     }
-                    Ok(None) => {}
+                    Ok(false) => {}
                     Err(_) => {
                         error!("An error occured while handling debug request {:?}", req);
                         return self.terminate();
@@ -81,7 +99,7 @@ impl DebuggerRequestHandler {
         &mut self,
         gba: &mut GameBoyAdvance,
         req: &mut DebuggerRequest,
-    ) -> Result<Option<DisconnectReason>, TargetError<<DebuggerTarget as Target>::Error>> {
+    ) -> Result<bool, TargetError<<DebuggerTarget as Target>::Error>> {
         use DebuggerRequest::*;
         match req {
             ReadRegs(regs) => {
@@ -95,6 +113,18 @@ impl DebuggerRequestHandler {
                 gba.cpu.write_registers(regs)?;
                 self.complete_request(None)
             }
+            ReadReg(reg_id, value) => {
+                let mut bytes = [0; 4];
+                gba.cpu.read_register((), *reg_id, &mut bytes)?;
+                *value.lock().unwrap() = u32::from_le_bytes(bytes);
+                trace!("Debugger requested to read {:?}: {:?}", reg_id, value);
+                self.complete_request(None)
+            }
+            WriteReg(reg_id, value) => {
+                trace!("Debugger requested to write {:?}: {:08x}", reg_id, value);
+                gba.cpu.write_register((), *reg_id, &value.to_le_bytes())?;
+                self.complete_request(None)
+            }
             ReadAddrs(addr, data) => {
                 let mut data = data.lock().unwrap();
                 trace!(
@@ -124,11 +154,10 @@ impl DebuggerRequestHandler {
                 self.stopped = false;
                 self.complete_request(None)
             }
-            Reset => {
-                debug!("Sending reset interrupt to gba");
-                self.stopped = true;
-                gba.cpu.reset();
-                self.complete_request(Some(SingleThreadStopReason::Signal(Signal::SIGTRAP)))
+            Monitor(cmd, output) => {
+                debug!("Debugger requested monitor command {:?}", cmd);
+                *output.lock().unwrap() = cmd.execute(gba);
+                self.complete_request(None)
             }
             SingleStep => {
                 debug!("Debugger requested single step");
@@ -145,13 +174,30 @@ impl DebuggerRequestHandler {
                 gba.cpu.del_breakpoint(*addr);
                 self.complete_request(None)
             }
-            Disconnected(reason) => Ok(Some(*reason)),
+            Connected => {
+                debug!("Debugger connected");
+                self.connected = true;
+                self.stopped = true;
+                gba.cpu.set_break_on_undefined(true);
+                // Forget about stop reasons that were never delivered to the previous client
+                self.stop_signal.0.lock().unwrap().take();
+                self.complete_request(None)
+            }
+            Disconnected(reason) => {
+                debug!("Debugger disconnected due to {:?}", reason);
+                // A client that went away abruptly may leave its breakpoints behind, so drop them and keep running
+                self.connected = false;
+                self.stopped = false;
+                gba.cpu.clear_breakpoints();
+                gba.cpu.set_break_on_undefined(false);
+                Ok(false)
+            }
+            ServerClosed => Ok(true),
         }
     }
 
-    fn terminate(mut self) -> Option<DebuggerRequestHandler> {
-        self.notify_stop_reason(SingleThreadStopReason::Exited(1));
-        self.thread.join().unwrap();
+    /// Dropping the handler stops the gdbserver thread
+    fn terminate(self) -> Option<DebuggerRequestHandler> {
         None
     }
 
@@ -169,12 +215,12 @@ impl DebuggerRequestHandler {
     fn complete_request(
         &mut self,
         stop_reason: Option<SingleThreadStopReason<u32>>,
-    ) -> Result<Option<DisconnectReason>, TargetError<<DebuggerTarget as Target>::Error>> {
+    ) -> Result<bool, TargetError<<DebuggerTarget as Target>::Error>> {
         self.notify_request_complete();
         if let Some(stop_reason) = stop_reason {
             self.notify_stop_reason(stop_reason);
         }
-        Ok(None)
+        Ok(false)
     }
 
     pub fn notify_stop_reason(&mut self, reason: SingleThreadStopReason<u32>) {
@@ -189,4 +235,84 @@ impl DebuggerRequestHandler {
         self.stopped = true;
         self.notify_stop_reason(SingleThreadStopReason::SwBreak(()));
     }
+
+    /// Stops with SIGILL, the cpu has already entered the Undefined exception for `insn`
+    pub fn notify_undefined(&mut self, insn: UndefinedInstruction) {
+        debug!(
+            "undefined instruction {:08x} at 0x{:08x}",
+            insn.opcode, insn.addr
+        );
+        self.stopped = true;
+        self.notify_stop_reason(SingleThreadStopReason::Signal(Signal::SIGILL));
+    }
+}
+
+impl Drop for DebuggerRequestHandler {
+    /// Stops the gdbserver thread and waits for it, which closes the listening socket.
+    /// A connected client is told that the target exited, then disconnected.
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.notify_stop_reason(SingleThreadStopReason::Exited(1));
+        if let Some(connection) = self.connection.lock().ok().and_then(|mut c| c.take()) {
+            // Fails when the client already hung up
+            let _ = connection.close();
+        }
+        if let Some(thread) = self.thread.take() {
+            if !thread.is_finished() {
+                // Fails when the thread already stopped accepting, there is nothing to wake then
+                let _ = self.waker.wake();
+            }
+            if thread.join().is_err() {
+                error!("gdbserver thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::prelude::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_drop_closes_idle_client() {
+        use std::os::unix::net::UnixStream;
+
+        let path = std::env::temp_dir().join(format!("rba-gdb-idle-{}.sock", std::process::id()));
+        let bios = vec![0; 0x4000].into_boxed_slice();
+        let mut gba = GameBoyAdvance::new(bios, Cartridge::empty(), NullAudio::new());
+        gba.start_gdbserver(GdbServerAddr::Unix(path.clone()));
+        let client = UnixStream::connect(&path).unwrap();
+
+        // Serve the handshake, the server thread then blocks reading the first packet of the client
+        let debugger = gba.debugger.as_mut().unwrap();
+        let request = debugger.rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(request, DebuggerRequest::Connected));
+        debugger.notify_request_complete();
+
+        // The server thread is joined, which removes the socket
+        drop(gba);
+        assert!(!path.exists());
+        drop(client);
+    }
+
+    #[test]
+    fn test_requests_fail_after_shutdown() {
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let mut target = DebuggerTarget::new(
+            tx,
+            Arc::new((Mutex::new(false), Condvar::new())),
+            Arc::new((Mutex::new(None), Condvar::new())),
+            String::new(),
+            Arc::new(AtomicBool::new(true)),
+        );
+        // Nothing answers, gdb must get an error instead of made up registers
+        let mut regs = Arm7tdmiRegs::default();
+        assert!(target.read_registers(&mut regs).is_err());
+        drop(rx);
+        assert!(target.read_addrs(0x0800_0000, &mut [0; 4]).is_err());
+    }
 }
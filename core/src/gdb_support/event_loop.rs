@@ -49,7 +49,7 @@ impl run_blocking::BlockingEventLoop for DebuggerEventLoop {
         target: &mut DebuggerTarget,
     ) -> Result<Option<SingleThreadStopReason<u32>>, <DebuggerTarget as Target>::Error> {
         info!("on_interrupt: sending stop message");
-        target.debugger_request(DebuggerRequest::Interrupt);
+        target.debugger_request(DebuggerRequest::Interrupt)?;
         info!("Waiting for target to stop <blocking>");
         Ok(Some(target.wait_for_stop_reason_blocking()))
     }
@@ -1,8 +1,9 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
 
 use arm7tdmi::{
-    gdb::wait_for_connection,
-    gdbstub::{conn::ConnectionExt, stub::GdbStub},
+    gdb::{GdbListener, GdbServerAddr},
+    gdbstub::stub::{DisconnectReason, GdbStub},
 };
 
 use crate::{GBAError, GameBoyAdvance};
@@ -10,10 +11,14 @@ use crate::{GBAError, GameBoyAdvance};
 use super::target::DebuggerTarget;
 use super::{event_loop::DebuggerEventLoop, DebuggerRequestHandler};
 
-/// Starts a gdbserver thread
+/// Starts a gdbserver thread.
+/// The listener is bound right away but clients are accepted on the gdbserver thread, so emulation keeps running
+/// until gdb attaches. Once a client disconnects, the thread goes back to waiting for the next one, until `shutdown`
+/// is set. The connection of the current client is kept in `connection`, for the emulation side to close it.
+/// Otherwise a client that sends nothing would keep the thread blocked reading from it.
 pub(crate) fn start_gdb_server_thread(
     gba: &mut GameBoyAdvance,
-    port: u16,
+    addr: GdbServerAddr,
 ) -> Result<DebuggerRequestHandler, GBAError> {
     let (tx, rx) = crossbeam::channel::unbounded();
     let request_complete_signal = Arc::new((Mutex::new(false), Condvar::new()));
@@ -21,28 +26,69 @@ pub(crate) fn start_gdb_server_thread(
     let stop_signal_2 = stop_signal.clone();
     let request_complete_signal_2 = request_complete_signal.clone();
     let memory_map = gba.sysbus.generate_memory_map_xml().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_2 = shutdown.clone();
+    let connection = Arc::new(Mutex::new(None));
+    let connection_2 = connection.clone();
 
-    let conn = wait_for_connection(port)?;
+    let listener = GdbListener::bind(&addr)?;
+    let waker = listener.waker()?;
     let thread = std::thread::spawn(move || {
         debug!("starting GDB Server thread");
-        let conn: Box<dyn ConnectionExt<Error = std::io::Error>> = Box::new(conn);
-
-        let mut target =
-            DebuggerTarget::new(tx, request_complete_signal_2, stop_signal_2, memory_map);
-        let gdbserver = GdbStub::new(conn);
-        let disconnect_reason = gdbserver
-            .run_blocking::<DebuggerEventLoop>(&mut target)
-            .map_err(|e| e.to_string())
-            .unwrap();
-        target.disconnect(disconnect_reason);
+        let mut target = DebuggerTarget::new(
+            tx,
+            request_complete_signal_2,
+            stop_signal_2,
+            memory_map,
+            shutdown_2.clone(),
+        );
+        loop {
+            let (conn, closer) = match listener.accept() {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("failed to accept gdb connection on {}: {:?}", addr, e);
+                    break;
+                }
+            };
+            {
+                // Checked under the lock, so the connection is either closed on shutdown or never served
+                let mut connection = connection_2.lock().unwrap();
+                if shutdown_2.load(Ordering::SeqCst) {
+                    break;
+                }
+                *connection = Some(closer);
+            }
+            if target.connect().is_err() {
+                break;
+            }
+            let gdbserver = GdbStub::new(conn);
+            let disconnect_reason = match gdbserver.run_blocking::<DebuggerEventLoop>(&mut target) {
+                Ok(disconnect_reason) => disconnect_reason,
+                Err(e) => {
+                    warn!("gdb connection closed with an error: {}", e);
+                    DisconnectReason::Disconnect
+                }
+            };
+            target.disconnect(disconnect_reason);
+            connection_2.lock().unwrap().take();
+            if shutdown_2.load(Ordering::SeqCst) {
+                break;
+            }
+            info!("gdb disconnected, waiting for a new connection on {}", addr);
+        }
+        target.shutdown();
     });
 
     let debugger = DebuggerRequestHandler {
         rx,
         request_complete_signal,
         stop_signal,
-        thread,
-        stopped: true,
+        thread: Some(thread),
+        shutdown,
+        waker,
+        connection,
+        stopped: false,
+        connected: false,
     };
     Ok(debugger)
 }
@@ -5,7 +5,7 @@ use arm7tdmi::{
     memory::Addr,
 };
 
-use crate::sysbus::{consts, SysBus};
+use crate::sysbus::{consts, CpuBus, SysBus};
 
 impl SysBus {
     pub fn generate_memory_map_xml(&self) -> Result<String, Box<dyn std::error::Error>> {
@@ -30,9 +30,9 @@ impl SysBus {
         add_memory(consts::EWRAM_ADDR, self.ewram.len())?;
         add_memory(consts::IWRAM_ADDR, self.iwram.len())?;
         add_memory(consts::IOMEM_ADDR, 0x400)?;
-        add_memory(consts::PALRAM_ADDR, self.io.gpu.palette_ram.len())?;
-        add_memory(consts::VRAM_ADDR, self.io.gpu.vram.len())?;
-        add_memory(consts::OAM_ADDR, self.io.gpu.oam.len())?;
+        add_memory(consts::PALRAM_ADDR, self.io.gpu.get_palette_ram().len())?;
+        add_memory(consts::VRAM_ADDR, self.io.gpu.get_vram().len())?;
+        add_memory(consts::OAM_ADDR, self.io.gpu.get_oam().len())?;
         add_memory(consts::CART_BASE, self.cartridge.get_rom_bytes().len())?;
 
         xml.set_root_element(memory_map);
@@ -44,7 +44,7 @@ impl SysBus {
     }
 }
 
-impl MemoryGdbInterface for SysBus {
+impl MemoryGdbInterface for CpuBus {
     fn memory_map_xml(&self, offset: u64, length: usize, buf: &mut [u8]) -> usize {
         copy_range_to_buf(
             self.generate_memory_map_xml().unwrap().as_bytes(),
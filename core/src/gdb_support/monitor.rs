@@ -0,0 +1,753 @@
+/// gdb `monitor` commands, parsed on the gdbserver thread and executed on the emulation thread
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use arm7tdmi::asm::{assemble_arm, assemble_thumb};
+use arm7tdmi::memory::{Addr, BusIO, DebugWrite};
+use arm7tdmi::trace::{InstructionTracer, TraceFilter, TraceFormat};
+use arm7tdmi::{CpuMode, CpuState};
+use rustboyadvance_utils::elf::read_line_table;
+use rustboyadvance_utils::{read_bin_file, write_bin_file};
+
+use crate::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::iodev::{consts::*, io_reg_string};
+use crate::GameBoyAdvance;
+
+pub(crate) const HELP: &str = "\
+reset               reset the cpu
+savestate <path>    save the emulator state to <path>
+loadstate <path>    restore the emulator state from <path>
+frames [n]          run for n frames (default 1), stopping at breakpoints
+io                  dump the io registers
+dma                 show the state of the dma channels
+timers              show the state of the timers
+irq                 show the interrupt controller state
+trace <path> [mgba|verbose] [start-end] [mode,...]
+                    trace executed instructions to <path>, optionally only
+                    within an address range and in the given cpu modes
+trace off           stop tracing
+profile on          start profiling the cycles spent by every instruction
+profile [n]         show the n most expensive functions and instructions
+profile folded <path>
+                    write the profiled call stacks to <path> for flamegraph tools
+profile off         stop profiling and show the final report
+coverage on|off     start or stop recording the executed instructions
+coverage save <path> [elf]
+                    write the executed addresses to <path>, or the line
+                    coverage in lcov format using the line info of <elf>
+backtrace [raw]     show the call stack tracked by the emulator, also as bt,
+                    raw lists the entry point, return address and stack
+                    pointer of every frame for core/gdb/unwinder.py, which
+                    hands the frames to gdb's own bt
+asm <addr> [arm|thumb] <instruction>
+                    assemble an instruction and patch it in at <addr>, rom
+                    included, in the current cpu state unless told otherwise
+cart                show cartridge and backup information
+screenshot <path>   save the current frame to <path> as a bmp
+help                show this message";
+
+/// Tells the user to drop the register values gdb has cached
+const FLUSH_REGS_HINT: &str = "use `maint flush register-cache` to refresh gdb's view";
+
+#[derive(Debug)]
+pub(crate) enum MonitorCommand {
+    Help,
+    Reset,
+    SaveState(PathBuf),
+    LoadState(PathBuf),
+    Frames(usize),
+    Io,
+    Dma,
+    Timers,
+    Irq,
+    Trace(TraceCommand),
+    Profile(ProfileCommand),
+    Coverage(CoverageCommand),
+    Backtrace {
+        raw: bool,
+    },
+    Asm {
+        addr: Addr,
+        state: Option<CpuState>,
+        line: String,
+    },
+    Cart,
+    Screenshot(PathBuf),
+}
+
+#[derive(Debug)]
+pub(crate) enum TraceCommand {
+    Status,
+    Stop,
+    Start {
+        path: PathBuf,
+        format: TraceFormat,
+        filter: TraceFilter,
+    },
+}
+
+impl TraceCommand {
+    fn parse(args: &[&str]) -> Result<TraceCommand, String> {
+        let (path, options) = match args {
+            [] => return Ok(TraceCommand::Status),
+            ["off"] => return Ok(TraceCommand::Stop),
+            [path, options @ ..] => (PathBuf::from(path), options),
+        };
+        let mut format = TraceFormat::default();
+        let mut filter = TraceFilter::default();
+        for option in options {
+            if let Ok(f) = option.parse() {
+                format = f;
+            } else if option.contains('-') {
+                filter.addr_range = Some(TraceFilter::parse_addr_range(option)?);
+            } else {
+                filter.modes = option
+                    .split(',')
+                    .map(CpuMode::from_str)
+                    .collect::<Result<_, _>>()?;
+            }
+        }
+        Ok(TraceCommand::Start {
+            path,
+            format,
+            filter,
+        })
+    }
+}
+
+/// Number of functions and instructions in a profile report unless told otherwise
+const DEFAULT_PROFILE_REPORT_LEN: usize = 20;
+
+#[derive(Debug)]
+pub(crate) enum ProfileCommand {
+    Start,
+    Stop,
+    Report(usize),
+    Folded(PathBuf),
+}
+
+impl ProfileCommand {
+    fn parse(args: &[&str]) -> Result<ProfileCommand, String> {
+        match args {
+            ["on"] => Ok(ProfileCommand::Start),
+            ["off"] => Ok(ProfileCommand::Stop),
+            [] => Ok(ProfileCommand::Report(DEFAULT_PROFILE_REPORT_LEN)),
+            ["folded", path] => Ok(ProfileCommand::Folded(PathBuf::from(path))),
+            [n] => n
+                .parse()
+                .map(ProfileCommand::Report)
+                .map_err(|_| format!("{} is not a valid report length", n)),
+            _ => Err("usage: profile [on|off|n|folded <path>]".to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum CoverageCommand {
+    Status,
+    Start,
+    Stop,
+    Save { path: PathBuf, elf: Option<PathBuf> },
+}
+
+impl CoverageCommand {
+    fn parse(args: &[&str]) -> Result<CoverageCommand, String> {
+        match args {
+            [] => Ok(CoverageCommand::Status),
+            ["on"] => Ok(CoverageCommand::Start),
+            ["off"] => Ok(CoverageCommand::Stop),
+            ["save", path] => Ok(CoverageCommand::Save {
+                path: PathBuf::from(path),
+                elf: None,
+            }),
+            ["save", path, elf] => Ok(CoverageCommand::Save {
+                path: PathBuf::from(path),
+                elf: Some(PathBuf::from(elf)),
+            }),
+            _ => Err("usage: coverage [on|off|save <path> [elf]]".to_string()),
+        }
+    }
+}
+
+fn parse_addr(s: &str) -> Result<Addr, String> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    Addr::from_str_radix(digits, 16).map_err(|_| format!("{} is not a valid address", s))
+}
+
+impl FromStr for MonitorCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use MonitorCommand::*;
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => ("help", &[][..]),
+        };
+        let path_arg = |args: &[&str]| match args {
+            [path] => Ok(PathBuf::from(path)),
+            _ => Err(format!("`{}` expects a path", command)),
+        };
+        let cmd = match (command, args) {
+            ("help", []) => Help,
+            ("reset", []) => Reset,
+            ("savestate", args) => SaveState(path_arg(args)?),
+            ("loadstate", args) => LoadState(path_arg(args)?),
+            ("frames", []) => Frames(1),
+            ("frames", [n]) => Frames(
+                n.parse()
+                    .map_err(|_| format!("{} is not a valid number of frames", n))?,
+            ),
+            ("io", []) => Io,
+            ("dma", []) => Dma,
+            ("timers", []) => Timers,
+            ("irq", []) => Irq,
+            ("trace", args) => Trace(TraceCommand::parse(args)?),
+            ("profile", args) => Profile(ProfileCommand::parse(args)?),
+            ("coverage", args) => Coverage(CoverageCommand::parse(args)?),
+            ("backtrace", []) | ("bt", []) => Backtrace { raw: false },
+            ("backtrace", ["raw"]) | ("bt", ["raw"]) => Backtrace { raw: true },
+            ("asm", [addr, rest @ ..]) => {
+                let (state, line) = match rest {
+                    ["arm", line @ ..] => (Some(CpuState::ARM), line),
+                    ["thumb", line @ ..] => (Some(CpuState::THUMB), line),
+                    line => (None, line),
+                };
+                if line.is_empty() {
+                    return Err("usage: asm <addr> [arm|thumb] <instruction>".to_string());
+                }
+                Asm {
+                    addr: parse_addr(addr)?,
+                    state,
+                    line: line.join(" "),
+                }
+            }
+            ("asm", []) => return Err("usage: asm <addr> [arm|thumb] <instruction>".to_string()),
+            ("cart", []) => Cart,
+            ("screenshot", args) => Screenshot(path_arg(args)?),
+            ("help", _)
+            | ("reset", _)
+            | ("io", _)
+            | ("dma", _)
+            | ("timers", _)
+            | ("irq", _)
+            | ("cart", _) => return Err(format!("`{}` takes no arguments", command)),
+            ("frames", _) => return Err("too many arguments for `frames`".to_string()),
+            ("backtrace", _) | ("bt", _) => return Err(format!("usage: {} [raw]", command)),
+            (unk, _) => return Err(format!("unknown command: {}", unk)),
+        };
+        Ok(cmd)
+    }
+}
+
+impl MonitorCommand {
+    /// Runs the command and returns the text to show in the gdb console
+    pub(crate) fn execute(&self, gba: &mut GameBoyAdvance) -> String {
+        use MonitorCommand::*;
+        let mut out = String::new();
+        let result = match self {
+            Help => {
+                out.push_str(HELP);
+                Ok(())
+            }
+            Reset => {
+                gba.cpu.reset();
+                writeln!(out, "cpu reset, {}", FLUSH_REGS_HINT).map_err(Into::into)
+            }
+            SaveState(path) => save_state(gba, path, &mut out),
+            LoadState(path) => load_state(gba, path, &mut out),
+            Frames(count) => run_frames(gba, *count, &mut out),
+            Io => dump_io(gba, &mut out),
+            Dma => dump_dma(gba, &mut out),
+            Timers => dump_timers(gba, &mut out),
+            Irq => dump_irq(gba, &mut out),
+            Trace(cmd) => trace(gba, cmd, &mut out),
+            Profile(cmd) => profile(gba, cmd, &mut out),
+            Coverage(cmd) => coverage(gba, cmd, &mut out),
+            Backtrace { raw } => backtrace(gba, *raw, &mut out),
+            Asm { addr, state, line } => patch(gba, *addr, *state, line, &mut out),
+            Cart => dump_cart(gba, &mut out),
+            Screenshot(path) => screenshot(gba, path, &mut out),
+        };
+        if let Err(e) = result {
+            let _ = writeln!(out, "error: {}", e);
+        }
+        out
+    }
+}
+
+/// Errors are reported back to the gdb console, so we only care for the message
+type MonitorResult = Result<(), Box<dyn std::error::Error>>;
+
+fn save_state(gba: &mut GameBoyAdvance, path: &Path, out: &mut String) -> MonitorResult {
+    let state = gba.save_state()?;
+    write_bin_file(path, &state)?;
+    writeln!(out, "saved state to {}", path.display())?;
+    Ok(())
+}
+
+fn load_state(gba: &mut GameBoyAdvance, path: &Path, out: &mut String) -> MonitorResult {
+    let state = read_bin_file(path)?;
+    gba.restore_state(&state)?;
+    writeln!(
+        out,
+        "loaded state from {}, {}",
+        path.display(),
+        FLUSH_REGS_HINT
+    )?;
+    Ok(())
+}
+
+fn run_frames(gba: &mut GameBoyAdvance, count: usize, out: &mut String) -> MonitorResult {
+    for frame in 0..count {
+        gba.frame_interruptible();
+        if let Some(bp) = gba.cpu.check_breakpoint() {
+            writeln!(
+                out,
+                "breakpoint 0x{:08x} hit during frame {}/{}",
+                bp,
+                frame + 1,
+                count
+            )?;
+            break;
+        }
+        if let Some(insn) = gba.cpu.take_undefined_break() {
+            writeln!(
+                out,
+                "undefined instruction {:08x} at 0x{:08x} during frame {}/{}",
+                insn.opcode,
+                insn.addr,
+                frame + 1,
+                count
+            )?;
+            break;
+        }
+    }
+    writeln!(
+        out,
+        "stopped at 0x{:08x}, {}",
+        gba.cpu.get_next_pc(),
+        FLUSH_REGS_HINT
+    )?;
+    Ok(())
+}
+
+fn trace(gba: &mut GameBoyAdvance, cmd: &TraceCommand, out: &mut String) -> MonitorResult {
+    match cmd {
+        TraceCommand::Status => {
+            let status = if gba.cpu.is_tracing() { "on" } else { "off" };
+            writeln!(out, "instruction tracing is {}", status)?;
+        }
+        TraceCommand::Stop => {
+            gba.cpu.stop_trace()?;
+            writeln!(out, "instruction tracing stopped")?;
+        }
+        TraceCommand::Start {
+            path,
+            format,
+            filter,
+        } => {
+            let tracer = InstructionTracer::create(path, *format, filter.clone())?;
+            gba.cpu.stop_trace()?;
+            gba.cpu.start_trace(tracer);
+            writeln!(out, "tracing instructions to {}", path.display())?;
+        }
+    }
+    Ok(())
+}
+
+fn profile(gba: &mut GameBoyAdvance, cmd: &ProfileCommand, out: &mut String) -> MonitorResult {
+    let mut buf = vec![];
+    match cmd {
+        ProfileCommand::Start => {
+            gba.start_profiling();
+            writeln!(out, "profiling started")?;
+        }
+        ProfileCommand::Stop => match gba.stop_profiling() {
+            Some(profiler) => {
+                gba.write_profile_report(&profiler, &mut buf, DEFAULT_PROFILE_REPORT_LEN)?;
+                out.push_str(&String::from_utf8_lossy(&buf));
+                writeln!(out, "profiling stopped")?;
+            }
+            None => writeln!(out, "not profiling")?,
+        },
+        ProfileCommand::Report(top) => match gba.profiler() {
+            Some(profiler) => {
+                gba.write_profile_report(profiler, &mut buf, *top)?;
+                out.push_str(&String::from_utf8_lossy(&buf));
+            }
+            None => writeln!(out, "not profiling, start with `profile on`")?,
+        },
+        ProfileCommand::Folded(path) => match gba.profiler() {
+            Some(profiler) => {
+                let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+                gba.write_profile_folded(profiler, &mut file)?;
+                file.flush()?;
+                writeln!(out, "wrote folded call stacks to {}", path.display())?;
+            }
+            None => writeln!(out, "not profiling, start with `profile on`")?,
+        },
+    }
+    Ok(())
+}
+
+fn coverage(gba: &mut GameBoyAdvance, cmd: &CoverageCommand, out: &mut String) -> MonitorResult {
+    match cmd {
+        CoverageCommand::Status => match gba.coverage() {
+            Some(coverage) => writeln!(
+                out,
+                "{} instructions executed so far",
+                coverage.executed().len()
+            )?,
+            None => writeln!(out, "not recording coverage, start with `coverage on`")?,
+        },
+        CoverageCommand::Start => {
+            gba.start_coverage();
+            writeln!(out, "recording coverage")?;
+        }
+        CoverageCommand::Stop => {
+            gba.stop_coverage();
+            writeln!(out, "stopped recording coverage")?;
+        }
+        CoverageCommand::Save { path, elf } => {
+            let coverage = gba
+                .coverage()
+                .ok_or("not recording coverage, start with `coverage on`")?;
+            let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+            match elf {
+                Some(elf) => {
+                    let lines = read_line_table(&read_bin_file(elf)?)?;
+                    coverage.write_lcov(&mut file, &gba.get_game_code(), &lines)?;
+                }
+                None => coverage.write_address_list(&mut file)?,
+            }
+            file.flush()?;
+            writeln!(out, "wrote coverage to {}", path.display())?;
+        }
+    }
+    Ok(())
+}
+
+fn backtrace(gba: &mut GameBoyAdvance, raw: bool, out: &mut String) -> MonitorResult {
+    if raw {
+        // Innermost first, the order gdb unwinds in. Nothing is printed while tracking is disabled,
+        // so the unwinder leaves every frame to gdb.
+        if let Some(call_stack) = gba.cpu.call_stack() {
+            for frame in call_stack.frames().iter().rev() {
+                writeln!(
+                    out,
+                    "0x{:08x} 0x{:08x} 0x{:08x}",
+                    frame.target, frame.return_addr, frame.sp
+                )?;
+            }
+        }
+        return Ok(());
+    }
+    match gba.backtrace() {
+        Some(frames) => {
+            for (i, frame) in frames.iter().enumerate() {
+                writeln!(out, "#{:<3} {}", i, frame)?;
+            }
+        }
+        None => writeln!(out, "call stack tracking is disabled")?,
+    }
+    Ok(())
+}
+
+fn patch(
+    gba: &mut GameBoyAdvance,
+    addr: Addr,
+    state: Option<CpuState>,
+    line: &str,
+    out: &mut String,
+) -> MonitorResult {
+    let state = state.unwrap_or_else(|| gba.cpu.cpsr.state());
+    let bytes = match state {
+        CpuState::ARM => {
+            let insn = assemble_arm(line, addr)?;
+            writeln!(out, "0x{:08x}: {:08x}\t{}", addr, insn.raw, insn)?;
+            insn.raw.to_le_bytes().to_vec()
+        }
+        CpuState::THUMB => {
+            let mut bytes = vec![];
+            for insn in assemble_thumb(line, addr)? {
+                writeln!(out, "0x{:08x}: {:04x}\t{}", insn.pc, insn.raw, insn)?;
+                bytes.extend(insn.raw.to_le_bytes());
+            }
+            bytes
+        }
+    };
+    gba.sysbus.debug_write_bytes(addr, &bytes);
+
+    // The cpu already fetched the next two instructions, refetch them if they were patched
+    let next_pc = gba.cpu.get_next_pc();
+    let fetched = match gba.cpu.cpsr.state() {
+        CpuState::ARM => next_pc..next_pc + 8,
+        CpuState::THUMB => next_pc..next_pc + 4,
+    };
+    if addr < fetched.end && fetched.start < addr + bytes.len() as Addr {
+        gba.cpu.set_next_pc(next_pc);
+    }
+    Ok(())
+}
+
+fn dump_io(gba: &mut GameBoyAdvance, out: &mut String) -> MonitorResult {
+    for io_addr in (IO_BASE..IO_BASE + 0x400).step_by(2) {
+        let name = io_reg_string(io_addr);
+        if name == "UNKNOWN" {
+            continue;
+        }
+        let value = gba.io_devs.read_16(io_addr - IO_BASE);
+        write!(out, "{:08x} {:<16} {:04x}", io_addr, name, value)?;
+        if let Some(decoded) = decode_io_reg(gba, io_addr) {
+            write!(out, "  {}", decoded)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn decode_io_reg(gba: &GameBoyAdvance, io_addr: Addr) -> Option<String> {
+    let io = &gba.io_devs;
+    let decoded = match io_addr {
+        REG_DISPCNT => format!("{:?}", io.gpu.dispcnt),
+        REG_DISPSTAT => format!("{:?}", io.gpu.dispstat),
+        REG_BG0CNT..=REG_BG3CNT => {
+            format!("{:?}", io.gpu.bgcnt[((io_addr - REG_BG0CNT) / 2) as usize])
+        }
+        REG_BLDCNT => format!("{:?}", io.gpu.bldcnt),
+        REG_BLDALPHA => format!("{:?}", io.gpu.bldalpha),
+        REG_IE => format!("{:?}", io.intc.interrupt_enable),
+        REG_IF => format!("{:?}", io.intc.interrupt_flags.get()),
+        REG_WAITCNT => format!("{:?}", io.waitcnt),
+        REG_DMA0CNT_H => format!("{:?}", io.dmac.channels[0].ctrl),
+        REG_DMA1CNT_H => format!("{:?}", io.dmac.channels[1].ctrl),
+        REG_DMA2CNT_H => format!("{:?}", io.dmac.channels[2].ctrl),
+        REG_DMA3CNT_H => format!("{:?}", io.dmac.channels[3].ctrl),
+        REG_TM0CNT_H => format!("{:?}", io.timers[0].ctl),
+        REG_TM1CNT_H => format!("{:?}", io.timers[1].ctl),
+        REG_TM2CNT_H => format!("{:?}", io.timers[2].ctl),
+        REG_TM3CNT_H => format!("{:?}", io.timers[3].ctl),
+        _ => return None,
+    };
+    Some(decoded)
+}
+
+fn dump_dma(gba: &mut GameBoyAdvance, out: &mut String) -> MonitorResult {
+    for (id, channel) in gba.io_devs.dmac.channels.iter().enumerate() {
+        writeln!(
+            out,
+            "DMA{}: src={:08x} dst={:08x} count={:x} running={} {:?}",
+            id,
+            channel.src,
+            channel.dst,
+            channel.wc,
+            channel.is_running(),
+            channel.ctrl
+        )?;
+    }
+    Ok(())
+}
+
+fn dump_timers(gba: &mut GameBoyAdvance, out: &mut String) -> MonitorResult {
+    const COUNTER_REGS: [Addr; 4] = [REG_TM0CNT_L, REG_TM1CNT_L, REG_TM2CNT_L, REG_TM3CNT_L];
+    for (id, &counter_reg) in COUNTER_REGS.iter().enumerate() {
+        // Reading the counter through the io bus brings scheduled timers up to date
+        let counter = gba.io_devs.read_16(counter_reg - IO_BASE);
+        let timer = &gba.io_devs.timers[id];
+        writeln!(
+            out,
+            "TM{}: counter={:04x} reload={:04x} {:?}",
+            id, counter, timer.initial_data, timer.ctl
+        )?;
+    }
+    Ok(())
+}
+
+fn dump_irq(gba: &mut GameBoyAdvance, out: &mut String) -> MonitorResult {
+    let intc = &gba.io_devs.intc;
+    writeln!(out, "IME: {}", intc.interrupt_master_enable)?;
+    writeln!(out, "IE:  {:?}", intc.interrupt_enable)?;
+    writeln!(out, "IF:  {:?}", intc.interrupt_flags.get())?;
+    writeln!(out, "pending: {}", intc.irq_pending())?;
+    writeln!(out, "halted: {:?}", gba.io_devs.haltcnt)?;
+    writeln!(out, "cpu irq disabled: {}", gba.cpu.cpsr.irq_disabled())?;
+    Ok(())
+}
+
+fn dump_cart(gba: &mut GameBoyAdvance, out: &mut String) -> MonitorResult {
+    let cartridge = &gba.sysbus.cartridge;
+    let header = &cartridge.header;
+    writeln!(out, "title:    {}", header.game_title)?;
+    writeln!(out, "code:     {}", header.game_code)?;
+    writeln!(out, "maker:    {}", header.maker_code)?;
+    writeln!(out, "version:  {}", header.software_version)?;
+    writeln!(out, "checksum: {:02x}", header.checksum)?;
+    writeln!(
+        out,
+        "rom size: {}",
+        bytesize::ByteSize::b(cartridge.get_rom_bytes().len() as u64)
+    )?;
+    writeln!(out, "backup:   {}", cartridge.backup)?;
+    writeln!(out, "detected: {}", cartridge.backup_detection())?;
+    let rtc = cartridge
+        .get_gpio()
+        .as_ref()
+        .is_some_and(|gpio| gpio.rtc.is_some());
+    writeln!(out, "rtc:      {}", if rtc { "present" } else { "none" })?;
+    if let Some(symbols) = cartridge.get_symbols() {
+        writeln!(out, "symbols:  {}", symbols.len())?;
+    }
+    Ok(())
+}
+
+fn screenshot(gba: &mut GameBoyAdvance, path: &Path, out: &mut String) -> MonitorResult {
+    let mut bmp = Vec::new();
+    write_bmp(&mut bmp, gba.get_frame_buffer())?;
+    write_bin_file(path, &bmp)?;
+    writeln!(out, "saved screenshot to {}", path.display())?;
+    Ok(())
+}
+
+/// Encodes a 0x00RRGGBB frame as a 24bit bmp
+fn write_bmp<W: Write>(w: &mut W, frame: &[u32]) -> io::Result<()> {
+    const HEADERS_SIZE: u32 = 14 + 40;
+    // rows are already 4 byte aligned as 240 * 3 = 720
+    let image_size = (DISPLAY_WIDTH * DISPLAY_HEIGHT * 3) as u32;
+
+    // BITMAPFILEHEADER
+    w.write_all(b"BM")?;
+    w.write_u32::<LittleEndian>(HEADERS_SIZE + image_size)?;
+    w.write_u32::<LittleEndian>(0)?;
+    w.write_u32::<LittleEndian>(HEADERS_SIZE)?;
+    // BITMAPINFOHEADER
+    w.write_u32::<LittleEndian>(40)?;
+    w.write_i32::<LittleEndian>(DISPLAY_WIDTH as i32)?;
+    w.write_i32::<LittleEndian>(DISPLAY_HEIGHT as i32)?;
+    w.write_u16::<LittleEndian>(1)?;
+    w.write_u16::<LittleEndian>(24)?;
+    w.write_u32::<LittleEndian>(0)?;
+    w.write_u32::<LittleEndian>(image_size)?;
+    w.write_i32::<LittleEndian>(2835)?;
+    w.write_i32::<LittleEndian>(2835)?;
+    w.write_u32::<LittleEndian>(0)?;
+    w.write_u32::<LittleEndian>(0)?;
+
+    // Pixel rows are stored bottom-up
+    for row in frame.chunks_exact(DISPLAY_WIDTH).rev() {
+        for &pixel in row {
+            w.write_all(&[pixel as u8, (pixel >> 8) as u8, (pixel >> 16) as u8])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_monitor_commands() {
+        assert!(matches!("".parse(), Ok(MonitorCommand::Help)));
+        assert!(matches!("frames".parse(), Ok(MonitorCommand::Frames(1))));
+        assert!(matches!(
+            " frames  60 ".parse(),
+            Ok(MonitorCommand::Frames(60))
+        ));
+        assert!(matches!(
+            "bt".parse(),
+            Ok(MonitorCommand::Backtrace { raw: false })
+        ));
+        assert!(matches!(
+            "backtrace raw".parse(),
+            Ok(MonitorCommand::Backtrace { raw: true })
+        ));
+        assert!(matches!(
+            "profile".parse(),
+            Ok(MonitorCommand::Profile(ProfileCommand::Report(
+                DEFAULT_PROFILE_REPORT_LEN
+            )))
+        ));
+        assert!(matches!(
+            "profile 5".parse(),
+            Ok(MonitorCommand::Profile(ProfileCommand::Report(5)))
+        ));
+        match "profile folded /tmp/stacks.folded".parse() {
+            Ok(MonitorCommand::Profile(ProfileCommand::Folded(path))) => {
+                assert_eq!(path, Path::new("/tmp/stacks.folded"))
+            }
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+        assert!("profile folded".parse::<MonitorCommand>().is_err());
+        match "coverage save /tmp/scene.info game.elf".parse() {
+            Ok(MonitorCommand::Coverage(CoverageCommand::Save { path, elf })) => {
+                assert_eq!(path, Path::new("/tmp/scene.info"));
+                assert_eq!(elf.as_deref(), Some(Path::new("game.elf")));
+            }
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+        assert!("backtrace full".parse::<MonitorCommand>().is_err());
+        match "asm 0x08000100 thumb ldr r0, [r1, #4]".parse() {
+            Ok(MonitorCommand::Asm { addr, state, line }) => {
+                assert_eq!(addr, 0x0800_0100);
+                assert_eq!(state, Some(CpuState::THUMB));
+                assert_eq!(line, "ldr r0, [r1, #4]");
+            }
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+        assert!(matches!(
+            "asm 3000000 nop".parse(),
+            Ok(MonitorCommand::Asm { state: None, .. })
+        ));
+        assert!("asm 0x08000000 thumb".parse::<MonitorCommand>().is_err());
+        assert!(matches!(
+            "trace off".parse(),
+            Ok(MonitorCommand::Trace(TraceCommand::Stop))
+        ));
+        assert!(matches!(
+            "trace".parse(),
+            Ok(MonitorCommand::Trace(TraceCommand::Status))
+        ));
+        match "trace /tmp/trace.log verbose 8000000-80000ff irq,svc".parse() {
+            Ok(MonitorCommand::Trace(TraceCommand::Start {
+                path,
+                format,
+                filter,
+            })) => {
+                assert_eq!(path, Path::new("/tmp/trace.log"));
+                assert_eq!(format, TraceFormat::Verbose);
+                assert_eq!(filter.addr_range, Some(0x0800_0000..=0x0800_00ff));
+                assert_eq!(filter.modes, vec![CpuMode::Irq, CpuMode::Supervisor]);
+            }
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+        assert!("trace /tmp/trace.log hyp"
+            .parse::<MonitorCommand>()
+            .is_err());
+        assert!(
+            matches!("savestate /tmp/a.sav".parse(), Ok(MonitorCommand::SaveState(p)) if p == Path::new("/tmp/a.sav"))
+        );
+        assert!("savestate".parse::<MonitorCommand>().is_err());
+        assert!("frames lots".parse::<MonitorCommand>().is_err());
+        assert!("dma 1".parse::<MonitorCommand>().is_err());
+        assert!("bogus".parse::<MonitorCommand>().is_err());
+    }
+
+    #[test]
+    fn test_bmp_layout() {
+        let mut frame = vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT];
+        // top-left pixel ends up in the last row of the file
+        frame[0] = 0x00112233;
+        let mut bmp = Vec::new();
+        write_bmp(&mut bmp, &frame).unwrap();
+
+        assert_eq!(bmp.len(), 54 + DISPLAY_WIDTH * DISPLAY_HEIGHT * 3);
+        assert_eq!(&bmp[0..2], b"BM");
+        let last_row = 54 + (DISPLAY_HEIGHT - 1) * DISPLAY_WIDTH * 3;
+        assert_eq!(&bmp[last_row..last_row + 3], &[0x33, 0x22, 0x11]);
+    }
+}
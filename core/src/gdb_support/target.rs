@@ -1,11 +1,17 @@
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex, WaitTimeoutResult};
 use std::time::Duration;
 
 /// Implementing the Target trait for gdbstub
+use arm7tdmi::gdb::regs::{Arm7tdmiArch, Arm7tdmiRegId, Arm7tdmiRegs, TARGET_DESCRIPTION_XML};
 use arm7tdmi::gdb::{copy_range_to_buf, gdbstub, gdbstub_arch};
 use crossbeam::channel::Sender;
 use gdbstub::common::Signal;
 use gdbstub::stub::{DisconnectReason, SingleThreadStopReason};
+use gdbstub::target::ext::base::single_register_access::{
+    SingleRegisterAccess, SingleRegisterAccessOps,
+};
 use gdbstub::target::ext::base::singlethread::{
     SingleThreadBase, SingleThreadResume, SingleThreadSingleStep,
 };
@@ -14,8 +20,8 @@ use gdbstub::target::ext::base::BaseOps;
 use gdbstub::target::ext::breakpoints::BreakpointsOps;
 use gdbstub::target::ext::monitor_cmd::{outputln, ConsoleOutput};
 use gdbstub::target::{self, Target, TargetError, TargetResult};
-use gdbstub_arch::arm::reg::ArmCoreRegs;
 
+use super::monitor::{MonitorCommand, HELP};
 use super::DebuggerRequest;
 
 pub(crate) struct DebuggerTarget {
@@ -23,6 +29,8 @@ pub(crate) struct DebuggerTarget {
     request_complete_signal: Arc<(Mutex<bool>, Condvar)>,
     pub(crate) stop_signal: Arc<(Mutex<Option<SingleThreadStopReason<u32>>>, Condvar)>,
     pub(crate) memory_map: String,
+    /// Set when the emulation side stops the server, it no longer answers requests then
+    shutdown: Arc<AtomicBool>,
 }
 
 impl DebuggerTarget {
@@ -31,27 +39,39 @@ impl DebuggerTarget {
         request_complete_signal: Arc<(Mutex<bool>, Condvar)>,
         stop_signal: Arc<(Mutex<Option<SingleThreadStopReason<u32>>>, Condvar)>,
         memory_map: String,
+        shutdown: Arc<AtomicBool>,
     ) -> DebuggerTarget {
         DebuggerTarget {
             tx,
             request_complete_signal,
             stop_signal,
             memory_map,
+            shutdown,
         }
     }
 
-    pub fn debugger_request(&mut self, req: DebuggerRequest) {
+    /// Fails once the emulation side stopped serving requests, the results of the request are not filled in then
+    pub fn debugger_request(&mut self, req: DebuggerRequest) -> Result<(), ()> {
         let (lock, cvar) = &*self.request_complete_signal;
         let mut finished = lock.lock().unwrap();
-        // now send the request
-        self.tx.send(req).unwrap();
+        // now send the request, the emulation side may already be gone
+        if self.tx.send(req).is_err() {
+            return Err(());
+        }
         // wait for the notification
         while !*finished {
-            finished = cvar.wait(finished).unwrap();
+            if self.shutdown.load(Ordering::SeqCst) {
+                return Err(());
+            }
+            finished = cvar
+                .wait_timeout(finished, Duration::from_millis(10))
+                .unwrap()
+                .0;
         }
         // ack the other side we got the signal
         *finished = false;
         cvar.notify_one();
+        Ok(())
     }
 
     pub fn wait_for_stop_reason_timeout(
@@ -79,16 +99,27 @@ impl DebuggerTarget {
         stop_reason.take().expect("None is not expected here")
     }
 
+    /// Stops the emulation and waits until it is ready to serve a newly connected client
+    pub fn connect(&mut self) -> Result<(), ()> {
+        self.debugger_request(DebuggerRequest::Connected)
+    }
+
     pub fn disconnect(&mut self, disconnect_reason: DisconnectReason) {
-        self.tx
-            .send(DebuggerRequest::Disconnected(disconnect_reason))
-            .unwrap();
+        let _ = self
+            .tx
+            .send(DebuggerRequest::Disconnected(disconnect_reason));
+    }
+
+    /// Notify the emulation thread that the gdbserver is no longer accepting connections
+    pub fn shutdown(&mut self) {
+        // The emulation side might already be gone, nothing to do in that case
+        let _ = self.tx.send(DebuggerRequest::ServerClosed);
     }
 }
 
 impl Target for DebuggerTarget {
     type Error = ();
-    type Arch = gdbstub_arch::arm::Armv4t; // as an example
+    type Arch = Arm7tdmiArch;
 
     #[inline(always)]
     fn base_ops(&mut self) -> BaseOps<Self::Arch, Self::Error> {
@@ -108,31 +139,44 @@ impl Target for DebuggerTarget {
     fn support_monitor_cmd(&mut self) -> Option<target::ext::monitor_cmd::MonitorCmdOps<'_, Self>> {
         Some(self)
     }
+
+    fn support_target_description_xml_override(
+        &mut self,
+    ) -> Option<
+        target::ext::target_description_xml_override::TargetDescriptionXmlOverrideOps<'_, Self>,
+    > {
+        Some(self)
+    }
 }
 
 impl SingleThreadBase for DebuggerTarget {
-    fn read_registers(&mut self, regs: &mut ArmCoreRegs) -> TargetResult<(), Self> {
-        let regs_copy = Arc::new(Mutex::new(ArmCoreRegs::default()));
-        self.debugger_request(DebuggerRequest::ReadRegs(regs_copy.clone()));
+    fn read_registers(&mut self, regs: &mut Arm7tdmiRegs) -> TargetResult<(), Self> {
+        let regs_copy = Arc::new(Mutex::new(Arm7tdmiRegs::default()));
+        self.debugger_request(DebuggerRequest::ReadRegs(regs_copy.clone()))?;
         regs_copy.lock().unwrap().clone_into(regs);
         Ok(())
     }
 
-    fn write_registers(&mut self, regs: &ArmCoreRegs) -> TargetResult<(), Self> {
-        self.debugger_request(DebuggerRequest::WriteRegs(regs.clone()));
+    fn write_registers(&mut self, regs: &Arm7tdmiRegs) -> TargetResult<(), Self> {
+        self.debugger_request(DebuggerRequest::WriteRegs(regs.clone()))?;
         Ok(())
     }
 
     fn read_addrs(&mut self, start_addr: u32, data: &mut [u8]) -> TargetResult<(), Self> {
         let buffer = Arc::new(Mutex::new(vec![0; data.len()].into_boxed_slice()));
-        self.debugger_request(DebuggerRequest::ReadAddrs(start_addr, buffer.clone()));
+        self.debugger_request(DebuggerRequest::ReadAddrs(start_addr, buffer.clone()))?;
         data.copy_from_slice(&buffer.lock().unwrap());
         Ok(())
     }
 
-    fn write_addrs(&mut self, _start_addr: u32, _data: &[u8]) -> TargetResult<(), Self> {
-        // todo!("implement DebugWrite bus extention")
-        Err(TargetError::NonFatal)
+    fn write_addrs(&mut self, start_addr: u32, data: &[u8]) -> TargetResult<(), Self> {
+        self.debugger_request(DebuggerRequest::WriteAddrs(start_addr, data.into()))?;
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn support_single_register_access(&mut self) -> Option<SingleRegisterAccessOps<'_, (), Self>> {
+        Some(self)
     }
 
     // most targets will want to support at resumption as well...
@@ -143,9 +187,35 @@ impl SingleThreadBase for DebuggerTarget {
     }
 }
 
+impl SingleRegisterAccess<()> for DebuggerTarget {
+    fn read_register(
+        &mut self,
+        _tid: (),
+        reg_id: Arm7tdmiRegId,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        let value = Arc::new(Mutex::new(0));
+        self.debugger_request(DebuggerRequest::ReadReg(reg_id, value.clone()))?;
+        let bytes = value.lock().unwrap().to_le_bytes();
+        buf[..4].copy_from_slice(&bytes);
+        Ok(bytes.len())
+    }
+
+    fn write_register(
+        &mut self,
+        _tid: (),
+        reg_id: Arm7tdmiRegId,
+        val: &[u8],
+    ) -> TargetResult<(), Self> {
+        let val = u32::from_le_bytes(val.try_into().map_err(|_| TargetError::NonFatal)?);
+        self.debugger_request(DebuggerRequest::WriteReg(reg_id, val))?;
+        Ok(())
+    }
+}
+
 impl SingleThreadResume for DebuggerTarget {
     fn resume(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
-        self.debugger_request(DebuggerRequest::Resume);
+        self.debugger_request(DebuggerRequest::Resume)?;
         Ok(())
     }
 
@@ -160,7 +230,7 @@ impl SingleThreadResume for DebuggerTarget {
 
 impl SingleThreadSingleStep for DebuggerTarget {
     fn step(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
-        self.debugger_request(DebuggerRequest::SingleStep);
+        self.debugger_request(DebuggerRequest::SingleStep)?;
         Ok(())
     }
 }
@@ -181,6 +251,23 @@ impl target::ext::memory_map::MemoryMap for DebuggerTarget {
     }
 }
 
+impl target::ext::target_description_xml_override::TargetDescriptionXmlOverride for DebuggerTarget {
+    fn target_description_xml(
+        &self,
+        _annex: &[u8],
+        offset: u64,
+        length: usize,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        Ok(copy_range_to_buf(
+            TARGET_DESCRIPTION_XML.as_bytes(),
+            offset,
+            length,
+            buf,
+        ))
+    }
+}
+
 impl target::ext::breakpoints::Breakpoints for DebuggerTarget {
     // there are several kinds of breakpoints - this target uses software breakpoints
     #[inline(always)]
@@ -197,7 +284,7 @@ impl target::ext::breakpoints::SwBreakpoint for DebuggerTarget {
         addr: u32,
         _kind: gdbstub_arch::arm::ArmBreakpointKind,
     ) -> TargetResult<bool, Self> {
-        self.debugger_request(DebuggerRequest::AddSwBreakpoint(addr));
+        self.debugger_request(DebuggerRequest::AddSwBreakpoint(addr))?;
         Ok(true)
     }
 
@@ -206,7 +293,7 @@ impl target::ext::breakpoints::SwBreakpoint for DebuggerTarget {
         addr: u32,
         _kind: gdbstub_arch::arm::ArmBreakpointKind,
     ) -> TargetResult<bool, Self> {
-        self.debugger_request(DebuggerRequest::DelSwBreakpoint(addr));
+        self.debugger_request(DebuggerRequest::DelSwBreakpoint(addr))?;
         Ok(true)
     }
 }
@@ -225,13 +312,17 @@ impl target::ext::monitor_cmd::MonitorCmd for DebuggerTarget {
             }
         };
 
-        match cmd {
-            "reset" => {
-                self.debugger_request(DebuggerRequest::Reset);
-                outputln!(out, "sent reset signal");
+        match cmd.parse::<MonitorCommand>() {
+            Ok(MonitorCommand::Help) => outputln!(out, "{}", HELP),
+            Ok(cmd) => {
+                let output = Arc::new(Mutex::new(String::new()));
+                self.debugger_request(DebuggerRequest::Monitor(cmd, output.clone()))?;
+                let output = output.lock().unwrap();
+                out.write_raw(output.as_bytes());
             }
-            unk => {
-                outputln!(out, "unknown command: {}", unk);
+            Err(e) => {
+                outputln!(out, "{}", e);
+                outputln!(out, "type `monitor help` for the list of commands");
             }
         }
 
@@ -1,7 +1,7 @@
 use num::FromPrimitive;
 use serde::{Deserialize, Serialize};
 
-use arm7tdmi::memory::{Addr, BusIO, DebugRead};
+use arm7tdmi::memory::{Addr, BusIO, DebugRead, DebugWrite};
 use rustboyadvance_utils::index2d;
 
 use super::dma::{DmaNotifer, TIMING_HBLANK, TIMING_VBLANK};
@@ -124,9 +124,10 @@ pub struct Gpu {
     pub bldcnt: BlendControl,
     pub bldalpha: BlendAlpha,
     pub bldy: u16,
-    pub palette_ram: Box<[u8]>,
-    pub vram: Box<[u8]>,
-    pub oam: Box<[u8]>,
+    // The page table of SysBus points into these, so they are only ever modified in place
+    palette_ram: Box<[u8]>,
+    vram: Box<[u8]>,
+    oam: Box<[u8]>,
     pub(super) vram_obj_tiles_start: u32,
     pub(super) obj_buffer: Box<[ObjBufferEntry]>,
     pub(super) frame_buffer: Box<[u32]>,
@@ -336,6 +337,30 @@ impl Gpu {
         &self.frame_buffer
     }
 
+    pub fn get_palette_ram(&self) -> &[u8] {
+        &self.palette_ram
+    }
+
+    pub fn get_vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn get_oam(&self) -> &[u8] {
+        &self.oam
+    }
+
+    pub fn get_palette_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.palette_ram
+    }
+
+    pub fn get_vram_mut(&mut self) -> &mut [u8] {
+        &mut self.vram
+    }
+
+    pub fn get_oam_mut(&mut self) -> &mut [u8] {
+        &mut self.oam
+    }
+
     #[inline]
     fn update_vcount(&mut self, value: usize) {
         self.vcount = value;
@@ -429,19 +454,22 @@ impl Gpu {
     }
 }
 
+/// 96KB of VRAM mirrored every 128KB, the last 32KB mirror the object tiles
+fn vram_offset(addr: Addr) -> u32 {
+    let ofs = addr & ((VIDEO_RAM_SIZE as u32) - 1);
+    if ofs >= 0x18000 {
+        ofs - 0x8000
+    } else {
+        ofs
+    }
+}
+
 impl BusIO for Gpu {
     fn read_8(&mut self, addr: Addr) -> u8 {
         let page = (addr >> 24) as usize;
         match page {
             PAGE_PALRAM => self.palette_ram.read_8(addr & 0x3ff),
-            PAGE_VRAM => {
-                // complicated
-                let mut ofs = addr & ((VIDEO_RAM_SIZE as u32) - 1);
-                if ofs > 0x18000 {
-                    ofs -= 0x8000;
-                }
-                self.vram.read_8(ofs)
-            }
+            PAGE_VRAM => self.vram.read_8(vram_offset(addr)),
             PAGE_OAM => self.oam.read_8(addr & 0x3ff),
             _ => unreachable!(),
         }
@@ -451,13 +479,7 @@ impl BusIO for Gpu {
         let page = (addr >> 24) as usize;
         match page {
             PAGE_PALRAM => self.palette_ram.write_16(addr & 0x3fe, value),
-            PAGE_VRAM => {
-                let mut ofs = addr & ((VIDEO_RAM_SIZE as u32) - 1);
-                if ofs > 0x18000 {
-                    ofs -= 0x8000;
-                }
-                self.vram.write_16(ofs, value)
-            }
+            PAGE_VRAM => self.vram.write_16(vram_offset(addr), value),
             PAGE_OAM => self.oam.write_16(addr & 0x3fe, value),
             _ => unreachable!(),
         }
@@ -472,10 +494,7 @@ impl BusIO for Gpu {
         match page {
             PAGE_PALRAM => self.palette_ram.write_16(addr & 0x3fe, expand_value(value)),
             PAGE_VRAM => {
-                let mut ofs = addr & ((VIDEO_RAM_SIZE as u32) - 1);
-                if ofs > 0x18000 {
-                    ofs -= 0x8000;
-                }
+                let ofs = vram_offset(addr);
                 if ofs < self.vram_obj_tiles_start {
                     self.vram.write_16(ofs & !1, expand_value(value));
                 }
@@ -491,13 +510,26 @@ impl DebugRead for Gpu {
         let page = (addr >> 24) as usize;
         match page {
             PAGE_PALRAM => self.palette_ram.read_8(addr & 0x3ff),
-            PAGE_VRAM => self.vram.read_8(addr & ((VIDEO_RAM_SIZE as u32) - 1)),
+            PAGE_VRAM => self.vram.read_8(vram_offset(addr)),
             PAGE_OAM => self.oam.read_8(addr & 0x3ff),
             _ => unreachable!(),
         }
     }
 }
 
+impl DebugWrite for Gpu {
+    /// Pokes the byte itself, unlike 8bit stores by the cpu that are ignored or duplicated to the whole halfword
+    fn debug_write_8(&mut self, addr: Addr, value: u8) {
+        let page = (addr >> 24) as usize;
+        match page {
+            PAGE_PALRAM => self.palette_ram.write_8(addr & 0x3ff, value),
+            PAGE_VRAM => self.vram.write_8(vram_offset(addr), value),
+            PAGE_OAM => self.oam.write_8(addr & 0x3ff, value),
+            _ => unreachable!(),
+        }
+    }
+}
+
 #[cfg(feature = "debugger")]
 impl fmt::Display for Gpu {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -0,0 +1,77 @@
+/// Detection of busy-wait loops, such as polling VCOUNT, DISPSTAT or a flag set by an interrupt handler.
+///
+/// A loop is idle once an iteration ends in exactly the state it started in, without the cpu writing anything or
+/// reading state that changes on its own. The state the loop polls only changes when the scheduler handles an event,
+/// so until then every iteration would do the same, and the emulation can fast forward to the next event instead.
+///
+/// Loops listed in the game overrides are skipped without that proof, for loops that e.g. advance a random seed while
+/// they wait.
+use arm7tdmi::memory::Addr;
+use arm7tdmi::Arm7tdmiCore;
+
+use super::sysbus::CpuBus;
+
+/// Backward branches that jump further than this are not considered tight loops
+const MAX_LOOP_SIZE: u32 = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LoopIteration {
+    /// The branch that closes the loop
+    branch: Addr,
+    gpr: [u32; 15],
+    cpsr: u32,
+    side_effects: usize,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct IdleLoopDetector {
+    last_iteration: Option<LoopIteration>,
+    skipped_cycles: u64,
+    /// Branches known to close idle loops
+    known: Vec<Addr>,
+}
+
+impl IdleLoopDetector {
+    pub fn new(known: Vec<Addr>) -> IdleLoopDetector {
+        IdleLoopDetector {
+            known,
+            ..Default::default()
+        }
+    }
+
+    /// Called after the cpu executed the instruction at `pc`.
+    /// Returns true when it closed an iteration of an idle loop.
+    #[inline]
+    pub fn on_step(&mut self, pc: Addr, cpu: &Arm7tdmiCore<CpuBus>) -> bool {
+        let target = cpu.get_next_pc();
+        if target <= pc && self.known.contains(&pc) {
+            return true;
+        }
+        if target > pc || pc - target > MAX_LOOP_SIZE {
+            return false;
+        }
+        let iteration = LoopIteration {
+            branch: pc,
+            gpr: cpu.gpr,
+            cpsr: cpu.cpsr.get(),
+            side_effects: cpu.bus.side_effects(),
+        };
+        let idle = self.last_iteration.as_ref() == Some(&iteration);
+        self.last_iteration = Some(iteration);
+        idle
+    }
+
+    /// Must be called whenever the system state may have changed under the cpu, like when events are handled
+    #[inline]
+    pub fn forget(&mut self) {
+        self.last_iteration = None;
+    }
+
+    pub fn record_skip(&mut self, cycles: usize) {
+        self.skipped_cycles += cycles as u64;
+    }
+
+    pub fn skipped_cycles(&self) -> u64 {
+        self.skipped_cycles
+    }
+}
@@ -74,6 +74,15 @@ impl IoDevices {
     pub fn set_sysbus_ptr(&mut self, ptr: SysBusPtr) {
         self.sysbus_ptr = ptr;
     }
+
+    fn write_haltcnt(&mut self, value: u8) {
+        if value & 0x80 != 0 {
+            // self.haltcnt = HaltState::Stop;
+            panic!("Can't handle HaltCtrl == Stop yet");
+        } else {
+            self.haltcnt = HaltState::Halt;
+        }
+    }
 }
 
 impl InterruptConnect for IoDevices {
@@ -279,14 +288,9 @@ impl BusIO for IoDevices {
                 (*io.sysbus_ptr).on_waitcnt_written(io.waitcnt);
             }
 
-            REG_POSTFLG => io.post_boot_flag = value != 0,
-            REG_HALTCNT => {
-                if value & 0x80 != 0 {
-                    // io.haltcnt = HaltState::Stop;
-                    panic!("Can't handle HaltCtrl == Stop yet");
-                } else {
-                    io.haltcnt = HaltState::Halt;
-                }
+            REG_POSTFLG => {
+                io.post_boot_flag = value & 0xff != 0;
+                io.write_haltcnt((value >> 8) as u8);
             }
 
             x if DebugPort::is_debug_access(x) => io.debug.write(io_addr, value),
@@ -311,6 +315,9 @@ impl BusIO for IoDevices {
             0x0400_00A4 | 0x0400_00A5 | 0x0400_00A6 | 0x0400_00A7 => {
                 self.sound.write_fifo(1, value as i8)
             }
+            // HALTCNT is write only, so these can't go through a read-modify-write of the halfword
+            REG_POSTFLG => self.post_boot_flag = value != 0,
+            REG_HALTCNT => self.write_haltcnt(value),
             _ => {
                 let t = self.read_16(addr & !1);
                 let t = if addr & 1 != 0 {
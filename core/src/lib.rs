@@ -33,11 +33,13 @@ pub use arm7tdmi;
 pub use arm7tdmi::disass;
 mod bios;
 pub mod cartridge;
+pub mod coverage;
 pub mod gpu;
+mod idle_loop;
 mod sched;
 pub mod sound;
 pub mod sysbus;
-pub use sysbus::SysBus;
+pub use sysbus::{CpuBus, SysBus};
 pub mod interrupt;
 pub mod iodev;
 pub use interrupt::Interrupt;
@@ -48,7 +50,9 @@ pub mod dma;
 pub mod gdb_support;
 pub mod keypad;
 mod mgba_debug;
+pub mod multiboot;
 pub(crate) mod overrides;
+pub mod profiler;
 pub mod timer;
 
 use arm7tdmi::gdb::gdbstub::stub::GdbStubError;
@@ -105,15 +109,17 @@ impl From<GdbStubError<(), std::io::Error>> for GBAError {
 }
 
 pub mod prelude {
-    pub use super::cartridge::{Cartridge, GamepakBuilder};
+    pub use super::cartridge::{Cartridge, GamepakBuilder, RtcClock};
     #[cfg(feature = "debugger")]
     pub use super::debugger::Debugger;
     pub use super::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+    pub use super::multiboot::{BootMode, MultibootImage};
     pub use super::sound::interface::{
         AudioInterface, DynAudioInterface, NullAudio, SimpleAudioInterface,
     };
     pub use super::{GBAError, GBAResult, GameBoyAdvance};
     pub use arm7tdmi;
+    pub use arm7tdmi::gdb::GdbServerAddr;
     pub use arm7tdmi::memory::{Addr, BusIO, MemoryAccess, MemoryAccessWidth, MemoryInterface};
     pub use rustboyadvance_utils::{read_bin_file, write_bin_file};
 }
@@ -0,0 +1,150 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use rustboyadvance_utils::read_bin_file;
+
+use super::cartridge::header::{self, CartridgeHeader};
+use super::sysbus::consts::WORK_RAM_SIZE;
+use super::{GBAError, GBAResult};
+
+/// Offset of the boot mode byte in the multiboot header
+const BOOT_MODE_OFFSET: usize = 0xc4;
+/// Offset of the slave ID byte in the multiboot header
+const SLAVE_ID_OFFSET: usize = 0xc5;
+/// The multiboot header extends the cartridge header up to the JOYBUS entry point
+const MULTIBOOT_HEADER_SIZE: usize = 0xe4;
+
+/// How the BIOS received the image, which it records in the multiboot header for the image to inspect
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootMode {
+    Joybus,
+    Normal,
+    /// The slave ID is 1 to 3, depending on the link port the image was received on
+    Multiplay {
+        slave_id: u8,
+    },
+}
+
+impl BootMode {
+    fn boot_mode(self) -> u8 {
+        match self {
+            BootMode::Joybus => 1,
+            BootMode::Normal => 2,
+            BootMode::Multiplay { .. } => 3,
+        }
+    }
+
+    /// Joybus transfers leave the slave ID as the image had it, normal mode transfers always come from slave 1
+    fn slave_id(self) -> Option<u8> {
+        match self {
+            BootMode::Joybus => None,
+            BootMode::Normal => Some(1),
+            BootMode::Multiplay { slave_id } => Some(slave_id),
+        }
+    }
+}
+
+impl FromStr for BootMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "joybus" => Ok(BootMode::Joybus),
+            "normal" => Ok(BootMode::Normal),
+            "multiplay1" => Ok(BootMode::Multiplay { slave_id: 1 }),
+            "multiplay2" => Ok(BootMode::Multiplay { slave_id: 2 }),
+            "multiplay3" => Ok(BootMode::Multiplay { slave_id: 3 }),
+            _ => Err(format!("{} is not a valid boot mode", s)),
+        }
+    }
+}
+
+/// A program that runs from EWRAM at 0x02000000, as downloaded by the BIOS over the link cable
+#[derive(Clone, Debug)]
+pub struct MultibootImage {
+    pub header: CartridgeHeader,
+    bytes: Vec<u8>,
+}
+
+impl MultibootImage {
+    pub fn from_bytes(bytes: Vec<u8>) -> GBAResult<MultibootImage> {
+        if bytes.len() > WORK_RAM_SIZE {
+            return Err(GBAError::CartridgeLoadError(format!(
+                "multiboot image is {} bytes, but at most {} fit in EWRAM",
+                bytes.len(),
+                WORK_RAM_SIZE
+            )));
+        }
+        if bytes.len() < MULTIBOOT_HEADER_SIZE {
+            return Err(GBAError::CartridgeLoadError(
+                "incomplete multiboot header".to_string(),
+            ));
+        }
+        let header = header::parse(&bytes)?;
+        Ok(MultibootImage { header, bytes })
+    }
+
+    pub fn from_file(path: &Path) -> GBAResult<MultibootImage> {
+        MultibootImage::from_bytes(read_bin_file(path)?)
+    }
+
+    /// Whether a file should be booted as a multiboot image rather than inserted as a cartridge, going by its name
+    pub fn is_multiboot_path(path: &Path) -> bool {
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return false,
+        };
+        name.ends_with(".mb") || name.ends_with(".mb.gba") || name.ends_with("_mb.gba")
+    }
+
+    /// The image as it is in EWRAM after the BIOS received it
+    pub(crate) fn load(&self, ewram: &mut [u8], boot_mode: BootMode) {
+        ewram[..self.bytes.len()].copy_from_slice(&self.bytes);
+        ewram[BOOT_MODE_OFFSET] = boot_mode.boot_mode();
+        if let Some(slave_id) = boot_mode.slave_id() {
+            ewram[SLAVE_ID_OFFSET] = slave_id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let mut bytes = vec![0; 0x100];
+        bytes[0xa0..0xac].copy_from_slice(b"MULTIBOOTDEM");
+        bytes[SLAVE_ID_OFFSET] = 0xff;
+        let image = MultibootImage::from_bytes(bytes).unwrap();
+        assert_eq!(image.header.game_title, "MULTIBOOTDEM");
+
+        let mut ewram = vec![0xaa; WORK_RAM_SIZE];
+        image.load(&mut ewram, BootMode::Joybus);
+        assert_eq!(&ewram[0xa0..0xac], b"MULTIBOOTDEM");
+        assert_eq!(ewram[BOOT_MODE_OFFSET], 1);
+        assert_eq!(ewram[SLAVE_ID_OFFSET], 0xff);
+        assert_eq!(ewram[0x100], 0xaa);
+
+        image.load(&mut ewram, BootMode::Normal);
+        assert_eq!((ewram[BOOT_MODE_OFFSET], ewram[SLAVE_ID_OFFSET]), (2, 1));
+        image.load(&mut ewram, BootMode::Multiplay { slave_id: 3 });
+        assert_eq!((ewram[BOOT_MODE_OFFSET], ewram[SLAVE_ID_OFFSET]), (3, 3));
+    }
+
+    #[test]
+    fn test_size_limits() {
+        assert!(MultibootImage::from_bytes(vec![0; 0xc0]).is_err());
+        assert!(MultibootImage::from_bytes(vec![0; WORK_RAM_SIZE]).is_ok());
+        assert!(MultibootImage::from_bytes(vec![0; WORK_RAM_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn test_is_multiboot_path() {
+        assert!(MultibootImage::is_multiboot_path(Path::new("demo.mb")));
+        assert!(MultibootImage::is_multiboot_path(Path::new(
+            "dir/demo_mb.gba"
+        )));
+        assert!(MultibootImage::is_multiboot_path(Path::new("DEMO.MB.GBA")));
+        assert!(!MultibootImage::is_multiboot_path(Path::new("game.gba")));
+    }
+}
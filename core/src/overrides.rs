@@ -1,58 +1,300 @@
 use std::collections::HashMap;
+use std::path::Path;
 use std::str::FromStr;
 
-use yaml_rust::YamlLoader;
+use arm7tdmi::memory::Addr;
+use yaml_rust::{Yaml, YamlLoader};
 
 use super::cartridge::BackupType;
+use super::{GBAError, GBAResult};
 
-#[derive(Debug)]
+/// Settings of an entry, the ones it leaves out are taken from entries of lower priority
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct GameOverride {
-    force_rtc: bool,
+    force_rtc: Option<bool>,
+    solar_sensor: Option<bool>,
+    gyro: Option<bool>,
+    rumble: Option<bool>,
+    tilt_sensor: Option<bool>,
     save_type: Option<BackupType>,
+    flash_chip_id: Option<u16>,
+    idle_loops: Option<Vec<Addr>>,
 }
 
 impl GameOverride {
     pub fn force_rtc(&self) -> bool {
-        self.force_rtc
+        self.force_rtc.unwrap_or(false)
+    }
+    pub fn solar_sensor(&self) -> bool {
+        self.solar_sensor.unwrap_or(false)
+    }
+    pub fn gyro(&self) -> bool {
+        self.gyro.unwrap_or(false)
+    }
+    /// A rumble motor without the gyro sensor
+    pub fn rumble(&self) -> bool {
+        self.rumble.unwrap_or(false)
+    }
+    pub fn tilt_sensor(&self) -> bool {
+        self.tilt_sensor.unwrap_or(false)
     }
     pub fn save_type(&self) -> Option<BackupType> {
         self.save_type
     }
+    /// The ID the flash chip answers with instead of the Macronix one matching its size
+    pub fn flash_chip_id(&self) -> Option<u16> {
+        self.flash_chip_id
+    }
+    /// Addresses of the branches that close the idle loops of the game
+    pub fn idle_loops(&self) -> &[Addr] {
+        self.idle_loops.as_deref().unwrap_or(&[])
+    }
+
+    /// Takes every setting `other` has, keeping the rest
+    fn merge(&mut self, other: &GameOverride) {
+        fn take<T: Clone>(this: &mut Option<T>, other: &Option<T>) {
+            if other.is_some() {
+                *this = other.clone();
+            }
+        }
+        take(&mut self.force_rtc, &other.force_rtc);
+        take(&mut self.solar_sensor, &other.solar_sensor);
+        take(&mut self.gyro, &other.gyro);
+        take(&mut self.rumble, &other.rumble);
+        take(&mut self.tilt_sensor, &other.tilt_sensor);
+        take(&mut self.save_type, &other.save_type);
+        take(&mut self.flash_chip_id, &other.flash_chip_id);
+        take(&mut self.idle_loops, &other.idle_loops);
+    }
 }
 
-lazy_static! {
-    static ref GAME_OVERRIDES: HashMap<String, GameOverride> = {
-        let mut m = HashMap::new();
-
-        let docs = YamlLoader::load_from_str(include_str!("../overrides.yaml"))
-            .expect("failed to load overrides file");
-
-        let doc = &docs[0];
-        let games = doc.as_vec().unwrap();
-
-        for game in games {
-            let game_code = String::from(game["code"].as_str().unwrap());
-            let force_rtc = game["rtc"].as_bool().unwrap_or(false);
-            let save_type = if let Some(save_type) = game["save_type"].as_str() {
-                match BackupType::from_str(save_type) {
-                    Ok(x) => Some(x),
-                    _ => panic!("{}: invalid save type {:#}", game_code, save_type),
+const KEYS: &[&str] = &[
+    "code",
+    "name",
+    "crc32",
+    "rtc",
+    "solar_sensor",
+    "gyro",
+    "rumble",
+    "tilt_sensor",
+    "save_type",
+    "flash_chip_id",
+    "idle_loops",
+];
+
+/// The entries of an overrides file.
+/// Entries of a specific ROM take priority over the ones for every version of a game.
+#[derive(Debug, Default)]
+pub struct GameOverrides {
+    by_crc32: HashMap<u32, GameOverride>,
+    by_code: HashMap<String, GameOverride>,
+}
+
+fn parse_bool(value: &Yaml, key: &str) -> Result<bool, String> {
+    value
+        .as_bool()
+        .ok_or_else(|| format!("{} must be true or false", key))
+}
+
+fn parse_int(value: &Yaml, key: &str, max: u32) -> Result<u32, String> {
+    match value.as_i64() {
+        Some(x) if x >= 0 && x <= max as i64 => Ok(x as u32),
+        _ => Err(format!("{} must be an integer up to {:#x}", key, max)),
+    }
+}
+
+fn parse_entry(entry: &Yaml) -> Result<(Option<u32>, Option<String>, GameOverride), String> {
+    let hash = entry
+        .as_hash()
+        .ok_or_else(|| "expected a mapping of keys to values".to_string())?;
+
+    let mut crc32 = None;
+    let mut game_code = None;
+    let mut game_override = GameOverride::default();
+    for (key, value) in hash {
+        let key = key
+            .as_str()
+            .ok_or_else(|| format!("invalid key {:?}", key))?;
+        match key {
+            "code" => match value.as_str() {
+                Some(code) if code.len() == 4 => game_code = Some(code.to_string()),
+                _ => return Err("code must be the 4 characters of the game code".to_string()),
+            },
+            "name" => {}
+            "crc32" => crc32 = Some(parse_int(value, key, u32::MAX)?),
+            "rtc" => game_override.force_rtc = Some(parse_bool(value, key)?),
+            "solar_sensor" => game_override.solar_sensor = Some(parse_bool(value, key)?),
+            "gyro" => game_override.gyro = Some(parse_bool(value, key)?),
+            "rumble" => game_override.rumble = Some(parse_bool(value, key)?),
+            "tilt_sensor" => game_override.tilt_sensor = Some(parse_bool(value, key)?),
+            "save_type" => {
+                let save_type = value
+                    .as_str()
+                    .ok_or_else(|| "save_type must be a string".to_string())?;
+                game_override.save_type = Some(BackupType::from_str(save_type)?);
+            }
+            "flash_chip_id" => {
+                game_override.flash_chip_id = Some(parse_int(value, key, 0xffff)? as u16)
+            }
+            "idle_loops" => {
+                let addrs = value
+                    .as_vec()
+                    .ok_or_else(|| "idle_loops must be a list of addresses".to_string())?;
+                let addrs = addrs
+                    .iter()
+                    .map(|addr| parse_int(addr, "idle loop address", u32::MAX))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if let Some(addr) = addrs.iter().find(|addr| *addr & 1 != 0) {
+                    return Err(format!("idle loop address {:#010x} is unaligned", addr));
                 }
-            } else {
-                None
-            };
+                game_override.idle_loops = Some(addrs);
+            }
+            _ => {
+                return Err(format!(
+                    "unknown key {}, expected one of {}",
+                    key,
+                    KEYS.join(", ")
+                ))
+            }
+        }
+    }
+    if crc32.is_none() && game_code.is_none() {
+        return Err("entries need a code or crc32".to_string());
+    }
+    Ok((crc32, game_code, game_override))
+}
 
-            let game_overrride = GameOverride {
-                force_rtc,
-                save_type,
+impl GameOverrides {
+    /// Parses the entries of an overrides file, `source` names it in the errors
+    pub fn parse(yaml: &str, source: &str) -> Result<GameOverrides, String> {
+        let docs = YamlLoader::load_from_str(yaml).map_err(|e| format!("{}: {}", source, e))?;
+        let entries = match docs.first() {
+            Some(Yaml::Array(entries)) => entries.as_slice(),
+            None => &[],
+            Some(_) => return Err(format!("{}: expected a list of entries", source)),
+        };
+
+        let mut overrides = GameOverrides::default();
+        for (i, entry) in entries.iter().enumerate() {
+            let describe = || match entry["code"].as_str() {
+                Some(code) => format!("{}: entry {} ({})", source, i + 1, code),
+                None => format!("{}: entry {}", source, i + 1),
+            };
+            let (crc32, game_code, game_override) =
+                parse_entry(entry).map_err(|e| format!("{}: {}", describe(), e))?;
+            let duplicate = match (crc32, game_code) {
+                (Some(crc32), _) => overrides.by_crc32.insert(crc32, game_override),
+                (None, Some(game_code)) => overrides.by_code.insert(game_code, game_override),
+                (None, None) => unreachable!(),
             };
-            m.insert(game_code, game_overrride);
+            if duplicate.is_some() {
+                return Err(format!("{}: the game has an earlier entry", describe()));
+            }
+        }
+        Ok(overrides)
+    }
+
+    pub fn from_file(path: &Path) -> GBAResult<GameOverrides> {
+        let yaml = std::fs::read_to_string(path)?;
+        GameOverrides::parse(&yaml, &path.to_string_lossy()).map_err(GBAError::CartridgeLoadError)
+    }
+
+    /// The entry for the game code with the entry for the specific ROM merged over it
+    fn get(&self, game_code: &str, rom_crc32: u32) -> Option<GameOverride> {
+        let by_code = self.by_code.get(game_code);
+        let by_crc32 = self.by_crc32.get(&rom_crc32);
+        let mut game_override = by_code.or(by_crc32)?.clone();
+        if let (Some(_), Some(by_crc32)) = (by_code, by_crc32) {
+            game_override.merge(by_crc32);
         }
+        Some(game_override)
+    }
+}
+
+lazy_static! {
+    static ref GAME_OVERRIDES: GameOverrides =
+        GameOverrides::parse(include_str!("../overrides.yaml"), "overrides.yaml").unwrap();
+}
 
-        m
-    };
+/// Looks up the overrides of the game, starting with the built-in ones.
+/// Each of `user_overrides` takes priority over the ones before it, settings an entry leaves out are kept.
+pub fn get_game_overrides(
+    user_overrides: &[GameOverrides],
+    game_code: &str,
+    rom_crc32: u32,
+) -> Option<GameOverride> {
+    let mut found = None;
+    for overrides in std::iter::once(&*GAME_OVERRIDES).chain(user_overrides) {
+        if let Some(game_override) = overrides.get(game_code, rom_crc32) {
+            found
+                .get_or_insert_with(GameOverride::default)
+                .merge(&game_override);
+        }
+    }
+    found
 }
 
-pub fn get_game_overrides(game_code: &str) -> Option<&GameOverride> {
-    GAME_OVERRIDES.get(game_code)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> Result<GameOverrides, String> {
+        GameOverrides::parse(yaml, "test.yaml")
+    }
+
+    #[test]
+    fn test_crc32_takes_priority() {
+        let overrides = parse(
+            "
+- code: ABCE
+  save_type: sram
+  rtc: true
+
+- code: ABCE
+  name: A hack of ABCE
+  crc32: 0x12345678
+  save_type: flash128k
+",
+        )
+        .unwrap();
+        let get = |crc32| overrides.get("ABCE", crc32).unwrap();
+        assert_eq!(get(0x12345678).save_type(), Some(BackupType::Flash1M));
+        assert!(get(0x12345678).force_rtc());
+        assert_eq!(get(0).save_type(), Some(BackupType::Sram));
+        assert!(overrides.get("ABCJ", 0).is_none());
+    }
+
+    #[test]
+    fn test_user_overrides_take_priority() {
+        // Boktai in the built-in overrides has an RTC and a solar sensor
+        let user = [
+            parse("- code: U3IE\n  solar_sensor: false\n  flash_chip_id: 0x1362").unwrap(),
+            parse("- code: U3IE\n  idle_loops: [0x080000c4, 0x03000010]").unwrap(),
+        ];
+        let game_override = get_game_overrides(&user, "U3IE", 0).unwrap();
+        assert!(game_override.force_rtc());
+        assert!(!game_override.solar_sensor());
+        assert_eq!(game_override.flash_chip_id(), Some(0x1362));
+        assert_eq!(game_override.idle_loops(), &[0x080000c4, 0x03000010]);
+
+        let game_override = get_game_overrides(&user[1..], "ZZZZ", 0);
+        assert!(game_override.is_none());
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let error = |yaml| parse(yaml).unwrap_err();
+        assert!(error("code: ABCE").contains("expected a list of entries"));
+        assert_eq!(
+            error("- code: ABCE\n- code: ABCJ\n  rtc: yes"),
+            "test.yaml: entry 2 (ABCJ): rtc must be true or false"
+        );
+        assert!(error("- code: ABCE\n  rcc: true").contains("unknown key rcc"));
+        assert!(error("- name: Nameless").contains("need a code or crc32"));
+        assert!(error("- code: ABCE\n  save_type: flash").contains("not a valid save type"));
+        assert!(error("- code: ABCE\n  flash_chip_id: 0x10000").contains("flash_chip_id"));
+        assert!(error("- code: ABCE\n  idle_loops: [0x08000001]").contains("unaligned"));
+        assert!(error("- code: ABCE\n- code: ABCE").contains("earlier entry"));
+        assert!(parse("").unwrap().by_code.is_empty());
+    }
 }
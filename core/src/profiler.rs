@@ -0,0 +1,337 @@
+/// Cycle-exact execution profiler.
+///
+/// Every executed instruction is accounted with the exact amount of cycles the bus charged for it, so unlike a
+/// sampling profiler nothing is missed, including short functions running off slow gamepak waitstates.
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use arm7tdmi::callstack::CallStack;
+use arm7tdmi::memory::Addr;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PcStats {
+    /// How many times the instruction was executed
+    pub instructions: u64,
+    /// Cycles spent executing it, including waitstates and internal cycles
+    pub cycles: u64,
+    /// Entry point of the function it was executed from, if the call stack knows it
+    pub function_entry: Option<Addr>,
+}
+
+/// Accumulated cost of a function, not including the functions it calls
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionStats {
+    pub name: String,
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Profiler {
+    pcs: HashMap<Addr, PcStats>,
+    /// Cycles per call stack, keyed by the entry points of the active functions followed by the pc
+    stacks: HashMap<Vec<Addr>, u64>,
+    /// Key of the instruction being executed, reused to avoid allocating for stacks seen before
+    current: Vec<Addr>,
+    cpu_cycles: u64,
+    dma_cycles: u64,
+    halt_cycles: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// Must be called before the cpu executes the instruction at `pc`, while `call_stack` still belongs to it
+    pub(crate) fn begin_instruction(&mut self, pc: Addr, call_stack: Option<&CallStack>) {
+        self.current.clear();
+        if let Some(call_stack) = call_stack {
+            self.current
+                .extend(call_stack.frames().iter().map(|frame| frame.target));
+        }
+        self.current.push(pc);
+    }
+
+    /// Accounts the instruction passed to `begin_instruction`, which took `cycles` to execute
+    pub(crate) fn end_instruction(&mut self, cycles: usize) {
+        let cycles = cycles as u64;
+        let (&pc, frames) = self
+            .current
+            .split_last()
+            .expect("begin_instruction was not called");
+        let stats = self.pcs.entry(pc).or_default();
+        stats.instructions += 1;
+        stats.cycles += cycles;
+        stats.function_entry = frames.last().copied();
+        match self.stacks.get_mut(self.current.as_slice()) {
+            Some(total) => *total += cycles,
+            None => {
+                self.stacks.insert(self.current.clone(), cycles);
+            }
+        }
+        self.cpu_cycles += cycles;
+    }
+
+    pub(crate) fn record_dma(&mut self, cycles: usize) {
+        self.dma_cycles += cycles as u64;
+    }
+
+    pub(crate) fn record_halt(&mut self, cycles: usize) {
+        self.halt_cycles += cycles as u64;
+    }
+
+    pub fn pc_stats(&self) -> &HashMap<Addr, PcStats> {
+        &self.pcs
+    }
+
+    /// Cycles the cpu spent executing instructions
+    pub fn cpu_cycles(&self) -> u64 {
+        self.cpu_cycles
+    }
+
+    /// Cycles the cpu was stalled while dma owned the bus
+    pub fn dma_cycles(&self) -> u64 {
+        self.dma_cycles
+    }
+
+    /// Cycles the cpu was halted waiting for an interrupt
+    pub fn halt_cycles(&self) -> u64 {
+        self.halt_cycles
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.cpu_cycles + self.dma_cycles + self.halt_cycles
+    }
+
+    /// Functions sorted by the cycles spent in them, most expensive first.
+    /// `symbolize` resolves an address to the function containing it, and the offset into it.
+    pub fn functions<'a, S>(&self, symbolize: S) -> Vec<FunctionStats>
+    where
+        S: Fn(Addr) -> Option<(&'a str, u32)>,
+    {
+        let mut functions: HashMap<String, FunctionStats> = HashMap::new();
+        for (&pc, stats) in &self.pcs {
+            let name = function_name(&symbolize, pc, stats.function_entry);
+            let function = functions
+                .entry(name.clone())
+                .or_insert_with(|| FunctionStats {
+                    name,
+                    instructions: 0,
+                    cycles: 0,
+                });
+            function.instructions += stats.instructions;
+            function.cycles += stats.cycles;
+        }
+        let mut functions: Vec<FunctionStats> = functions.into_values().collect();
+        functions.sort_by(|a, b| b.cycles.cmp(&a.cycles).then_with(|| a.name.cmp(&b.name)));
+        functions
+    }
+
+    /// Writes a human readable report of the `top` most expensive functions and instructions
+    pub fn write_report<'a, W, S>(&self, w: &mut W, symbolize: S, top: usize) -> io::Result<()>
+    where
+        W: Write,
+        S: Fn(Addr) -> Option<(&'a str, u32)>,
+    {
+        let total = self.total_cycles();
+        let percent = |cycles: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                cycles as f64 * 100.0 / total as f64
+            }
+        };
+        writeln!(
+            w,
+            "{} cycles profiled: cpu {:.1}%, dma {:.1}%, halted {:.1}%",
+            total,
+            percent(self.cpu_cycles),
+            percent(self.dma_cycles),
+            percent(self.halt_cycles)
+        )?;
+
+        writeln!(w)?;
+        writeln!(
+            w,
+            "{:>12} {:>6} {:>12} {:>8}  function",
+            "cycles", "%", "instructions", "cpi"
+        )?;
+        for function in self.functions(&symbolize).iter().take(top) {
+            writeln!(
+                w,
+                "{:>12} {:>5.1}% {:>12} {:>8.2}  {}",
+                function.cycles,
+                percent(function.cycles),
+                function.instructions,
+                function.cycles as f64 / function.instructions as f64,
+                function.name
+            )?;
+        }
+
+        let mut pcs: Vec<(&Addr, &PcStats)> = self.pcs.iter().collect();
+        pcs.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then_with(|| a.0.cmp(b.0)));
+        writeln!(w)?;
+        writeln!(
+            w,
+            "{:>12} {:>6} {:>12} {:>8}  address",
+            "cycles", "%", "executed", "cpi"
+        )?;
+        for (&pc, stats) in pcs.into_iter().take(top) {
+            write!(
+                w,
+                "{:>12} {:>5.1}% {:>12} {:>8.2}  0x{:08x}",
+                stats.cycles,
+                percent(stats.cycles),
+                stats.instructions,
+                stats.cycles as f64 / stats.instructions as f64,
+                pc
+            )?;
+            match symbolize(pc) {
+                Some((name, offset)) => writeln!(w, " {}+0x{:x}", name, offset)?,
+                None => writeln!(w)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the cycles spent in every call stack in the folded format consumed by flamegraph tools
+    /// (`outer;inner;leaf cycles`), e.g. `flamegraph.pl` or `inferno-flamegraph`
+    pub fn write_folded<'a, W, S>(&self, w: &mut W, symbolize: S) -> io::Result<()>
+    where
+        W: Write,
+        S: Fn(Addr) -> Option<(&'a str, u32)>,
+    {
+        let mut folded: HashMap<String, u64> = HashMap::new();
+        for (stack, cycles) in &self.stacks {
+            let (&pc, frames) = stack.split_last().unwrap();
+            let mut names: Vec<String> = frames
+                .iter()
+                .map(|&entry| function_name(&symbolize, entry, Some(entry)))
+                .collect();
+            // The innermost frame usually is the function of the pc, unless it tail-called another one
+            let leaf = function_name(&symbolize, pc, frames.last().copied());
+            if names.last() != Some(&leaf) {
+                names.push(leaf);
+            }
+            *folded.entry(names.join(";")).or_default() += cycles;
+        }
+        let mut folded: Vec<(String, u64)> = folded.into_iter().collect();
+        folded.sort();
+        for (stack, cycles) in folded {
+            writeln!(w, "{} {}", stack, cycles)?;
+        }
+        if self.dma_cycles != 0 {
+            writeln!(w, "[dma] {}", self.dma_cycles)?;
+        }
+        if self.halt_cycles != 0 {
+            writeln!(w, "[halted] {}", self.halt_cycles)?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of the function `pc` belongs to, by symbol if possible, else by its entry point or at least its memory region
+fn function_name<'a, S>(symbolize: &S, pc: Addr, entry: Option<Addr>) -> String
+where
+    S: Fn(Addr) -> Option<(&'a str, u32)>,
+{
+    if let Some((name, _)) = symbolize(pc) {
+        return name.to_owned();
+    }
+    if let Some(entry) = entry {
+        return format!("sub_{:08x}", entry);
+    }
+    let region = match pc >> 24 {
+        0x00 => "bios",
+        0x02 => "ewram",
+        0x03 => "iwram",
+        0x08..=0x0d => "rom",
+        _ => "unknown",
+    };
+    format!("[{}]", region)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbolize(addr: Addr) -> Option<(&'static str, u32)> {
+        match addr {
+            0x0800_0000..=0x0800_00ff => Some(("main", addr - 0x0800_0000)),
+            0x0800_0100..=0x0800_01ff => Some(("update", addr - 0x0800_0100)),
+            _ => None,
+        }
+    }
+
+    fn execute(profiler: &mut Profiler, stack: &[Addr], pc: Addr, cycles: usize) {
+        profiler.current = stack.to_vec();
+        profiler.current.push(pc);
+        profiler.end_instruction(cycles);
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut profiler = Profiler::new();
+        execute(&mut profiler, &[], 0x0800_0000, 3);
+        execute(&mut profiler, &[0x0800_0100], 0x0800_0100, 5);
+        execute(&mut profiler, &[0x0800_0100], 0x0800_0104, 5);
+        execute(&mut profiler, &[0x0800_0100, 0x0300_0000], 0x0300_0000, 1);
+        execute(&mut profiler, &[0x0800_0100, 0x0300_0000], 0x0300_0000, 1);
+        profiler.record_halt(10);
+
+        assert_eq!(profiler.cpu_cycles(), 15);
+        assert_eq!(profiler.total_cycles(), 25);
+        assert_eq!(
+            profiler.pc_stats()[&0x0300_0000],
+            PcStats {
+                instructions: 2,
+                cycles: 2,
+                function_entry: Some(0x0300_0000),
+            }
+        );
+        let functions = profiler.functions(symbolize);
+        let summary: Vec<(&str, u64, u64)> = functions
+            .iter()
+            .map(|f| (f.name.as_str(), f.instructions, f.cycles))
+            .collect();
+        assert_eq!(
+            summary,
+            [("update", 2, 10), ("main", 1, 3), ("sub_03000000", 2, 2)]
+        );
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        let mut profiler = Profiler::new();
+        execute(&mut profiler, &[], 0x0800_0000, 3);
+        execute(&mut profiler, &[], 0x0200_0000, 2);
+        execute(&mut profiler, &[0x0800_0100], 0x0800_0100, 5);
+        execute(&mut profiler, &[0x0800_0100], 0x0800_0104, 5);
+        execute(&mut profiler, &[0x0800_0100, 0x0300_0000], 0x0300_0010, 1);
+        profiler.record_dma(7);
+
+        let mut folded = vec![];
+        profiler.write_folded(&mut folded, symbolize).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "[ewram] 2\n\
+             main 3\n\
+             update 10\n\
+             update;sub_03000000 1\n\
+             [dma] 7\n"
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let mut profiler = Profiler::new();
+        execute(&mut profiler, &[], 0x0800_0004, 4);
+        let mut report = vec![];
+        profiler.write_report(&mut report, symbolize, 10).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("4 cycles profiled: cpu 100.0%, dma 0.0%, halted 0.0%\n"));
+        assert!(report.contains("  main\n"));
+        assert!(report.contains("  0x08000004 main+0x4\n"));
+    }
+}
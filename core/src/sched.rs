@@ -193,7 +193,8 @@ impl Scheduler {
     #[inline]
     pub fn get_cycles_to_next_event(&self) -> usize {
         if let Some(event) = self.events.peek() {
-            event.time - self.timestamp
+            // Events can be overdue, the cpu doesn't stop in the middle of an instruction
+            event.time.saturating_sub(self.timestamp)
         } else {
             0
         }
@@ -1,16 +1,22 @@
 use serde::{Deserialize, Serialize};
 
 use super::arm7tdmi::memory::{
-    Addr, BusIO, DebugRead, MemoryAccess, MemoryAccessWidth, MemoryInterface,
+    Addr, BusIO, DebugRead, DebugWrite, MemoryAccess, MemoryAccessWidth, MemoryInterface,
 };
 use super::bios::Bios;
 use super::cartridge::Cartridge;
 use super::dma::DmaNotifer;
+use super::iodev::consts::{REG_TM0CNT_L, REG_TM3CNT_H};
 use super::iodev::{IoDevices, WaitControl};
 use super::sched::*;
 use arm7tdmi::Arm7tdmiCore;
 use rustboyadvance_utils::{Shared, WeakPointer};
 
+use std::ops::{Deref, DerefMut};
+
+mod page_table;
+use page_table::{PageAccess, PageTable, PAGE_SHIFT, PAGE_SIZE};
+
 pub mod consts {
     pub const WORK_RAM_SIZE: usize = 256 * 1024;
     pub const INTERNAL_RAM_SIZE: usize = 32 * 1024;
@@ -143,28 +149,52 @@ impl CycleLookupTables {
     }
 }
 
-#[derive(Clone)]
 pub struct SysBus {
-    pub io: Shared<IoDevices>,
+    /// The page table points into the video memory of the gpu, replace it only through `set_io_devices`
+    pub(crate) io: Shared<IoDevices>,
     scheduler: Shared<Scheduler>,
-    arm_core: WeakPointer<Arm7tdmiCore<SysBus>>,
+    arm_core: WeakPointer<Arm7tdmiCore<CpuBus>>,
 
     pub(crate) bios: Bios,
     pub(crate) ewram: Box<[u8]>,
     pub(crate) iwram: Box<[u8]>,
-    pub cartridge: Cartridge,
+    /// The page table points into the rom, which stays in place as long as the cartridge is inserted
+    pub(crate) cartridge: Cartridge,
 
     cycle_luts: CycleLookupTables,
+    /// Fast path for the plain memory regions, must be remapped whenever one of their buffers is replaced
+    pages: PageTable,
 
     pub trace_access: bool,
 }
 
 pub type SysBusPtr = WeakPointer<SysBus>;
 
+/// The clone gets its own memory buffers, so its page table is built anew
+impl Clone for SysBus {
+    fn clone(&self) -> SysBus {
+        let mut sysbus = SysBus {
+            io: self.io.clone(),
+            scheduler: self.scheduler.clone(),
+            arm_core: self.arm_core.clone(),
+            bios: self.bios.clone(),
+            ewram: self.ewram.clone(),
+            iwram: self.iwram.clone(),
+            cartridge: self.cartridge.clone(),
+            cycle_luts: self.cycle_luts.clone(),
+            pages: PageTable::new(),
+            trace_access: self.trace_access,
+        };
+        sysbus.remap_pages();
+        sysbus
+    }
+}
+
 impl SchedulerConnect for SysBus {
     fn connect_scheduler(&mut self, scheduler: SharedScheduler) {
         self.scheduler = scheduler.clone();
         self.io.connect_scheduler(scheduler.clone());
+        self.cartridge.connect_scheduler(scheduler.clone());
     }
 }
 
@@ -181,7 +211,9 @@ impl SysBus {
         luts.init();
         luts.update_gamepak_waitstates(io.waitcnt);
 
-        SysBus {
+        let mut cartridge = cartridge;
+        cartridge.connect_scheduler(scheduler.clone());
+        let mut sysbus = SysBus {
             io,
             scheduler,
             arm_core: WeakPointer::default(),
@@ -191,8 +223,11 @@ impl SysBus {
             ewram,
             iwram,
             cycle_luts: luts,
+            pages: PageTable::new(),
             trace_access: false,
-        }
+        };
+        sysbus.remap_pages();
+        sysbus
     }
 
     pub fn new(
@@ -208,10 +243,12 @@ impl SysBus {
 
     pub fn set_ewram(&mut self, buffer: Box<[u8]>) {
         self.ewram = buffer;
+        self.remap_pages();
     }
 
     pub fn set_iwram(&mut self, buffer: Box<[u8]>) {
         self.iwram = buffer;
+        self.remap_pages();
     }
 
     pub fn get_ewram(&self) -> &[u8] {
@@ -224,15 +261,74 @@ impl SysBus {
 
     pub fn set_io_devices(&mut self, io_devs: Shared<IoDevices>) {
         self.io = io_devs;
+        self.remap_pages();
+    }
+
+    /// Rebuilds the page table from the current memory buffers.
+    /// The BIOS is read protected, so it is left to the slow path along with I/O, backup media and eeprom.
+    fn remap_pages(&mut self) {
+        let mut pages = PageTable::new();
+        pages.map_mirrored(EWRAM_ADDR, &mut self.ewram, PageAccess::AllWrites);
+        pages.map_mirrored(IWRAM_ADDR, &mut self.iwram, PageAccess::AllWrites);
+
+        // 8bit writes to video memory are either ignored or duplicated to both halves
+        let gpu = &mut self.io.gpu;
+        pages.map_mirrored(
+            PALRAM_ADDR,
+            gpu.get_palette_ram_mut(),
+            PageAccess::WideWrites,
+        );
+        pages.map_mirrored(OAM_ADDR, gpu.get_oam_mut(), PageAccess::WideWrites);
+        let vram = gpu.get_vram_mut().as_mut_ptr();
+        for page_addr in (VRAM_ADDR..VRAM_ADDR + 0x0100_0000).step_by(PAGE_SIZE as usize) {
+            // 96KB mirrored every 128KB, the last 32KB mirror the object tiles
+            unsafe {
+                match page_addr & 0x1_ffff {
+                    0 => pages.map_page(page_addr, vram, 0xffff, PageAccess::WideWrites),
+                    _ => pages.map_page(
+                        page_addr,
+                        vram.add(0x1_0000),
+                        0x7fff,
+                        PageAccess::WideWrites,
+                    ),
+                }
+            }
+        }
+
+        // Pages past the end of the rom read as open bus, and the first one holds the gpio port if there is one.
+        // The upper 16MB of WS2 are left to the eeprom.
+        let rom = self.cartridge.get_rom_bytes();
+        let first_page = if self.cartridge.get_gpio().is_some() {
+            1
+        } else {
+            0
+        };
+        let num_pages = rom.len() >> PAGE_SHIFT;
+        for (mirror, max_pages) in [
+            (GAMEPAK_WS0_LO, 0x200),
+            (GAMEPAK_WS1_LO, 0x200),
+            (GAMEPAK_WS2_LO, 0x100),
+        ] {
+            for page in first_page..num_pages.min(max_pages) {
+                let offset = page << PAGE_SHIFT;
+                // Read only pages are never written through
+                let memory = unsafe { rom.as_ptr().add(offset) as *mut u8 };
+                unsafe {
+                    pages.map_page(mirror + offset as u32, memory, 0xffff, PageAccess::ReadOnly)
+                };
+            }
+        }
+        self.pages = pages;
     }
 
     /// must be called whenever this object is instanciated
-    pub fn init(&mut self, arm_core: WeakPointer<Arm7tdmiCore<SysBus>>) {
+    pub fn init(&mut self, arm_core: WeakPointer<Arm7tdmiCore<CpuBus>>) {
         self.arm_core = arm_core.clone();
         self.bios.connect_arm_core(arm_core);
         let ptr = SysBusPtr::new(self as *mut SysBus);
         // HACK
         self.io.set_sysbus_ptr(ptr);
+        self.remap_pages();
     }
 
     pub fn on_waitcnt_written(&mut self, waitcnt: WaitControl) {
@@ -310,6 +406,9 @@ impl SysBus {
 impl BusIO for SysBus {
     #[inline]
     fn read_32(&mut self, addr: Addr) -> u32 {
+        if let Some(value) = self.pages.read_32(addr) {
+            return value;
+        }
         match addr & 0xff000000 {
             BIOS_ADDR => {
                 if addr <= 0x3ffc {
@@ -340,6 +439,9 @@ impl BusIO for SysBus {
 
     #[inline]
     fn read_16(&mut self, addr: Addr) -> u16 {
+        if let Some(value) = self.pages.read_16(addr) {
+            return value;
+        }
         match addr & 0xff000000 {
             BIOS_ADDR => {
                 if addr <= 0x3ffe {
@@ -370,6 +472,9 @@ impl BusIO for SysBus {
 
     #[inline]
     fn read_8(&mut self, addr: Addr) -> u8 {
+        if let Some(value) = self.pages.read_8(addr) {
+            return value;
+        }
         match addr & 0xff000000 {
             BIOS_ADDR => {
                 if addr <= 0x3fff {
@@ -400,6 +505,9 @@ impl BusIO for SysBus {
 
     #[inline]
     fn write_32(&mut self, addr: Addr, value: u32) {
+        if self.pages.write_32(addr, value) {
+            return;
+        }
         match addr & 0xff000000 {
             BIOS_ADDR => {}
             EWRAM_ADDR => self.ewram.write_32(addr & 0x3_fffc, value),
@@ -425,6 +533,9 @@ impl BusIO for SysBus {
 
     #[inline]
     fn write_16(&mut self, addr: Addr, value: u16) {
+        if self.pages.write_16(addr, value) {
+            return;
+        }
         match addr & 0xff000000 {
             BIOS_ADDR => {}
             EWRAM_ADDR => self.ewram.write_16(addr & 0x3_fffe, value),
@@ -450,6 +561,9 @@ impl BusIO for SysBus {
 
     #[inline]
     fn write_8(&mut self, addr: Addr, value: u8) {
+        if self.pages.write_8(addr, value) {
+            return;
+        }
         match addr & 0xff000000 {
             BIOS_ADDR => {}
             EWRAM_ADDR => self.ewram.write_8(addr & 0x3_ffff, value),
@@ -502,6 +616,22 @@ impl DebugRead for SysBus {
     }
 }
 
+impl DebugWrite for SysBus {
+    fn debug_write_8(&mut self, addr: Addr, value: u8) {
+        match addr & 0xff000000 {
+            BIOS_ADDR => self.bios.debug_write_8(addr, value),
+            EWRAM_ADDR => self.ewram.write_8(addr & 0x3_ffff, value),
+            IWRAM_ADDR => self.iwram.write_8(addr & 0x7fff, value),
+            // Registers are written like the cpu would, their side effects included
+            IOMEM_ADDR => self.write_8(addr, value),
+            PALRAM_ADDR | VRAM_ADDR | OAM_ADDR => self.io.gpu.debug_write_8(addr, value),
+            GAMEPAK_WS0_LO | GAMEPAK_WS0_HI | GAMEPAK_WS1_LO | GAMEPAK_WS1_HI | GAMEPAK_WS2_LO
+            | GAMEPAK_WS2_HI | SRAM_LO | SRAM_HI => self.cartridge.debug_write_8(addr, value),
+            _ => {}
+        }
+    }
+}
+
 impl MemoryInterface for SysBus {
     #[inline]
     fn load_8(&mut self, addr: u32, access: MemoryAccess) -> u8 {
@@ -545,8 +675,317 @@ impl MemoryInterface for SysBus {
     }
 }
 
+/// The cpu's handle to the system bus, which is shared with the dma controller and the rest of the system
+#[derive(Clone)]
+pub struct CpuBus {
+    sysbus: Shared<SysBus>,
+    /// Counts the accesses that change the state of the system, or read state that changes without a scheduler event.
+    /// A loop that makes none of them can only be waiting for the next event.
+    side_effects: usize,
+}
+
+impl CpuBus {
+    pub fn new(sysbus: Shared<SysBus>) -> CpuBus {
+        CpuBus {
+            sysbus,
+            side_effects: 0,
+        }
+    }
+
+    pub(crate) fn side_effects(&self) -> usize {
+        self.side_effects
+    }
+
+    /// Timer counters are computed from the current timestamp, and reading eeprom or the backup chips advances their state
+    #[inline]
+    fn is_volatile_read(addr: Addr) -> bool {
+        match addr & 0xff00_0000 {
+            IOMEM_ADDR => (REG_TM0CNT_L..REG_TM3CNT_H + 2).contains(&addr),
+            GAMEPAK_WS2_HI | SRAM_LO | SRAM_HI => true,
+            _ => false,
+        }
+    }
+
+    #[inline]
+    fn note_read(&mut self, addr: Addr) {
+        if Self::is_volatile_read(addr) {
+            self.side_effects = self.side_effects.wrapping_add(1);
+        }
+    }
+
+    #[inline]
+    fn note_write(&mut self) {
+        self.side_effects = self.side_effects.wrapping_add(1);
+    }
+}
+
+impl Deref for CpuBus {
+    type Target = SysBus;
+
+    fn deref(&self) -> &SysBus {
+        &self.sysbus
+    }
+}
+
+impl DerefMut for CpuBus {
+    fn deref_mut(&mut self) -> &mut SysBus {
+        &mut self.sysbus
+    }
+}
+
+impl MemoryInterface for CpuBus {
+    #[inline]
+    fn load_8(&mut self, addr: u32, access: MemoryAccess) -> u8 {
+        self.note_read(addr);
+        self.sysbus.load_8(addr, access)
+    }
+
+    #[inline]
+    fn load_16(&mut self, addr: u32, access: MemoryAccess) -> u16 {
+        self.note_read(addr);
+        self.sysbus.load_16(addr, access)
+    }
+
+    #[inline]
+    fn load_32(&mut self, addr: u32, access: MemoryAccess) -> u32 {
+        self.note_read(addr);
+        self.sysbus.load_32(addr, access)
+    }
+
+    #[inline]
+    fn store_8(&mut self, addr: u32, value: u8, access: MemoryAccess) {
+        self.note_write();
+        self.sysbus.store_8(addr, value, access)
+    }
+
+    #[inline]
+    fn store_16(&mut self, addr: u32, value: u16, access: MemoryAccess) {
+        self.note_write();
+        self.sysbus.store_16(addr, value, access)
+    }
+
+    #[inline]
+    fn store_32(&mut self, addr: u32, value: u32, access: MemoryAccess) {
+        self.note_write();
+        self.sysbus.store_32(addr, value, access)
+    }
+
+    #[inline]
+    fn idle_cycle(&mut self) {
+        self.sysbus.idle_cycle()
+    }
+}
+
+impl BusIO for CpuBus {
+    fn read_32(&mut self, addr: Addr) -> u32 {
+        self.sysbus.read_32(addr)
+    }
+
+    fn read_16(&mut self, addr: Addr) -> u16 {
+        self.sysbus.read_16(addr)
+    }
+
+    fn read_8(&mut self, addr: Addr) -> u8 {
+        self.sysbus.read_8(addr)
+    }
+
+    fn write_32(&mut self, addr: Addr, value: u32) {
+        self.note_write();
+        self.sysbus.write_32(addr, value)
+    }
+
+    fn write_16(&mut self, addr: Addr, value: u16) {
+        self.note_write();
+        self.sysbus.write_16(addr, value)
+    }
+
+    fn write_8(&mut self, addr: Addr, value: u8) {
+        self.note_write();
+        self.sysbus.write_8(addr, value)
+    }
+}
+
+impl DebugRead for CpuBus {
+    fn debug_read_8(&mut self, addr: Addr) -> u8 {
+        self.sysbus.debug_read_8(addr)
+    }
+}
+
+impl DebugWrite for CpuBus {
+    fn debug_write_8(&mut self, addr: Addr, value: u8) {
+        self.note_write();
+        self.sysbus.debug_write_8(addr, value)
+    }
+}
+
 impl DmaNotifer for SysBus {
     fn notify(&mut self, timing: u16) {
         self.io.dmac.notify_from_gpu(timing);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::prelude::*;
+
+    fn memories(bus: &SysBus) -> Vec<Box<[u8]>> {
+        vec![
+            bus.ewram.clone(),
+            bus.iwram.clone(),
+            bus.io.gpu.get_palette_ram().into(),
+            bus.io.gpu.get_vram().into(),
+            bus.io.gpu.get_oam().into(),
+        ]
+    }
+
+    /// Copies the contents in place, replacing the buffers would leave the page table dangling
+    fn restore_memories(bus: &mut SysBus, memories: &[Box<[u8]>]) {
+        bus.ewram.copy_from_slice(&memories[0]);
+        bus.iwram.copy_from_slice(&memories[1]);
+        let gpu = &mut bus.io.gpu;
+        gpu.get_palette_ram_mut().copy_from_slice(&memories[2]);
+        gpu.get_vram_mut().copy_from_slice(&memories[3]);
+        gpu.get_oam_mut().copy_from_slice(&memories[4]);
+    }
+
+    #[test]
+    fn test_page_table_matches_slow_path() {
+        const ADDRESSES: &[Addr] = &[
+            0x0200_0000,
+            0x0203_fffc,
+            0x02ff_fff0,
+            0x0300_7ffc,
+            0x03ff_8000,
+            0x0500_03fc,
+            0x0506_0400,
+            0x0600_0000,
+            0x0601_7ffc,
+            0x0601_8000,
+            0x0601_fffc,
+            0x0602_0000,
+            0x0603_8004,
+            0x06ff_fffc,
+            0x0700_0000,
+            0x07ff_fffc,
+            0x0800_0000,
+            0x0800_00c4,
+            0x0802_0000,
+            0x0802_7ffc,
+            0x0802_8000,
+            0x0a01_0004,
+            0x0c02_7ff0,
+        ];
+
+        for with_rtc in [false, true] {
+            // Ends halfway through a page, so the last page takes the slow path
+            let mut rom: Vec<u8> = (0..0x2_8000u32).map(|i| (i * 7 + (i >> 8)) as u8).collect();
+            rom[0xa0..0xc0].fill(0);
+            let mut builder = GamepakBuilder::new()
+                .buffer(&rom)
+                .with_sram()
+                .without_backup_to_file();
+            if with_rtc {
+                builder = builder.with_rtc();
+            }
+            let bios = vec![0; 0x4000].into_boxed_slice();
+            let gba = GameBoyAdvance::new(bios, builder.build().unwrap(), NullAudio::new());
+
+            let mut fast = gba.sysbus.clone();
+            for (i, byte) in fast.ewram.iter_mut().enumerate() {
+                *byte = (i * 3) as u8;
+            }
+            for (i, byte) in fast.iwram.iter_mut().enumerate() {
+                *byte = (i * 5) as u8;
+            }
+            for (i, byte) in fast.io.gpu.get_vram_mut().iter_mut().enumerate() {
+                *byte = (i * 11 + (i >> 9)) as u8;
+            }
+            // Without a page table this one goes through the slow path only
+            let mut slow = (*fast).clone();
+            slow.pages = PageTable::new();
+
+            // The cpu aligns its accesses before they reach the bus, so only aligned ones are compared
+            for &base in ADDRESSES {
+                for addr in base..base + 4 {
+                    assert_eq!(
+                        fast.read_32(addr & !3),
+                        slow.read_32(addr & !3),
+                        "{:08x}",
+                        addr
+                    );
+                    assert_eq!(
+                        fast.read_16(addr & !1),
+                        slow.read_16(addr & !1),
+                        "{:08x}",
+                        addr
+                    );
+                    assert_eq!(fast.read_8(addr), slow.read_8(addr), "{:08x}", addr);
+                }
+            }
+
+            for &base in ADDRESSES.iter().filter(|&&addr| addr < 0x0800_0000) {
+                for width in [8, 16, 32] {
+                    let addr = (base + 3) & !(width / 8 - 1);
+                    let before = memories(&fast);
+                    let write = |bus: &mut SysBus| match width {
+                        8 => bus.write_8(addr, 0xa5),
+                        16 => bus.write_16(addr, 0xa55a),
+                        _ => bus.write_32(addr, 0xa55a_1234),
+                    };
+                    write(&mut fast);
+                    let after_fast = memories(&fast);
+                    // Video memory is shared between the two, so put it back before repeating the write
+                    restore_memories(&mut fast, &before);
+                    restore_memories(&mut slow, &before);
+                    write(&mut slow);
+                    assert!(
+                        after_fast == memories(&slow),
+                        "{}bit write to {:08x}",
+                        width,
+                        addr
+                    );
+                    restore_memories(&mut fast, &after_fast);
+                    restore_memories(&mut slow, &after_fast);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_clone_maps_its_own_memory() {
+        let bios = vec![0; 0x4000].into_boxed_slice();
+        let gba = GameBoyAdvance::new(bios, Cartridge::empty(), NullAudio::new());
+        let mut bus = (*gba.sysbus).clone();
+        bus.ewram[0] = 0x5a;
+        assert_eq!(bus.pages.read_8(EWRAM_ADDR), Some(0x5a));
+        assert_eq!(gba.sysbus.pages.read_8(EWRAM_ADDR), Some(0));
+    }
+
+    #[test]
+    fn test_debug_write_video_memory() {
+        let bios = vec![0; 0x4000].into_boxed_slice();
+        let gba = GameBoyAdvance::new(bios, Cartridge::empty(), NullAudio::new());
+        let mut bus = gba.sysbus.clone();
+
+        // The cpu would duplicate these to both bytes of the halfword, or drop them for OAM and object tiles
+        for addr in [
+            0x0500_0011,
+            0x0600_0021,
+            0x0601_0031,
+            0x0700_0041,
+            0x0203_0001,
+        ] {
+            bus.write_16(addr & !1, 0x1234);
+            bus.debug_write_8(addr, 0xab);
+            assert_eq!(bus.debug_read_8(addr), 0xab, "{:08x}", addr);
+            assert_eq!(bus.read_16(addr & !1), 0xab34, "{:08x}", addr);
+        }
+
+        // The mirror of the object tiles
+        bus.debug_write_8(0x0601_8000, 0xcd);
+        assert_eq!(bus.read_8(0x0601_0000), 0xcd);
+        assert_eq!(bus.debug_read_8(0x0601_8000), 0xcd);
+    }
+}
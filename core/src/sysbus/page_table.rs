@@ -0,0 +1,194 @@
+/// Page table mapping the plain memory regions of the address space straight to host memory.
+///
+/// Accesses to unmapped pages return None and take the slow path of SysBus, which handles I/O, the backup media, the
+/// read protected BIOS, open bus, and the byte write quirks of video memory.
+use std::ptr;
+
+use arm7tdmi::memory::Addr;
+
+pub const PAGE_SHIFT: u32 = 16;
+pub const PAGE_SIZE: u32 = 1 << PAGE_SHIFT;
+/// Only the low 256MB of the address space is backed by anything
+const NUM_PAGES: usize = 1 << (28 - PAGE_SHIFT);
+
+#[derive(Debug, Clone, Copy)]
+struct Page {
+    /// Host memory for the page, null for pages that take the slow path
+    ptr: *mut u8,
+    /// Applied to the address, smaller than the page for regions that mirror within a page
+    mask: u32,
+}
+
+impl Default for Page {
+    fn default() -> Page {
+        Page {
+            ptr: ptr::null_mut(),
+            mask: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageAccess {
+    ReadOnly,
+    /// 8bit writes take the slow path
+    WideWrites,
+    AllWrites,
+}
+
+pub struct PageTable {
+    read: Box<[Page]>,
+    write: Box<[Page]>,
+    write_8: Box<[Page]>,
+}
+
+impl Default for PageTable {
+    fn default() -> PageTable {
+        PageTable::new()
+    }
+}
+
+impl PageTable {
+    pub fn new() -> PageTable {
+        PageTable {
+            read: vec![Page::default(); NUM_PAGES].into_boxed_slice(),
+            write: vec![Page::default(); NUM_PAGES].into_boxed_slice(),
+            write_8: vec![Page::default(); NUM_PAGES].into_boxed_slice(),
+        }
+    }
+
+    /// Maps the page at `page_addr` to `memory`, accessed at `addr & mask`.
+    ///
+    /// # Safety
+    /// `memory` must stay alive and in place, and hold `mask + 1` bytes, until the table is remapped or dropped
+    pub unsafe fn map_page(
+        &mut self,
+        page_addr: Addr,
+        memory: *mut u8,
+        mask: u32,
+        access: PageAccess,
+    ) {
+        let index = (page_addr >> PAGE_SHIFT) as usize;
+        let page = Page {
+            ptr: memory.add((page_addr & mask) as usize),
+            mask: mask & (PAGE_SIZE - 1),
+        };
+        self.read[index] = page;
+        self.write[index] = match access {
+            PageAccess::ReadOnly => Page::default(),
+            _ => page,
+        };
+        self.write_8[index] = match access {
+            PageAccess::AllWrites => page,
+            _ => Page::default(),
+        };
+    }
+
+    /// Maps the 16MB region starting at `region` to `memory`, mirrored every `memory.len()` bytes
+    pub fn map_mirrored(&mut self, region: Addr, memory: &mut [u8], access: PageAccess) {
+        debug_assert!(memory.len().is_power_of_two());
+        let mask = memory.len() as u32 - 1;
+        for page_addr in (region..region + 0x0100_0000).step_by(PAGE_SIZE as usize) {
+            unsafe { self.map_page(page_addr, memory.as_mut_ptr(), mask, access) };
+        }
+    }
+
+    #[inline]
+    fn page(table: &[Page], addr: Addr) -> Option<&Page> {
+        table
+            .get((addr >> PAGE_SHIFT) as usize)
+            .filter(|page| !page.ptr.is_null())
+    }
+
+    #[inline]
+    pub fn read_32(&self, addr: Addr) -> Option<u32> {
+        let page = Self::page(&self.read, addr)?;
+        let ptr = unsafe { page.ptr.add((addr & page.mask & !3) as usize) };
+        Some(u32::from_le(unsafe {
+            (ptr as *const u32).read_unaligned()
+        }))
+    }
+
+    #[inline]
+    pub fn read_16(&self, addr: Addr) -> Option<u16> {
+        let page = Self::page(&self.read, addr)?;
+        let ptr = unsafe { page.ptr.add((addr & page.mask & !1) as usize) };
+        Some(u16::from_le(unsafe {
+            (ptr as *const u16).read_unaligned()
+        }))
+    }
+
+    #[inline]
+    pub fn read_8(&self, addr: Addr) -> Option<u8> {
+        let page = Self::page(&self.read, addr)?;
+        Some(unsafe { *page.ptr.add((addr & page.mask) as usize) })
+    }
+
+    /// Returns false if the write has to take the slow path
+    #[inline]
+    pub fn write_32(&mut self, addr: Addr, value: u32) -> bool {
+        match Self::page(&self.write, addr) {
+            Some(page) => {
+                let ptr = unsafe { page.ptr.add((addr & page.mask & !3) as usize) };
+                unsafe { (ptr as *mut u32).write_unaligned(value.to_le()) };
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[inline]
+    pub fn write_16(&mut self, addr: Addr, value: u16) -> bool {
+        match Self::page(&self.write, addr) {
+            Some(page) => {
+                let ptr = unsafe { page.ptr.add((addr & page.mask & !1) as usize) };
+                unsafe { (ptr as *mut u16).write_unaligned(value.to_le()) };
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[inline]
+    pub fn write_8(&mut self, addr: Addr, value: u8) -> bool {
+        match Self::page(&self.write_8, addr) {
+            Some(page) => {
+                unsafe { *page.ptr.add((addr & page.mask) as usize) = value };
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mirroring() {
+        let mut small = vec![0u8; 0x400];
+        let mut large = vec![0u8; 0x4_0000];
+        let mut pages = PageTable::new();
+        pages.map_mirrored(0x0500_0000, &mut small, PageAccess::WideWrites);
+        pages.map_mirrored(0x0200_0000, &mut large, PageAccess::AllWrites);
+
+        assert!(pages.write_32(0x0500_0404, 0xdead_beef));
+        assert_eq!(pages.read_32(0x05ff_fc04), Some(0xdead_beef));
+        assert_eq!(pages.read_16(0x0500_0006), Some(0xdead));
+        assert!(!pages.write_8(0x0500_0004, 0));
+
+        assert!(pages.write_8(0x0203_ffff, 0x12));
+        assert!(pages.write_16(0x0207_0002, 0x3456));
+        assert_eq!(pages.read_32(0x02ff_0000), Some(0x3456_0000));
+        assert_eq!(pages.read_8(0x02ff_ffff), Some(0x12));
+        assert_eq!(
+            (large[0x3_ffff], large[0x3_0002], large[0x3_0003]),
+            (0x12, 0x56, 0x34)
+        );
+
+        assert_eq!(pages.read_32(0x0300_0000), None);
+        assert_eq!(pages.read_8(0x1000_0000), None);
+        assert!(!pages.write_32(0xffff_fffc, 0));
+    }
+}
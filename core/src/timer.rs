@@ -48,10 +48,11 @@ impl Timer {
         0x1_0000 - (self.data as u32)
     }
 
+    /// The counter value at `timestamp`, `data` only holds the value the timer was started or reloaded with
     #[inline]
-    fn sync_timer_data(&mut self, timestamp: usize) {
+    fn current_data(&self, timestamp: usize) -> u16 {
         let ticks_passed = (timestamp - self.start_time) >> self.prescalar_shift;
-        self.data += ticks_passed as u16;
+        self.data.wrapping_add(ticks_passed as u16)
     }
 
     #[inline]
@@ -212,14 +213,14 @@ impl Timers {
 
     #[inline]
     fn read_timer_data(&mut self, id: usize, sched: &Scheduler) -> u16 {
-        let timer = &mut self.timers[id];
+        let timer = &self.timers[id];
         if timer.is_scheduled {
             // this timer is controlled by the sched so we need to manually calculate
             // the current value of the counter
-            timer.sync_timer_data(sched.timestamp());
+            timer.current_data(sched.timestamp())
+        } else {
+            timer.data
         }
-
-        timer.data
     }
 
     pub fn handle_read(&mut self, io_addr: u32, sched: &Scheduler) -> u16 {
@@ -1,6 +1,8 @@
 use goblin::elf;
 use log::{info, warn};
 use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
 
 pub use goblin::error::Error as GoblinError;
 
@@ -65,3 +67,86 @@ pub fn load_elf(elf_bytes: &[u8], base: usize) -> goblin::error::Result<LoadedEl
         symbols: read_symbols_from_elf(&elf),
     })
 }
+
+/// The machine code generated for a source line, from the DWARF line table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineRange {
+    /// First address of the code
+    pub start: u32,
+    /// Address following the code
+    pub end: u32,
+    pub file: PathBuf,
+    pub line: u32,
+}
+
+/// Reads the DWARF line tables of an ELF built with debug info (`-g`), like `addr2line` does
+pub fn read_line_table(elf_bytes: &[u8]) -> Result<Vec<LineRange>, Box<dyn Error>> {
+    let elf = elf::Elf::parse(elf_bytes)?;
+    let load_section = |id: gimli::SectionId| -> Result<&[u8], gimli::Error> {
+        let section = elf.section_headers.iter().find(
+            |sh| matches!(elf.shdr_strtab.get(sh.sh_name), Some(Ok(name)) if name == id.name()),
+        );
+        Ok(match section {
+            Some(sh) if sh.sh_type != elf::section_header::SHT_NOBITS => elf_bytes
+                .get(sh.file_range())
+                .ok_or(gimli::Error::UnexpectedEof(gimli::ReaderOffsetId(0)))?,
+            _ => &[],
+        })
+    };
+    let dwarf = gimli::Dwarf::load(load_section)?;
+    let dwarf = dwarf.borrow(|section| gimli::EndianSlice::new(section, gimli::LittleEndian));
+
+    let mut ranges = Vec::new();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let program = match unit.line_program.clone() {
+            Some(program) => program,
+            None => continue,
+        };
+        let comp_dir = unit
+            .comp_dir
+            .map(|dir| PathBuf::from(dir.to_string_lossy().as_ref()))
+            .unwrap_or_default();
+
+        // Each row starts the code of a line, which runs until the next row of the same sequence
+        let mut pending: Option<LineRange> = None;
+        let mut rows = program.rows();
+        while let Some((header, row)) = rows.next_row()? {
+            let address = row.address() as u32;
+            if let Some(mut range) = pending.take() {
+                range.end = address;
+                if range.end > range.start {
+                    ranges.push(range);
+                }
+            }
+            if row.end_sequence() {
+                continue;
+            }
+            let (file, line) = match (row.file(header), row.line()) {
+                (Some(file), Some(line)) => (file, line.get() as u32),
+                _ => continue,
+            };
+            let mut path = comp_dir.clone();
+            // Directory 0 is the compilation directory
+            if file.directory_index() != 0 {
+                if let Some(dir) = file.directory(header) {
+                    path.push(dwarf.attr_string(&unit, dir)?.to_string_lossy().as_ref());
+                }
+            }
+            path.push(
+                dwarf
+                    .attr_string(&unit, file.path_name())?
+                    .to_string_lossy()
+                    .as_ref(),
+            );
+            pending = Some(LineRange {
+                start: address,
+                end: address,
+                file: path,
+                line,
+            });
+        }
+    }
+    Ok(ranges)
+}